@@ -0,0 +1,44 @@
+// チャンネル数の違うバッファをつなぐときの対応
+// チャンネルの並びは CLAP と同じで L R の後に C LFE や後ろのスピーカーが続くとする
+// 4ch はクアッド (L R BL BR) として扱う。アンビソニックスはプラグインでデコードする
+
+const SURROUND_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+// (src のチャンネル, dst のチャンネル, ゲイン)
+pub fn channel_map(src_nchannels: usize, dst_nchannels: usize) -> Vec<(usize, usize, f32)> {
+    if src_nchannels == 0 || dst_nchannels == 0 {
+        return vec![];
+    }
+    if src_nchannels == dst_nchannels {
+        return (0..src_nchannels).map(|ch| (ch, ch, 1.0)).collect();
+    }
+    if src_nchannels == 1 {
+        return (0..dst_nchannels).map(|ch| (0, ch, 1.0)).collect();
+    }
+    if dst_nchannels == 1 {
+        let gain = 1.0 / src_nchannels as f32;
+        return (0..src_nchannels).map(|ch| (ch, 0, gain)).collect();
+    }
+    if dst_nchannels == 2 {
+        // サラウンドからステレオへのダウンミックス
+        let (center, rest_start) = match src_nchannels {
+            4 => (None, 2),
+            3 | 5 => (Some(2), 3),
+            // 3 は LFE なので捨てる
+            _ => (Some(2), 4),
+        };
+        let mut map = vec![(0, 0, 1.0), (1, 1, 1.0)];
+        if let Some(center) = center {
+            map.push((center, 0, SURROUND_GAIN));
+            map.push((center, 1, SURROUND_GAIN));
+        }
+        for ch in rest_start..src_nchannels {
+            map.push((ch, (ch - rest_start) % 2, SURROUND_GAIN));
+        }
+        return map;
+    }
+    // ステレオをサラウンドに入れるときは L R だけ
+    (0..src_nchannels.min(dst_nchannels))
+        .map(|ch| (ch, ch, 1.0))
+        .collect()
+}
@@ -39,17 +39,33 @@ impl ClapManager {
     }
 
     pub fn scan(&mut self) {
+        for path in self.scan_begin() {
+            self.scan_file(&path);
+        }
+        self.scan_end();
+    }
+
+    // プラグインの init は CLAP の仕様でメインスレッドから呼ぶので 1 ファイルずつ進められるように
+    pub fn scan_begin(&mut self) -> Vec<PathBuf> {
         self.descriptions.clear();
-        for path in self.find_clap_files(&Path::new("C:\\Program Files\\Common Files\\CLAP")) {
-            log::debug!("path {path:?}");
-            log::debug!("extension {:?}", path.extension());
-            if path.extension() == Some(OsStr::new("clap")) || path.is_dir() {
-                match self.scan_plugin_file(&path) {
-                    Ok(_) => (),
-                    Err(error) => log::error!("scan clap file is failed! {:?} {:?}", path, error),
-                }
+        clap_dirs()
+            .iter()
+            .flat_map(|dir| self.find_clap_files(dir))
+            .collect()
+    }
+
+    pub fn scan_file(&mut self, path: &Path) {
+        log::debug!("path {path:?}");
+        log::debug!("extension {:?}", path.extension());
+        if path.extension() == Some(OsStr::new("clap")) || path.is_dir() {
+            match self.scan_plugin_file(path) {
+                Ok(_) => (),
+                Err(error) => log::error!("scan clap file is failed! {:?} {:?}", path, error),
             }
         }
+    }
+
+    pub fn scan_end(&mut self) {
         self.descriptions.sort_by_key(|x| x.name.clone());
         self.save();
     }
@@ -150,3 +166,19 @@ impl ClapManager {
         Ok(())
     }
 }
+
+// CLAP の仕様で決まっている探す場所
+#[cfg(windows)]
+fn clap_dirs() -> Vec<PathBuf> {
+    vec![PathBuf::from("C:\\Program Files\\Common Files\\CLAP")]
+}
+
+#[cfg(not(windows))]
+fn clap_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(home) = std::env::var_os("HOME") {
+        dirs.push(PathBuf::from(home).join(".clap"));
+    }
+    dirs.push(PathBuf::from("/usr/lib/clap"));
+    dirs
+}
@@ -1,3 +1,5 @@
+use crate::process_data::{MAX_BUFFER_CHANNELS, MAX_FRAMES};
+
 const DB_CURVE_EXPONENT: f32 = 2.0;
 
 pub fn db_to_norm(db: f32, min_db: f32, max_db: f32) -> f32 {
@@ -15,3 +17,69 @@ pub fn db_from_norm(norm: f32, min_db: f32, max_db: f32) -> f32 {
 pub fn linear_to_db(val: f32) -> f32 {
     20.0 * val.max(1e-20).log10()
 }
+
+pub fn db_to_linear(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+// プラグインのレイテンシーに合わせて遅らせたドライ信号
+#[derive(Clone)]
+pub struct DelayLine {
+    buffer: Vec<Vec<f64>>,
+    delay: usize,
+    position: usize,
+    pub out: [[f64; MAX_FRAMES]; MAX_BUFFER_CHANNELS],
+}
+
+impl Default for DelayLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DelayLine {
+    pub fn new() -> Self {
+        Self {
+            buffer: vec![],
+            delay: 0,
+            position: 0,
+            out: [[0.0; MAX_FRAMES]; MAX_BUFFER_CHANNELS],
+        }
+    }
+
+    pub fn process(
+        &mut self,
+        delay: usize,
+        input: &[[f64; MAX_FRAMES]],
+        constant_mask: u64,
+        nframes: usize,
+    ) {
+        // レイテンシーが変わったときだけ確保し直す
+        if self.buffer.is_empty() || self.delay != delay {
+            self.buffer = vec![vec![0.0; delay]; MAX_BUFFER_CHANNELS];
+            self.delay = delay;
+            self.position = 0;
+        }
+        for (channel, (input, (out, buffer))) in input
+            .iter()
+            .zip(self.out.iter_mut().zip(self.buffer.iter_mut()))
+            .enumerate()
+        {
+            let constp = (constant_mask & (1 << channel)) != 0;
+            let mut position = self.position;
+            for (frame, out) in out.iter_mut().take(nframes).enumerate() {
+                let x = if constp { input[0] } else { input[frame] };
+                if delay == 0 {
+                    *out = x;
+                } else {
+                    *out = buffer[position];
+                    buffer[position] = x;
+                    position = (position + 1) % delay;
+                }
+            }
+        }
+        if delay != 0 {
+            self.position = (self.position + nframes) % delay;
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// メインとプラグインプロセスの間の通信
+// Windows は名前付きパイプとイベント、Linux は Unix ドメインソケットと共有メモリ上の futex
+
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::process_data::ProcessData;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use linux::{ControlClient, ControlServer, ProcessQuitter, ProcessRequester, ProcessResponder};
+#[cfg(windows)]
+pub use windows::{
+    ControlClient, ControlServer, ProcessQuitter, ProcessRequester, ProcessResponder,
+};
+
+// 1 ブロックの処理にこれ以上かかるならプラグインプロセスが落ちたか固まったとみなす
+pub const PROCESS_TIMEOUT: Duration = Duration::from_millis(250);
+
+// 制御用の接続のメイン側。bind してからプラグインプロセスを起動して接続を待つ
+pub trait ControlListen: Sized {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    fn control_bind() -> Result<Self>;
+    fn control_accept(self) -> impl Future<Output = Result<Self::Stream>> + Send;
+}
+
+// 制御用の接続のプラグインプロセス側
+pub trait ControlConnect: AsyncRead + AsyncWrite + Unpin + Send + Sized {
+    fn control_connect() -> impl Future<Output = Result<Self>> + Send;
+}
+
+pub enum ProcessWake {
+    Process,
+    Quit,
+}
+
+// Singer 側。1 ブロックの処理を頼んで終わるまで待つ。PROCESS_TIMEOUT を過ぎたらエラー
+pub trait ProcessRequest: Sized {
+    fn create(id: usize, process_data: *mut ProcessData) -> Result<Self>;
+    fn request(&self) -> Result<()>;
+    // 落ちたプロセスが残した合図を消す
+    fn reset(&self) -> Result<()>;
+}
+
+// プラグインプロセス側。頼まれるか終了するまで待つ
+pub trait ProcessResponse: Sized {
+    fn open(id: usize, process_data: *mut ProcessData) -> Result<Self>;
+    fn wait(&self) -> Result<ProcessWake>;
+    fn respond(&self) -> Result<()>;
+}
+
+// プラグインプロセスの中でホストの process_loop を終わらせる
+pub trait ProcessQuit: Sized {
+    fn create(id: usize) -> Result<Self>;
+    fn quit(&self) -> Result<()>;
+}
@@ -0,0 +1,178 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use shared_memory::Shmem;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{
+    process_data::ProcessData,
+    shmem::{open_shared_memory, process_data_name},
+};
+
+use super::{
+    ControlConnect, ControlListen, ProcessQuit, ProcessRequest, ProcessResponse, ProcessWake,
+    PROCESS_TIMEOUT,
+};
+
+pub type ControlServer = UnixListener;
+pub type ControlClient = UnixStream;
+
+// 複数起動してもぶつからないようにメインプロセスの PID を入れる
+fn control_socket_path(pid: u32) -> PathBuf {
+    std::env::temp_dir().join(format!("sing_like_coding.{pid}.ctrl.sock"))
+}
+
+impl ControlListen for UnixListener {
+    type Stream = UnixStream;
+
+    fn control_bind() -> Result<Self> {
+        let path = control_socket_path(std::process::id());
+        // 前回落ちたときのソケットが残っていることがある
+        let _ = std::fs::remove_file(&path);
+        Ok(UnixListener::bind(path)?)
+    }
+
+    async fn control_accept(self) -> Result<Self::Stream> {
+        let (stream, _addr) = UnixListener::accept(&self).await?;
+        Ok(stream)
+    }
+}
+
+impl ControlConnect for UnixStream {
+    async fn control_connect() -> Result<Self> {
+        // プラグインプロセスはメインプロセスから直接起動される
+        let path = control_socket_path(std::os::unix::process::parent_id());
+        Ok(UnixStream::connect(path).await?)
+    }
+}
+
+// 共有メモリはプロセスごとに別のアドレスにマップされるので FUTEX_PRIVATE_FLAG は付けない
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timespec = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timespec_ptr = timespec
+        .as_ref()
+        .map_or(std::ptr::null(), |x| x as *const libc::timespec);
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            timespec_ptr,
+        );
+    }
+}
+
+fn futex_wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX);
+    }
+}
+
+// 自動リセットのイベントと同じように使う
+fn signal_set(word: &AtomicU32) {
+    word.store(1, Ordering::Release);
+    futex_wake(word);
+}
+
+fn signal_wait(word: &AtomicU32) {
+    while word.swap(0, Ordering::Acquire) == 0 {
+        futex_wait(word, 0, None);
+    }
+}
+
+fn signal_wait_timeout(word: &AtomicU32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while word.swap(0, Ordering::Acquire) == 0 {
+        let rest = deadline.saturating_duration_since(Instant::now());
+        if rest.is_zero() {
+            return false;
+        }
+        futex_wait(word, 0, Some(rest));
+    }
+    true
+}
+
+#[derive(Clone)]
+pub struct ProcessRequester {
+    process_data: *mut ProcessData,
+}
+
+impl ProcessRequest for ProcessRequester {
+    fn create(_id: usize, process_data: *mut ProcessData) -> Result<Self> {
+        let this = Self { process_data };
+        // 前回の共有メモリが残っていたときのため
+        this.reset()?;
+        Ok(this)
+    }
+
+    fn request(&self) -> Result<()> {
+        let process_data = unsafe { &*self.process_data };
+        signal_set(&process_data.signal_request);
+        if !signal_wait_timeout(&process_data.signal_response, PROCESS_TIMEOUT) {
+            bail!("No response from the plugin process.");
+        }
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<()> {
+        let process_data = unsafe { &*self.process_data };
+        process_data.signal_request.store(0, Ordering::Release);
+        process_data.signal_response.store(0, Ordering::Release);
+        Ok(())
+    }
+}
+
+pub struct ProcessResponder {
+    process_data: *mut ProcessData,
+}
+
+impl ProcessResponse for ProcessResponder {
+    fn open(_id: usize, process_data: *mut ProcessData) -> Result<Self> {
+        Ok(Self { process_data })
+    }
+
+    fn wait(&self) -> Result<ProcessWake> {
+        let process_data = unsafe { &*self.process_data };
+        signal_wait(&process_data.signal_request);
+        if process_data.signal_quit.load(Ordering::Acquire) != 0 {
+            Ok(ProcessWake::Quit)
+        } else {
+            Ok(ProcessWake::Process)
+        }
+    }
+
+    fn respond(&self) -> Result<()> {
+        let process_data = unsafe { &*self.process_data };
+        signal_set(&process_data.signal_response);
+        Ok(())
+    }
+}
+
+pub struct ProcessQuitter {
+    shmem: Shmem,
+}
+
+impl ProcessQuit for ProcessQuitter {
+    fn create(id: usize) -> Result<Self> {
+        let shmem = open_shared_memory::<ProcessData>(&process_data_name(id))?;
+        let process_data = unsafe { &*(shmem.as_ptr() as *const ProcessData) };
+        process_data.signal_quit.store(0, Ordering::Release);
+        Ok(Self { shmem })
+    }
+
+    // 処理の依頼と同じ合図で起こして、quit を見て終わってもらう
+    fn quit(&self) -> Result<()> {
+        let process_data = unsafe { &*(self.shmem.as_ptr() as *const ProcessData) };
+        process_data.signal_quit.store(1, Ordering::Release);
+        signal_set(&process_data.signal_request);
+        Ok(())
+    }
+}
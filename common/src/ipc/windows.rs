@@ -0,0 +1,173 @@
+use std::ffi::CString;
+
+use anyhow::{bail, Result};
+use tokio::net::windows::named_pipe::{
+    ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+};
+use windows::{
+    core::PCSTR,
+    Win32::{
+        Foundation::{HANDLE, WAIT_EVENT, WAIT_OBJECT_0},
+        Storage::FileSystem::SYNCHRONIZE,
+        System::Threading::{
+            CreateEventA, OpenEventA, ResetEvent, SetEvent, WaitForMultipleObjects,
+            WaitForSingleObject, EVENT_MODIFY_STATE, INFINITE, SYNCHRONIZATION_ACCESS_RIGHTS,
+        },
+    },
+};
+
+use crate::{process_data::ProcessData, str::to_pcstr, PIPE_CTRL_NAME};
+
+use super::{
+    ControlConnect, ControlListen, ProcessQuit, ProcessRequest, ProcessResponse, ProcessWake,
+    PROCESS_TIMEOUT,
+};
+
+pub type ControlServer = NamedPipeServer;
+pub type ControlClient = NamedPipeClient;
+
+impl ControlListen for NamedPipeServer {
+    type Stream = NamedPipeServer;
+
+    fn control_bind() -> Result<Self> {
+        Ok(ServerOptions::new().create(PIPE_CTRL_NAME)?)
+    }
+
+    async fn control_accept(self) -> Result<Self::Stream> {
+        self.connect().await?;
+        Ok(self)
+    }
+}
+
+impl ControlConnect for NamedPipeClient {
+    async fn control_connect() -> Result<Self> {
+        Ok(ClientOptions::new().open(PIPE_CTRL_NAME)?)
+    }
+}
+
+fn event_request_name(id: usize) -> (PCSTR, CString) {
+    to_pcstr(&format!("SingLikeCoding.Process.Request.{}", id)).unwrap()
+}
+
+fn event_response_name(id: usize) -> (PCSTR, CString) {
+    to_pcstr(&format!("SingLikeCoding.Process.Response.{}", id)).unwrap()
+}
+
+fn event_quit_name(id: usize) -> (PCSTR, CString) {
+    to_pcstr(&format!("SingLikeCoding.Process.Quit.{}", id)).unwrap()
+}
+
+fn event_create(name: PCSTR) -> Result<HANDLE> {
+    let event = unsafe {
+        CreateEventA(
+            None,
+            false.into(), // 自動リセット
+            false.into(), // 初期非シグナル
+            name,
+        )?
+    };
+    Ok(event)
+}
+
+#[derive(Clone)]
+pub struct ProcessRequester {
+    event_request: HANDLE,
+    event_response: HANDLE,
+}
+
+impl ProcessRequest for ProcessRequester {
+    fn create(id: usize, _process_data: *mut ProcessData) -> Result<Self> {
+        let (event_name, _x) = event_request_name(id);
+        let event_request = event_create(event_name)?;
+        let (event_name, _x) = event_response_name(id);
+        let event_response = event_create(event_name)?;
+        Ok(Self {
+            event_request,
+            event_response,
+        })
+    }
+
+    fn request(&self) -> Result<()> {
+        unsafe { SetEvent(self.event_request) }?;
+        let event =
+            unsafe { WaitForSingleObject(self.event_response, PROCESS_TIMEOUT.as_millis() as u32) };
+        if event != WAIT_OBJECT_0 {
+            bail!("No response from the plugin process.");
+        }
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<()> {
+        unsafe { ResetEvent(self.event_request) }?;
+        unsafe { ResetEvent(self.event_response) }?;
+        Ok(())
+    }
+}
+
+pub struct ProcessResponder {
+    events_wait: [HANDLE; 2],
+    event_response: HANDLE,
+}
+
+impl ProcessResponse for ProcessResponder {
+    fn open(id: usize, _process_data: *mut ProcessData) -> Result<Self> {
+        let (event_name, _x) = event_request_name(id);
+        let event_request = unsafe {
+            OpenEventA(
+                EVENT_MODIFY_STATE | SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE.0),
+                false,
+                event_name,
+            )?
+        };
+
+        let (event_quit_name, _x) = event_quit_name(id);
+        let event_quit = unsafe {
+            OpenEventA(
+                SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE.0),
+                false,
+                event_quit_name,
+            )?
+        };
+
+        let (event_name, _x) = event_response_name(id);
+        let event_response = unsafe { OpenEventA(EVENT_MODIFY_STATE, false, event_name)? };
+
+        Ok(Self {
+            events_wait: [event_request, event_quit],
+            event_response,
+        })
+    }
+
+    fn wait(&self) -> Result<ProcessWake> {
+        let event = unsafe { WaitForMultipleObjects(&self.events_wait, false.into(), INFINITE) };
+        if event == WAIT_OBJECT_0 {
+            Ok(ProcessWake::Process)
+        } else if event == WAIT_EVENT(1) {
+            Ok(ProcessWake::Quit)
+        } else {
+            Err(anyhow::anyhow!("WaitForMultipleObjects failed"))
+        }
+    }
+
+    fn respond(&self) -> Result<()> {
+        unsafe { SetEvent(self.event_response) }?;
+        Ok(())
+    }
+}
+
+pub struct ProcessQuitter {
+    event_quit: HANDLE,
+}
+
+impl ProcessQuit for ProcessQuitter {
+    fn create(id: usize) -> Result<Self> {
+        let (event_quit_name, _x) = event_quit_name(id);
+        let event_quit = event_create(event_quit_name)?;
+        Ok(Self { event_quit })
+    }
+
+    fn quit(&self) -> Result<()> {
+        unsafe { SetEvent(self.event_quit) }?;
+        Ok(())
+    }
+}
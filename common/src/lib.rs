@@ -1,7 +1,9 @@
 pub mod audio_buffer;
+pub mod channel_map;
 pub mod clap_manager;
 pub mod dsp;
 pub mod event;
+pub mod ipc;
 pub mod module;
 pub mod plugin;
 pub mod plugin_ref;
@@ -12,5 +14,6 @@ pub mod shmem;
 pub mod str;
 pub mod util;
 
+#[cfg(windows)]
 pub const PIPE_CTRL_NAME: &'static str = r"\\.\pipe\sing_like_coding\ctrl";
 pub const PIPE_BUFFER_SIZE: u32 = 8092;
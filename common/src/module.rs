@@ -1,5 +1,11 @@
+use clap_sys::{
+    ext::params::{CLAP_PARAM_IS_AUTOMATABLE, CLAP_PARAM_IS_BYPASS, CLAP_PARAM_IS_STEPPED},
+    id::clap_id,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::{dsp::db_to_norm, plugin::param::Param};
+
 pub type ModuleId = usize;
 pub type ModuleIndex = (usize, usize); // (track_index, module_index)
 
@@ -9,7 +15,29 @@ pub struct Module {
     pub plugin_id: String,
     pub name: String,
     pub audio_inputs: Vec<AudioInput>,
+    #[serde(default, with = "state_base64")]
     pub state: Option<Vec<u8>>,
+    #[serde(default)]
+    pub bypass_p: bool,
+    #[serde(default = "mix_default")]
+    pub mix: f32, // 0.0 dry - 1.0 wet
+    #[serde(default)]
+    pub gain: f32, // dB
+    #[serde(default)]
+    pub aux_outputs: Vec<AuxOutput>,
+}
+
+// ホスト側で処理するモジュールのパラメータ
+// プラグインのパラメータとぶつからないように clap_id の上の方を使う
+pub const MODULE_BYPASS_PARAM_ID: clap_id = 0xFFFF_FF00;
+pub const MODULE_MIX_PARAM_ID: clap_id = 0xFFFF_FF01;
+pub const MODULE_GAIN_PARAM_ID: clap_id = 0xFFFF_FF02;
+
+pub const MODULE_GAIN_DB_MIN: f32 = -60.0;
+pub const MODULE_GAIN_DB_MAX: f32 = 12.0;
+
+fn mix_default() -> f32 {
+    1.0
 }
 
 impl Module {
@@ -25,6 +53,67 @@ impl Module {
             name,
             audio_inputs,
             state: None,
+            bypass_p: false,
+            mix: mix_default(),
+            gain: 0.0,
+            aux_outputs: vec![],
+        }
+    }
+
+    // パラメータ選択用
+    pub fn host_params(&self) -> Vec<Param> {
+        let param = |id, flags, name: &str, default_value, value| Param {
+            id,
+            flags,
+            name: name.to_string(),
+            module: "Host".to_string(),
+            min_value: 0.0,
+            max_value: 1.0,
+            default_value,
+            value,
+        };
+        vec![
+            param(
+                MODULE_BYPASS_PARAM_ID,
+                CLAP_PARAM_IS_AUTOMATABLE | CLAP_PARAM_IS_STEPPED | CLAP_PARAM_IS_BYPASS,
+                "Bypass",
+                0.0,
+                if self.bypass_p { 1.0 } else { 0.0 },
+            ),
+            param(
+                MODULE_MIX_PARAM_ID,
+                CLAP_PARAM_IS_AUTOMATABLE,
+                "Dry/Wet",
+                1.0,
+                self.mix as f64,
+            ),
+            param(
+                MODULE_GAIN_PARAM_ID,
+                CLAP_PARAM_IS_AUTOMATABLE,
+                "Gain",
+                db_to_norm(0.0, MODULE_GAIN_DB_MIN, MODULE_GAIN_DB_MAX) as f64,
+                db_to_norm(self.gain, MODULE_GAIN_DB_MIN, MODULE_GAIN_DB_MAX) as f64,
+            ),
+        ]
+    }
+}
+
+// 2 番目以降の出力ポートをミキサーに直接流す
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuxOutput {
+    pub port_index: usize,
+    pub volume: f32, // dB
+    pub pan: f32,    // 0.0 left - 0.5 center - 1.0 right
+    pub mute: bool,
+}
+
+impl AuxOutput {
+    pub fn new(port_index: usize) -> Self {
+        Self {
+            port_index,
+            volume: 0.0,
+            pan: 0.5,
+            mute: false,
         }
     }
 }
@@ -35,3 +124,37 @@ pub struct AudioInput {
     pub src_port_index: usize,
     pub dst_port_index: usize,
 }
+
+// プラグインの state は数値の配列だと巨大になるので base64 文字列で保存する
+// 古い数値の配列も読めるようにしておく
+mod state_base64 {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum State {
+        Base64(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(
+        state: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match state {
+            Some(bytes) => serializer.serialize_some(&STANDARD.encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<State>::deserialize(deserializer)? {
+            Some(State::Base64(text)) => STANDARD.decode(text).map(Some).map_err(D::Error::custom),
+            Some(State::Bytes(bytes)) => Ok(Some(bytes)),
+            None => Ok(None),
+        }
+    }
+}
@@ -1,56 +1,202 @@
-use windows::Win32::{
-    Foundation::HANDLE,
-    System::Threading::{CreateEventA, SetEvent, WaitForSingleObject, INFINITE},
-};
+use clap_sys::id::clap_id;
 
 use crate::{
+    dsp::{db_to_linear, DelayLine},
+    ipc::{ProcessRequest, ProcessRequester},
     process_data::ProcessData,
-    shmem::{event_request_name, event_response_name},
 };
 
 #[derive(Clone)]
 pub struct PluginRef {
     pub id: usize,
     pub ptr: *mut ProcessData,
-    pub event_request: HANDLE,
-    pub event_response: HANDLE,
+    process_requester: ProcessRequester,
+    // プラグインプロセスで読み込みが終わるまでは処理を頼まない
+    pub loaded_p: bool,
+    // 応答がなかった。読み込み直すまで無音にする
+    pub fault_p: bool,
     pub latency: u32,
+    pub bypass_param_id: Option<clap_id>, // プラグイン自身のバイパスパラメータ
+    bypass_sent_p: Option<bool>,
+    // オートメーションで変わった値。None ならモジュールの値を使う
+    pub bypass_p: Option<bool>,
+    pub mix: Option<f32>,
+    pub gain: Option<f32>,
+    dry: DelayLine,
 }
 
 impl PluginRef {
     pub fn new(id: usize, ptr: *mut ProcessData) -> anyhow::Result<Self> {
-        let (event_name, _x) = event_request_name(id);
-        let event_request = unsafe {
-            CreateEventA(
-                None,
-                false.into(), // 自動リセット
-                false.into(), // 初期非シグナル
-                event_name,
-            )?
-        };
-
-        let (event_name, _x) = event_response_name(id);
-        let event_response = unsafe {
-            CreateEventA(
-                None,
-                false.into(), // 自動リセット
-                false.into(), // 初期非シグナル
-                event_name,
-            )?
-        };
+        let process_requester = ProcessRequester::create(id, ptr)?;
 
         Ok(Self {
             id,
             ptr,
-            event_request,
-            event_response,
+            process_requester,
+            loaded_p: false,
+            fault_p: false,
             latency: 0,
+            bypass_param_id: None,
+            bypass_sent_p: None,
+            bypass_p: None,
+            mix: None,
+            gain: None,
+            dry: DelayLine::new(),
         })
     }
 
     pub fn process(&mut self) -> anyhow::Result<()> {
-        unsafe { SetEvent(self.event_request) }?;
-        unsafe { WaitForSingleObject(self.event_response, INFINITE) };
+        if !self.active_p() {
+            self.silence();
+            return Ok(());
+        }
+        if let Err(e) = self.process_requester.request() {
+            log::error!("plugin {} {}", self.id, e);
+            self.fault_p = true;
+            self.silence();
+        }
+        Ok(())
+    }
+
+    // 入力イベントがいっぱいなら、そこまでをイベントだけの依頼で先に渡して空ける
+    pub fn events_input_reserve(&mut self) -> anyhow::Result<()> {
+        if !self.process_data().events_input_full_p() {
+            return Ok(());
+        }
+        if self.active_p() {
+            self.process_data_mut().events_only_p = 1;
+            let result = self.process_requester.request();
+            self.process_data_mut().events_only_p = 0;
+            if let Err(e) = result {
+                log::error!("plugin {} {}", self.id, e);
+                self.fault_p = true;
+            }
+        }
+        let active_p = self.active_p();
+        let data = self.process_data_mut();
+        if !active_p {
+            data.nevents_dropped_input += data.nevents_input;
+        }
+        data.nevents_input = 0;
+        Ok(())
+    }
+
+    pub fn active_p(&self) -> bool {
+        self.loaded_p && !self.fault_p
+    }
+
+    pub fn loaded_set(&mut self, loaded_p: bool) -> anyhow::Result<()> {
+        if loaded_p {
+            self.process_requester.reset()?;
+            self.fault_p = false;
+        }
+        self.loaded_p = loaded_p;
+        Ok(())
+    }
+
+    fn silence(&mut self) {
+        let data = self.process_data_mut();
+        for port in 0..data.nports_out {
+            for buffer in data.buffer_out_mut(port) {
+                buffer[0] = 0.0;
+            }
+            data.constant_mask_out[port] |= (1 << data.nchannels_out[port]) - 1;
+        }
+        data.nevents_output = 0;
+    }
+
+    // バイパス、ドライ/ウェット、ゲインはホスト側で処理する
+    // gain は dB
+    pub fn process_with_host_params(
+        &mut self,
+        bypass_p: bool,
+        mix: f32,
+        gain: f32,
+    ) -> anyhow::Result<()> {
+        let host_bypass_p = match self.bypass_param_id {
+            Some(param_id) => {
+                if self.bypass_sent_p != Some(bypass_p) {
+                    let value = if bypass_p { 1.0 } else { 0.0 };
+                    self.events_input_reserve()?;
+                    self.process_data_mut()
+                        .input_param_value(param_id, value, 0);
+                    self.bypass_sent_p = Some(bypass_p);
+                }
+                false
+            }
+            None => bypass_p,
+        };
+
+        // 切り替えたときに途切れないようにドライ信号は常に遅らせておく
+        let latency = self.latency as usize;
+        let data = unsafe { &mut *(self.ptr) };
+        let nframes = data.nframes;
+        let nchannels_dry = if data.nports_in == 0 {
+            0
+        } else {
+            data.nchannels_in[0]
+        };
+        if nchannels_dry != 0 {
+            self.dry.process(
+                latency,
+                data.buffer_in(0),
+                data.constant_mask_in[0],
+                nframes,
+            );
+        }
+
+        if !host_bypass_p {
+            self.process()?;
+            if !self.active_p() {
+                return Ok(());
+            }
+        }
+        if bypass_p && !host_bypass_p {
+            // プラグインがバイパスしている
+            return Ok(());
+        }
+
+        let data = unsafe { &mut *(self.ptr) };
+        let mix = if host_bypass_p {
+            0.0
+        } else {
+            mix.clamp(0.0, 1.0) as f64
+        };
+        if mix < 1.0 && data.nports_out > 0 {
+            let mut constant_mask = data.constant_mask_out[0];
+            for (channel, buffer) in data.buffer_out_mut(0).iter_mut().enumerate() {
+                let bit = 1 << channel;
+                let constp = (constant_mask & bit) != 0;
+                let constant = buffer[0];
+                for (frame, x) in buffer.iter_mut().take(nframes).enumerate() {
+                    let wet = if constp { constant } else { *x };
+                    let dry = if nchannels_dry == 0 {
+                        0.0
+                    } else {
+                        self.dry.out[channel % nchannels_dry][frame]
+                    };
+                    *x = wet * mix + dry * (1.0 - mix);
+                }
+                constant_mask &= !bit;
+            }
+            data.constant_mask_out[0] = constant_mask;
+        }
+        if !host_bypass_p && gain != 0.0 {
+            let gain = db_to_linear(gain) as f64;
+            for port in 0..data.nports_out {
+                let constant_mask = data.constant_mask_out[port];
+                for (channel, buffer) in data.buffer_out_mut(port).iter_mut().enumerate() {
+                    if (constant_mask & (1 << channel)) != 0 {
+                        buffer[0] *= gain;
+                    } else {
+                        for x in buffer.iter_mut().take(nframes) {
+                            *x *= gain;
+                        }
+                    }
+                }
+            }
+        }
+
         Ok(())
     }
 
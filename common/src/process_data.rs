@@ -1,3 +1,5 @@
+use std::{ops::Range, sync::atomic::AtomicU32};
+
 use clap_sys::{
     fixedpoint::{clap_beattime, clap_sectime},
     id::clap_id,
@@ -5,9 +7,12 @@ use clap_sys::{
 
 use crate::dsp::linear_to_db;
 
-pub const MAX_CHANNELS: usize = 2;
+// 曲のチャンネル数は 5.1 や 7.1 まで
+pub const MAX_CHANNELS: usize = 8;
+// 全ポートのチャンネルを合わせた数。ポートごとのチャンネル数はプラグインに合わせる
+pub const MAX_BUFFER_CHANNELS: usize = 16;
 pub const MAX_FRAMES: usize = 2048;
-pub const MAX_EVENTS: usize = 128;
+pub const MAX_EVENTS: usize = 1024;
 pub const MAX_PORTS: usize = 8;
 
 #[repr(C)]
@@ -15,6 +20,10 @@ pub struct ProcessData {
     pub nframes: usize,
     pub play_p: u8,
     pub loop_p: u8,
+    // 対応しているプラグインは data64 で処理する
+    pub process_64_p: u8,
+    // 1 なら入力イベントを受け取るだけで処理はしない。入りきらないイベントを分けて渡す
+    pub events_only_p: u8,
     pub bpm: f64,
     pub lpb: u16,
     pub sample_rate: f64,
@@ -29,19 +38,32 @@ pub struct ProcessData {
     pub loop_end_seconds: clap_sectime,
     pub bar_start: clap_beattime,
     pub bar_number: i32,
+    pub tsig_num: u16,
+    pub tsig_denom: u16,
 
     pub nevents_input: usize,
     pub events_input: [Event; MAX_EVENTS],
     pub nevents_output: usize,
     pub events_output: [Event; MAX_EVENTS],
+    // 分けても渡せずに落としたイベントの数。ブロックごとに 0 に戻す
+    pub nevents_dropped_input: usize,
+    pub nevents_dropped_output: usize,
     pub nports_in: usize,
-    pub buffer_in: [[[f32; MAX_FRAMES]; MAX_CHANNELS]; MAX_PORTS],
+    // ポートの順に nchannels_in[port] チャンネルずつ詰めて使う。buffer_in(port) で取り出す
+    // ミックスまで精度を落とさないように f64 で持つ
+    // 64 ビットに対応していないプラグインにはプラグインプロセスで f32 にして渡す
+    pub buffer_pool_in: [[f64; MAX_FRAMES]; MAX_BUFFER_CHANNELS],
     pub nchannels_in: [usize; MAX_PORTS],
     pub constant_mask_in: [u64; MAX_PORTS],
     pub nports_out: usize,
-    pub buffer_out: [[[f32; MAX_FRAMES]; MAX_CHANNELS]; MAX_PORTS],
+    pub buffer_pool_out: [[f64; MAX_FRAMES]; MAX_BUFFER_CHANNELS],
     pub nchannels_out: [usize; MAX_PORTS],
     pub constant_mask_out: [u64; MAX_PORTS],
+
+    // Linux で処理の依頼と応答に使う futex
+    pub signal_request: AtomicU32,
+    pub signal_response: AtomicU32,
+    pub signal_quit: AtomicU32,
 }
 
 #[repr(C)]
@@ -53,160 +75,226 @@ pub struct Event {
     pub channel: i16,
     pub param_id: clap_id,
     pub value: f64,
+    // EventKind::Midi のときの MIDI メッセージ
+    pub midi: [u8; 3],
     pub delay: usize,
 }
 
+const EVENT_EMPTY: Event = Event {
+    kind: EventKind::NoteOn,
+    key: 0,
+    velocity: 0.0,
+    channel: 0,
+    param_id: 0,
+    value: 0.0,
+    midi: [0; 3],
+    delay: 0,
+};
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 pub enum EventKind {
     NoteOn = 1,
     NoteOff = 2,
     ParamValue = 3,
+    Midi = 4,
 }
 
 impl ProcessData {
-    pub fn new() -> Self {
-        Self {
-            nframes: MAX_FRAMES,
-            play_p: 0,
-            loop_p: 0,
-            bpm: 120.0,
-            lpb: 4,
-            sample_rate: 48000.0,
-            steady_time: 0,
-            song_pos_beats: 0,
-            song_pos_seconds: 0,
-            loop_start_beats: 0,
-            loop_end_beats: 0,
-            loop_start_seconds: 0,
-            loop_end_seconds: 0,
-            bar_start: 0,
-            bar_number: 0,
-            nevents_input: 0,
-            events_input: [Event {
-                kind: EventKind::NoteOn,
-                key: 0,
-                velocity: 0.0,
-                channel: 0,
-                param_id: 0,
-                value: 0.0,
-                delay: 0,
-            }; MAX_EVENTS],
-            nevents_output: 0,
-            events_output: [Event {
-                kind: EventKind::NoteOn,
-                key: 0,
-                velocity: 0.0,
-                channel: 0,
-                param_id: 0,
-                value: 0.0,
-                delay: 0,
-            }; MAX_EVENTS],
-            nports_in: 1,
-            buffer_in: [[[0.0; MAX_FRAMES]; MAX_CHANNELS]; MAX_PORTS],
-            nchannels_in: [2; MAX_PORTS],
-            constant_mask_in: [0; MAX_PORTS],
-            nports_out: 1,
-            buffer_out: [[[0.0; MAX_FRAMES]; MAX_CHANNELS]; MAX_PORTS],
-            nchannels_out: [2; MAX_PORTS],
-            constant_mask_out: [0; MAX_PORTS],
+    // バッファが大きいのでスタックに置かずに直接ヒープに作る
+    pub fn new_boxed() -> Box<Self> {
+        let mut this = Box::<Self>::new_uninit();
+        unsafe {
+            let ptr = this.as_mut_ptr();
+            // バッファと数値は 0 でいいので、0 では不正な値になる Event だけ書く
+            std::ptr::write_bytes(ptr, 0, 1);
+            let events_input = std::ptr::addr_of_mut!((*ptr).events_input) as *mut Event;
+            let events_output = std::ptr::addr_of_mut!((*ptr).events_output) as *mut Event;
+            for i in 0..MAX_EVENTS {
+                events_input.add(i).write(EVENT_EMPTY);
+                events_output.add(i).write(EVENT_EMPTY);
+            }
+            let mut this = this.assume_init();
+            this.nframes = MAX_FRAMES;
+            this.bpm = 120.0;
+            this.lpb = 4;
+            this.sample_rate = 48000.0;
+            this.tsig_num = 4;
+            this.tsig_denom = 4;
+            this.channels_set(&[2], &[2]);
+            this
         }
     }
 
+    // プラグインのポートのチャンネル数を、前のポートから順にプールに入る分だけ割り当てる
+    pub fn channels_set(&mut self, nchannels_in: &[usize], nchannels_out: &[usize]) {
+        for (nports, nchannels, counts) in [
+            (&mut self.nports_in, &mut self.nchannels_in, nchannels_in),
+            (&mut self.nports_out, &mut self.nchannels_out, nchannels_out),
+        ] {
+            *nports = counts.len().min(MAX_PORTS);
+            *nchannels = [0; MAX_PORTS];
+            let mut rest = MAX_BUFFER_CHANNELS;
+            for (nchannels, count) in nchannels.iter_mut().zip(counts) {
+                *nchannels = (*count).min(rest);
+                rest -= *nchannels;
+            }
+        }
+    }
+
+    pub fn buffer_in(&self, port: usize) -> &[[f64; MAX_FRAMES]] {
+        &self.buffer_pool_in[channel_range(&self.nchannels_in, port)]
+    }
+
+    pub fn buffer_in_mut(&mut self, port: usize) -> &mut [[f64; MAX_FRAMES]] {
+        &mut self.buffer_pool_in[channel_range(&self.nchannels_in, port)]
+    }
+
+    pub fn buffer_out(&self, port: usize) -> &[[f64; MAX_FRAMES]] {
+        &self.buffer_pool_out[channel_range(&self.nchannels_out, port)]
+    }
+
+    pub fn buffer_out_mut(&mut self, port: usize) -> &mut [[f64; MAX_FRAMES]] {
+        &mut self.buffer_pool_out[channel_range(&self.nchannels_out, port)]
+    }
+
     pub fn peak(&self, port: usize, channel: usize) -> f32 {
+        let buffer = &self.buffer_out(port)[channel];
         let value = if self.constant_mask_out[port] & (1 << channel) == 0 {
-            self.buffer_out[port][channel][..self.nframes]
+            buffer[..self.nframes]
                 .iter()
-                .fold(0.0, |acc: f32, x| acc.max(x.abs()))
+                .fold(0.0, |acc: f64, x| acc.max(x.abs()))
         } else {
-            self.buffer_out[port][channel][0].abs()
+            buffer[0].abs()
         };
-        linear_to_db(value)
+        linear_to_db(value as f32)
     }
 
     pub fn prepare(&mut self) {
         self.nevents_input = 0;
         self.nevents_output = 0;
+        self.nevents_dropped_input = 0;
+        self.nevents_dropped_output = 0;
+        for buffer in self
+            .buffer_pool_in
+            .iter_mut()
+            .chain(self.buffer_pool_out.iter_mut())
+        {
+            buffer[0] = 0.0;
+        }
+        let mask = (1 << MAX_BUFFER_CHANNELS) - 1;
         for port in 0..MAX_PORTS {
-            for channel in 0..MAX_CHANNELS {
-                self.buffer_in[port][channel][0] = 0.0;
-                self.buffer_out[port][channel][0] = 0.0;
-                let bit = 1 << channel;
-                self.constant_mask_in[port] |= bit;
-                self.constant_mask_out[port] |= bit;
-            }
+            self.constant_mask_in[port] |= mask;
+            self.constant_mask_out[port] |= mask;
         }
     }
 
     pub fn input_note_on(&mut self, key: i16, velocity: f64, channel: i16, delay: usize) {
-        if self.nevents_input == MAX_EVENTS {
-            panic!("{} {:?}", self.nevents_input, self.events_input);
-        }
-        self.events_input[self.nevents_input].kind = EventKind::NoteOn;
-        self.events_input[self.nevents_input].key = key;
-        self.events_input[self.nevents_input].velocity = velocity;
-        self.events_input[self.nevents_input].channel = channel;
-        self.events_input[self.nevents_input].delay = delay;
-        self.nevents_input += 1;
+        self.input_event(Event {
+            kind: EventKind::NoteOn,
+            key,
+            velocity,
+            channel,
+            delay,
+            ..EVENT_EMPTY
+        });
     }
 
     pub fn input_note_off(&mut self, key: i16, channel: i16, delay: usize) {
-        if self.nevents_input == MAX_EVENTS {
-            panic!("{} {:?}", self.nevents_input, self.events_input);
-        }
-        self.events_input[self.nevents_input].kind = EventKind::NoteOff;
-        self.events_input[self.nevents_input].key = key;
-        self.events_input[self.nevents_input].velocity = 0.0;
-        self.events_input[self.nevents_input].channel = channel;
-        self.events_input[self.nevents_input].delay = delay;
-        self.nevents_input += 1;
+        self.input_event(Event {
+            kind: EventKind::NoteOff,
+            key,
+            channel,
+            delay,
+            ..EVENT_EMPTY
+        });
     }
 
     pub fn input_param_value(&mut self, param_id: clap_id, value: f64, delay: usize) {
-        if self.nevents_input == MAX_EVENTS {
-            panic!();
-        }
-        self.events_input[self.nevents_input].kind = EventKind::ParamValue;
-        self.events_input[self.nevents_input].param_id = param_id;
-        self.events_input[self.nevents_input].value = value;
-        self.events_input[self.nevents_input].delay = delay;
-        self.nevents_input += 1;
+        self.input_event(Event {
+            kind: EventKind::ParamValue,
+            param_id,
+            value,
+            delay,
+            ..EVENT_EMPTY
+        });
+    }
+
+    pub fn input_midi(&mut self, midi: [u8; 3], delay: usize) {
+        self.input_event(Event {
+            kind: EventKind::Midi,
+            midi,
+            delay,
+            ..EVENT_EMPTY
+        });
     }
 
     pub fn output_note_on(&mut self, key: i16, velocity: f64, channel: i16, delay: usize) {
-        if self.nevents_output == MAX_EVENTS {
-            panic!();
-        }
-        self.events_output[self.nevents_output].kind = EventKind::NoteOn;
-        self.events_output[self.nevents_output].key = key;
-        self.events_output[self.nevents_output].velocity = velocity;
-        self.events_output[self.nevents_output].channel = channel;
-        self.events_output[self.nevents_output].delay = delay;
-        self.nevents_output += 1;
+        self.output_event(Event {
+            kind: EventKind::NoteOn,
+            key,
+            velocity,
+            channel,
+            delay,
+            ..EVENT_EMPTY
+        });
     }
 
     pub fn output_note_off(&mut self, key: i16, channel: i16, delay: usize) {
-        if self.nevents_output == MAX_EVENTS {
-            panic!();
-        }
-        self.events_output[self.nevents_output].kind = EventKind::NoteOff;
-        self.events_output[self.nevents_output].key = key;
-        self.events_output[self.nevents_output].velocity = 0.0;
-        self.events_output[self.nevents_output].channel = channel;
-        self.events_output[self.nevents_output].delay = delay;
-        self.nevents_output += 1;
+        self.output_event(Event {
+            kind: EventKind::NoteOff,
+            key,
+            channel,
+            delay,
+            ..EVENT_EMPTY
+        });
     }
 
     pub fn output_param_value(&mut self, param_id: clap_id, value: f64, delay: usize) {
-        if self.nevents_output == MAX_EVENTS {
-            // Song::idle_p のとき
-            // panic!();
+        self.output_event(Event {
+            kind: EventKind::ParamValue,
+            param_id,
+            value,
+            delay,
+            ..EVENT_EMPTY
+        });
+    }
+
+    pub fn events_input_full_p(&self) -> bool {
+        self.nevents_input >= MAX_EVENTS
+    }
+
+    pub fn events_output_full_p(&self) -> bool {
+        self.nevents_output >= MAX_EVENTS
+    }
+
+    // 入れる側でいっぱいなら先に渡しておく
+    // それでも入らないときはオーディオスレッドなので panic せずに数えておいて UI に出す
+    fn input_event(&mut self, event: Event) {
+        if self.events_input_full_p() {
+            self.nevents_dropped_input += 1;
             return;
         }
-        self.events_output[self.nevents_output].kind = EventKind::ParamValue;
-        self.events_output[self.nevents_output].param_id = param_id;
-        self.events_output[self.nevents_output].value = value;
-        self.events_output[self.nevents_output].delay = delay;
+        self.events_input[self.nevents_input] = event;
+        self.nevents_input += 1;
+    }
+
+    fn output_event(&mut self, event: Event) {
+        if self.events_output_full_p() {
+            self.nevents_dropped_output += 1;
+            return;
+        }
+        self.events_output[self.nevents_output] = event;
         self.nevents_output += 1;
     }
 }
+
+// ポートのチャンネルがプールのどこにあるか
+fn channel_range(nchannels: &[usize; MAX_PORTS], port: usize) -> Range<usize> {
+    let start = nchannels[..port]
+        .iter()
+        .sum::<usize>()
+        .min(MAX_BUFFER_CHANNELS);
+    let end = (start + nchannels[port]).min(MAX_BUFFER_CHANNELS);
+    start..end
+}
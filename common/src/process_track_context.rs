@@ -17,6 +17,7 @@ pub struct ProcessTrackContext {
     pub line_offset: isize,
     pub line_offset_stack: Vec<isize>,
     pub plugins: Vec<PluginRef>,
+    pub audio_clip_p: bool, // buffer にオーディオクリップの音が入っている
 }
 
 unsafe impl Send for ProcessTrackContext {}
@@ -1,32 +1,119 @@
 use bincode::{config, Decode, Encode};
+use clap_sys::id::clap_id;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::{audio_buffer::AudioBuffer, module::ModuleId, plugin::param::Param};
 
+// 繋いだときに突き合わせる。メッセージを変えたら上げる
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub type RequestId = u64;
+
+// 返事を待たない通知の request_id
+pub const NOTIFICATION_ID: RequestId = 0;
+
+// 繋いだ直後に互いに送り合う
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Hello {
+    pub version: u32,
+}
+
+// 返事には要求と同じ request_id を付ける
 #[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Envelope<T> {
+    pub request_id: RequestId,
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(request_id: RequestId, message: T) -> Self {
+        Self {
+            request_id,
+            message,
+        }
+    }
+
+    pub fn notification(message: T) -> Self {
+        Self::new(NOTIFICATION_ID, message)
+    }
+
+    pub fn notification_p(&self) -> bool {
+        self.request_id == NOTIFICATION_ID
+    }
+}
+
+#[derive(Clone, Encode, Decode, PartialEq, Debug)]
 pub enum MainToPlugin {
     Hwnd(isize),
-    Load(ModuleId, String, bool, Option<Vec<u8>>),
+    // id, plugin id, gui_open_p, state, 曲のチャンネル数, 専用のプロセスで動かすか
+    Load(ModuleId, String, bool, Option<Vec<u8>>, usize, bool),
     Unload(usize),
     GuiOpen(ModuleId),
     Params(ModuleId),
     StateLoad(ModuleId, Vec<u8>),
     StateSave(ModuleId),
+    Channels(usize),
     Scan,
     Quit,
 }
 
+impl MainToPlugin {
+    pub fn module_id(&self) -> Option<ModuleId> {
+        match self {
+            MainToPlugin::Load(id, ..)
+            | MainToPlugin::Unload(id)
+            | MainToPlugin::GuiOpen(id)
+            | MainToPlugin::Params(id)
+            | MainToPlugin::StateLoad(id, _)
+            | MainToPlugin::StateSave(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub enum PluginToMain {
     DidHwnd,
-    DidLoad(usize, u32), // id, latency
+    // id, latency, bypass param id, 出力ポート名
+    DidLoad(usize, u32, Option<clap_id>, Vec<String>),
     DidUnload(ModuleId),
     DidGuiOpen,
     DidParams(Vec<Param>),
     DidStateLoad,
     DidStateSave(ModuleId, Vec<u8>),
+    DidChannels,
     DidScan,
     Quit,
+    // 以下はメイン側の Communicator が作る
+    // 返事の前にプラグインプロセスが落ちた。送ったメッセージのモジュール
+    Crashed(Option<ModuleId>),
+    // プラグインプロセスを起動し直した。そのプロセスにいたモジュール。通知
+    Respawned(Vec<ModuleId>),
+    // 以下はプラグインからの通知
+    ParamsRescan(ModuleId),
+    LatencyChanged(ModuleId, u32),
+    GuiClosed(ModuleId),
+    RestartRequested(ModuleId),
+    Log(ModuleId, LogLevel, String),
+}
+
+#[derive(Clone, Copy, Encode, Decode, PartialEq, Debug)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Warning => log::Level::Warn,
+            LogLevel::Error => log::Level::Error,
+        }
+    }
 }
 
 #[derive(Encode, Decode, PartialEq, Debug)]
@@ -69,3 +156,26 @@ where
 
     Ok(message)
 }
+
+// 両側から Hello を送り合い、PROTOCOL_VERSION が違えば繋がない
+pub async fn handshake<P>(pipe: &mut P) -> anyhow::Result<()>
+where
+    P: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    send(
+        pipe,
+        &Hello {
+            version: PROTOCOL_VERSION,
+        },
+    )
+    .await?;
+    let hello: Hello = receive(pipe).await?;
+    if hello.version != PROTOCOL_VERSION {
+        anyhow::bail!(
+            "protocol version mismatch: ours {} theirs {}",
+            PROTOCOL_VERSION,
+            hello.version
+        );
+    }
+    Ok(())
+}
@@ -1,9 +1,4 @@
-use std::ffi::CString;
-
 use shared_memory::{Shmem, ShmemConf, ShmemError};
-use windows::core::PCSTR;
-
-use crate::str::to_pcstr;
 
 pub const SONG_STATE_NAME: &str = "SingLikeCoding.Song.State";
 
@@ -11,18 +6,6 @@ pub fn process_data_name(id: usize) -> String {
     format!("SingLikeCoding.Process.Data.{}", id)
 }
 
-pub fn event_request_name(id: usize) -> (PCSTR, CString) {
-    to_pcstr(&format!("SingLikeCoding.Process.Request.{}", id)).unwrap()
-}
-
-pub fn event_response_name(id: usize) -> (PCSTR, CString) {
-    to_pcstr(&format!("SingLikeCoding.Process.Response.{}", id)).unwrap()
-}
-
-pub fn event_quit_name(id: usize) -> (PCSTR, CString) {
-    to_pcstr(&format!("SingLikeCoding.Process.Quit.{}", id)).unwrap()
-}
-
 pub fn create_shared_memory<T>(name: &str) -> anyhow::Result<Shmem> {
     let shmem = ShmemConf::new().size(size_of::<T>()).os_id(name).create();
     let shmem = match shmem {
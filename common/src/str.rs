@@ -1,14 +1,18 @@
+#[cfg(windows)]
 use std::{
     ffi::{CString, OsStr},
     os::windows::ffi::OsStrExt,
 };
 
+#[cfg(windows)]
 use windows::core::PCSTR;
 
+#[cfg(windows)]
 pub fn to_pcwstr(s: &str) -> Vec<u16> {
     OsStr::new(s).encode_wide().chain(Some(0)).collect()
 }
 
+#[cfg(windows)]
 pub fn to_pcstr(s: &str) -> anyhow::Result<(PCSTR, CString)> {
     let c_string = CString::new(s)?; // null バイトが含まれていればエラー
     let pcstr = PCSTR(c_string.as_ptr().cast());
@@ -1,15 +1,17 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
-use common::protocol::{MainToPlugin, PluginToMain};
+use common::protocol::{Envelope, MainToPlugin, PluginToMain};
 use eframe::egui::{self, Align2, Context, Window};
 use raw_window_handle::{HasWindowHandle, RawWindowHandle};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 use crate::app_state::AppState;
 use crate::communicator::Communicator;
 use crate::device::Device;
+use crate::recovery;
 use crate::singer::Singer;
 use crate::view::root_view::RootView;
 
@@ -36,8 +38,8 @@ struct AppMain<'a> {
     device: Option<Device>,
     singer: Arc<Mutex<Singer>>,
     view: RootView,
-    recevier_from_main_thread: Option<Receiver<MainToPlugin>>,
-    sender_communicator_to_main_thread: Option<Sender<PluginToMain>>,
+    recevier_from_main_thread: Option<UnboundedReceiver<Envelope<MainToPlugin>>>,
+    sender_communicator_to_main_thread: Option<Sender<Envelope<PluginToMain>>>,
 }
 
 pub enum Msg {
@@ -49,17 +51,20 @@ impl<'a> Default for AppMain<'a> {
     fn default() -> Self {
         let (sender_to_main, receiver_from_audio) = channel();
         let (sender_to_singer, recevier_from_ui) = channel();
-        let (sender_to_plugin, recevier_from_main_thread) = channel();
+        let (sender_to_plugin, recevier_from_main_thread) = unbounded_channel();
         let (sender_communicator_to_main_thread, receiver_communicator_to_main_thread) = channel();
         let (sender_midi, receiver_midi) = channel();
+        let (sender_midi_sync, receiver_midi_sync) = channel();
         let singer = Arc::new(Mutex::new(Singer::new(sender_to_main)));
         Singer::start_listener(singer.clone(), recevier_from_ui);
         Singer::start_listener_midi(singer.clone(), receiver_midi);
+        Singer::start_listener_midi_sync(singer.clone(), receiver_midi_sync);
 
         let mut device = Device::open_default(singer.clone()).unwrap();
         device.start().unwrap();
         let device = Some(device);
 
+        let audio_input_buffer = singer.lock().unwrap().audio_input_buffer.clone();
         let app_state = AppState::new(
             singer.lock().unwrap().song.clone(),
             sender_to_singer,
@@ -67,6 +72,8 @@ impl<'a> Default for AppMain<'a> {
             sender_to_plugin,
             receiver_communicator_to_main_thread,
             sender_midi,
+            sender_midi_sync,
+            audio_input_buffer,
         );
         let view = RootView::new();
 
@@ -87,6 +94,7 @@ impl<'a> eframe::App for AppMain<'a> {
             let _ = x.stop();
         });
         self.state.quit();
+        recovery::session_end();
         self.state
             .send_to_plugin(MainToPlugin::Quit, Box::new(|_, _| Ok(())))
             .unwrap();
@@ -102,6 +110,7 @@ impl<'a> eframe::App for AppMain<'a> {
                 receiver_main_thread_to_communicator,
                 self.sender_communicator_to_main_thread.take().unwrap(),
                 ctx.clone(),
+                self.state.plugin_processes.clone(),
             )
             .unwrap();
             tokio::spawn(async move {
@@ -113,8 +122,10 @@ impl<'a> eframe::App for AppMain<'a> {
             self.state.gui_context = Some(ctx.clone());
         }
         let _ = self.view.view(ctx, &mut self.device, &mut self.state);
+        let _ = self.state.autosave();
 
         let _ = maybe_exit(ctx, &mut self.state);
+        maybe_restore(ctx, &mut self.state);
 
         // 節電
         let fps = if self.state.song_state.play_p {
@@ -129,11 +140,35 @@ impl<'a> eframe::App for AppMain<'a> {
 
 fn get_hwnd(frame: &eframe::Frame) -> isize {
     if let Ok(window_handle) = frame.window_handle() {
-        if let RawWindowHandle::Win32(h) = window_handle.as_raw() {
-            return isize::from(h.hwnd);
+        match window_handle.as_raw() {
+            RawWindowHandle::Win32(h) => return isize::from(h.hwnd),
+            RawWindowHandle::Xlib(h) => return h.window as isize,
+            RawWindowHandle::Xcb(h) => return h.window.get() as isize,
+            _ => (),
         }
     }
-    unreachable!("get_hwd failed!");
+    // Wayland などではプラグインの窓を載せる先がない
+    0
+}
+
+fn maybe_restore(ctx: &Context, state: &mut AppState) {
+    if state.recovery_p {
+        Window::new("Restore Unsaved Work?")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label("The last session did not exit cleanly.");
+                ui.horizontal(|ui| {
+                    if ui.button("Restore").clicked() {
+                        let _ = state.recovery_restore();
+                    }
+                    if ui.button("Discard").clicked() {
+                        state.recovery_discard();
+                    }
+                });
+            });
+    }
 }
 
 fn maybe_exit(ctx: &Context, state: &mut AppState) -> anyhow::Result<()> {
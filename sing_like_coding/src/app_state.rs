@@ -1,10 +1,15 @@
 use std::{
+    cell::Cell,
     collections::{HashMap, HashSet, VecDeque},
     env::current_exe,
-    fs::{self, create_dir_all, File},
-    io::Write,
-    path::PathBuf,
-    sync::mpsc::{Receiver, Sender},
+    fs::{create_dir_all, File},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
@@ -14,25 +19,40 @@ use clap_sys::id::clap_id;
 use common::{
     dsp::{db_from_norm, db_to_norm},
     event::Event,
-    module::{AudioInput, Module, ModuleIndex},
+    module::{AudioInput, AuxOutput, Module, ModuleId, ModuleIndex},
     plugin::{description::Description, param::Param},
-    protocol::{MainToPlugin, PluginToMain},
+    protocol::{Envelope, MainToPlugin, PluginToMain, RequestId, NOTIFICATION_ID},
     shmem::{open_shared_memory, SONG_STATE_NAME},
 };
 use eframe::egui::Color32;
-use midly::{MidiMessage, Smf};
 use rfd::FileDialog;
 use shared_memory::Shmem;
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
+    audio_file::audio_file_info,
+    audio_input_device::{AudioInputBuffer, AudioInputDevice},
     command::{track_add::TrackAdd, Command},
+    communicator::PluginProcessInfo,
     config::Config,
     eval::Eval,
     midi_device::MidiDevice,
-    model::{lane::Lane, lane_item::LaneItem, note::Note, song::Song, track::Track},
+    midi_file::{midi_file_export, MidiImport},
+    midi_sync::MidiSyncMessage,
+    model::{
+        audio_clip::AudioClip,
+        lane::Lane,
+        lane_item::LaneItem,
+        note::Note,
+        song::Song,
+        song_file::{song_to_string, SizeReport},
+        track::Track,
+    },
+    project::Project,
+    recovery,
     singer::{AudioToMain, MainToAudio},
     song_state::SongState,
-    util::midi_tick_to_line_delay,
+    undo_history::{UndoContext, UndoHistorySnapshot},
     view::{
         root_view::Route,
         stereo_peak_meter::{DB_MAX, DB_MIN},
@@ -70,6 +90,8 @@ pub enum UiCommand {
     TrackAdd,
     TrackMute(Option<usize>, Option<bool>),
     TrackPan(usize, f32),
+    TrackInput(usize, bool),
+    TrackMonitor(usize, bool),
     TrackRecOn(usize),
     TrackRecOff(usize),
     TrackSolo(Option<usize>, Option<bool>),
@@ -117,11 +139,14 @@ pub enum LaneCommand {
 
 #[derive(Clone)]
 pub enum ModuleCommand {
+    Bypass,
     CursorUp,
     CursorDown,
     CursorLeft,
     CursorRight,
     Delete,
+    MoveUp,
+    MoveDown,
     Open,
     Rename,
     Sidechain,
@@ -266,9 +291,10 @@ pub struct CursorModule {
     pub index: usize,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum FocusedPart {
     Track,
+    #[default]
     Lane,
     Module,
     Mixer,
@@ -279,7 +305,16 @@ pub enum RenameTarget {
     Module(ModuleIndex),
 }
 
+// プラグインからの返事で呼ぶ
+type PluginCallback = dyn Fn(&mut AppState, PluginToMain) -> Result<()>;
+// 曲の読み込みや保存が済んだら呼ぶ
+type StateCallback = dyn Fn(&mut AppState) -> Result<()>;
+
 pub struct AppState<'a> {
+    audio_device_input: Option<AudioInputDevice>,
+    audio_input_buffer: Arc<Mutex<AudioInputBuffer>>,
+    autosave_last: Instant,
+    autosave_p: bool,
     pub config: Config,
     pub confirm_exit_popup_p: bool,
     pub confirm_exit_popup_focus_request_p: bool,
@@ -295,31 +330,44 @@ pub struct AppState<'a> {
     pub labeled_lines: Vec<usize>,
     pub lane_item_last: LaneItem,
     midi_device_input: Option<MidiDevice>,
+    pub midi_import: Option<MidiImport>,
     pub pattern_p: bool,
     pub rename_buffer: String,
     pub rename_request_focus_p: bool,
+    pub recovery_p: bool,
     pub rename_target: Option<RenameTarget>,
     pub route: Route,
     pub select_p: bool,
+    pub size_report: Option<SizeReport>,
     pub selection_track_min: Option<CursorTrack>,
     pub selection_track_max: Option<CursorTrack>,
     pub song: Song,
     pub song_change_p: bool,
     song_next: Option<Song>,
-    song_apply_callbacks: VecDeque<Box<dyn Fn(&mut AppState) -> Result<()>>>,
+    song_apply_callbacks: VecDeque<Box<StateCallback>>,
     pub song_dirty_p: bool,
+    undo_context_sent: Option<UndoContext>,
+    pub undo_history: Option<UndoHistorySnapshot>,
     sender_to_singer: Sender<MainToAudio>,
     receiver_from_audio: Receiver<AudioToMain>,
-    sender_to_loop: Sender<MainToPlugin>,
+    sender_to_loop: UnboundedSender<Envelope<MainToPlugin>>,
     sender_midi: Sender<Event>,
-    receiver_communicator_to_main_thread: Receiver<PluginToMain>,
+    sender_midi_sync: Sender<MidiSyncMessage>,
+    receiver_communicator_to_main_thread: Receiver<Envelope<PluginToMain>>,
     _song_state_shmem: Shmem,
     pub song_state: &'a SongState,
     ui_command_last: UiCommand,
-    callbacks_plugin_to_main: VecDeque<Box<dyn Fn(&mut AppState, PluginToMain) -> Result<()>>>,
+    callbacks_plugin_to_main: HashMap<RequestId, Box<PluginCallback>>,
+    request_id_last: RequestId,
     pub gui_context: Option<eframe::egui::Context>,
 
     pub param_select_view_params: Vec<Param>,
+    param_select_view_module_id: Option<ModuleId>, // param_select_view_params のモジュール
+    pub module_output_ports: HashMap<ModuleId, Vec<String>>, // 出力ポート名
+    module_crashes: HashSet<ModuleId>,             // プラグインプロセスを落としたモジュール
+    module_gui_opens: HashSet<ModuleId>,           // プラグインの GUI を開いているモジュール
+    pub plugin_processes: Arc<Mutex<Vec<PluginProcessInfo>>>,
+    pub plugin_process_window_open_p: bool,
 
     // for MainView layout.
     pub offset_tracks: Vec<f32>,
@@ -336,14 +384,20 @@ impl<'a> AppState<'a> {
         song: Song,
         sender_to_singer: Sender<MainToAudio>,
         receiver_from_audio: Receiver<AudioToMain>,
-        sender_to_loop: Sender<MainToPlugin>,
-        receiver_communicator_to_main_thread: Receiver<PluginToMain>,
+        sender_to_loop: UnboundedSender<Envelope<MainToPlugin>>,
+        receiver_communicator_to_main_thread: Receiver<Envelope<PluginToMain>>,
         sender_midi: Sender<Event>,
+        sender_midi_sync: Sender<MidiSyncMessage>,
+        audio_input_buffer: Arc<Mutex<AudioInputBuffer>>,
     ) -> Self {
         let song_state_shmem = open_shared_memory::<SongState>(SONG_STATE_NAME).unwrap();
         let song_state = unsafe { &*(song_state_shmem.as_ptr() as *const SongState) };
 
         let mut this = Self {
+            audio_device_input: None,
+            audio_input_buffer,
+            autosave_last: Instant::now(),
+            autosave_p: false,
             config: Config::load().unwrap_or_default(),
             confirm_exit_popup_p: false,
             confirm_exit_popup_focus_request_p: true,
@@ -363,12 +417,15 @@ impl<'a> AppState<'a> {
             labeled_lines: vec![],
             lane_item_last: LaneItem::default(),
             midi_device_input: None,
+            midi_import: None,
             pattern_p: false,
             rename_buffer: Default::default(),
+            recovery_p: recovery::session_start().unwrap_or(false),
             rename_target: None,
             rename_request_focus_p: false,
             route: Route::Track,
             select_p: false,
+            size_report: None,
             selection_track_min: Default::default(),
             selection_track_max: Default::default(),
             song: song.clone(),
@@ -376,18 +433,28 @@ impl<'a> AppState<'a> {
             song_next: Some(song),
             song_apply_callbacks: Default::default(),
             song_dirty_p: false,
+            undo_context_sent: None,
+            undo_history: None,
             sender_to_singer,
             receiver_from_audio,
             sender_to_loop,
             sender_midi: sender_midi.clone(),
+            sender_midi_sync: sender_midi_sync.clone(),
             receiver_communicator_to_main_thread,
             _song_state_shmem: song_state_shmem,
             song_state,
             ui_command_last: UiCommand::Nop,
             callbacks_plugin_to_main: Default::default(),
+            request_id_last: NOTIFICATION_ID,
             gui_context: None,
 
             param_select_view_params: vec![],
+            param_select_view_module_id: None,
+            module_output_ports: Default::default(),
+            module_crashes: Default::default(),
+            module_gui_opens: Default::default(),
+            plugin_processes: Default::default(),
+            plugin_process_window_open_p: false,
 
             offset_tracks: vec![],
             offset_flatten_lanes: vec![],
@@ -399,12 +466,32 @@ impl<'a> AppState<'a> {
         };
 
         if let Some(midi_device_input) = &this.config.midi_device_input {
-            match MidiDevice::new(midi_device_input, sender_midi) {
+            match MidiDevice::new(midi_device_input, sender_midi, sender_midi_sync) {
                 Ok(midi_device) => this.midi_device_input = Some(midi_device),
 
                 Err(e) => log::warn!("MidiDevice::new is failed. {}", e),
             }
         }
+        if let Some(audio_device_input) = this.config.audio_device_input.clone() {
+            match AudioInputDevice::new(
+                &audio_device_input,
+                this.song.sample_rate,
+                this.audio_input_buffer.clone(),
+            ) {
+                Ok(device) => this.audio_device_input = Some(device),
+                Err(e) => log::warn!("AudioInputDevice::new is failed. {}", e),
+            }
+        }
+        if this.config.midi_clock_output.is_some() {
+            this.send_to_audio(MainToAudio::MidiClockOutput(
+                this.config.midi_clock_output.clone(),
+            ))
+            .unwrap();
+        }
+        if this.config.midi_sync_follow_p {
+            this.send_to_audio(MainToAudio::MidiSyncFollow(true))
+                .unwrap();
+        }
 
         this
     }
@@ -414,6 +501,20 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    pub fn channels_set(&mut self, nchannels: usize) -> Result<()> {
+        self.send_to_audio(MainToAudio::Channels(nchannels))?;
+        Ok(())
+    }
+
+    pub fn process_64_toggle(&mut self) -> Result<()> {
+        self.send_to_audio(MainToAudio::Process64(!self.song.process_64_p))?;
+        self.info = format!(
+            "64-bit processing {}.",
+            if self.song.process_64_p { "off" } else { "on" }
+        );
+        Ok(())
+    }
+
     pub fn color_cursor(&self) -> Color32 {
         if SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -584,6 +685,53 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    // 保存済みの曲ならプロジェクトの samples にコピーして相対パスにする
+    pub fn audio_clip_insert(&mut self, path: &Path) -> Result<()> {
+        let (length, sample_rate) = match audio_file_info(path) {
+            Ok(x) => x,
+            Err(e) => {
+                self.info = format!("Failed to read {}. {}", path.display(), e);
+                return Ok(());
+            }
+        };
+        let file = if let Some(song_file) = self.song_state.song_file_get() {
+            Project::new(Path::new(&song_file)).sample_import(path)?
+        } else {
+            path.to_string_lossy().to_string()
+        };
+        self.send_to_audio(MainToAudio::LaneItem(vec![(
+            self.cursor_track,
+            Some(LaneItem::AudioClip(AudioClip::new(
+                file,
+                sample_rate,
+                length,
+            ))),
+        )]))?;
+        Ok(())
+    }
+
+    // カーソル位置のオーディオクリップ。gain は dB、それ以外は ms
+    pub fn eval_audio_clip(&mut self, word: &str, value: f64) -> Result<()> {
+        let Some(LaneItem::AudioClip(mut clip)) = self.song.lane_item(&self.cursor_track).cloned()
+        else {
+            self.info = "No audio clip at the cursor.".to_string();
+            return Ok(());
+        };
+        match word {
+            "gain" => clip.gain = value as f32,
+            "fadein" => clip.fade_in = clip.frames_from_ms(value),
+            "fadeout" => clip.fade_out = clip.frames_from_ms(value),
+            "offset" => clip.offset = clip.frames_from_ms(value),
+            "length" => clip.length = clip.frames_from_ms(value),
+            _ => return Ok(()),
+        }
+        self.send_to_audio(MainToAudio::LaneItem(vec![(
+            self.cursor_track,
+            Some(LaneItem::AudioClip(clip)),
+        )]))?;
+        Ok(())
+    }
+
     pub fn eval_call(&mut self, label: String) -> Result<()> {
         self.send_to_audio(MainToAudio::LaneItem(vec![(
             self.cursor_track.clone(),
@@ -662,104 +810,165 @@ impl<'a> AppState<'a> {
         _lane_index: usize,
         path: &PathBuf,
     ) -> Result<()> {
-        let data = fs::read(path)?;
-        let smf = Smf::parse(&data)?;
-        let ppq = match smf.header.timing {
-            midly::Timing::Metrical(t) => t.as_int() as u32,
-            _ => panic!("Unsupported timing format"),
+        match MidiImport::read(path, track_index, self.song.lpb, self.song.bpm) {
+            Ok(midi_import) => self.midi_import = Some(midi_import),
+            Err(e) => self.info = format!("Failed to read {}. {}", path.display(), e),
+        }
+        Ok(())
+    }
+
+    pub fn midi_import_apply(&mut self) -> Result<()> {
+        let Some(midi_import) = self.midi_import.take() else {
+            return Ok(());
         };
-        let lpb = self.song.lpb as u32;
-        // MIDI の 1 拍（1 quarter note）を lpb で割る
-        let ticks_per_line = ppq / lpb;
-
-        for smf_track in smf.tracks.iter() {
-            let mut ticks = 0u32;
-            let mut key_lane_map = HashMap::new();
-            let mut lane_line_used = HashSet::new();
-            let mut lane_items = vec![];
-            for event in smf_track.iter() {
-                ticks += event.delta.as_int();
-                let (line, delay) = midi_tick_to_line_delay(ticks, ticks_per_line);
-                let lane_item = match event.kind {
-                    midly::TrackEventKind::Midi {
-                        channel,
-                        message: MidiMessage::NoteOn { key, vel },
-                    } if vel > 0 => LaneItem::Note(Note {
-                        key: key.as_int() as i16,
-                        velocity: vel.as_int() as f64,
-                        delay,
-                        channel: channel.as_int() as i16,
-                        ..Default::default()
-                    }),
-                    midly::TrackEventKind::Midi {
-                        channel,
-                        message: MidiMessage::NoteOn { key, vel: _ },
-                    }
-                    | midly::TrackEventKind::Midi {
-                        channel,
-                        message: MidiMessage::NoteOff { key, vel: _ },
-                    } => LaneItem::Note(Note {
-                        key: key.as_int() as i16, // OFF にする lane をさがすために
-                        off: true,
-                        delay,
-                        channel: channel.as_int() as i16,
-                        ..Default::default()
-                    }),
-                    midly::TrackEventKind::Midi {
-                        channel: _,
-                        message: _,
-                    } => continue,
-                    midly::TrackEventKind::SysEx(_items) => continue,
-                    midly::TrackEventKind::Escape(_items) => continue,
-                    midly::TrackEventKind::Meta(_meta_message) => continue,
-                };
+        self.undo_group("Import MIDI", |state| {
+            state.midi_import_apply_with(&midi_import)
+        })?;
+        self.info = format!("Imported {}.", midi_import.path.display());
+        Ok(())
+    }
 
-                let mut lane = 0;
+    fn midi_import_apply_with(&mut self, midi_import: &MidiImport) -> Result<()> {
+        if midi_import.tempo_p {
+            if let Some(bpm) = midi_import.bpm {
+                self.bpm_set(bpm)?;
+            }
+            if let Some((numerator, denominator)) = midi_import.time_signature {
+                self.send_to_audio(MainToAudio::TimeSignature(numerator, denominator))?;
+            }
+        }
 
-                match &lane_item {
-                    LaneItem::Note(Note { key, off: true, .. }) => {
-                        if let Some(x) = key_lane_map.get(key) {
-                            lane = *x;
-                        }
+        if midi_import.tracks_new_p {
+            let mut track_index = self.song.tracks.len();
+            for midi_import_track in midi_import.tracks.iter() {
+                if midi_import_track.events.is_empty() {
+                    // コンダクタートラックなど
+                    continue;
+                }
+                let mut track = Track::new();
+                track.name = midi_import_track.name.clone();
+                for (lane, line, lane_item) in
+                    midi_import.lane_items(&midi_import_track.events, &mut track.automation_params)
+                {
+                    while track.lanes.len() <= lane {
+                        track.lane_add();
                     }
-                    _ => {}
+                    track.lanes[lane].items.insert(line, lane_item);
                 }
+                self.send_to_audio(MainToAudio::TrackInsert(track_index, track))?;
+                track_index += 1;
+            }
+        } else {
+            let track_index = midi_import.track_index;
+            let Some(track) = self.song.tracks.get(track_index) else {
+                return Ok(());
+            };
+            let mut events = midi_import
+                .tracks
+                .iter()
+                .flat_map(|x| x.events.iter().cloned())
+                .collect::<Vec<_>>();
+            events.sort_by_key(|(position, _)| *position);
+            let mut automation_params = track.automation_params.clone();
+            let nautomation_params = automation_params.len();
+            let lane_items = midi_import
+                .lane_items(&events, &mut automation_params)
+                .into_iter()
+                .map(|(lane, line, lane_item)| {
+                    (
+                        CursorTrack {
+                            track: track_index,
+                            lane,
+                            line,
+                        },
+                        Some(lane_item),
+                    )
+                })
+                .collect::<Vec<_>>();
 
-                while lane_line_used.contains(&(lane, line)) {
-                    lane += 1;
-                }
-                match &lane_item {
-                    LaneItem::Note(Note {
-                        key, off: false, ..
-                    }) => {
-                        key_lane_map.insert(*key, lane);
-                        lane_line_used.insert((lane, line));
+            // 新しいパラメータは PointNew で追加してから LaneItem で上書きする
+            for (index, (module_index, param_id)) in automation_params
+                .iter()
+                .enumerate()
+                .skip(nautomation_params)
+            {
+                let cursor = lane_items.iter().find_map(|(cursor, item)| match item {
+                    Some(LaneItem::Point(point)) if point.automation_params_index == index => {
+                        Some(cursor)
                     }
-                    _ => {}
+                    _ => None,
+                });
+                if let Some(cursor) = cursor {
+                    self.send_to_audio(MainToAudio::PointNew(*cursor, *module_index, *param_id))?;
                 }
+            }
+            self.send_to_audio(MainToAudio::LaneItem(lane_items))?;
+        }
+        Ok(())
+    }
 
-                lane_items.push((
-                    CursorTrack {
-                        track: track_index,
-                        lane,
-                        line,
-                    },
-                    Some(lane_item),
-                ));
+    pub fn midi_file_export(&mut self, cc_p: bool) -> Result<()> {
+        let file_name = Path::new(&self.song.name).with_extension("mid");
+        let directory = match self.song_state.song_file_get() {
+            Some(song_file) => Project::new(Path::new(&song_file)).renders_dir(),
+            None => song_directory(),
+        };
+        if let Some(path) = FileDialog::new()
+            .set_directory(directory)
+            .set_file_name(file_name.to_string_lossy())
+            .add_filter("MIDI", &["mid", "midi"])
+            .save_file()
+        {
+            let warnings = midi_file_export(&self.song, &path, cc_p)?;
+            self.info = format!("Exported {}.", path.display());
+            for warning in warnings {
+                self.info = format!("{} {}", self.info, warning);
             }
+        }
+        Ok(())
+    }
 
-            self.send_to_audio(MainToAudio::LaneItem(lane_items))?;
+    pub fn audio_device_input_open(&mut self, name: &str) -> Result<()> {
+        // 古いストリームを先に閉じる
+        self.audio_device_input = None;
+        match AudioInputDevice::new(name, self.song.sample_rate, self.audio_input_buffer.clone()) {
+            Ok(device) => self.audio_device_input = Some(device),
+            Err(e) => {
+                self.info = format!("Failed to open {}. {}", name, e);
+                return Ok(());
+            }
         }
+        self.config.audio_device_input = Some(name.to_string());
+        self.config.save()?;
         Ok(())
     }
 
     pub fn midi_device_input_open(&mut self, name: &str) -> Result<()> {
-        self.midi_device_input = Some(MidiDevice::new(name, self.sender_midi.clone())?);
+        self.midi_device_input = Some(MidiDevice::new(
+            name,
+            self.sender_midi.clone(),
+            self.sender_midi_sync.clone(),
+        )?);
         self.config.midi_device_input = Some(name.to_string());
         self.config.save()?;
         Ok(())
     }
 
+    pub fn midi_clock_output_set(&mut self, midi_clock_output: Option<String>) -> Result<()> {
+        self.send_to_audio(MainToAudio::MidiClockOutput(midi_clock_output.clone()))?;
+        self.config.midi_clock_output = midi_clock_output;
+        self.config.save()?;
+        Ok(())
+    }
+
+    pub fn midi_sync_follow_toggle(&mut self) -> Result<()> {
+        let follow_p = !self.config.midi_sync_follow_p;
+        self.send_to_audio(MainToAudio::MidiSyncFollow(follow_p))?;
+        self.config.midi_sync_follow_p = follow_p;
+        self.config.save()?;
+        Ok(())
+    }
+
     fn module_at(&self, module_index: ModuleIndex) -> Option<&Module> {
         self.song.module_at(module_index)
     }
@@ -768,6 +977,17 @@ impl<'a> AppState<'a> {
         self.song.module_at_mut(module_index)
     }
 
+    // 応答しなくなったか、プラグインプロセスを落とした
+    pub fn module_fault_p(&self, module_index: ModuleIndex) -> bool {
+        let (track_index, module_index) = module_index;
+        let Some(module) = self.module_at((track_index, module_index)) else {
+            return false;
+        };
+        self.module_crashes.contains(&module.id)
+            || (module_index < u64::BITS as usize
+                && self.song_state.tracks[track_index].module_faults & (1 << module_index) != 0)
+    }
+
     fn module_load(&mut self, module_index: ModuleIndex, gui_open_p: bool) -> Result<()> {
         let module = self.module_at_mut(module_index);
         if module.is_none() {
@@ -777,14 +997,80 @@ impl<'a> AppState<'a> {
         let module_id = module.id;
         let plugin_id = module.plugin_id.clone();
         let state = module.state.take();
+        let isolated_p = self.plugin_isolated_p(&plugin_id);
         self.send_to_plugin(
-            MainToPlugin::Load(module_id, plugin_id, gui_open_p, state),
+            MainToPlugin::Load(
+                module_id,
+                plugin_id,
+                gui_open_p,
+                state,
+                self.song.nchannels,
+                isolated_p,
+            ),
             // TODO singer にプラグインがアクティブになったことを通知？
-            Box::new(|_, _| Ok(())),
+            Box::new(move |state, message| {
+                if gui_open_p && matches!(message, PluginToMain::DidLoad(..)) {
+                    state.module_gui_opens.insert(module_id);
+                }
+                Ok(())
+            }),
         )?;
         Ok(())
     }
 
+    pub fn module_gui_open(&mut self, module_id: ModuleId) -> Result<()> {
+        self.send_to_plugin(
+            MainToPlugin::GuiOpen(module_id),
+            Box::new(move |state, message| {
+                if message == PluginToMain::DidGuiOpen {
+                    state.module_gui_opens.insert(module_id);
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    // パラメータを選ぶ画面に出すパラメータを取ってくる
+    pub fn param_select_view_params_fetch(&mut self, module_id: ModuleId) -> Result<()> {
+        self.param_select_view_module_id = Some(module_id);
+        self.send_to_plugin(
+            MainToPlugin::Params(module_id),
+            Box::new(move |state, message| {
+                if let PluginToMain::DidParams(mut params) = message {
+                    if state.param_select_view_module_id != Some(module_id) {
+                        return Ok(());
+                    }
+                    // ホスト側で処理するバイパスなども選べるように
+                    if let Some(module) = state
+                        .song
+                        .module_index_by_id(module_id)
+                        .and_then(|module_index| state.song.module_at(module_index))
+                    {
+                        params.append(&mut module.host_params());
+                    }
+                    state.param_select_view_params = params;
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    // プラグインプロセスを起動し直したので、落とした原因のモジュール以外を保存してある state で読み込み直す
+    fn modules_reload(&mut self, module_ids: Vec<ModuleId>) -> Result<()> {
+        let mut n = 0;
+        for track_index in 0..self.song.tracks.len() {
+            for module_index in 0..self.song.tracks[track_index].modules_loaded().len() {
+                let module_id = self.song.tracks[track_index].modules[module_index].id;
+                if module_ids.contains(&module_id) && !self.module_crashes.contains(&module_id) {
+                    self.module_load((track_index, module_index), false)?;
+                    n += 1;
+                }
+            }
+        }
+        self.info = format!("Restarted the plugin process and reloaded {} modules.", n);
+        Ok(())
+    }
+
     pub fn now_update(&mut self) {
         self.elapsed = self.now.elapsed().as_secs_f32();
         self.now = Instant::now();
@@ -985,31 +1271,164 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    pub fn plugin_isolated_p(&self, plugin_id: &str) -> bool {
+        self.config.plugins_isolated.iter().any(|x| x == plugin_id)
+    }
+
+    // そのプラグインのモジュールを全部、専用のプロセスか共有のプロセスに移す
+    pub fn plugin_isolate_toggle(&mut self, module_index: ModuleIndex) -> Result<()> {
+        let Some(module) = self.module_at(module_index) else {
+            return Ok(());
+        };
+        let plugin_id = module.plugin_id.clone();
+        let name = module.name.clone();
+        let isolated_p = !self.plugin_isolated_p(&plugin_id);
+        if isolated_p {
+            self.config.plugins_isolated.push(plugin_id.clone());
+        } else {
+            self.config.plugins_isolated.retain(|x| *x != plugin_id);
+        }
+        self.config.save()?;
+
+        let modules = self
+            .song
+            .tracks
+            .iter()
+            .enumerate()
+            .flat_map(|(track_index, track)| {
+                track
+                    .modules_loaded()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, module)| module.plugin_id == plugin_id)
+                    .map(move |(module_index, module)| ((track_index, module_index), module.id))
+            })
+            .collect::<Vec<_>>();
+        let module_ids = modules.iter().map(|(_, id)| *id).collect();
+        self.module_states_save_by_ids(
+            module_ids,
+            Box::new(move |state| {
+                for (module_index, module_id) in modules.iter() {
+                    state.send_to_audio(MainToAudio::PluginLoaded(*module_id, false))?;
+                    state.send_to_plugin(
+                        MainToPlugin::Unload(*module_id),
+                        Box::new(|_, _| Ok(())),
+                    )?;
+                    state.module_load(*module_index, false)?;
+                }
+                state.info = if isolated_p {
+                    format!("{} runs in its own process.", name)
+                } else {
+                    format!("{} runs in the shared process.", name)
+                };
+                Ok(())
+            }),
+        )
+    }
+
+    pub fn plugin_process_report(&mut self) -> Result<()> {
+        self.plugin_process_window_open_p = true;
+        Ok(())
+    }
+
     pub fn plugin_delete(&mut self, module_index: ModuleIndex) -> Result<()> {
+        if self.track_frozen_p(module_index.0) {
+            return Ok(());
+        }
         if let Some(module_id) = self.module_at(module_index).map(|x| x.id) {
-            self.send_to_audio(MainToAudio::PluginDelete(module_index))?;
-            self.send_to_plugin(MainToPlugin::Unload(module_id), Box::new(|_, _| Ok(())))?;
+            // undo で戻せるように state を取ってから消す
+            self.module_states_save_by_ids(
+                vec![module_id],
+                Box::new(move |state| {
+                    state.send_to_audio(MainToAudio::PluginDelete(module_index))?;
+                    state.send_to_plugin(MainToPlugin::Unload(module_id), Box::new(|_, _| Ok(())))
+                }),
+            )?;
         }
         Ok(())
     }
 
+    // カーソル位置に挿入する。末尾の + ならそこに追加
     pub fn plugin_load(&mut self, description: &Description, gui_open_p: bool) -> Result<()> {
         let track_index = self.cursor_track.track;
-        self.send_to_audio(MainToAudio::PluginLoad(
+        if self.track_frozen_p(track_index) {
+            return Ok(());
+        }
+        let module_index = (
             track_index,
+            self.cursor_module
+                .index
+                .min(self.song.tracks[track_index].modules.len()),
+        );
+        self.send_to_audio(MainToAudio::PluginLoad(
+            module_index,
             description.id.clone(),
             description.name.clone(),
         ))?;
 
         self.song_apply_callbacks.push_back(Box::new(move |state| {
-            let module_index = state.song.tracks[track_index].modules.len() - 1;
-            state.module_load((track_index, module_index), gui_open_p)?;
+            state.module_load(module_index, gui_open_p)?;
             Ok(())
         }));
 
         Ok(())
     }
 
+    pub fn module_bypass_set(&mut self, module_index: ModuleIndex, bypass_p: bool) -> Result<()> {
+        self.send_to_audio(MainToAudio::ModuleBypass(module_index, bypass_p))?;
+        Ok(())
+    }
+
+    pub fn module_aux_outputs_set(
+        &mut self,
+        module_index: ModuleIndex,
+        aux_outputs: Vec<AuxOutput>,
+    ) -> Result<()> {
+        self.send_to_audio(MainToAudio::ModuleAuxOutputs(module_index, aux_outputs))?;
+        Ok(())
+    }
+
+    pub fn module_aux_output_toggle(
+        &mut self,
+        module_index: ModuleIndex,
+        port_index: usize,
+    ) -> Result<()> {
+        let Some(module) = self.song.module_at(module_index) else {
+            return Ok(());
+        };
+        let mut aux_outputs = module.aux_outputs.clone();
+        if let Some(index) = aux_outputs.iter().position(|x| x.port_index == port_index) {
+            aux_outputs.remove(index);
+        } else {
+            aux_outputs.push(AuxOutput::new(port_index));
+            aux_outputs.sort_by_key(|x| x.port_index);
+        }
+        self.module_aux_outputs_set(module_index, aux_outputs)
+    }
+
+    pub fn module_gain_set(&mut self, module_index: ModuleIndex, gain: f32) -> Result<()> {
+        self.send_to_audio(MainToAudio::ModuleGain(module_index, gain))?;
+        Ok(())
+    }
+
+    pub fn module_mix_set(&mut self, module_index: ModuleIndex, mix: f32) -> Result<()> {
+        self.send_to_audio(MainToAudio::ModuleMix(module_index, mix))?;
+        Ok(())
+    }
+
+    fn module_move(&mut self, delta: isize) -> Result<()> {
+        let track_index = self.cursor_track.track;
+        let index = self.cursor_module.index;
+        let index_new = index.saturating_add_signed(delta);
+        let len = self.song.tracks[track_index].modules.len();
+        if index >= len || index_new >= len || index_new == index {
+            return Ok(());
+        }
+        self.send_to_audio(MainToAudio::ModuleMove((track_index, index), delta))?;
+        self.cursor_module.index = index_new;
+        Ok(())
+    }
+
     pub fn plugin_sidechain(
         &mut self,
         module_index: ModuleIndex,
@@ -1033,36 +1452,122 @@ impl<'a> AppState<'a> {
     }
 
     pub fn receive_from_communicator(&mut self) -> Result<()> {
-        while let Ok(mut message) = self.receiver_communicator_to_main_thread.try_recv() {
+        while let Ok(envelope) = self.receiver_communicator_to_main_thread.try_recv() {
+            let Envelope {
+                request_id,
+                mut message,
+            } = envelope;
+            if request_id == NOTIFICATION_ID {
+                self.plugin_notification(message)?;
+                continue;
+            }
             match &mut message {
                 PluginToMain::DidHwnd => {}
-                PluginToMain::DidLoad(id, latency) => {
+                PluginToMain::DidLoad(id, latency, bypass_param_id, audio_output_names) => {
                     self.send_to_audio(MainToAudio::PluginLatency(*id, *latency))?;
+                    self.send_to_audio(MainToAudio::PluginBypassParam(*id, *bypass_param_id))?;
+                    self.send_to_audio(MainToAudio::PluginLoaded(*id, true))?;
+                    self.module_output_ports
+                        .insert(*id, std::mem::take(audio_output_names));
+                    self.module_crashes.remove(id);
+                }
+                PluginToMain::DidUnload(id) => {
+                    self.module_gui_opens.remove(id);
                 }
-                PluginToMain::DidUnload(_) => {}
                 PluginToMain::DidGuiOpen => {}
                 PluginToMain::DidParams(_params) => {}
                 PluginToMain::DidStateLoad => {}
                 PluginToMain::DidStateSave(id, state) => {
+                    // undo で削除したモジュールを戻すときに使うので singer 側にも
+                    self.send_to_audio(MainToAudio::ModuleState(*id, state.clone()))?;
                     if let Some(module) = self.song.module_by_id_mut(*id) {
                         module.state = Some(std::mem::take(state));
                     }
                 }
+                PluginToMain::DidChannels => {}
                 PluginToMain::DidScan => {}
                 PluginToMain::Quit => {}
+                PluginToMain::Crashed(id) => {
+                    let name = match *id {
+                        Some(id) => {
+                            self.module_crashes.insert(id);
+                            self.song
+                                .module_by_id_mut(id)
+                                .map(|module| module.name.clone())
+                        }
+                        None => None,
+                    };
+                    self.info = match name {
+                        Some(name) => format!("{} crashed the plugin process.", name),
+                        None => "The plugin process crashed.".to_string(),
+                    };
+                }
+                // 以下は通知なので来ない
+                PluginToMain::Respawned(_)
+                | PluginToMain::ParamsRescan(_)
+                | PluginToMain::LatencyChanged(_, _)
+                | PluginToMain::GuiClosed(_)
+                | PluginToMain::RestartRequested(_)
+                | PluginToMain::Log(_, _, _) => {
+                    log::warn!("unexpected reply {request_id} {:?}", message);
+                }
             }
-            if let Some(callback) = self.callbacks_plugin_to_main.pop_front() {
+            if let Some(callback) = self.callbacks_plugin_to_main.remove(&request_id) {
                 callback(self, message)?;
             }
         }
         Ok(())
     }
 
-    fn redo(&mut self) -> Result<()> {
-        self.send_to_audio(MainToAudio::Redo)?;
+    // 返事ではないのでコールバックは呼ばない
+    fn plugin_notification(&mut self, message: PluginToMain) -> Result<()> {
+        match message {
+            PluginToMain::Respawned(module_ids) => {
+                // 読み込み直すときに GUI は開かない
+                for id in module_ids.iter() {
+                    self.module_gui_opens.remove(id);
+                }
+                self.modules_reload(module_ids)?;
+            }
+            PluginToMain::ParamsRescan(id) => {
+                if self.param_select_view_module_id == Some(id) {
+                    self.param_select_view_params_fetch(id)?;
+                }
+            }
+            PluginToMain::LatencyChanged(id, latency) => {
+                self.send_to_audio(MainToAudio::PluginLatency(id, latency))?;
+            }
+            PluginToMain::GuiClosed(id) => {
+                self.module_gui_opens.remove(&id);
+            }
+            PluginToMain::RestartRequested(id) => {
+                // 状態を取ってから読み込み直す
+                // 返事が来るまでにモジュールが移動や削除されているかもしれないので、その時に探す
+                self.send_to_plugin(
+                    MainToPlugin::StateSave(id),
+                    Box::new(move |state, _| {
+                        let Some(module_index) = state.song.module_index_by_id(id) else {
+                            return Ok(());
+                        };
+                        let gui_open_p = state.module_gui_opens.contains(&id);
+                        state.send_to_audio(MainToAudio::PluginLoaded(id, false))?;
+                        state.send_to_plugin(MainToPlugin::Unload(id), Box::new(|_, _| Ok(())))?;
+                        state.module_load(module_index, gui_open_p)
+                    }),
+                )?;
+            }
+            PluginToMain::Log(id, level, message) => {
+                log::log!(log::Level::from(level), "plugin {id}: {message}");
+            }
+            message => log::warn!("unexpected notification {:?}", message),
+        }
         Ok(())
     }
 
+    fn redo(&mut self) -> Result<()> {
+        self.undo_travel(MainToAudio::Redo)
+    }
+
     pub fn run_ui_command(&mut self, command: &UiCommand) -> Result<()> {
         let command = command.clone();
         let digit = self.digit.clone();
@@ -1137,6 +1642,12 @@ impl<'a> AppState<'a> {
                 let mute = mute.unwrap_or(!self.song.tracks[track_index].mute);
                 self.send_to_audio(MainToAudio::TrackMute(track_index, mute))?;
             }
+            UiCommand::TrackInput(track_index, input_p) => {
+                self.send_to_audio(MainToAudio::TrackInput(*track_index, *input_p))?;
+            }
+            UiCommand::TrackMonitor(track_index, monitor_p) => {
+                self.send_to_audio(MainToAudio::TrackMonitor(*track_index, *monitor_p))?;
+            }
             UiCommand::TrackRecOn(track_index) => {
                 self.rec_set(*track_index, true)?;
             }
@@ -1249,6 +1760,12 @@ impl<'a> AppState<'a> {
                 self.selection_track_min = None;
                 self.selection_track_max = None;
             }
+            UiCommand::Module(ModuleCommand::Bypass) => {
+                if let Some(module) = self.module_at_cursort() {
+                    let bypass_p = !module.bypass_p;
+                    self.module_bypass_set(self.module_index_at_cursor(), bypass_p)?;
+                }
+            }
             UiCommand::Module(ModuleCommand::CursorUp) => {
                 if self.cursor_module.index == 0 {
                     self.cursor_module.index =
@@ -1285,16 +1802,13 @@ impl<'a> AppState<'a> {
                 }
             }
             UiCommand::Module(ModuleCommand::Delete) => {
-                if let Some(module_id) = self.module_at_cursort().map(|x| x.id) {
-                    let track_index = self.cursor_track.track;
-                    let module_index = self.cursor_module.index;
-                    self.send_to_audio(MainToAudio::PluginDelete((track_index, module_index)))?;
-                    self.send_to_plugin(MainToPlugin::Unload(module_id), Box::new(|_, _| Ok(())))?;
-                }
+                self.plugin_delete((self.cursor_track.track, self.cursor_module.index))?;
             }
+            UiCommand::Module(ModuleCommand::MoveUp) => self.module_move(-1)?,
+            UiCommand::Module(ModuleCommand::MoveDown) => self.module_move(1)?,
             UiCommand::Module(ModuleCommand::Open) => {
                 if let Some(module) = self.module_at_cursort() {
-                    self.send_to_plugin(MainToPlugin::GuiOpen(module.id), Box::new(|_, _| Ok(())))?;
+                    self.module_gui_open(module.id)?;
                 } else {
                     self.route = Route::PluginSelect;
                 }
@@ -1351,6 +1865,13 @@ impl<'a> AppState<'a> {
     }
 
     fn send_to_audio(&mut self, command: MainToAudio) -> Result<AudioToMain> {
+        if !matches!(command, MainToAudio::UndoContext(_)) {
+            let context = self.undo_context();
+            if self.undo_context_sent != Some(context) {
+                self.undo_context_sent = Some(context);
+                self.send_to_audio(MainToAudio::UndoContext(context))?;
+            }
+        }
         self.sender_to_singer.send(command)?;
         let res = self.receiver_from_audio.recv()?;
         match res {
@@ -1366,10 +1887,13 @@ impl<'a> AppState<'a> {
     pub fn send_to_plugin(
         &mut self,
         command: MainToPlugin,
-        callback: Box<dyn Fn(&mut AppState, PluginToMain) -> Result<()>>,
+        callback: Box<PluginCallback>,
     ) -> Result<()> {
-        self.callbacks_plugin_to_main.push_back(callback);
-        self.sender_to_loop.send(command)?;
+        self.request_id_last += 1;
+        let request_id = self.request_id_last;
+        self.callbacks_plugin_to_main.insert(request_id, callback);
+        self.sender_to_loop
+            .send(Envelope::new(request_id, command))?;
         Ok(())
     }
 
@@ -1379,8 +1903,19 @@ impl<'a> AppState<'a> {
             self.send_to_audio(MainToAudio::Song)?;
         }
         if let Some(song) = self.song_next.take() {
+            let nchannels_changed_p = song.nchannels != self.song.nchannels;
             self.song = song;
             self.song_change_p = true;
+            if nchannels_changed_p {
+                // プラグインの audio-ports-config を選び直す
+                self.send_to_plugin(
+                    MainToPlugin::Channels(self.song.nchannels),
+                    Box::new(|_, _| Ok(())),
+                )?;
+            }
+            if self.undo_history.is_some() {
+                self.undo_history_fetch()?;
+            }
             self.compute_track_offsets();
             self.cursor_track.track = self
                 .cursor_track
@@ -1452,61 +1987,217 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    pub fn song_new(&mut self) -> Result<()> {
+        self.send_to_audio(MainToAudio::SongNew)?;
+        self.song_apply_callbacks.push_back(Box::new(|state| {
+            state.cursor_track = Default::default();
+            state.cursor_module = Default::default();
+            state.select_p = false;
+            state.selection_track_min = Default::default();
+            state.selection_track_max = Default::default();
+            state.song_dirty_p = false;
+            state.info = "New song.".to_string();
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    pub fn song_new_from_template(&mut self, path: String) -> Result<()> {
+        self.song_open_file(
+            path.clone(),
+            Box::new(move |state| {
+                // テンプレートを上書きしないように
+                state.send_to_audio(MainToAudio::SongFile("".to_string()))?;
+                state.song_dirty_p = false;
+                state.info = format!("New song from {}.", path);
+                Ok(())
+            }),
+        )
+    }
+
+    pub fn song_template_save(&mut self) -> Result<()> {
+        let Some(path) = FileDialog::new()
+            .set_directory(template_directory())
+            .set_file_name(&self.song.name)
+            .add_filter("Song", &["json"])
+            .save_file()
+        else {
+            return Ok(());
+        };
+        self.module_states_save(Box::new(move |state| {
+            state.song_write(&path)?;
+            state.info = format!("Saved template {}.", path.display());
+            Ok(())
+        }))
+    }
+
     pub fn song_open(&mut self) -> Result<()> {
         if let Some(path) = FileDialog::new()
             .set_directory(song_directory())
+            .add_filter("Song", &["json"])
             .pick_file()
         {
-            let path = path.to_str().map(|s| s.to_string()).unwrap();
-            self.send_to_audio(MainToAudio::SongOpen(path.clone()))?;
-            self.song_apply_callbacks.push_back(Box::new(move |state| {
-                state.cursor_track = Default::default();
-                state.cursor_module = Default::default();
-                state.select_p = false;
-                state.selection_track_min = Default::default();
-                state.selection_track_max = Default::default();
-                for track_index in 0..state.song.tracks.len() {
-                    for module_index in 0..state.song.tracks[track_index].modules.len() {
-                        state.module_load((track_index, module_index), false)?;
-                    }
-                }
+            self.song_open_path(path.to_str().map(|s| s.to_string()).unwrap())?;
+        }
+        Ok(())
+    }
+
+    pub fn song_open_path(&mut self, path: String) -> Result<()> {
+        self.song_open_file(
+            path.clone(),
+            Box::new(move |state| {
                 state.song_dirty_p = false;
+                state.config.recent_project_add(&path)?;
                 state.info = format!("Opened {}.", path);
                 Ok(())
-            }));
-        }
+            }),
+        )
+    }
+
+    fn song_open_file(&mut self, path: String, callback: Box<StateCallback>) -> Result<()> {
+        self.send_to_audio(MainToAudio::SongOpen(path))?;
+        self.song_apply_callbacks.push_back(Box::new(move |state| {
+            state.cursor_track = Default::default();
+            state.cursor_module = Default::default();
+            state.select_p = false;
+            state.selection_track_min = Default::default();
+            state.selection_track_max = Default::default();
+            for track_index in 0..state.song.tracks.len() {
+                for module_index in 0..state.song.tracks[track_index].modules_loaded().len() {
+                    state.module_load((track_index, module_index), false)?;
+                }
+            }
+            callback(state)
+        }));
         Ok(())
     }
 
     pub fn song_save(&mut self) -> Result<()> {
-        let mut callback_p = false;
+        if self.song_state.song_file_get().is_none() {
+            return self.song_save_as(false);
+        }
+        self.module_states_save(Box::new(|state| state.song_save_file()))
+    }
 
-        let module_indexs = (0..self.song.tracks.len())
-            .flat_map(|track_index| {
-                (0..self.song.tracks[track_index].modules.len())
-                    .map(move |module_index| (track_index, module_index))
-            })
+    // copy_p の場合は別のプロジェクトに書き出すだけで、開いている曲はそのまま
+    pub fn song_save_as(&mut self, copy_p: bool) -> Result<()> {
+        let Some(path) = FileDialog::new()
+            .set_directory(song_directory())
+            .set_file_name(&self.song.name)
+            .save_file()
+        else {
+            return Ok(());
+        };
+        let project = Project::create(&path)?;
+        if let Some(song_file) = self.song_state.song_file_get() {
+            Project::new(Path::new(&song_file)).copy_assets_to(&project)?;
+        }
+        let song_file = project.song_file();
+        self.module_states_save(Box::new(move |state| {
+            let items = state.audio_clips_import(&project)?;
+            let mut song = state.song.clone();
+            for (cursor, item) in items.iter() {
+                if let Some(item) = item {
+                    song.tracks[cursor.track].lanes[cursor.lane]
+                        .items
+                        .insert(cursor.line, item.clone());
+                }
+            }
+            song_file_write(&song, &song_file)?;
+            let song_file = song_file.to_str().unwrap();
+            if copy_p {
+                state.info = format!("Saved a copy to {}.", song_file);
+            } else {
+                if !items.is_empty() {
+                    state.send_to_audio(MainToAudio::LaneItem(items))?;
+                }
+                state.send_to_audio(MainToAudio::SongFile(song_file.to_string()))?;
+                state.song_dirty_p = false;
+                recovery::recovery_discard();
+                state.config.recent_project_add(song_file)?;
+                state.info = format!("Saved {}.", song_file);
+            }
+            Ok(())
+        }))
+    }
+
+    // 全モジュールの state をプラグインから取得してから callback を呼ぶ
+    fn module_states_save(&mut self, callback: Box<StateCallback>) -> Result<()> {
+        let module_ids = self
+            .song
+            .tracks
+            .iter()
+            .flat_map(|track| track.modules_loaded().iter().map(|module| module.id))
             .collect::<Vec<_>>();
-        let modules_len = module_indexs.len();
-        for (index, module_index) in module_indexs.iter().enumerate() {
-            let callback: Box<dyn Fn(&mut AppState, PluginToMain) -> Result<()>> =
-                if index == modules_len - 1 {
-                    Box::new(|state, _| state.song_save_file())
-                } else {
-                    Box::new(|_, _| Ok(()))
-                };
-            let module = &self.song.tracks[module_index.0].modules[module_index.1];
-            self.send_to_plugin(MainToPlugin::StateSave(module.id), callback)?;
-            callback_p = true;
-        }
+        self.module_states_save_by_ids(module_ids, callback)
+    }
 
-        if !callback_p {
-            self.song_save_file()?;
+    fn module_states_save_by_ids(
+        &mut self,
+        module_ids: Vec<ModuleId>,
+        callback: Box<StateCallback>,
+    ) -> Result<()> {
+        if module_ids.is_empty() {
+            return callback(self);
+        }
+
+        // 返事は送った順に来るとは限らないので数える
+        let callback: Rc<StateCallback> = Rc::from(callback);
+        let remaining = Rc::new(Cell::new(module_ids.len()));
+        for module_id in module_ids {
+            let callback = callback.clone();
+            let remaining = remaining.clone();
+            self.send_to_plugin(
+                MainToPlugin::StateSave(module_id),
+                Box::new(move |state, _| {
+                    remaining.set(remaining.get() - 1);
+                    if remaining.get() == 0 {
+                        (*callback)(state)?;
+                    }
+                    Ok(())
+                }),
+            )?;
         }
 
         Ok(())
     }
 
+    pub fn autosave(&mut self) -> Result<()> {
+        if !self.song_dirty_p
+            || self.autosave_p
+            || self.autosave_last.elapsed() < recovery::AUTOSAVE_INTERVAL
+        {
+            return Ok(());
+        }
+        self.autosave_p = true;
+        self.module_states_save(Box::new(|state| {
+            state.autosave_p = false;
+            state.autosave_last = Instant::now();
+            recovery::recovery_save(&state.song, state.song_state.song_file_get().as_deref())
+        }))
+    }
+
+    pub fn recovery_restore(&mut self) -> Result<()> {
+        self.recovery_p = false;
+        let song_file = recovery::recovery_song_file();
+        self.song_open_file(
+            recovery::recovery_file().to_str().unwrap().to_string(),
+            Box::new(move |state| {
+                let song_file = song_file.clone().unwrap_or_default();
+                state.send_to_audio(MainToAudio::SongFile(song_file))?;
+                // 保存されるまでは未保存扱い
+                state.song_dirty_p = true;
+                state.info = "Restored unsaved work.".to_string();
+                Ok(())
+            }),
+        )
+    }
+
+    pub fn recovery_discard(&mut self) {
+        self.recovery_p = false;
+        recovery::recovery_discard();
+    }
+
     pub fn compute_track_offsets(&mut self) {
         self.offset_tracks.clear();
         self.offset_flatten_lanes.clear();
@@ -1848,6 +2539,9 @@ impl<'a> AppState<'a> {
                     LaneItem::Point(point) => {
                         point.value = (point.value as i16 + value_delta).clamp(0, 0xff) as u8;
                     }
+                    LaneItem::AudioClip(clip) => {
+                        clip.delay = (clip.delay as i16 + delay_delta).clamp(0, 0xff) as u8;
+                    }
                     LaneItem::Label(_) => {}
                     LaneItem::Call(_) => {}
                     LaneItem::Ret => {}
@@ -1868,6 +2562,9 @@ impl<'a> AppState<'a> {
                     LaneItem::Point(point) => {
                         point.value = (point.value as i16 + value_delta).clamp(0, 0xff) as u8;
                     }
+                    LaneItem::AudioClip(clip) => {
+                        clip.delay = (clip.delay as i16 + delay_delta).clamp(0, 0xff) as u8;
+                    }
                     LaneItem::Label(_) => {}
                     LaneItem::Call(_) => {}
                     LaneItem::Ret => {}
@@ -1928,27 +2625,65 @@ impl<'a> AppState<'a> {
     }
 
     fn song_save_file(&mut self) -> Result<()> {
-        let song_file = if let Some(song_file) = &self.song_state.song_file_get() {
-            song_file.into()
-        } else {
-            if let Some(path) = FileDialog::new()
-                .set_directory(song_directory())
-                .set_file_name(&self.song.name)
-                .save_file()
-            {
-                self.send_to_audio(MainToAudio::SongFile(
-                    path.to_str().map(|s| s.to_string()).unwrap(),
-                ))?;
-                path
-            } else {
-                return Ok(());
-            }
+        let Some(song_file) = self.song_state.song_file_get() else {
+            return Ok(());
         };
-        let mut file = File::create(&song_file).unwrap();
-        let json = serde_json::to_string_pretty(&self.song).unwrap();
-        file.write_all(json.as_bytes()).unwrap();
+        self.song_write(Path::new(&song_file))?;
         self.song_dirty_p = false;
-        self.info = format!("Saved {}.", song_file.display());
+        recovery::recovery_discard();
+        self.config.recent_project_add(&song_file)?;
+        self.info = format!("Saved {}.", song_file);
+        Ok(())
+    }
+
+    fn song_write(&mut self, song_file: &Path) -> Result<()> {
+        song_file_write(&self.song, song_file)
+    }
+
+    // プロジェクトの外にあるオーディオクリップを project の samples にコピーしてパスを付け替える
+    // 付け替えたクリップを返す
+    fn audio_clips_import(
+        &self,
+        project: &Project,
+    ) -> Result<Vec<(CursorTrack, Option<LaneItem>)>> {
+        let mut files: HashMap<String, String> = HashMap::new();
+        let mut items = vec![];
+        for (track_index, track) in self.song.tracks.iter().enumerate() {
+            for (lane_index, lane) in track.lanes.iter().enumerate() {
+                for (line, item) in lane.items.iter() {
+                    let LaneItem::AudioClip(clip) = item else {
+                        continue;
+                    };
+                    // 相対パスは copy_assets_to でコピー済み
+                    if !Path::new(&clip.file).is_absolute() {
+                        continue;
+                    }
+                    let file = match files.get(&clip.file) {
+                        Some(file) => file.clone(),
+                        None => {
+                            let file = project.sample_import(Path::new(&clip.file))?;
+                            files.insert(clip.file.clone(), file.clone());
+                            file
+                        }
+                    };
+                    let mut clip = clip.clone();
+                    clip.file = file;
+                    items.push((
+                        CursorTrack {
+                            track: track_index,
+                            lane: lane_index,
+                            line: *line,
+                        },
+                        Some(LaneItem::AudioClip(clip)),
+                    ));
+                }
+            }
+        }
+        Ok(items)
+    }
+
+    pub fn song_size_report(&mut self) -> Result<()> {
+        self.size_report = Some(SizeReport::new(&self.song)?);
         Ok(())
     }
 
@@ -1968,29 +2703,29 @@ impl<'a> AppState<'a> {
 
     fn track_copy(&mut self) -> Result<()> {
         let track_index = self.cursor_track.track;
-        let modules_len = self.song.tracks[track_index].modules.len();
+        let modules_len = self.song.tracks[track_index].modules_loaded().len();
         if modules_len == 0 {
-            let json = serde_json::to_string_pretty(&self.song.tracks[track_index])?;
+            let mut track = self.song.tracks[track_index].clone();
+            track.detach(track_index);
+            let json = serde_json::to_string_pretty(&track)?;
             let mut clipboard = Clipboard::new().unwrap();
             clipboard.set_text(&json)?;
         } else {
-            for module_index in 0..modules_len {
-                let callback: Box<dyn Fn(&mut AppState, PluginToMain) -> Result<()>> =
-                    if module_index + 1 == modules_len {
-                        Box::new(|state, _command| {
-                            let json = serde_json::to_string_pretty(
-                                &state.song.tracks[state.cursor_track.track],
-                            )?;
-                            let mut clipboard = Clipboard::new().unwrap();
-                            clipboard.set_text(&json)?;
-                            Ok(())
-                        })
-                    } else {
-                        Box::new(|_state, _command| Ok(()))
-                    };
-                let module = &self.song.tracks[track_index].modules[module_index];
-                self.send_to_plugin(MainToPlugin::StateSave(module.id), callback)?;
-            }
+            let module_ids = self.song.tracks[track_index].modules[..modules_len]
+                .iter()
+                .map(|module| module.id)
+                .collect();
+            self.module_states_save_by_ids(
+                module_ids,
+                Box::new(move |state| {
+                    let mut track = state.song.tracks[track_index].clone();
+                    track.detach(track_index);
+                    let json = serde_json::to_string_pretty(&track)?;
+                    let mut clipboard = Clipboard::new().unwrap();
+                    clipboard.set_text(&json)?;
+                    Ok(())
+                }),
+            )?;
         }
 
         Ok(())
@@ -2006,57 +2741,74 @@ impl<'a> AppState<'a> {
     }
 
     fn track_delete(&mut self) -> Result<()> {
+        let track_index = self.cursor_track.track;
         // main は消さない
-        if self.cursor_track.track != 0 {
-            self.send_to_audio(MainToAudio::TrackDelete(self.cursor_track.track))?;
+        if track_index == 0 {
+            return Ok(());
         }
-        Ok(())
+        let module_ids = self.song.tracks[track_index]
+            .modules_loaded()
+            .iter()
+            .map(|module| module.id)
+            .collect::<Vec<_>>();
+        // undo で戻せるように state を取ってから消す
+        self.module_states_save_by_ids(
+            module_ids.clone(),
+            Box::new(move |state| {
+                state.send_to_audio(MainToAudio::TrackDelete(track_index))?;
+                for module_id in module_ids.iter() {
+                    state.send_to_plugin(
+                        MainToPlugin::Unload(*module_id),
+                        Box::new(|_, _| Ok(())),
+                    )?;
+                }
+                Ok(())
+            }),
+        )
     }
 
     fn track_dup(&mut self) -> Result<()> {
         let track_index = self.cursor_track.track;
-        let modules_len = self.track_at_cursor().unwrap().modules.len();
+        let modules_len = self.track_at_cursor().unwrap().modules_loaded().len();
         if modules_len == 0 {
-            self.send_to_audio(MainToAudio::TrackInsert(
-                track_index + 1,
-                self.track_at_cursor().unwrap().clone(),
-            ))?;
+            let mut track = self.track_at_cursor().unwrap().clone();
+            track.references_shift_for_insert(track_index + 1);
+            self.send_to_audio(MainToAudio::TrackInsert(track_index + 1, track))?;
             self.track_next();
         } else {
-            for module_index in 0..modules_len {
-                let callback: Box<dyn Fn(&mut AppState, PluginToMain) -> Result<()>> =
-                    if module_index + 1 == modules_len {
-                        Box::new(move |state, _command| {
-                            state.send_to_audio(MainToAudio::TrackInsert(
-                                track_index + 1,
-                                state.song.tracks[track_index].clone(),
-                            ))?;
-                            state.song_apply_callbacks.push_back(Box::new(move |state| {
-                                for module_index in
-                                    0..state.song.tracks[track_index + 1].modules.len()
-                                {
-                                    state.module_load((track_index + 1, module_index), false)?;
-                                }
-                                state.track_next();
-                                Ok(())
-                            }));
-                            Ok(())
-                        })
-                    } else {
-                        Box::new(|_state, _command| Ok(()))
-                    };
-                self.send_to_plugin(
-                    MainToPlugin::StateSave(
-                        self.track_at_cursor().unwrap().modules[module_index].id,
-                    ),
-                    callback,
-                )?;
-            }
+            let module_ids = self.track_at_cursor().unwrap().modules[..modules_len]
+                .iter()
+                .map(|module| module.id)
+                .collect();
+            self.module_states_save_by_ids(
+                module_ids,
+                Box::new(move |state| {
+                    let mut track = state.song.tracks[track_index].clone();
+                    track.references_shift_for_insert(track_index + 1);
+                    state.send_to_audio(MainToAudio::TrackInsert(track_index + 1, track))?;
+                    state.song_apply_callbacks.push_back(Box::new(move |state| {
+                        for module_index in 0..state.song.tracks[track_index + 1].modules.len() {
+                            state.module_load((track_index + 1, module_index), false)?;
+                        }
+                        state.track_next();
+                        Ok(())
+                    }));
+                    Ok(())
+                }),
+            )?;
         }
 
         Ok(())
     }
 
+    pub fn track_midi_output_set(&mut self, midi_output: Option<String>) -> Result<()> {
+        self.send_to_audio(MainToAudio::TrackMidiOutput(
+            self.cursor_track.track,
+            midi_output,
+        ))?;
+        Ok(())
+    }
+
     fn track_move(&mut self, delta: isize) -> Result<()> {
         if delta == 0 {
             return Ok(());
@@ -2075,35 +2827,149 @@ impl<'a> AppState<'a> {
 
     fn track_paste(&mut self) -> Result<()> {
         if let Ok(text) = Clipboard::new()?.get_text() {
-            if let Ok(track) = serde_json::from_str::<Track>(&text) {
-                self.send_to_audio(MainToAudio::TrackInsert(self.cursor_track.track, track))?;
-                self.song_apply_callbacks.push_back(Box::new(|state| {
-                    let track =
-                        &mut state.song_next.as_mut().unwrap().tracks[state.cursor_track.track];
-                    let commands = track
-                        .modules
-                        .iter_mut()
-                        .map(|module| {
-                            MainToPlugin::Load(
-                                module.id,
-                                module.plugin_id.clone(),
-                                false,
-                                module.state.take(),
-                            )
-                        })
-                        .collect::<Vec<_>>();
-
-                    for command in commands {
-                        state.send_to_plugin(
-                            command,
-                            // TODO singer にプラグインがアクティブになったことを通知？
-                            Box::new(|_, _| Ok(())),
-                        )?;
-                    }
+            if let Ok(mut track) = serde_json::from_str::<Track>(&text) {
+                track.attach(self.cursor_track.track);
+                self.track_insert_with_modules(self.cursor_track.track, track)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn track_preset_insert(&mut self, path: &Path) -> Result<()> {
+        let mut track = serde_json::from_reader::<_, Track>(BufReader::new(File::open(path)?))?;
+        let track_index = self.cursor_track.track + 1;
+        track.attach(track_index);
+        self.track_insert_with_modules(track_index, track)?;
+        self.song_apply_callbacks.push_back(Box::new(move |state| {
+            state.cursor_track.track = track_index;
+            Ok(())
+        }));
+        self.info = format!("Inserted {}.", path.display());
+        Ok(())
+    }
+
+    pub fn track_preset_save(&mut self) -> Result<()> {
+        let track_index = self.cursor_track.track;
+        let Some(track) = self.track_current() else {
+            return Ok(());
+        };
+        let module_ids = track
+            .modules_loaded()
+            .iter()
+            .map(|module| module.id)
+            .collect();
+        let Some(path) = FileDialog::new()
+            .set_directory(preset_directory())
+            .set_file_name(&track.name)
+            .add_filter("Track", &["json"])
+            .save_file()
+        else {
+            return Ok(());
+        };
+        self.module_states_save_by_ids(
+            module_ids,
+            Box::new(move |state| {
+                let mut track = state.song.tracks[track_index].clone();
+                track.detach(track_index);
+                let json = serde_json::to_string_pretty(&track)?;
+                File::create(&path)?.write_all(json.as_bytes())?;
+                state.info = format!("Saved preset {}.", path.display());
+                Ok(())
+            }),
+        )
+    }
+
+    // フリーズ中のトラックのモジュールは編集できない
+    fn track_frozen_p(&mut self, track_index: usize) -> bool {
+        let frozen_p = self
+            .song
+            .tracks
+            .get(track_index)
+            .is_some_and(|track| track.freeze.is_some());
+        if frozen_p {
+            self.info = "Unfreeze the track first.".to_string();
+        }
+        frozen_p
+    }
+
+    pub fn track_freeze_toggle(&mut self) -> Result<()> {
+        let track_index = self.cursor_track.track;
+        let Some(track) = self.track_current() else {
+            return Ok(());
+        };
+        if track_index == 0 {
+            self.info = "The main track can't be frozen.".to_string();
+            return Ok(());
+        }
+        let module_ids_before = self.module_ids();
+        if track.freeze.is_some() {
+            self.send_to_audio(MainToAudio::TrackFreezeSet(track_index, None))?;
+            self.plugins_reconcile(module_ids_before);
+            return Ok(());
+        }
+        if track.modules.is_empty() {
+            self.info = "The track has no modules.".to_string();
+            return Ok(());
+        }
+        if self.song_state.song_file_get().is_none() {
+            self.info = "Save the song before freezing.".to_string();
+            return Ok(());
+        }
+        let module_ids = track.modules.iter().map(|module| module.id).collect();
+        // アンロードしても戻せるように state を取ってから書き出す
+        self.module_states_save_by_ids(
+            module_ids,
+            Box::new(move |state| {
+                state.info = "Freezing...".to_string();
+                state.send_to_audio(MainToAudio::TrackFreeze(track_index))?;
+                state.plugins_reconcile(module_ids_before.clone());
+                state.song_apply_callbacks.push_back(Box::new(move |state| {
+                    let track = &state.song.tracks[track_index];
+                    state.info = if track.freeze.is_some() {
+                        format!("Froze {}.", track.name)
+                    } else {
+                        format!("Failed to freeze {}.", track.name)
+                    };
                     Ok(())
                 }));
+                Ok(())
+            }),
+        )
+    }
+
+    // プラグインの state ごとトラックを挿入する
+    fn track_insert_with_modules(&mut self, track_index: usize, track: Track) -> Result<()> {
+        self.send_to_audio(MainToAudio::TrackInsert(track_index, track))?;
+        self.song_apply_callbacks.push_back(Box::new(move |state| {
+            let nchannels = state.song.nchannels;
+            let plugins_isolated = state.config.plugins_isolated.clone();
+            let track = &mut state.song_next.as_mut().unwrap().tracks[track_index];
+            let frozen_p = track.freeze.is_some();
+            let commands = track
+                .modules
+                .iter_mut()
+                .filter(|_| !frozen_p)
+                .map(|module| {
+                    MainToPlugin::Load(
+                        module.id,
+                        module.plugin_id.clone(),
+                        false,
+                        module.state.take(),
+                        nchannels,
+                        plugins_isolated.contains(&module.plugin_id),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            for command in commands {
+                state.send_to_plugin(
+                    command,
+                    // TODO singer にプラグインがアクティブになったことを通知？
+                    Box::new(|_, _| Ok(())),
+                )?;
             }
-        }
+            Ok(())
+        }));
         Ok(())
     }
 
@@ -2149,9 +3015,150 @@ impl<'a> AppState<'a> {
     }
 
     fn undo(&mut self) -> Result<()> {
-        self.send_to_audio(MainToAudio::Undo)?;
+        self.undo_travel(MainToAudio::Undo)
+    }
+
+    pub fn undo_jump(&mut self, position: usize) -> Result<()> {
+        self.undo_travel(MainToAudio::UndoJump(position))
+    }
+
+    // Undo, Redo, UndoJump
+    fn undo_travel(&mut self, command: MainToAudio) -> Result<()> {
+        let module_ids = self.module_ids();
+        self.send_to_audio(command)?;
+        self.plugins_reconcile(module_ids);
+
+        let snapshot = self.undo_history_snapshot()?;
+        if let Some(context) = snapshot.context_last {
+            // 変更された場所が見えるようにカーソルを戻す。main view はカーソルが中央になるようにスクロールする
+            self.song_apply_callbacks.push_back(Box::new(move |state| {
+                state.undo_context_apply(&context);
+                Ok(())
+            }));
+        }
+        if self.undo_history.is_some() {
+            self.undo_history = Some(snapshot);
+        }
+        Ok(())
+    }
+
+    fn undo_context(&self) -> UndoContext {
+        UndoContext {
+            cursor_track: self.cursor_track,
+            focused_part: self.focused_part,
+            select_p: self.select_p,
+            selection_track_min: self.selection_track_min,
+            selection_track_max: self.selection_track_max,
+        }
+    }
+
+    fn undo_context_apply(&mut self, context: &UndoContext) {
+        let clamp = |song: &Song, cursor: CursorTrack| {
+            let track = cursor.track.min(song.tracks.len().saturating_sub(1));
+            let lane = song
+                .tracks
+                .get(track)
+                .map_or(0, |x| cursor.lane.min(x.lanes.len().saturating_sub(1)));
+            CursorTrack {
+                track,
+                lane,
+                line: cursor.line,
+            }
+        };
+        self.cursor_track = clamp(&self.song, context.cursor_track);
+        self.focused_part = context.focused_part;
+        self.select_p = context.select_p;
+        self.selection_track_min = context.selection_track_min.map(|x| clamp(&self.song, x));
+        self.selection_track_max = context.selection_track_max.map(|x| clamp(&self.song, x));
+    }
+
+    fn undo_history_snapshot(&mut self) -> Result<UndoHistorySnapshot> {
+        match self.send_to_audio(MainToAudio::UndoHistory)? {
+            AudioToMain::UndoHistory(snapshot) => Ok(snapshot),
+            _ => unreachable!(),
+        }
+    }
+
+    fn undo_history_fetch(&mut self) -> Result<()> {
+        self.undo_history = Some(self.undo_history_snapshot()?);
         Ok(())
     }
+
+    pub fn undo_history_toggle(&mut self) -> Result<()> {
+        if self.undo_history.is_some() {
+            self.undo_history = None;
+        } else {
+            self.undo_history_fetch()?;
+        }
+        Ok(())
+    }
+
+    // 複数のメッセージを 1 回の undo にまとめる
+    fn undo_group(
+        &mut self,
+        description: &str,
+        f: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        self.send_to_audio(MainToAudio::UndoGroupBegin(description.to_string()))?;
+        let result = f(self);
+        self.send_to_audio(MainToAudio::UndoGroupEnd)?;
+        result
+    }
+
+    fn module_ids(&self) -> HashSet<ModuleId> {
+        self.song_next
+            .as_ref()
+            .unwrap_or(&self.song)
+            .tracks
+            .iter()
+            .flat_map(|track| track.modules_loaded().iter().map(|module| module.id))
+            .collect()
+    }
+
+    // undo/redo やフリーズで増えたモジュールをロードし、消えたモジュールをアンロードする
+    fn plugins_reconcile(&mut self, module_ids_before: HashSet<ModuleId>) {
+        self.song_apply_callbacks.push_back(Box::new(move |state| {
+            let modules = state
+                .song
+                .tracks
+                .iter()
+                .flat_map(|track| track.modules_loaded().iter())
+                .map(|module| (module.id, module.plugin_id.clone(), module.state.clone()))
+                .collect::<Vec<_>>();
+            for (module_id, plugin_id, module_state) in modules.iter() {
+                if !module_ids_before.contains(module_id) {
+                    state.send_to_plugin(
+                        MainToPlugin::Load(
+                            *module_id,
+                            plugin_id.clone(),
+                            false,
+                            module_state.clone(),
+                            state.song.nchannels,
+                            state.plugin_isolated_p(plugin_id),
+                        ),
+                        Box::new(|_, _| Ok(())),
+                    )?;
+                }
+            }
+            for module_id in module_ids_before.iter() {
+                if !modules.iter().any(|(id, _, _)| id == module_id) {
+                    state.send_to_plugin(
+                        MainToPlugin::Unload(*module_id),
+                        Box::new(|_, _| Ok(())),
+                    )?;
+                }
+            }
+            Ok(())
+        }));
+    }
+}
+
+fn song_file_write(song: &Song, song_file: &Path) -> Result<()> {
+    recovery::backup_rotate(song_file)?;
+    let mut file = File::create(song_file)?;
+    let json = song_to_string(song)?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
 }
 
 fn song_directory() -> PathBuf {
@@ -2161,3 +3168,19 @@ fn song_directory() -> PathBuf {
     create_dir_all(&dir).unwrap();
     dir
 }
+
+pub fn template_directory() -> PathBuf {
+    let exe_path = current_exe().unwrap();
+    let dir = exe_path.parent().unwrap();
+    let dir = dir.join("user").join("template");
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn preset_directory() -> PathBuf {
+    let exe_path = current_exe().unwrap();
+    let dir = exe_path.parent().unwrap();
+    let dir = dir.join("user").join("preset");
+    create_dir_all(&dir).unwrap();
+    dir
+}
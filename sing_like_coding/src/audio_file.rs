@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use anyhow::Result;
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+// [channel][frame] の f32 で読み書きする
+
+pub fn audio_file_read(path: &Path) -> Result<(Vec<Vec<f32>>, f64)> {
+    let mut reader = WavReader::open(path)?;
+    let spec = reader.spec();
+    let nchannels = spec.channels as usize;
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        SampleFormat::Int => {
+            let scale = 1.0 / (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|x| x.map(|x| x as f32 * scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    let mut audio = vec![Vec::with_capacity(samples.len() / nchannels); nchannels];
+    for (i, x) in samples.into_iter().enumerate() {
+        audio[i % nchannels].push(x);
+    }
+    Ok((audio, spec.sample_rate as f64))
+}
+
+// (フレーム数, サンプルレート)
+pub fn audio_file_info(path: &Path) -> Result<(usize, f64)> {
+    let reader = WavReader::open(path)?;
+    Ok((reader.duration() as usize, reader.spec().sample_rate as f64))
+}
+
+pub fn audio_file_write(path: &Path, audio: &[Vec<f32>], sample_rate: f64) -> Result<()> {
+    let spec = WavSpec {
+        channels: audio.len() as u16,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    let nframes = audio.first().map_or(0, |x| x.len());
+    for frame in 0..nframes {
+        for channel in audio.iter() {
+            writer.write_sample(channel[frame])?;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    InputCallbackInfo, SampleFormat, SampleRate, Stream, StreamConfig,
+};
+
+// 出力が止まっているときなどにたまり続けないように
+const INPUT_BUFFER_MAX_FRAMES: usize = 8192;
+
+// 入力デバイスから Singer へ渡す
+#[derive(Default)]
+pub struct AudioInputBuffer {
+    pub nchannels: usize,
+    pub samples: VecDeque<f32>, // インターリーブ
+    pub latency: usize,         // 入力のレイテンシー(フレーム)
+}
+
+pub struct AudioInputDevice {
+    _stream: Stream,
+}
+
+impl AudioInputDevice {
+    pub fn list() -> Vec<String> {
+        cpal::default_host()
+            .input_devices()
+            .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn new(
+        name: &str,
+        sample_rate: f64,
+        input_buffer: Arc<Mutex<AudioInputBuffer>>,
+    ) -> Result<Self> {
+        let host = cpal::default_host();
+        let device = host
+            .input_devices()?
+            .find(|device| device.name().ok().as_deref() == Some(name))
+            .ok_or_else(|| anyhow!("{name} is not found!"))?;
+        // 出力と同じサンプルレートで開く
+        let supported_config = device
+            .supported_input_configs()?
+            .find(|config| {
+                config.sample_format() == SampleFormat::F32
+                    && config.min_sample_rate().0 as f64 <= sample_rate
+                    && sample_rate <= config.max_sample_rate().0 as f64
+            })
+            .ok_or_else(|| anyhow!("{name} does not support {sample_rate}Hz."))?
+            .with_sample_rate(SampleRate(sample_rate as u32));
+        let config: StreamConfig = supported_config.into();
+        log::info!("audio input {} {:?}", name, &config);
+
+        let nchannels = config.channels as usize;
+        {
+            let mut input_buffer = input_buffer.lock().unwrap();
+            input_buffer.nchannels = nchannels;
+            input_buffer.samples.clear();
+        }
+        let stream = device.build_input_stream(
+            &config,
+            move |input: &[f32], info: &InputCallbackInfo| {
+                let timestamp = info.timestamp();
+                let latency = timestamp
+                    .callback
+                    .duration_since(&timestamp.capture)
+                    .map_or(0, |x| (x.as_secs_f64() * sample_rate) as usize);
+                let mut input_buffer = input_buffer.lock().unwrap();
+                input_buffer.latency = latency;
+                input_buffer.samples.extend(input.iter());
+                let over = input_buffer
+                    .samples
+                    .len()
+                    .saturating_sub(INPUT_BUFFER_MAX_FRAMES * nchannels);
+                input_buffer.samples.drain(..over);
+            },
+            |err| log::warn!("an error occurred on the input audio stream: {}", err),
+            None,
+        )?;
+        stream.play()?;
+
+        Ok(Self { _stream: stream })
+    }
+}
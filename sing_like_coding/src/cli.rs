@@ -0,0 +1,26 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::{midi_file::midi_file_export, model::song_file::song_read};
+
+// sing_like_coding midi-export <song.json> <out.mid> [--cc]
+pub fn main(args: Vec<String>) -> Result<()> {
+    match args.first().map(|x| x.as_str()) {
+        Some("midi-export") => {
+            let (Some(song_file), Some(midi_file)) = (args.get(1), args.get(2)) else {
+                bail!("usage: sing_like_coding midi-export <song.json> <out.mid> [--cc]");
+            };
+            let cc_p = args[3..].iter().any(|x| x == "--cc");
+            let song = song_read(Path::new(song_file))?;
+            let warnings = midi_file_export(&song, Path::new(midi_file), cc_p)?;
+            for warning in warnings {
+                eprintln!("{}", warning);
+            }
+            println!("Exported {}.", midi_file);
+            Ok(())
+        }
+        Some(command) => bail!("Unknown command {command}"),
+        None => Ok(()),
+    }
+}
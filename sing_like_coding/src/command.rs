@@ -2,12 +2,31 @@ use anyhow::Result;
 
 use crate::app_state::AppState;
 
+pub mod audio_device_input;
+pub mod midi_clock_output;
 pub mod midi_device_input;
+pub mod midi_device_output;
+pub mod midi_export;
+pub mod midi_sync_follow;
+pub mod plugin_isolate;
 pub mod plugin_load;
+pub mod plugin_process_report;
 pub mod plugin_scan;
+pub mod song_new;
+pub mod song_new_from_template;
 pub mod song_open;
+pub mod song_open_recent;
+pub mod song_process_64;
 pub mod song_save;
+pub mod song_save_as;
+pub mod song_save_copy;
+pub mod song_size_report;
+pub mod song_template_save;
 pub mod track_add;
+pub mod track_freeze;
+pub mod track_preset_insert;
+pub mod track_preset_save;
+pub mod undo_history;
 
 pub trait Command: Send {
     fn call(&mut self, state: &mut AppState) -> Result<()>;
@@ -0,0 +1,22 @@
+use crate::{app_state::AppState, view::root_view::Route};
+
+use super::Command;
+
+pub struct AudioDeviceInput {}
+
+impl Command for AudioDeviceInput {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.route = Route::AudioDeviceInputSelect;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Audio Device Input"
+    }
+}
+
+impl AudioDeviceInput {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
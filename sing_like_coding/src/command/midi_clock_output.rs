@@ -0,0 +1,22 @@
+use crate::{app_state::AppState, view::root_view::Route};
+
+use super::Command;
+
+pub struct MidiClockOutput {}
+
+impl Command for MidiClockOutput {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.route = Route::MidiClockOutputSelect;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Midi Clock Output"
+    }
+}
+
+impl MidiClockOutput {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
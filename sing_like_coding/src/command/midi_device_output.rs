@@ -0,0 +1,22 @@
+use crate::{app_state::AppState, view::root_view::Route};
+
+use super::Command;
+
+pub struct MidiDeviceOutput {}
+
+impl Command for MidiDeviceOutput {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.route = Route::MidiDeviceOutputSelect;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Midi Device Output"
+    }
+}
+
+impl MidiDeviceOutput {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
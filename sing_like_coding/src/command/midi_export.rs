@@ -0,0 +1,27 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct MidiExport {
+    cc_p: bool,
+}
+
+impl Command for MidiExport {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.midi_file_export(self.cc_p)
+    }
+
+    fn name(&self) -> &str {
+        if self.cc_p {
+            "MIDI Export with CC"
+        } else {
+            "MIDI Export"
+        }
+    }
+}
+
+impl MidiExport {
+    pub fn new(cc_p: bool) -> Self {
+        Self { cc_p }
+    }
+}
@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct MidiSyncFollow {}
+
+impl Command for MidiSyncFollow {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.midi_sync_follow_toggle()
+    }
+
+    fn name(&self) -> &str {
+        "Midi Sync Follow Toggle"
+    }
+}
+
+impl MidiSyncFollow {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct PluginIsolate {}
+
+impl Command for PluginIsolate {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.plugin_isolate_toggle((state.cursor_track.track, state.cursor_module.index))
+    }
+
+    fn name(&self) -> &str {
+        "Plugin Isolate Toggle"
+    }
+}
+
+impl PluginIsolate {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
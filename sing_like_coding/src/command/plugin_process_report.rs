@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct PluginProcessReport {}
+
+impl Command for PluginProcessReport {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.plugin_process_report()
+    }
+
+    fn name(&self) -> &str {
+        "Plugin Process Report"
+    }
+}
+
+impl PluginProcessReport {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct SongNew {}
+
+impl Command for SongNew {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.song_new()
+    }
+
+    fn name(&self) -> &str {
+        "New"
+    }
+}
+
+impl SongNew {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,22 @@
+use crate::{app_state::AppState, view::root_view::Route};
+
+use super::Command;
+
+pub struct SongNewFromTemplate {}
+
+impl Command for SongNewFromTemplate {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.route = Route::TemplateSelect;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "New from Template"
+    }
+}
+
+impl SongNewFromTemplate {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
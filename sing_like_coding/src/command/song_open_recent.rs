@@ -0,0 +1,22 @@
+use crate::{app_state::AppState, view::root_view::Route};
+
+use super::Command;
+
+pub struct SongOpenRecent {}
+
+impl Command for SongOpenRecent {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.route = Route::RecentProjectSelect;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Open Recent"
+    }
+}
+
+impl SongOpenRecent {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
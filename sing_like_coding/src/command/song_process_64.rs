@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct SongProcess64 {}
+
+impl Command for SongProcess64 {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.process_64_toggle()
+    }
+
+    fn name(&self) -> &str {
+        "Song 64-bit Processing Toggle"
+    }
+}
+
+impl SongProcess64 {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct SongSaveAs {}
+
+impl Command for SongSaveAs {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.song_save_as(false)
+    }
+
+    fn name(&self) -> &str {
+        "Save As"
+    }
+}
+
+impl SongSaveAs {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct SongSaveCopy {}
+
+impl Command for SongSaveCopy {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.song_save_as(true)
+    }
+
+    fn name(&self) -> &str {
+        "Save Copy"
+    }
+}
+
+impl SongSaveCopy {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
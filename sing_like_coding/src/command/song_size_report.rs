@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct SongSizeReport {}
+
+impl Command for SongSizeReport {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.song_size_report()
+    }
+
+    fn name(&self) -> &str {
+        "Song Size Report"
+    }
+}
+
+impl SongSizeReport {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
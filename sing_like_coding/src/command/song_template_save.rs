@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct SongTemplateSave {}
+
+impl Command for SongTemplateSave {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.song_template_save()
+    }
+
+    fn name(&self) -> &str {
+        "Save as Template"
+    }
+}
+
+impl SongTemplateSave {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct TrackFreeze {}
+
+impl Command for TrackFreeze {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.track_freeze_toggle()
+    }
+
+    fn name(&self) -> &str {
+        "Freeze/Unfreeze Track"
+    }
+}
+
+impl TrackFreeze {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
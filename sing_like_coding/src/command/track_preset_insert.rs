@@ -0,0 +1,22 @@
+use crate::{app_state::AppState, view::root_view::Route};
+
+use super::Command;
+
+pub struct TrackPresetInsert {}
+
+impl Command for TrackPresetInsert {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.route = Route::TrackPresetSelect;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Insert Track Preset"
+    }
+}
+
+impl TrackPresetInsert {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct TrackPresetSave {}
+
+impl Command for TrackPresetSave {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.track_preset_save()
+    }
+
+    fn name(&self) -> &str {
+        "Save Track Preset"
+    }
+}
+
+impl TrackPresetSave {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
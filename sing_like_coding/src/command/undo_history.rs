@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct UndoHistory {}
+
+impl Command for UndoHistory {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.undo_history_toggle()
+    }
+
+    fn name(&self) -> &str {
+        "Undo History"
+    }
+}
+
+impl UndoHistory {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
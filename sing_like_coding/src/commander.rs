@@ -13,14 +13,50 @@ impl Commander {
     pub fn new() -> Self {
         Self {
             commands: vec![
+                Arc::new(Mutex::new(
+                    command::audio_device_input::AudioDeviceInput::new(),
+                )),
+                Arc::new(Mutex::new(
+                    command::midi_clock_output::MidiClockOutput::new(),
+                )),
                 Arc::new(Mutex::new(
                     command::midi_device_input::MidiDeviceInput::new(),
                 )),
+                Arc::new(Mutex::new(
+                    command::midi_device_output::MidiDeviceOutput::new(),
+                )),
+                Arc::new(Mutex::new(command::midi_export::MidiExport::new(false))),
+                Arc::new(Mutex::new(command::midi_export::MidiExport::new(true))),
+                Arc::new(Mutex::new(command::midi_sync_follow::MidiSyncFollow::new())),
+                Arc::new(Mutex::new(command::plugin_isolate::PluginIsolate::new())),
                 Arc::new(Mutex::new(command::plugin_load::PluginLoad::new())),
+                Arc::new(Mutex::new(
+                    command::plugin_process_report::PluginProcessReport::new(),
+                )),
                 Arc::new(Mutex::new(command::plugin_scan::PluginScan::new())),
+                Arc::new(Mutex::new(command::song_new::SongNew::new())),
+                Arc::new(Mutex::new(
+                    command::song_new_from_template::SongNewFromTemplate::new(),
+                )),
                 Arc::new(Mutex::new(command::song_open::SongOpen::new())),
+                Arc::new(Mutex::new(command::song_open_recent::SongOpenRecent::new())),
+                Arc::new(Mutex::new(command::song_process_64::SongProcess64::new())),
                 Arc::new(Mutex::new(command::song_save::SongSave::new())),
+                Arc::new(Mutex::new(command::song_save_as::SongSaveAs::new())),
+                Arc::new(Mutex::new(command::song_save_copy::SongSaveCopy::new())),
+                Arc::new(Mutex::new(command::song_size_report::SongSizeReport::new())),
+                Arc::new(Mutex::new(
+                    command::song_template_save::SongTemplateSave::new(),
+                )),
                 Arc::new(Mutex::new(command::track_add::TrackAdd::new())),
+                Arc::new(Mutex::new(command::track_freeze::TrackFreeze::new())),
+                Arc::new(Mutex::new(
+                    command::track_preset_insert::TrackPresetInsert::new(),
+                )),
+                Arc::new(Mutex::new(
+                    command::track_preset_save::TrackPresetSave::new(),
+                )),
+                Arc::new(Mutex::new(command::undo_history::UndoHistory::new())),
             ],
         }
     }
@@ -1,51 +1,508 @@
-use std::process::{Command, Stdio};
-use std::sync::mpsc::{Receiver, Sender};
+use std::collections::{BTreeMap, HashMap};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use common::protocol::{receive, send, MainToPlugin, PluginToMain};
-use common::PIPE_CTRL_NAME;
-use tokio::net::windows::named_pipe::ServerOptions;
+use common::ipc::{ControlListen, ControlServer};
+use common::module::ModuleId;
+use common::protocol::{handshake, receive, send, Envelope, MainToPlugin, PluginToMain, RequestId};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::process_stats::process_stats;
+
+// プラグインプロセスが落ちていないか見る間隔
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(500);
+// 起動し直してすぐにまた落ちるなら、読み込み直しても同じなので諦める
+const RESPAWN_INTERVAL_MIN: Duration = Duration::from_secs(5);
+// 起動したプラグインプロセスがこれまでに接続してこなければ落ちたとみなす
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ControlStream = <ControlServer as ControlListen>::Stream;
+
+// どのプラグインプロセスか
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum ProcessKey {
+    Shared,
+    Module(ModuleId), // そのモジュール専用
+}
+
+// プロセスごとの読み込みタスクから。serial で起動し直す前のプロセスと区別する
+enum ProcessEvent {
+    Received(ProcessKey, u64, Envelope<PluginToMain>),
+    Closed(ProcessKey, u64),
+}
+
+// 画面に出す用
+#[derive(Clone, Debug)]
+pub struct PluginProcessInfo {
+    pub pid: u32,
+    pub shared_p: bool,
+    pub module_ids: Vec<ModuleId>,
+    pub memory: usize,  // バイト
+    pub cpu_usage: f64, // 1.0 で 1 コア分
+}
+
+struct PluginProcess {
+    serial: u64,
+    child: Child,
+    writer: WriteHalf<ControlStream>,
+    // 返事を待っている要求と、そのモジュール
+    pending: BTreeMap<RequestId, Option<ModuleId>>,
+    // 返事を待ち終えたら終わらせる
+    retiring_p: bool,
+    spawned_at: Instant,
+    respawned_p: bool,
+    cpu_time_last: Duration,
+    cpu_usage: f64,
+    memory: usize,
+}
 
 pub struct Communicator {
-    receiver_from_main: Receiver<MainToPlugin>,
-    sender_communicator_to_main_thread: Sender<PluginToMain>,
+    receiver_from_main: UnboundedReceiver<Envelope<MainToPlugin>>,
+    sender_communicator_to_main_thread: Sender<Envelope<PluginToMain>>,
     gui_context: eframe::egui::Context,
+    plugin_processes: Arc<Mutex<Vec<PluginProcessInfo>>>,
+    processes: HashMap<ProcessKey, PluginProcess>,
+    module_processes: HashMap<ModuleId, ProcessKey>,
+    // 複数のプロセスに送った要求の残りの返事の数
+    replies_remaining: HashMap<RequestId, usize>,
+    sender_process_event: UnboundedSender<ProcessEvent>,
+    receiver_process_event: UnboundedReceiver<ProcessEvent>,
+    serial_last: u64,
+    quit_request_id: Option<RequestId>,
+    quit_p: bool,
+    watched_at: Instant,
 }
 
 impl Communicator {
     pub fn new(
-        receiver_from_main: Receiver<MainToPlugin>,
-        sender_communicator_to_main_thread: Sender<PluginToMain>,
+        receiver_from_main: UnboundedReceiver<Envelope<MainToPlugin>>,
+        sender_communicator_to_main_thread: Sender<Envelope<PluginToMain>>,
         gui_context: eframe::egui::Context,
+        plugin_processes: Arc<Mutex<Vec<PluginProcessInfo>>>,
     ) -> anyhow::Result<Self> {
+        let (sender_process_event, receiver_process_event) = unbounded_channel();
         Ok(Self {
             receiver_from_main,
             sender_communicator_to_main_thread,
             gui_context,
+            plugin_processes,
+            processes: Default::default(),
+            module_processes: Default::default(),
+            replies_remaining: Default::default(),
+            sender_process_event,
+            receiver_process_event,
+            serial_last: 0,
+            quit_request_id: None,
+            quit_p: false,
+            watched_at: Instant::now(),
         })
     }
 
     pub async fn run(&mut self, hwnd: isize) -> anyhow::Result<()> {
-        let mut pipe = ServerOptions::new().create(PIPE_CTRL_NAME)?;
-        let _child = Command::new("sing_like_coding_plugin.exe")
-            .stdout(Stdio::inherit())
-            .spawn()?;
-        pipe.connect().await?;
-
-        send(&mut pipe, &MainToPlugin::Hwnd(hwnd)).await?;
-        let _did_hwnd: PluginToMain = receive(&mut pipe).await?;
+        // 起動できなくても、共有のプロセスへの要求には Crashed を返す
+        match self.spawn(ProcessKey::Shared, hwnd, false).await {
+            Ok(process) => {
+                self.processes.insert(ProcessKey::Shared, process);
+            }
+            Err(e) => log::error!("failed to spawn plugin process {e}"),
+        }
 
+        let mut watchdog = tokio::time::interval(WATCHDOG_INTERVAL);
         loop {
-            let message = self.receiver_from_main.recv()?;
-            send(&mut pipe, &message).await?;
-
-            let message: PluginToMain = receive(&mut pipe).await?;
-            let break_p = message == PluginToMain::Quit;
-            self.sender_communicator_to_main_thread.send(message)?;
-            self.gui_context.request_repaint();
-            if break_p {
+            tokio::select! {
+                envelope = self.receiver_from_main.recv() => {
+                    let Some(envelope) = envelope else {
+                        return Ok(());
+                    };
+                    self.request(envelope, hwnd).await?;
+                }
+                Some(event) = self.receiver_process_event.recv() => {
+                    self.process_event(event, hwnd).await?;
+                }
+                _ = watchdog.tick() => {
+                    self.watch(hwnd).await?;
+                }
+            }
+            if self.quit_p {
                 log::debug!("#### end Communicator run loop.");
                 return Ok(());
             }
         }
     }
+
+    // 返事を待たずに送る。返事は process_event で受け取る
+    async fn request(
+        &mut self,
+        envelope: Envelope<MainToPlugin>,
+        hwnd: isize,
+    ) -> anyhow::Result<()> {
+        let Envelope {
+            request_id,
+            message,
+        } = envelope;
+        let quit_p = message == MainToPlugin::Quit;
+        if quit_p {
+            self.quit_request_id = Some(request_id);
+        }
+        let module_id = message.module_id();
+
+        let keys = self.process_keys(&message);
+        for key in keys.iter() {
+            // 共有のプロセスを諦めたときは起動しない
+            if matches!(key, ProcessKey::Module(_)) && !self.processes.contains_key(key) {
+                match self.spawn(*key, hwnd, false).await {
+                    Ok(process) => {
+                        self.processes.insert(*key, process);
+                    }
+                    Err(e) => log::error!("failed to spawn plugin process {e}"),
+                }
+            }
+        }
+
+        let mut sent = 0;
+        let mut crashed_keys = vec![];
+        for key in keys.iter() {
+            let Some(process) = self.processes.get_mut(key) else {
+                continue;
+            };
+            match send(
+                &mut process.writer,
+                &Envelope::new(request_id, message.clone()),
+            )
+            .await
+            {
+                Ok(_) => {
+                    process.pending.insert(request_id, module_id);
+                    // 専用のプロセスはモジュールがいなくなったら終わらせる
+                    match (&message, key) {
+                        (MainToPlugin::Unload(_), ProcessKey::Module(_)) => {
+                            process.retiring_p = true
+                        }
+                        // 終わらせる前にまた読み込まれた
+                        (MainToPlugin::Load(..), _) => process.retiring_p = false,
+                        _ => (),
+                    }
+                    sent += 1;
+                }
+                Err(e) => {
+                    log::error!("failed to send {:?} to plugin process {e}", message);
+                    crashed_keys.push(*key);
+                }
+            }
+        }
+
+        if sent == 0 {
+            // 待っているコールバックが残らないように必ず返事をする
+            let reply = if quit_p {
+                PluginToMain::Quit
+            } else {
+                PluginToMain::Crashed(module_id)
+            };
+            self.reply(request_id, reply)?;
+        } else {
+            self.replies_remaining.insert(request_id, sent);
+        }
+
+        for key in crashed_keys {
+            self.respawn(key, hwnd, module_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn process_event(&mut self, event: ProcessEvent, hwnd: isize) -> anyhow::Result<()> {
+        match event {
+            ProcessEvent::Received(key, serial, envelope) => {
+                // 終わらせたプロセスや起動し直す前のプロセスからのものは捨てる
+                let Some(process) = self
+                    .processes
+                    .get_mut(&key)
+                    .filter(|process| process.serial == serial)
+                else {
+                    return Ok(());
+                };
+                if envelope.notification_p() {
+                    return self.forward(envelope);
+                }
+                process.pending.remove(&envelope.request_id);
+                let retire_p = process.retiring_p && process.pending.is_empty();
+                self.reply(envelope.request_id, envelope.message)?;
+                if retire_p {
+                    self.retire(key).await;
+                }
+            }
+            ProcessEvent::Closed(key, serial) => {
+                if self
+                    .processes
+                    .get(&key)
+                    .is_some_and(|process| process.serial == serial)
+                {
+                    log::error!("plugin process {:?} closed the pipe", key);
+                    self.respawn(key, hwnd, None).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn process_keys(&mut self, message: &MainToPlugin) -> Vec<ProcessKey> {
+        match message {
+            MainToPlugin::Load(id, _, _, _, _, isolated_p) => {
+                let key = if *isolated_p {
+                    ProcessKey::Module(*id)
+                } else {
+                    ProcessKey::Shared
+                };
+                self.module_processes.insert(*id, key);
+                vec![key]
+            }
+            MainToPlugin::Unload(id) => {
+                vec![self
+                    .module_processes
+                    .remove(id)
+                    .unwrap_or(ProcessKey::Shared)]
+            }
+            MainToPlugin::Channels(_) | MainToPlugin::Quit => {
+                self.processes.keys().copied().collect()
+            }
+            _ => match message.module_id() {
+                Some(id) => vec![self
+                    .module_processes
+                    .get(&id)
+                    .copied()
+                    .unwrap_or(ProcessKey::Shared)],
+                None => vec![ProcessKey::Shared],
+            },
+        }
+    }
+
+    // 複数のプロセスに送ったときは最後の返事を返す
+    fn reply(&mut self, request_id: RequestId, message: PluginToMain) -> anyhow::Result<()> {
+        if let Some(remaining) = self.replies_remaining.get_mut(&request_id) {
+            if *remaining > 1 {
+                *remaining -= 1;
+                return Ok(());
+            }
+            self.replies_remaining.remove(&request_id);
+        }
+        if self.quit_request_id == Some(request_id) {
+            self.quit_p = true;
+        }
+        self.forward(Envelope::new(request_id, message))
+    }
+
+    fn forward(&mut self, envelope: Envelope<PluginToMain>) -> anyhow::Result<()> {
+        self.sender_communicator_to_main_thread.send(envelope)?;
+        self.gui_context.request_repaint();
+        Ok(())
+    }
+
+    async fn retire(&mut self, key: ProcessKey) {
+        let Some(mut process) = self.processes.remove(&key) else {
+            return;
+        };
+        // 返事は process_event で捨てる
+        if let Err(e) = send(
+            &mut process.writer,
+            &Envelope::notification(MainToPlugin::Quit),
+        )
+        .await
+        {
+            log::warn!("failed to quit plugin process {e}");
+        }
+        // 終わるのを待つとしばらく止まってしまう
+        std::thread::spawn(move || {
+            let _ = process.child.wait();
+        });
+    }
+
+    // culprit は落ちたときに送っていたメッセージのモジュール
+    async fn respawn(
+        &mut self,
+        key: ProcessKey,
+        hwnd: isize,
+        culprit: Option<ModuleId>,
+    ) -> anyhow::Result<()> {
+        let Some(mut process) = self.processes.remove(&key) else {
+            return Ok(());
+        };
+        // 落ちずに固まっているだけかもしれない
+        let _ = process.child.kill();
+        let _ = process.child.wait();
+
+        // わからなければ一番古い返事待ちのモジュールのせいにする
+        let culprit = culprit.or_else(|| process.pending.values().next().copied().flatten());
+        for (request_id, module_id) in std::mem::take(&mut process.pending) {
+            let reply = if self.quit_request_id == Some(request_id) {
+                PluginToMain::Quit
+            } else {
+                PluginToMain::Crashed(module_id)
+            };
+            self.reply(request_id, reply)?;
+        }
+        if self.quit_request_id.is_some() {
+            return Ok(());
+        }
+
+        if let ProcessKey::Module(id) = key
+            && culprit == Some(id)
+        {
+            // 専用のプロセスを落としたモジュールは読み込み直さないので起動もしない
+            self.module_processes.remove(&id);
+            return Ok(());
+        }
+        if process.respawned_p && process.spawned_at.elapsed() < RESPAWN_INTERVAL_MIN {
+            log::error!("plugin process crashed again right after respawn, giving up");
+            return Ok(());
+        }
+        drop(process);
+
+        match self.spawn(key, hwnd, true).await {
+            Ok(process) => {
+                self.processes.insert(key, process);
+                let module_ids = self
+                    .module_processes
+                    .iter()
+                    .filter(|(_, x)| **x == key)
+                    .map(|(id, _)| *id)
+                    .collect();
+                self.forward(Envelope::notification(PluginToMain::Respawned(module_ids)))?;
+            }
+            Err(e) => log::error!("failed to respawn plugin process {e}"),
+        }
+        Ok(())
+    }
+
+    // 落ちたプロセスを起動し直して、メモリと CPU の使用量を集める
+    async fn watch(&mut self, hwnd: isize) -> anyhow::Result<()> {
+        let elapsed = self.watched_at.elapsed().as_secs_f64();
+        self.watched_at = Instant::now();
+
+        let mut dead_keys = vec![];
+        for (key, process) in self.processes.iter_mut() {
+            if let Some(status) = process.child.try_wait()? {
+                log::error!("plugin process {:?} exited {status}", key);
+                dead_keys.push(*key);
+                continue;
+            }
+            match process_stats(process.child.id()) {
+                Ok(stats) => {
+                    let cpu_time = stats.cpu_time.saturating_sub(process.cpu_time_last);
+                    process.cpu_usage = cpu_time.as_secs_f64() / elapsed;
+                    process.cpu_time_last = stats.cpu_time;
+                    process.memory = stats.memory;
+                }
+                Err(e) => log::debug!("process_stats {e}"),
+            }
+        }
+        for key in dead_keys {
+            self.respawn(key, hwnd, None).await?;
+        }
+
+        let mut infos = self
+            .processes
+            .iter()
+            .map(|(key, process)| PluginProcessInfo {
+                pid: process.child.id(),
+                shared_p: *key == ProcessKey::Shared,
+                module_ids: self
+                    .module_processes
+                    .iter()
+                    .filter(|(_, x)| *x == key)
+                    .map(|(id, _)| *id)
+                    .collect(),
+                memory: process.memory,
+                cpu_usage: process.cpu_usage,
+            })
+            .collect::<Vec<_>>();
+        infos.sort_by_key(|x| (!x.shared_p, x.module_ids.first().copied()));
+        *self.plugin_processes.lock().unwrap() = infos;
+        Ok(())
+    }
+
+    async fn spawn(
+        &mut self,
+        key: ProcessKey,
+        hwnd: isize,
+        respawned_p: bool,
+    ) -> anyhow::Result<PluginProcess> {
+        let server = ControlServer::control_bind()?;
+        // PATH に頼らず自分と同じディレクトリのものを起動する
+        let plugin_exe = std::env::current_exe()?.with_file_name(format!(
+            "sing_like_coding_plugin{}",
+            std::env::consts::EXE_SUFFIX
+        ));
+        let mut child = Command::new(plugin_exe).stdout(Stdio::inherit()).spawn()?;
+        let connect = async {
+            let mut pipe = server.control_accept().await?;
+            handshake(&mut pipe).await?;
+            // 起動時は他に送っているものがないので、その場で返事を待つ
+            send(&mut pipe, &Envelope::notification(MainToPlugin::Hwnd(hwnd))).await?;
+            let _did_hwnd: Envelope<PluginToMain> = receive(&mut pipe).await?;
+            anyhow::Ok(pipe)
+        };
+        let pipe = match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+            Ok(Ok(pipe)) => pipe,
+            result => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(match result {
+                    Ok(Err(e)) => e,
+                    _ => anyhow::anyhow!("plugin process did not connect in {:?}", CONNECT_TIMEOUT),
+                });
+            }
+        };
+
+        let (reader, writer) = tokio::io::split(pipe);
+        self.serial_last += 1;
+        reader_spawn(
+            key,
+            self.serial_last,
+            reader,
+            self.sender_process_event.clone(),
+        );
+
+        Ok(PluginProcess {
+            serial: self.serial_last,
+            child,
+            writer,
+            pending: Default::default(),
+            retiring_p: false,
+            spawned_at: Instant::now(),
+            respawned_p,
+            cpu_time_last: Duration::ZERO,
+            cpu_usage: 0.0,
+            memory: 0,
+        })
+    }
+}
+
+// 返事も通知も届いた順に流す
+fn reader_spawn(
+    key: ProcessKey,
+    serial: u64,
+    mut reader: ReadHalf<ControlStream>,
+    sender: UnboundedSender<ProcessEvent>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match receive(&mut reader).await {
+                Ok(envelope) => {
+                    if sender
+                        .send(ProcessEvent::Received(key, serial, envelope))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::debug!("plugin process {:?} receive {e}", key);
+                    let _ = sender.send(ProcessEvent::Closed(key, serial));
+                    return;
+                }
+            }
+        }
+    });
 }
@@ -8,9 +8,22 @@ use anyhow::Result;
 use common::util::dir_user_setting;
 use serde::{Deserialize, Serialize};
 
+const RECENT_PROJECTS_MAX: usize = 10;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub midi_device_input: Option<String>,
+    #[serde(default)]
+    pub audio_device_input: Option<String>,
+    #[serde(default)]
+    pub midi_clock_output: Option<String>,
+    #[serde(default)]
+    pub midi_sync_follow_p: bool,
+    #[serde(default)]
+    pub recent_projects: Vec<String>,
+    // モジュールごとに専用のプロセスで動かすプラグインの id
+    #[serde(default)]
+    pub plugins_isolated: Vec<String>,
 }
 
 impl Config {
@@ -25,6 +38,13 @@ impl Config {
         Ok(config)
     }
 
+    pub fn recent_project_add(&mut self, song_file: &str) -> Result<()> {
+        self.recent_projects.retain(|x| x != song_file);
+        self.recent_projects.insert(0, song_file.to_string());
+        self.recent_projects.truncate(RECENT_PROJECTS_MAX);
+        self.save()
+    }
+
     pub fn save(&self) -> Result<()> {
         let mut file = File::create(Self::file())?;
         let json = serde_json::to_string_pretty(&self)?;
@@ -37,6 +57,11 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             midi_device_input: None,
+            audio_device_input: None,
+            midi_clock_output: None,
+            midi_sync_follow_p: false,
+            recent_projects: vec![],
+            plugins_isolated: vec![],
         }
     }
 }
@@ -45,19 +45,24 @@ impl Device {
     pub fn start(&mut self) -> Result<()> {
         let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
 
-        {
-            let sample_rate = self.config.sample_rate.0 as f64;
-            self.singer.lock().unwrap().song.sample_rate = sample_rate;
-        }
+        let sample_rate = self.config.sample_rate.0 as f64;
+        self.singer.lock().unwrap().song.sample_rate = sample_rate;
 
         let channels = self.config.channels as usize;
         let singer = self.singer.clone();
         let stream = match self.sample_format {
             SampleFormat::U8 => self.device.build_output_stream(
                 &self.config,
-                move |output: &mut [f32], _| {
+                move |output: &mut [f32], info: &cpal::OutputCallbackInfo| {
                     // log::debug!("callback output.len {}", output.len());
-                    singer.lock().unwrap().process(output, channels).unwrap();
+                    let timestamp = info.timestamp();
+                    let latency = timestamp
+                        .playback
+                        .duration_since(&timestamp.callback)
+                        .map_or(0, |x| (x.as_secs_f64() * sample_rate) as usize);
+                    let mut singer = singer.lock().unwrap();
+                    singer.output_latency = latency;
+                    singer.process(output, channels).unwrap();
                 },
                 err_fn,
                 None,
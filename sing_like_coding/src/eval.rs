@@ -1,4 +1,7 @@
-use crate::app_state::AppState;
+use crate::{
+    app_state::AppState,
+    model::track::{MIDI_CC_MODULE_INDEX, MIDI_PITCH_BEND_PARAM_ID},
+};
 
 use anyhow::Result;
 
@@ -14,6 +17,19 @@ impl Eval {
                         state.bpm_set(value)?;
                     }
                 }
+                "ch" => {
+                    if let Some(Ok(value)) = stack.pop().map(|x| x.parse::<usize>()) {
+                        state.channels_set(value)?;
+                    }
+                }
+                "cc" => {
+                    if let Some(Ok(cc)) = stack.pop().map(|x| x.parse::<u32>()) {
+                        state.param_set(MIDI_CC_MODULE_INDEX, cc)?;
+                    }
+                }
+                "pb" => {
+                    state.param_set(MIDI_CC_MODULE_INDEX, MIDI_PITCH_BEND_PARAM_ID)?;
+                }
                 "call" | "c" => {
                     if let Some(label) = stack.pop() {
                         state.eval_call(label.to_string())?;
@@ -27,6 +43,11 @@ impl Eval {
                 "ret" | "r" => {
                     state.eval_ret()?;
                 }
+                "gain" | "fadein" | "fadeout" | "offset" | "length" => {
+                    if let Some(Ok(value)) = stack.pop().map(|x| x.parse::<f64>()) {
+                        state.eval_audio_clip(word, value)?;
+                    }
+                }
                 _ => {}
             }
         }
@@ -1,5 +1,8 @@
 pub mod app;
 mod app_state;
+mod audio_file;
+mod audio_input_device;
+pub mod cli;
 mod command;
 mod commander;
 mod communicator;
@@ -7,7 +10,12 @@ mod config;
 mod device;
 mod eval;
 mod midi_device;
+mod midi_file;
+mod midi_sync;
 mod model;
+mod process_stats;
+mod project;
+mod recovery;
 mod singer;
 mod song_state;
 mod undo_history;
@@ -2,6 +2,14 @@
 async fn main() -> tokio::io::Result<()> {
     unsafe { std::env::set_var("RUST_LOG", "sing_like_coding=debug") };
     env_logger::init();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        if let Err(e) = sing_like_coding::cli::main(args) {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     sing_like_coding::app::main().unwrap();
     Ok(())
 }
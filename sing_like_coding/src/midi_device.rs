@@ -1,10 +1,16 @@
-use std::sync::mpsc::Sender;
+use std::{
+    sync::mpsc::{channel, Sender},
+    thread,
+    time::Instant,
+};
 
 use anyhow::{anyhow, Result};
 use common::event::Event;
-use midir::{MidiInput, MidiInputConnection};
+use midir::{MidiInput, MidiInputConnection, MidiOutput};
 use wmidi::MidiMessage;
 
+use crate::midi_sync::MidiSyncMessage;
+
 pub struct MidiDevice {
     _connection: MidiInputConnection<()>,
 }
@@ -19,7 +25,11 @@ impl MidiDevice {
             .collect()
     }
 
-    pub fn new(name: &str, sender_midi: Sender<Event>) -> Result<Self> {
+    pub fn new(
+        name: &str,
+        sender_midi: Sender<Event>,
+        sender_midi_sync: Sender<MidiSyncMessage>,
+    ) -> Result<Self> {
         let input = MidiInput::new("SLC")?;
         let port = input
             .ports()
@@ -38,14 +48,87 @@ impl MidiDevice {
                         Event::NoteOn(key as i16, u8::from(velocity) as f64, 0)
                     }
                     MidiMessage::NoteOff(_channel, key, _velocity) => Event::NoteOff(key as i16, 0),
+                    MidiMessage::TimingClock => {
+                        let _ = sender_midi_sync.send(MidiSyncMessage::Clock(Instant::now()));
+                        return;
+                    }
+                    MidiMessage::Start => {
+                        let _ = sender_midi_sync.send(MidiSyncMessage::Start);
+                        return;
+                    }
+                    MidiMessage::Continue => {
+                        let _ = sender_midi_sync.send(MidiSyncMessage::Continue);
+                        return;
+                    }
+                    MidiMessage::Stop => {
+                        let _ = sender_midi_sync.send(MidiSyncMessage::Stop);
+                        return;
+                    }
+                    MidiMessage::SongPositionPointer(position) => {
+                        let _ = sender_midi_sync
+                            .send(MidiSyncMessage::SongPosition(u16::from(position)));
+                        return;
+                    }
                     _ => return,
                 };
                 let _ = sender_midi.send(event);
             },
             (),
-        )?;
+        );
+        // Linux の ConnectError は Sync でないので ? では anyhow にできない
+        let connection = connection.map_err(|e| anyhow!("{e}"))?;
         Ok(Self {
             _connection: connection,
         })
     }
 }
+
+/// 外部音源への MIDI 出力
+/// オーディオスレッドから時刻付きで送って、送信スレッドがその時刻まで待って出力する
+pub struct MidiOutputDevice {
+    sender: Sender<(Instant, Vec<u8>)>,
+}
+
+impl MidiOutputDevice {
+    pub fn list() -> Vec<String> {
+        let output = MidiOutput::new("SLC").unwrap();
+        output
+            .ports()
+            .iter()
+            .filter_map(|port| output.port_name(port).ok())
+            .collect()
+    }
+
+    pub fn new(name: &str) -> Result<Self> {
+        let output = MidiOutput::new("SLC")?;
+        let port = output
+            .ports()
+            .into_iter()
+            .find(|port| output.port_name(port).ok().as_deref() == Some(name))
+            .ok_or_else(|| anyhow!("{name} is not found!"))?;
+        let mut connection = output
+            .connect(&port, "SLC")
+            .map_err(|e| anyhow!("{name} connect failed. {e}"))?;
+        let (sender, receiver) = channel::<(Instant, Vec<u8>)>();
+        let name = name.to_string();
+        thread::spawn(move || {
+            // Sender が drop されたら抜ける
+            while let Ok((time, message)) = receiver.recv() {
+                let now = Instant::now();
+                if time > now {
+                    thread::sleep(time - now);
+                }
+                if let Err(e) = connection.send(&message) {
+                    log::warn!("MIDI output {name} send failed. {e}");
+                }
+            }
+            connection.close();
+        });
+        Ok(Self { sender })
+    }
+
+    /// time は昇順で送ること
+    pub fn send(&self, time: Instant, message: Vec<u8>) {
+        let _ = self.sender.send((time, message));
+    }
+}
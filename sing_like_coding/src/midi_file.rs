@@ -0,0 +1,781 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use clap_sys::id::clap_id;
+use common::{event::Event, process_track_context::ProcessTrackContext};
+use midly::{
+    num::{u14, u15, u24, u28, u4, u7},
+    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+};
+
+use crate::model::{
+    lane_item::LaneItem,
+    note::Note,
+    point::Point,
+    song::Song,
+    track::{Track, MIDI_CC_MODULE_INDEX, MIDI_PITCH_BEND_PARAM_ID},
+};
+
+// 1 line を 256 tick にすると delay がそのまま tick になる
+const TICKS_PER_LINE: u32 = 0x100;
+// 分解能は 15 ビットまで
+const PPQ_MAX: u32 = 0x7FFF;
+// CC にしたプラグインのパラメータは未定義の CC 102〜119 に割り当てる
+const CC_PARAM_START: u32 = 102;
+const CC_PARAM_END: u32 = 119;
+
+/// 書き出せなかったものを警告として返す
+pub fn midi_file_export(song: &Song, path: &Path, cc_p: bool) -> Result<Vec<String>> {
+    // lpb が大きいと 256 tick/line では収まらないので、line 単位は保ったまま delay を縮める
+    let ticks_per_line = TICKS_PER_LINE.min(PPQ_MAX / song.lpb.max(1) as u32);
+    if ticks_per_line == 0 {
+        bail!("LPB {} is too large for a MIDI file.", song.lpb);
+    }
+    let ppq = ticks_per_line * song.lpb.max(1) as u32;
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(ppq as u16)),
+    ));
+    let mut warnings = vec![];
+
+    // コンダクタートラック
+    let tempo = (60_000_000.0 / song.bpm).round() as u32;
+    smf.tracks.push(vec![
+        TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(song.name.as_bytes())),
+        },
+        TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo))),
+        },
+        TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TimeSignature(
+                song.time_signature.0,
+                song.time_signature.1.max(1).ilog2() as u8,
+                24,
+                8,
+            )),
+        },
+        TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        },
+    ]);
+
+    let line_end = song
+        .tracks
+        .iter()
+        .flat_map(|track| track.lanes.iter())
+        .filter_map(|lane| lane.items.keys().next_back())
+        .max()
+        .map(|line| line + 1)
+        .unwrap_or(0);
+
+    for track in song.tracks.iter() {
+        let mut events = track_events(track, line_end, cc_p, ticks_per_line, &mut warnings);
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let mut smf_track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(track.name.as_bytes())),
+        }];
+        let mut tick_last = 0;
+        for (tick, kind) in events {
+            smf_track.push(TrackEvent {
+                delta: u28::new(tick - tick_last),
+                kind,
+            });
+            tick_last = tick;
+        }
+        smf_track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        smf.tracks.push(smf_track);
+    }
+
+    smf.save(path)?;
+    Ok(warnings)
+}
+
+// 再生と同じように compute_midi_range で Call/Ret を解決しながら 1 line ずつ進める
+fn track_events(
+    track: &Track,
+    line_end: usize,
+    cc_p: bool,
+    ticks_per_line: u32,
+    warnings: &mut Vec<String>,
+) -> Vec<(u32, TrackEventKind<'static>)> {
+    // line * 0x100 + delay から tick へ
+    let tick = |position: usize| {
+        ((position as u64 * ticks_per_line as u64 + 0x80) / TICKS_PER_LINE as u64) as u32
+    };
+    // CC やピッチベンドは 1 チャンネル目に出す
+    let channel = u4::new(0);
+    let mut events = vec![];
+    let mut params_skipped = HashSet::new();
+    // 鳴っているキーとそのチャンネル
+    let mut on_keys = HashMap::new();
+    let mut context = ProcessTrackContext {
+        play_p: true,
+        ..Default::default()
+    };
+    for line in 0..line_end {
+        let start = line * 0x100;
+        context.event_list_input.clear();
+        track.compute_midi_range(&mut context, start..(start + 0x100));
+        // Call で飛んだ先の line のノートが鳴る
+        let line_source = line.saturating_add_signed(context.line_offset);
+        for event in context.event_list_input.iter() {
+            match event {
+                Event::NoteOn(key, velocity, delay) => {
+                    let note_channel = note_channel(track, line_source, *key, *delay);
+                    on_keys.insert(*key, note_channel);
+                    events.push((
+                        tick(start + delay),
+                        TrackEventKind::Midi {
+                            channel: note_channel,
+                            message: MidiMessage::NoteOn {
+                                key: midi_key(*key),
+                                vel: u7::new((*velocity as u8).clamp(1, 0x7F)),
+                            },
+                        },
+                    ))
+                }
+                Event::NoteOff(key, delay) => {
+                    let Some(note_channel) = on_keys.remove(key) else {
+                        continue;
+                    };
+                    events.push((
+                        tick(start + delay),
+                        TrackEventKind::Midi {
+                            channel: note_channel,
+                            message: MidiMessage::NoteOff {
+                                key: midi_key(*key),
+                                vel: u7::new(0),
+                            },
+                        },
+                    ))
+                }
+                Event::NoteAllOff => {}
+                Event::ParamValue(module_index, param_id, value, delay) => {
+                    if !cc_p {
+                        continue;
+                    }
+                    if *module_index == MIDI_CC_MODULE_INDEX
+                        && *param_id == MIDI_PITCH_BEND_PARAM_ID
+                    {
+                        events.push((
+                            tick(start + delay),
+                            TrackEventKind::Midi {
+                                channel,
+                                message: MidiMessage::PitchBend {
+                                    bend: PitchBend(u14::new(pitch_bend_from_value(*value))),
+                                },
+                            },
+                        ));
+                        continue;
+                    }
+                    let controller = if *module_index == MIDI_CC_MODULE_INDEX {
+                        *param_id & 0x7F
+                    } else if let Some(index) = track
+                        .automation_params
+                        .iter()
+                        .position(|x| *x == (*module_index, *param_id))
+                    {
+                        // 割り当てる CC が足りなければ他のパラメータとまぜずに捨てる
+                        if CC_PARAM_START + index as u32 > CC_PARAM_END {
+                            params_skipped.insert((*module_index, *param_id));
+                            continue;
+                        }
+                        CC_PARAM_START + index as u32
+                    } else {
+                        continue;
+                    };
+                    events.push((
+                        tick(start + delay),
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::Controller {
+                                controller: u7::new(controller as u8),
+                                value: u7::new((value * 127.0).round().clamp(0.0, 127.0) as u8),
+                            },
+                        },
+                    ))
+                }
+            }
+        }
+    }
+
+    if !params_skipped.is_empty() {
+        warnings.push(format!(
+            "{}: {} parameters were skipped because only CC {}-{} are available.",
+            track.name,
+            params_skipped.len(),
+            CC_PARAM_START,
+            CC_PARAM_END
+        ));
+    }
+
+    // 鳴りっぱなしのノートを止める
+    let end = tick(line_end * 0x100);
+    let mut on_keys = on_keys.into_iter().collect::<Vec<_>>();
+    on_keys.sort_by_key(|(key, _)| *key);
+    for (key, note_channel) in on_keys {
+        events.push((
+            end,
+            TrackEventKind::Midi {
+                channel: note_channel,
+                message: MidiMessage::NoteOff {
+                    key: midi_key(key),
+                    vel: u7::new(0),
+                },
+            },
+        ));
+    }
+
+    events
+}
+
+// 鳴らしたノートを line から探してチャンネルを決める
+fn note_channel(track: &Track, line: usize, key: i16, delay: usize) -> u4 {
+    track
+        .lanes
+        .iter()
+        .filter_map(|lane| match lane.items.get(&line) {
+            Some(LaneItem::Note(note)) => Some(note),
+            _ => None,
+        })
+        .find(|note| !note.off && note.key == key && note.delay as usize == delay)
+        .map(|note| u4::new(note.channel.clamp(0, 0x0F) as u8))
+        .unwrap_or(u4::new(0))
+}
+
+// 範囲外のキーは折り返さずに端に寄せる
+fn midi_key(key: i16) -> u7 {
+    u7::new(key.clamp(0, 0x7F) as u8)
+}
+
+// Point の値 0x80 がちょうどセンター 8192 になるように
+pub fn pitch_bend_from_value(value: f64) -> u16 {
+    (((value * 255.0).round() as u16) * 64).min(0x3FFF)
+}
+
+// MIDI_CC_MODULE_INDEX のパラメータを MIDI 出力やプラグインに送るメッセージにする
+pub fn midi_cc_message(param_id: clap_id, value: f64) -> [u8; 3] {
+    if param_id == MIDI_PITCH_BEND_PARAM_ID {
+        let bend = pitch_bend_from_value(value);
+        [0xE0, (bend & 0x7F) as u8, (bend >> 7) as u8]
+    } else {
+        [
+            0xB0,
+            (param_id as u8) & 0x7F,
+            (value * 127.0).round().clamp(0.0, 127.0) as u8,
+        ]
+    }
+}
+
+fn pitch_bend_to_point_value(bend: u16) -> u8 {
+    (bend / 64).min(0xFF) as u8
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MidiImportEvent {
+    NoteOn(i16, f64, i16), // key, velocity, channel
+    NoteOff(i16, i16),     // key, channel
+    Controller(u8, u8),
+    PitchBend(u16),
+}
+
+pub struct MidiImportTrack {
+    pub name: String,
+    pub events: Vec<(usize, MidiImportEvent)>, // (line * 0x100 + delay, event)
+}
+
+impl MidiImportTrack {
+    pub fn nnotes(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|(_, event)| matches!(event, MidiImportEvent::NoteOn(..)))
+            .count()
+    }
+
+    pub fn ncontrollers(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|(_, event)| {
+                matches!(
+                    event,
+                    MidiImportEvent::Controller(..) | MidiImportEvent::PitchBend(_)
+                )
+            })
+            .count()
+    }
+}
+
+/// 取り込む前にプレビューとオプションを表示するため、いったんここに読み込む
+pub struct MidiImport {
+    pub path: PathBuf,
+    pub track_index: usize,
+    pub timing: String,
+    pub bpm: Option<f64>,
+    pub time_signature: Option<(u8, u8)>,
+    pub tracks: Vec<MidiImportTrack>,
+    pub warnings: Vec<String>,
+    pub tracks_new_p: bool,
+    pub tempo_p: bool,
+    pub cc_p: bool,
+}
+
+impl MidiImport {
+    pub fn read(path: &Path, track_index: usize, lpb: u16, bpm: f64) -> Result<Self> {
+        let data = fs::read(path)?;
+        let smf = Smf::parse(&data)?;
+        let mut warnings = vec![];
+
+        let mut tempos = vec![];
+        let mut time_signature = None;
+        for event in smf.tracks.iter().flat_map(|track| track.iter()) {
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => tempos.push(tempo.as_int()),
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _))
+                    if time_signature.is_none() =>
+                {
+                    time_signature =
+                        Some((numerator, 1u8.checked_shl(denominator as u32).unwrap_or(4)))
+                }
+                _ => {}
+            }
+        }
+        let file_bpm = tempos
+            .first()
+            .filter(|tempo| **tempo > 0)
+            .map(|tempo| 60_000_000.0 / *tempo as f64);
+        if tempos.len() > 1 {
+            warnings.push(format!(
+                "{} tempo changes found. Only the first tempo is used.",
+                tempos.len() - 1
+            ));
+        }
+
+        // tick から line * 0x100 + delay への変換
+        let (timing, delays_per_tick) = match smf.header.timing {
+            Timing::Metrical(ppq) if ppq.as_int() > 0 => (
+                format!("{} ticks per quarter note", ppq.as_int()),
+                lpb as f64 * 256.0 / ppq.as_int() as f64,
+            ),
+            Timing::Metrical(_) => {
+                warnings.push("Invalid ticks per quarter note. 480 is used.".to_string());
+                (
+                    "0 ticks per quarter note".to_string(),
+                    lpb as f64 * 256.0 / 480.0,
+                )
+            }
+            Timing::Timecode(fps, subframe) => {
+                // SMPTE は秒単位なのでテンポから line に直す
+                let ticks_per_sec = fps.as_f32() as f64 * subframe as f64;
+                let bpm = file_bpm.unwrap_or(bpm);
+                warnings.push(format!(
+                    "SMPTE timing is converted to lines at {:.2} BPM.",
+                    bpm
+                ));
+                (
+                    format!("SMPTE {} fps, {} subframes", fps.as_f32(), subframe),
+                    bpm * lpb as f64 * 256.0 / 60.0 / ticks_per_sec.max(1.0),
+                )
+            }
+        };
+
+        let mut tracks = vec![];
+        for (smf_track_index, smf_track) in smf.tracks.iter().enumerate() {
+            let mut name = None;
+            let mut events = vec![];
+            let mut ticks = 0u64;
+            for event in smf_track.iter() {
+                ticks += event.delta.as_int() as u64;
+                let position = (ticks as f64 * delays_per_tick).round() as usize;
+                let event = match event.kind {
+                    TrackEventKind::Meta(MetaMessage::TrackName(bytes)) => {
+                        if name.is_none() {
+                            name = Some(String::from_utf8_lossy(bytes).trim().to_string());
+                        }
+                        continue;
+                    }
+                    TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOn { key, vel },
+                    } if vel > 0 => MidiImportEvent::NoteOn(
+                        key.as_int() as i16,
+                        vel.as_int() as f64,
+                        channel.as_int() as i16,
+                    ),
+                    TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOn { key, vel: _ },
+                    }
+                    | TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOff { key, vel: _ },
+                    } => MidiImportEvent::NoteOff(key.as_int() as i16, channel.as_int() as i16),
+                    TrackEventKind::Midi {
+                        channel: _,
+                        message: MidiMessage::Controller { controller, value },
+                    } => MidiImportEvent::Controller(controller.as_int(), value.as_int()),
+                    TrackEventKind::Midi {
+                        channel: _,
+                        message: MidiMessage::PitchBend { bend },
+                    } => MidiImportEvent::PitchBend(bend.0.as_int()),
+                    _ => continue,
+                };
+                events.push((position, event));
+            }
+            tracks.push(MidiImportTrack {
+                name: name
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or_else(|| format!("MIDI {:02}", smf_track_index + 1)),
+                events,
+            });
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            track_index,
+            timing,
+            bpm: file_bpm,
+            time_signature,
+            tracks,
+            warnings,
+            tracks_new_p: smf.tracks.len() > 1,
+            tempo_p: file_bpm.is_some() || time_signature.is_some(),
+            cc_p: true,
+        })
+    }
+
+    /// (lane, line, LaneItem) を返す
+    /// CC とピッチベンドのために automation_params が増えることがある
+    pub fn lane_items(
+        &self,
+        events: &[(usize, MidiImportEvent)],
+        automation_params: &mut Vec<(usize, clap_id)>,
+    ) -> Vec<(usize, usize, LaneItem)> {
+        let mut result = vec![];
+        let mut key_lane_map = HashMap::new();
+        let mut lane_line_used = HashSet::new();
+        let mut lane_max = 0;
+
+        for (position, event) in events.iter() {
+            let line = position / 0x100;
+            let delay = (position % 0x100) as u8;
+            let (lane_item, key) = match event {
+                MidiImportEvent::NoteOn(key, velocity, channel) => (
+                    LaneItem::Note(Note {
+                        key: *key,
+                        velocity: *velocity,
+                        delay,
+                        channel: *channel,
+                        ..Default::default()
+                    }),
+                    *key,
+                ),
+                MidiImportEvent::NoteOff(key, channel) => (
+                    LaneItem::Note(Note {
+                        key: *key, // OFF にする lane をさがすために
+                        off: true,
+                        delay,
+                        channel: *channel,
+                        ..Default::default()
+                    }),
+                    *key,
+                ),
+                _ => continue,
+            };
+
+            let mut lane = 0;
+            let off_p = matches!(event, MidiImportEvent::NoteOff(..));
+            if off_p && let Some(x) = key_lane_map.get(&key) {
+                lane = *x;
+            }
+            while lane_line_used.contains(&(lane, line)) {
+                lane += 1;
+            }
+            if !off_p {
+                key_lane_map.insert(key, lane);
+                lane_line_used.insert((lane, line));
+            }
+            lane_max = lane_max.max(lane + 1);
+            result.push((lane, line, lane_item));
+        }
+
+        if !self.cc_p {
+            return result;
+        }
+
+        // CC とピッチベンドはノートの右にパラメータごとの lane を作る
+        let mut param_lane_map = HashMap::new();
+        for (position, event) in events.iter() {
+            let (param_id, value) = match event {
+                MidiImportEvent::Controller(cc, value) => (
+                    *cc as clap_id,
+                    ((*value as f64) * 255.0 / 127.0).round() as u8,
+                ),
+                MidiImportEvent::PitchBend(bend) => {
+                    (MIDI_PITCH_BEND_PARAM_ID, pitch_bend_to_point_value(*bend))
+                }
+                _ => continue,
+            };
+            let param = (MIDI_CC_MODULE_INDEX, param_id);
+            let automation_params_index =
+                if let Some(index) = automation_params.iter().position(|x| *x == param) {
+                    index
+                } else {
+                    automation_params.push(param);
+                    automation_params.len() - 1
+                };
+            let lane_count = param_lane_map.len();
+            let lane = *param_lane_map
+                .entry(param_id)
+                .or_insert(lane_max + lane_count);
+            // 同じ line に複数あるときは最後の値
+            result.push((
+                lane,
+                position / 0x100,
+                LaneItem::Point(Point {
+                    automation_params_index,
+                    value,
+                    delay: (position % 0x100) as u8,
+                }),
+            ));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::lane::Lane;
+
+    fn song_for_test(lpb: u16) -> Song {
+        let mut song = Song::new();
+        song.bpm = 140.0;
+        song.lpb = lpb;
+        song.track_add();
+        let track = &mut song.tracks[0];
+        track.automation_params = vec![
+            (MIDI_CC_MODULE_INDEX, 7),
+            (MIDI_CC_MODULE_INDEX, MIDI_PITCH_BEND_PARAM_ID),
+        ];
+        let note = |key, delay, off| {
+            LaneItem::Note(Note {
+                key,
+                velocity: 100.0,
+                delay,
+                off,
+                ..Default::default()
+            })
+        };
+        track.lanes[0].items.insert(0, note(60, 0x10, false));
+        track.lanes[0].items.insert(2, note(60, 0x40, true));
+        track.lanes[0].items.insert(3, note(64, 0, false));
+        track.lanes.push(Lane::new());
+        let point = |automation_params_index, value, delay| {
+            LaneItem::Point(Point {
+                automation_params_index,
+                value,
+                delay,
+            })
+        };
+        track.lanes[1].items.insert(1, point(0, 129, 0x20));
+        track.lanes[1].items.insert(2, point(1, 200, 0x80));
+        track.lanes[1].items.insert(4, point(0, 255, 0));
+        song
+    }
+
+    fn round_trip(song: &Song, name: &str) -> MidiImport {
+        let path = std::env::temp_dir().join(format!(
+            "sing_like_coding_test_{}_{}.mid",
+            std::process::id(),
+            name
+        ));
+        let warnings = midi_file_export(song, &path, true).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let import = MidiImport::read(&path, 0, song.lpb, 120.0).unwrap();
+        let _ = fs::remove_file(&path);
+        import
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let song = song_for_test(4);
+        let import = round_trip(&song, "round_trip");
+
+        assert_eq!(import.bpm.map(|bpm| bpm.round()), Some(140.0));
+        assert_eq!(import.time_signature, Some((4, 4)));
+        // コンダクタートラック + 1 トラック
+        assert_eq!(import.tracks.len(), 2);
+        let track = &import.tracks[1];
+        assert_eq!(track.name, song.tracks[0].name);
+
+        let notes = track
+            .events
+            .iter()
+            .filter_map(|(position, event)| match event {
+                MidiImportEvent::NoteOn(key, velocity, _) => Some((*position, *key, *velocity)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(notes, vec![(0x10, 60, 100.0), (0x300, 64, 100.0)]);
+        let offs = track
+            .events
+            .iter()
+            .filter_map(|(position, event)| match event {
+                MidiImportEvent::NoteOff(key, _) => Some((*position, *key)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // 最後のノートは曲の終わりで止める
+        assert_eq!(offs, vec![(0x240, 60), (0x500, 64)]);
+
+        let mut automation_params = vec![];
+        let points = import
+            .lane_items(&track.events, &mut automation_params)
+            .into_iter()
+            .filter_map(|(_, line, item)| match item {
+                LaneItem::Point(point) => Some((
+                    automation_params[point.automation_params_index],
+                    line,
+                    point.delay,
+                    point.value,
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            points,
+            vec![
+                ((MIDI_CC_MODULE_INDEX, 7), 1, 0x20, 129),
+                (
+                    (MIDI_CC_MODULE_INDEX, MIDI_PITCH_BEND_PARAM_ID),
+                    2,
+                    0x80,
+                    200
+                ),
+                ((MIDI_CC_MODULE_INDEX, 7), 4, 0, 255),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_large_lpb_keeps_lines() {
+        let song = song_for_test(192);
+        let import = round_trip(&song, "large_lpb");
+        let positions = import.tracks[1]
+            .events
+            .iter()
+            .filter(|(_, event)| matches!(event, MidiImportEvent::NoteOn(..)))
+            .map(|(position, _)| *position)
+            .collect::<Vec<_>>();
+        // delay は丸められるが line はずれない
+        assert_eq!(positions.len(), 2);
+        assert_eq!(positions[0] / 0x100, 0);
+        assert!((positions[0] % 0x100).abs_diff(0x10) <= 1);
+        assert_eq!(positions[1], 0x300);
+    }
+
+    #[test]
+    fn export_uses_note_channel_and_clamps_keys() {
+        let mut song = song_for_test(4);
+        let track = &mut song.tracks[0];
+        track.lanes[1].items.clear();
+        let note = |key, channel| {
+            LaneItem::Note(Note {
+                key,
+                channel,
+                ..Default::default()
+            })
+        };
+        track.lanes[0].items.clear();
+        track.lanes[0].items.insert(0, note(62, 9));
+        track.lanes[0].items.insert(1, note(200, 3));
+        track.lanes[0].items.insert(2, note(-5, 20));
+        let import = round_trip(&song, "channel");
+
+        let notes = import.tracks[1]
+            .events
+            .iter()
+            .filter_map(|(position, event)| match event {
+                MidiImportEvent::NoteOn(key, _, channel) => Some((*position, true, *key, *channel)),
+                MidiImportEvent::NoteOff(key, channel) => Some((*position, false, *key, *channel)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            notes,
+            vec![
+                (0, true, 62, 9),
+                (0x100, false, 62, 9),
+                (0x100, true, 127, 3),
+                (0x200, false, 127, 3),
+                (0x200, true, 0, 15),
+                (0x300, false, 0, 15),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_skips_params_beyond_cc_range() {
+        let mut song = song_for_test(4);
+        let track = &mut song.tracks[0];
+        track.automation_params = (0..20).map(|param_id| (0, param_id)).collect();
+        track.lanes[1].items.clear();
+        for index in 0..20 {
+            track.lanes[1].items.insert(
+                index,
+                LaneItem::Point(Point {
+                    automation_params_index: index,
+                    value: 255,
+                    delay: 0,
+                }),
+            );
+        }
+        let path = std::env::temp_dir().join(format!(
+            "sing_like_coding_test_{}_cc_range.mid",
+            std::process::id()
+        ));
+        let warnings = midi_file_export(&song, &path, true).unwrap();
+        let import = MidiImport::read(&path, 0, song.lpb, 120.0).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(warnings.len(), 1);
+        let controllers = import.tracks[1]
+            .events
+            .iter()
+            .filter_map(|(_, event)| match event {
+                MidiImportEvent::Controller(cc, _) => Some(*cc as u32),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            controllers,
+            (CC_PARAM_START..=CC_PARAM_END).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn midi_cc_message_for_plugins() {
+        assert_eq!(midi_cc_message(7, 1.0), [0xB0, 7, 127]);
+        assert_eq!(midi_cc_message(7, 0.0), [0xB0, 7, 0]);
+        // Point 0x80 はピッチベンドのセンター
+        assert_eq!(
+            midi_cc_message(MIDI_PITCH_BEND_PARAM_ID, 128.0 / 255.0),
+            [0xE0, 0x00, 0x40]
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug)]
+pub enum MidiSyncMessage {
+    Clock(Instant),
+    Start,
+    Continue,
+    Stop,
+    SongPosition(u16), // 16分音符単位
+}
+
+/// 外部 MIDI クロックへの追従
+pub struct MidiSync {
+    clock_last: Option<Instant>,
+    sec_per_clock: Option<f64>,
+    pub transports: Vec<MidiSyncMessage>,
+}
+
+impl MidiSync {
+    pub fn new() -> Self {
+        Self {
+            clock_last: None,
+            sec_per_clock: None,
+            transports: vec![],
+        }
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        self.sec_per_clock.map(|x| 60.0 / (x * 24.0))
+    }
+
+    pub fn receive(&mut self, message: MidiSyncMessage) {
+        match message {
+            MidiSyncMessage::Clock(time) => {
+                if let Some(clock_last) = self.clock_last {
+                    let sec = (time - clock_last).as_secs_f64();
+                    // 途切れていた間隔は捨てる
+                    if 0.0 < sec && sec < 0.5 {
+                        // ジッターがあるのでならす
+                        self.sec_per_clock = Some(match self.sec_per_clock {
+                            Some(x) => x * 0.9 + sec * 0.1,
+                            None => sec,
+                        });
+                    }
+                }
+                self.clock_last = Some(time);
+            }
+            MidiSyncMessage::Start | MidiSyncMessage::Continue => {
+                self.clock_last = None;
+                self.transports.push(message);
+            }
+            MidiSyncMessage::Stop | MidiSyncMessage::SongPosition(_) => {
+                self.transports.push(message);
+            }
+        }
+    }
+}
@@ -1,6 +1,9 @@
+pub mod audio_clip;
+pub mod freeze;
 pub mod lane;
 pub mod lane_item;
 pub mod note;
 pub mod point;
 pub mod song;
+pub mod song_file;
 pub mod track;
@@ -0,0 +1,71 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+// file -> [channel][frame]
+pub type AudioFiles = HashMap<String, Arc<Vec<Vec<f32>>>>;
+
+// 置いた行から鳴るオーディオファイル
+// offset, length, fade_in, fade_out はファイルのフレーム数
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AudioClip {
+    pub file: String, // プロジェクトからの相対パス
+    pub sample_rate: f64,
+    pub offset: usize,
+    pub length: usize,
+    pub gain: f32, // dB
+    pub fade_in: usize,
+    pub fade_out: usize,
+    pub delay: u8,
+}
+
+impl AudioClip {
+    pub fn new(file: String, sample_rate: f64, length: usize) -> Self {
+        Self {
+            file,
+            sample_rate,
+            offset: 0,
+            length,
+            gain: 0.0,
+            fade_in: 0,
+            fade_out: 0,
+            delay: 0,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        Path::new(&self.file)
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn frames_from_ms(&self, ms: f64) -> usize {
+        (ms.max(0.0) * self.sample_rate / 1000.0) as usize
+    }
+
+    // position はクリップの頭からのフレーム数。gain は含まない
+    pub fn sample(&self, audio: &[f32], position: f64) -> f32 {
+        if position < 0.0 || position >= self.length as f64 {
+            return 0.0;
+        }
+        let index = self.offset as f64 + position;
+        let i = index as usize;
+        let Some(x0) = audio.get(i) else {
+            return 0.0;
+        };
+        let x1 = audio.get(i + 1).unwrap_or(x0);
+        let t = (index - i as f64) as f32;
+        let x = x0 + (x1 - x0) * t;
+
+        let mut envelope = 1.0;
+        if self.fade_in > 0 && position < self.fade_in as f64 {
+            envelope *= (position / self.fade_in as f64) as f32;
+        }
+        let rest = self.length as f64 - position;
+        if self.fade_out > 0 && rest < self.fade_out as f64 {
+            envelope *= (rest / self.fade_out as f64) as f32;
+        }
+        x * envelope
+    }
+}
@@ -0,0 +1,38 @@
+use std::{fmt::Debug, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+// フリーズしたトラックはモジュールを通さずに書き出した音を鳴らす
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Freeze {
+    pub file: String, // プロジェクトからの相対パス
+    // 書き出したときのテンポ。0.0 はわからない
+    #[serde(default)]
+    pub bpm: f64,
+    #[serde(skip)]
+    pub audio: Arc<Vec<Vec<f32>>>, // [channel][frame]
+}
+
+impl Freeze {
+    pub fn new(file: String, audio: Vec<Vec<f32>>, bpm: f64) -> Self {
+        Self {
+            file,
+            bpm,
+            audio: Arc::new(audio),
+        }
+    }
+
+    pub fn loaded_p(&self) -> bool {
+        !self.audio.is_empty()
+    }
+}
+
+impl Debug for Freeze {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Freeze")
+            .field("file", &self.file)
+            .field("bpm", &self.bpm)
+            .field("nframes", &self.audio.first().map_or(0, |x| x.len()))
+            .finish()
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use super::{note::Note, point::Point};
+use super::{audio_clip::AudioClip, note::Note, point::Point};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum LaneItem {
@@ -9,6 +9,7 @@ pub enum LaneItem {
     Call(String),
     Label(String),
     Ret,
+    AudioClip(AudioClip),
 }
 
 impl LaneItem {
@@ -19,6 +20,7 @@ impl LaneItem {
             LaneItem::Call(_) => 0,
             LaneItem::Label(_) => 0,
             LaneItem::Ret => 0,
+            LaneItem::AudioClip(AudioClip { delay, .. }) => *delay,
         }
     }
 }
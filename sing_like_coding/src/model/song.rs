@@ -6,24 +6,38 @@ use serde::{Deserialize, Serialize};
 
 use crate::app_state::CursorTrack;
 
-use super::{lane_item::LaneItem, track::Track};
+use super::{lane_item::LaneItem, song_file::SONG_FILE_VERSION, track::Track};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Song {
+    #[serde(default)]
+    pub version: u32,
     pub name: String,
     pub bpm: f64,
     pub sample_rate: f64,
     pub lpb: u16,
+    #[serde(default = "time_signature_default")]
+    pub time_signature: (u8, u8),
+    // 2 ステレオ 4 クアッド/アンビソニックス 1 次 6 5.1 8 7.1
+    #[serde(default = "nchannels_default")]
+    pub nchannels: usize,
+    // 64 ビットに対応しているプラグインは 64 ビットで処理する
+    #[serde(default)]
+    pub process_64_p: bool,
     pub tracks: Vec<Track>,
 }
 
 impl Song {
     pub fn new() -> Self {
         Self {
+            version: SONG_FILE_VERSION,
             name: Local::now().format("%Y%m%d.json").to_string(),
             bpm: 128.0,
             sample_rate: 48000.0,
             lpb: 4,
+            time_signature: time_signature_default(),
+            nchannels: nchannels_default(),
+            process_64_p: false,
             tracks: vec![],
         }
     }
@@ -34,6 +48,19 @@ impl Song {
             .find_map(|track| track.modules.iter_mut().find(|module| module.id == id))
     }
 
+    pub fn module_index_by_id(&self, id: ModuleId) -> Option<ModuleIndex> {
+        self.tracks
+            .iter()
+            .enumerate()
+            .find_map(|(track_index, track)| {
+                track
+                    .modules
+                    .iter()
+                    .position(|module| module.id == id)
+                    .map(|module_index| (track_index, module_index))
+            })
+    }
+
     pub fn track_add(&mut self) {
         let mut track = Track::new();
         track.name = if self.tracks.is_empty() {
@@ -53,8 +80,8 @@ impl Song {
         self.tracks.get_mut(track_index)
     }
 
-    pub fn track_delete(&mut self, track_index: usize) {
-        self.tracks.remove(track_index);
+    pub fn track_delete(&mut self, track_index: usize) -> Track {
+        let track = self.tracks.remove(track_index);
 
         for track in &mut self.tracks {
             for module in &mut track.modules {
@@ -69,12 +96,17 @@ impl Song {
                 }
             }
         }
+        track
     }
 
     pub fn track_insert(&mut self, track_index: usize, track: Track) {
         self.tracks.insert(track_index, track);
 
-        for track in &mut self.tracks {
+        // 挿入したトラック自身の参照は挿入後の位置を指している
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            if index == track_index {
+                continue;
+            }
             for module in &mut track.modules {
                 for audio_input in &mut module.audio_inputs {
                     let src_index = &mut audio_input.src_module_index.0;
@@ -132,6 +164,14 @@ impl Song {
     }
 }
 
+fn time_signature_default() -> (u8, u8) {
+    (4, 4)
+}
+
+fn nchannels_default() -> usize {
+    2
+}
+
 /// トポロジカル順にモジュールを依存レベルごとに分けて返す。
 /// Track 0:
 ///     Module 0
@@ -205,3 +245,78 @@ pub fn topological_levels(song: &Song) -> anyhow::Result<Vec<Vec<ModuleIndex>>>
 
     Ok(levels)
 }
+
+#[cfg(test)]
+mod tests {
+    use common::module::AudioInput;
+
+    use super::*;
+
+    fn module(id: ModuleId, audio_inputs: Vec<AudioInput>) -> Module {
+        Module::new(id, "plugin".to_string(), format!("M{}", id), audio_inputs)
+    }
+
+    fn audio_input(src_module_index: ModuleIndex) -> AudioInput {
+        AudioInput {
+            src_module_index,
+            src_port_index: 0,
+            dst_port_index: 1,
+        }
+    }
+
+    #[test]
+    fn track_preset_rebases_references() {
+        let mut song = Song::new();
+        for _ in 0..3 {
+            song.track_add();
+        }
+        // T02 のコンプレッサーに自分のトラックと T01 のサイドチェイン
+        song.tracks[1].modules.push(module(1, vec![]));
+        song.tracks[2].modules.push(module(2, vec![]));
+        song.tracks[2]
+            .modules
+            .push(module(3, vec![audio_input((2, 0)), audio_input((1, 0))]));
+
+        let mut preset = song.tracks[2].clone();
+        preset.detach(2);
+        let json = serde_json::to_string(&preset).unwrap();
+        let mut preset: Track = serde_json::from_str(&json).unwrap();
+        preset.attach(1);
+        song.track_insert(1, preset);
+
+        let inputs = &song.tracks[1].modules[1].audio_inputs;
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].src_module_index, (1, 0));
+        // 元のトラックの参照は挿入でずれる
+        let inputs = &song.tracks[3].modules[1].audio_inputs;
+        assert_eq!(inputs[0].src_module_index, (3, 0));
+        assert_eq!(inputs[1].src_module_index, (2, 0));
+        for track in song.tracks.iter() {
+            for module in track.modules.iter() {
+                for input in module.audio_inputs.iter() {
+                    assert!(input.src_module_index.0 < song.tracks.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn track_delete_and_insert_restores_references() {
+        let mut song = Song::new();
+        for _ in 0..4 {
+            song.track_add();
+        }
+        song.tracks[3].modules.push(module(1, vec![]));
+        song.tracks[1].modules.push(module(2, vec![]));
+        song.tracks[1]
+            .modules
+            .push(module(3, vec![audio_input((1, 0)), audio_input((3, 0))]));
+
+        let track = song.track_delete(1);
+        song.track_insert(1, track);
+
+        let inputs = &song.tracks[1].modules[1].audio_inputs;
+        assert_eq!(inputs[0].src_module_index, (1, 0));
+        assert_eq!(inputs[1].src_module_index, (3, 0));
+    }
+}
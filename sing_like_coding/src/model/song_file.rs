@@ -0,0 +1,225 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+
+use super::song::Song;
+
+// 曲ファイルのフォーマットを変えたらここを上げて MIGRATIONS に追加する
+pub const SONG_FILE_VERSION: u32 = 3;
+
+// MIGRATIONS[n] は version n + 1 から n + 2 へ
+const MIGRATIONS: &[fn(&mut Value) -> Result<()>] = &[migrate_1_to_2, migrate_2_to_3];
+
+pub fn song_read(path: &Path) -> Result<Song> {
+    let json = fs::read_to_string(path)?;
+    song_from_str(&json).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+pub fn song_from_str(json: &str) -> Result<Song> {
+    let mut value: Value = serde_json::from_str(json)?;
+    let version = song_version(&value)?;
+    if version > SONG_FILE_VERSION {
+        bail!(
+            "The song file format version {} is newer than supported version {}. Please update Sing Like Coding.",
+            version,
+            SONG_FILE_VERSION
+        );
+    }
+    for migration in MIGRATIONS[version as usize - 1..].iter() {
+        migration(&mut value)?;
+    }
+    let mut song: Song = serde_json::from_value(value)?;
+    song.version = SONG_FILE_VERSION;
+    Ok(song)
+}
+
+pub fn song_to_string(song: &Song) -> Result<String> {
+    Ok(serde_json::to_string_pretty(song)?)
+}
+
+pub struct SizeReportItem {
+    pub track_name: String,
+    pub module_name: String,
+    pub state_size: usize,
+    pub state_size_in_file: usize,
+}
+
+pub struct SizeReport {
+    pub song_size: usize,
+    pub items: Vec<SizeReportItem>, // 大きい順
+}
+
+impl SizeReport {
+    pub fn new(song: &Song) -> Result<Self> {
+        let song_size = song_to_string(song)?.len();
+        let mut items = song
+            .tracks
+            .iter()
+            .flat_map(|track| {
+                track.modules.iter().map(|module| {
+                    let state_size = module.state.as_ref().map_or(0, |x| x.len());
+                    SizeReportItem {
+                        track_name: track.name.clone(),
+                        module_name: module.name.clone(),
+                        state_size,
+                        // base64
+                        state_size_in_file: state_size.div_ceil(3) * 4,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        items.sort_by_key(|item| std::cmp::Reverse(item.state_size));
+        Ok(Self { song_size, items })
+    }
+
+    pub fn states_size(&self) -> usize {
+        self.items.iter().map(|item| item.state_size).sum()
+    }
+
+    pub fn states_size_in_file(&self) -> usize {
+        self.items.iter().map(|item| item.state_size_in_file).sum()
+    }
+}
+
+fn song_version(value: &Value) -> Result<u32> {
+    match value.get("version") {
+        // version がないバージョン管理前のファイルは version 1 と同じ形式
+        None => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .filter(|x| *x >= 1)
+            .map(|x| x as u32)
+            .ok_or_else(|| anyhow!("Invalid song file version {}", version)),
+    }
+}
+
+fn song_object(value: &mut Value) -> Result<&mut serde_json::Map<String, Value>> {
+    value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("The song file is not a JSON object."))
+}
+
+// Module の state が数値の配列から base64 文字列になった
+// 読み込みは数値の配列のままでもできるので version を上げるだけ
+fn migrate_1_to_2(value: &mut Value) -> Result<()> {
+    let song = song_object(value)?;
+    song.insert("version".to_string(), json!(2));
+    Ok(())
+}
+
+// 追加された項目を埋める
+// - Song の nchannels, process_64_p
+// - Track の freeze
+// - Module の bypass_p, mix, gain, aux_outputs
+// LaneItem::AudioClip は新しい variant なので既存のデータはそのまま読める
+fn migrate_2_to_3(value: &mut Value) -> Result<()> {
+    let song = song_object(value)?;
+    song.entry("nchannels").or_insert(json!(2));
+    song.entry("process_64_p").or_insert(json!(false));
+    let tracks = song
+        .get_mut("tracks")
+        .and_then(|x| x.as_array_mut())
+        .ok_or_else(|| anyhow!("The song file has no tracks."))?;
+    for track in tracks.iter_mut() {
+        let track = track
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("The track is not a JSON object."))?;
+        track.entry("freeze").or_insert(Value::Null);
+        let Some(modules) = track.get_mut("modules").and_then(|x| x.as_array_mut()) else {
+            continue;
+        };
+        for module in modules.iter_mut() {
+            let module = module
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("The module is not a JSON object."))?;
+            module.entry("bypass_p").or_insert(json!(false));
+            module.entry("mix").or_insert(json!(1.0));
+            module.entry("gain").or_insert(json!(0.0));
+            module.entry("aux_outputs").or_insert(json!([]));
+        }
+    }
+    song.insert("version".to_string(), json!(3));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn read_unversioned_file() {
+        let song = song_read(&fixture("song_v0.json")).unwrap();
+        assert_eq!(song.version, SONG_FILE_VERSION);
+        assert_eq!(song.bpm, 120.0);
+        assert_eq!(song.time_signature, (4, 4));
+        assert_eq!(song.tracks.len(), 2);
+        let track = &song.tracks[1];
+        assert_eq!(track.midi_output, None);
+        assert_eq!(track.modules[0].state.as_deref(), Some(&[1u8, 2, 3][..]));
+        assert_eq!(track.automation_params, vec![(0, 5)]);
+        assert_eq!(track.lanes[0].items.len(), 2);
+    }
+
+    #[test]
+    fn read_version_1_file() {
+        let song = song_read(&fixture("song_v1.json")).unwrap();
+        assert_eq!(song.version, SONG_FILE_VERSION);
+        assert_eq!(song.time_signature, (3, 4));
+        assert_eq!(
+            song.tracks[1].midi_output.as_deref(),
+            Some("Hardware Synth")
+        );
+        assert_eq!(
+            song.tracks[1].modules[0].state.as_deref(),
+            Some(&[1u8, 2, 3][..])
+        );
+
+        // 保存し直すと state は base64 になり、そのまま読み直せる
+        let json = song_to_string(&song).unwrap();
+        assert!(json.contains("\"state\": \"AQID\""));
+        let song = song_from_str(&json).unwrap();
+        assert_eq!(
+            song.tracks[1].modules[0].state.as_deref(),
+            Some(&[1u8, 2, 3][..])
+        );
+    }
+
+    #[test]
+    fn read_version_2_file() {
+        let song = song_read(&fixture("song_v2.json")).unwrap();
+        assert_eq!(song.version, SONG_FILE_VERSION);
+        assert_eq!(song.nchannels, 2);
+        assert!(!song.process_64_p);
+        let track = &song.tracks[1];
+        assert!(track.freeze.is_none());
+        let module = &track.modules[0];
+        assert!(!module.bypass_p);
+        assert_eq!(module.mix, 1.0);
+        assert_eq!(module.gain, 0.0);
+        assert!(module.aux_outputs.is_empty());
+        assert_eq!(module.state.as_deref(), Some(&[1u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn read_newer_file_fails() {
+        let e = song_read(&fixture("song_newer.json")).unwrap_err();
+        let message = e.to_string();
+        assert!(message.contains("song_newer.json"), "{message}");
+        assert!(message.contains("newer than supported"), "{message}");
+    }
+
+    #[test]
+    fn read_invalid_version_fails() {
+        assert!(song_from_str(r#"{"version": 0, "tracks": []}"#).is_err());
+        assert!(song_from_str(r#"{"version": "2", "tracks": []}"#).is_err());
+    }
+}
@@ -7,13 +7,30 @@ use std::{
 use anyhow::Result;
 use clap_sys::id::clap_id;
 use common::{
-    dsp::db_to_norm, event::Event, module::Module, process_track_context::ProcessTrackContext,
+    channel_map::channel_map,
+    dsp::{db_from_norm, db_to_linear, db_to_norm},
+    event::Event,
+    module::{
+        Module, MODULE_BYPASS_PARAM_ID, MODULE_GAIN_DB_MAX, MODULE_GAIN_DB_MIN,
+        MODULE_GAIN_PARAM_ID, MODULE_MIX_PARAM_ID,
+    },
+    process_track_context::ProcessTrackContext,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::view::stereo_peak_meter::{DB_MAX, DB_MIN};
+use crate::{
+    midi_file::midi_cc_message,
+    view::stereo_peak_meter::{DB_MAX, DB_MIN},
+};
+
+use super::{audio_clip::AudioFiles, freeze::Freeze, lane::Lane, lane_item::LaneItem, note::Note};
 
-use super::{lane::Lane, lane_item::LaneItem, note::Note};
+// automation_params の module_index がこれなら MIDI 出力とプラグインに送る CC
+pub const MIDI_CC_MODULE_INDEX: usize = usize::MAX;
+// MIDI_CC_MODULE_INDEX でこの param_id ならピッチベンド
+pub const MIDI_PITCH_BEND_PARAM_ID: clap_id = 0x80;
+// プリセットやクリップボードのトラックで、自分のトラックを指す audio_inputs の track index
+pub const TRACK_INDEX_SELF: usize = usize::MAX;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Track {
@@ -25,6 +42,10 @@ pub struct Track {
     pub modules: Vec<Module>,
     pub lanes: Vec<Lane>,
     pub automation_params: Vec<(usize, clap_id)>, // (module_index, param_id)
+    #[serde(default)]
+    pub midi_output: Option<String>,
+    #[serde(default)]
+    pub freeze: Option<Freeze>,
     #[serde(skip_serializing, skip_deserializing)]
     on_key_lane_map: HashMap<i16, usize>,
 }
@@ -40,10 +61,59 @@ impl Track {
             modules: vec![],
             lanes: vec![Lane::new()],
             automation_params: vec![],
+            midi_output: None,
+            freeze: None,
             on_key_lane_map: Default::default(),
         }
     }
 
+    // 曲の外に出すときに自分のトラックへの参照を TRACK_INDEX_SELF にする
+    // 他のトラックからのサイドチェインは持ち出せないので外す
+    pub fn detach(&mut self, track_index: usize) {
+        for module in self.modules.iter_mut() {
+            module
+                .audio_inputs
+                .retain(|input| input.src_module_index.0 == track_index);
+            for input in module.audio_inputs.iter_mut() {
+                input.src_module_index.0 = TRACK_INDEX_SELF;
+            }
+        }
+    }
+
+    // detach したトラックを track_index に置く
+    // 古いプリセットの絶対位置の参照はどこを指すかわからないので外す
+    pub fn attach(&mut self, track_index: usize) {
+        let nmodules = self.modules.len();
+        for module in self.modules.iter_mut() {
+            module.audio_inputs.retain(|input| {
+                input.src_module_index.0 == TRACK_INDEX_SELF && input.src_module_index.1 < nmodules
+            });
+            for input in module.audio_inputs.iter_mut() {
+                input.src_module_index.0 = track_index;
+            }
+        }
+    }
+
+    // 曲の中のトラックを複製して track_index に挿入するときに、挿入でずれる位置に合わせる
+    pub fn references_shift_for_insert(&mut self, track_index: usize) {
+        for module in self.modules.iter_mut() {
+            for input in module.audio_inputs.iter_mut() {
+                if input.src_module_index.0 >= track_index {
+                    input.src_module_index.0 += 1;
+                }
+            }
+        }
+    }
+
+    // フリーズ中はプラグインをアンロードしている
+    pub fn modules_loaded(&self) -> &[Module] {
+        if self.freeze.is_some() {
+            &[]
+        } else {
+            &self.modules
+        }
+    }
+
     pub fn process_module(
         &self,
         track_index: usize,
@@ -53,7 +123,16 @@ impl Track {
     ) -> Result<()> {
         self.prepare_module_event(context, module_index)?;
         self.prepare_module_audio(track_index, context, module_index, contexts)?;
-        context.plugins[module_index].process()?;
+        if module_index == 0 && context.audio_clip_p {
+            self.prepare_module_audio_clip(context);
+        }
+        let module = &self.modules[module_index];
+        let plugin_ref = &mut context.plugins[module_index];
+        plugin_ref.process_with_host_params(
+            plugin_ref.bypass_p.unwrap_or(module.bypass_p),
+            plugin_ref.mix.unwrap_or(module.mix),
+            plugin_ref.gain.unwrap_or(module.gain),
+        )?;
         Ok(())
     }
 
@@ -139,6 +218,9 @@ impl Track {
                                 return idle_p;
                             }
                         }
+                        LaneItem::AudioClip(_) => {
+                            // compute_audio_clip で
+                        }
                     }
                 }
             }
@@ -148,6 +230,78 @@ impl Track {
         idle_p
     }
 
+    // オーディオクリップの音を context.buffer に書く。鳴っていれば true
+    pub fn compute_audio_clip(
+        &self,
+        context: &mut ProcessTrackContext,
+        audio_files: &AudioFiles,
+        sample_rate: f64,
+        frames_per_delay: f64,
+    ) -> bool {
+        if !context.play_p {
+            return false;
+        }
+        let nframes = context.nframes;
+        let nchannels = context.nchannels;
+        // (play_position, buffer の位置, フレーム数)
+        let segments = if context.play_position.start <= context.play_position.end {
+            [(context.play_position.start, 0, nframes), (0, nframes, 0)]
+        } else {
+            let n = ((context
+                .loop_range
+                .end
+                .saturating_sub(context.play_position.start)) as f64
+                * frames_per_delay) as usize;
+            let n = n.min(nframes);
+            [
+                (context.play_position.start, 0, n),
+                (context.loop_range.start, n, nframes - n),
+            ]
+        };
+        let line_end = segments
+            .iter()
+            .map(|(start, _, n)| (*start as f64 + *n as f64 / frames_per_delay) as usize / 0x100)
+            .max()
+            .unwrap_or(0);
+
+        for buffer in context.buffer.buffer.iter_mut() {
+            buffer[..nframes].fill(0.0);
+        }
+        let mut sounding_p = false;
+        for lane in self.lanes.iter() {
+            for (line, item) in lane.items.range(..=line_end) {
+                let LaneItem::AudioClip(clip) = item else {
+                    continue;
+                };
+                let Some(audio) = audio_files.get(&clip.file).filter(|x| !x.is_empty()) else {
+                    continue;
+                };
+                let clip_start = (*line * 0x100 + clip.delay as usize) as f64;
+                let ratio = clip.sample_rate / sample_rate;
+                let length = clip.length as f64 / ratio;
+                let gain = db_to_linear(clip.gain);
+                for (start, offset, n) in segments {
+                    // クリップの頭からのフレーム数
+                    let position = (start as f64 - clip_start) * frames_per_delay;
+                    if n == 0 || position >= length || position + (n as f64) <= 0.0 {
+                        continue;
+                    }
+                    sounding_p = true;
+                    for (src_ch, dst_ch, channel_gain) in channel_map(audio.len(), nchannels) {
+                        let audio = &audio[src_ch];
+                        let buffer = &mut context.buffer.buffer[dst_ch];
+                        for i in 0..n {
+                            let x = clip.sample(audio, (position + i as f64) * ratio);
+                            buffer[offset + i] += x * gain * channel_gain;
+                        }
+                    }
+                }
+            }
+        }
+        context.buffer.constant_mask = 0;
+        sounding_p
+    }
+
     pub fn events_append(
         &mut self,
         events: &Vec<Event>,
@@ -222,92 +376,108 @@ impl Track {
                 context.plugins[autdio_input.src_module_index.1].ptr
             } else {
                 let context = contexts[autdio_input.src_module_index.0].lock().unwrap();
-                context.plugins[autdio_input.src_module_index.1].ptr
+                // フリーズの書き出し中のトラックはプラグインを貸し出している
+                let Some(plugin_ref) = context.plugins.get(autdio_input.src_module_index.1) else {
+                    continue;
+                };
+                plugin_ref.ptr
             };
             let src_process_data = unsafe { &*src_ptr };
             let src_constant_mask = src_process_data.constant_mask_out[autdio_input.src_port_index];
-            let src_buffer = &src_process_data.buffer_out[autdio_input.src_port_index];
-            let src_nchannels = src_process_data.nchannels_out[autdio_input.src_port_index];
+            let src_buffer = src_process_data.buffer_out(autdio_input.src_port_index);
+            let src_nchannels = src_buffer.len();
             let dst_process_data = context.plugins[module_index].process_data_mut();
-            let dst_constant_mask =
-                &mut dst_process_data.constant_mask_in[autdio_input.dst_port_index];
-            let dst_buffer = &mut dst_process_data.buffer_in[autdio_input.dst_port_index];
-            let dst_nchannels = dst_process_data.nchannels_in[autdio_input.dst_port_index];
+            let mut dst_constant_mask =
+                dst_process_data.constant_mask_in[autdio_input.dst_port_index];
+            let dst_buffer = dst_process_data.buffer_in_mut(autdio_input.dst_port_index);
+            let dst_nchannels = dst_buffer.len();
 
             match (src_nchannels, dst_nchannels) {
                 (src_nchannels, dst_nchannels) if src_nchannels == dst_nchannels => {
                     for ch in 0..src_nchannels {
                         let constant_mask_bit = 1 << ch;
                         if (src_constant_mask & constant_mask_bit) == 0 {
-                            *dst_constant_mask &= !constant_mask_bit;
+                            dst_constant_mask &= !constant_mask_bit;
                             dst_buffer[ch].copy_from_slice(&src_buffer[ch]);
                         } else {
-                            *dst_constant_mask |= constant_mask_bit;
+                            dst_constant_mask |= constant_mask_bit;
                             dst_buffer[ch][0] = src_buffer[ch][0];
                         }
                     }
                 }
-                (1, dst_nchannels) => {
-                    for ch in 0..dst_nchannels {
+                (1, _) => {
+                    for (ch, dst_buffer) in dst_buffer.iter_mut().enumerate() {
                         let constant_mask_bit = 1 << ch;
                         if (src_constant_mask & 1) == 0 {
-                            *dst_constant_mask &= !constant_mask_bit;
-                            dst_buffer[ch].copy_from_slice(&src_buffer[0]);
+                            dst_constant_mask &= !constant_mask_bit;
+                            dst_buffer.copy_from_slice(&src_buffer[0]);
                         } else {
-                            *dst_constant_mask |= constant_mask_bit;
-                            dst_buffer[ch][0] = src_buffer[0][0];
-                        }
-                    }
-                }
-                (src_nchannels, 1) => {
-                    for ch in 0..src_nchannels {
-                        let constant_mask_bit = 1 << ch;
-                        if (src_constant_mask & constant_mask_bit) == 0 {
-                            for i in 0..context.nframes {
-                                if ch == 0 {
-                                    dst_buffer[0][i] = src_buffer[ch][i] / src_nchannels as f32;
-                                } else {
-                                    dst_buffer[0][i] += src_buffer[ch][i] / src_nchannels as f32;
-                                }
-                            }
-                        } else {
-                            for i in 0..context.nframes {
-                                if ch == 0 {
-                                    dst_buffer[0][i] = src_buffer[ch][0] / src_nchannels as f32;
-                                } else {
-                                    dst_buffer[0][i] += src_buffer[ch][0] / src_nchannels as f32;
-                                }
-                            }
+                            dst_constant_mask |= constant_mask_bit;
+                            dst_buffer[0] = src_buffer[0][0];
                         }
                     }
-                    *dst_constant_mask = 0;
                 }
                 (src_nchannels, dst_nchannels) => {
-                    for ch in 0..(src_nchannels.min(dst_nchannels)) {
-                        let constant_mask_bit = 1 << ch;
-                        if (src_constant_mask & constant_mask_bit) == 0 {
-                            *dst_constant_mask &= !constant_mask_bit;
-                            dst_buffer[ch].copy_from_slice(&src_buffer[ch]);
-                        } else {
-                            *dst_constant_mask |= constant_mask_bit;
-                            dst_buffer[ch][0] = src_buffer[ch][0];
+                    // ダウンミックスなどは channel_map の対応で足し合わせる
+                    let nframes = context.nframes;
+                    for buffer in dst_buffer.iter_mut() {
+                        buffer[..nframes].fill(0.0);
+                    }
+                    for (src_ch, dst_ch, gain) in channel_map(src_nchannels, dst_nchannels) {
+                        let constp = (src_constant_mask & (1 << src_ch)) != 0;
+                        for i in 0..nframes {
+                            let x = if constp {
+                                src_buffer[src_ch][0]
+                            } else {
+                                src_buffer[src_ch][i]
+                            };
+                            dst_buffer[dst_ch][i] += x * gain as f64;
                         }
                     }
+                    dst_constant_mask = 0;
                 }
             }
+            dst_process_data.constant_mask_in[autdio_input.dst_port_index] = dst_constant_mask;
         }
 
         Ok(())
     }
 
+    // オーディオクリップの音は最初のモジュールの入力に足す
+    fn prepare_module_audio_clip(&self, context: &mut ProcessTrackContext) {
+        let nframes = context.nframes;
+        let nchannels = context.nchannels;
+        let Some(plugin_ref) = context.plugins.first_mut() else {
+            return;
+        };
+        let data = plugin_ref.process_data_mut();
+        let constant_mask = data.constant_mask_in[0];
+        data.constant_mask_in[0] &= !((1 << data.buffer_in(0).len()) - 1);
+        let buffer_in = data.buffer_in_mut(0);
+        for (channel, buffer) in buffer_in.iter_mut().enumerate() {
+            if (constant_mask & (1 << channel)) != 0 {
+                let constant = buffer[0];
+                buffer[..nframes].fill(constant);
+            }
+        }
+        for (src_ch, dst_ch, gain) in channel_map(nchannels, buffer_in.len()) {
+            let clip = &context.buffer.buffer[src_ch];
+            let buffer = &mut buffer_in[dst_ch];
+            for frame in 0..nframes {
+                buffer[frame] += clip[frame] as f64 * gain as f64;
+            }
+        }
+    }
+
     fn prepare_module_event(
         &self,
         context: &mut ProcessTrackContext,
         module_index: usize,
     ) -> Result<()> {
         let plugin_ref_self = &mut context.plugins[module_index];
-        let data = plugin_ref_self.process_data_mut();
         for event in context.event_list_input.iter() {
+            plugin_ref_self.events_input_reserve()?;
+            let data = plugin_ref_self.process_data_mut();
             match event {
                 Event::NoteOn(key, velocity, delay) => {
                     data.input_note_on(*key, *velocity, 0, *delay)
@@ -315,9 +485,34 @@ impl Track {
                 Event::NoteOff(key, delay) => data.input_note_off(*key, 0, *delay),
                 Event::NoteAllOff => {
                     for key in context.on_keys.drain(..).filter_map(|x| x) {
-                        data.input_note_off(key, 0, 0);
+                        plugin_ref_self.events_input_reserve()?;
+                        plugin_ref_self.process_data_mut().input_note_off(key, 0, 0);
                     }
                 }
+                // ホスト側のパラメータはバッファ単位で反映する
+                Event::ParamValue(mindex, MODULE_BYPASS_PARAM_ID, value, _)
+                    if *mindex == module_index =>
+                {
+                    plugin_ref_self.bypass_p = Some(*value >= 0.5);
+                }
+                Event::ParamValue(mindex, MODULE_MIX_PARAM_ID, value, _)
+                    if *mindex == module_index =>
+                {
+                    plugin_ref_self.mix = Some(*value as f32);
+                }
+                Event::ParamValue(mindex, MODULE_GAIN_PARAM_ID, value, _)
+                    if *mindex == module_index =>
+                {
+                    plugin_ref_self.gain = Some(db_from_norm(
+                        *value as f32,
+                        MODULE_GAIN_DB_MIN,
+                        MODULE_GAIN_DB_MAX,
+                    ));
+                }
+                // MIDI の CC とピッチベンドとしてプラグインに送る
+                Event::ParamValue(MIDI_CC_MODULE_INDEX, param_id, value, delay) => {
+                    data.input_midi(midi_cc_message(*param_id, *value), *delay)
+                }
                 Event::ParamValue(mindex, param_id, value, delay) => {
                     if *mindex == module_index {
                         data.input_param_value(*param_id, *value, *delay)
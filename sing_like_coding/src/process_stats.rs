@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::Result;
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::{CloseHandle, FILETIME},
+    System::{
+        ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+        Threading::{GetProcessTimes, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    },
+};
+
+pub struct ProcessStats {
+    pub memory: usize,      // 常駐しているメモリ(バイト)
+    pub cpu_time: Duration, // ユーザーとカーネルの合計
+}
+
+#[cfg(target_os = "linux")]
+pub fn process_stats(pid: u32) -> Result<ProcessStats> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status"))?;
+    let memory = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|x| x.trim().trim_end_matches("kB").trim().parse::<usize>().ok())
+        .map_or(0, |kb| kb * 1024);
+
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    // プロセス名に空白や括弧が入ることがあるので最後の ) より後ろを見る
+    let fields = stat
+        .rsplit_once(')')
+        .map(|(_, x)| x.split_whitespace().collect::<Vec<_>>())
+        .unwrap_or_default();
+    // utime と stime は 14, 15 番目で、) の後ろは 3 番目から
+    let ticks = fields
+        .get(11..13)
+        .ok_or_else(|| anyhow::anyhow!("unexpected /proc/{pid}/stat"))?
+        .iter()
+        .map(|x| x.parse::<u64>())
+        .sum::<Result<u64, _>>()?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+
+    Ok(ProcessStats {
+        memory,
+        cpu_time: Duration::from_secs_f64(ticks as f64 / ticks_per_second),
+    })
+}
+
+#[cfg(windows)]
+pub fn process_stats(pid: u32) -> Result<ProcessStats> {
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)? };
+    let mut counters = PROCESS_MEMORY_COUNTERS::default();
+    let mut creation_time = FILETIME::default();
+    let mut exit_time = FILETIME::default();
+    let mut kernel_time = FILETIME::default();
+    let mut user_time = FILETIME::default();
+    let result = unsafe {
+        GetProcessMemoryInfo(
+            process,
+            &mut counters,
+            size_of::<PROCESS_MEMORY_COUNTERS>() as u32,
+        )
+        .and_then(|_| {
+            GetProcessTimes(
+                process,
+                &mut creation_time,
+                &mut exit_time,
+                &mut kernel_time,
+                &mut user_time,
+            )
+        })
+    };
+    unsafe {
+        let _ = CloseHandle(process);
+    }
+    result?;
+
+    // FILETIME は 100ns 単位
+    let filetime = |x: FILETIME| ((x.dwHighDateTime as u64) << 32) | x.dwLowDateTime as u64;
+    Ok(ProcessStats {
+        memory: counters.WorkingSetSize,
+        cpu_time: Duration::from_nanos((filetime(kernel_time) + filetime(user_time)) * 100),
+    })
+}
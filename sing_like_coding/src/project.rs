@@ -0,0 +1,144 @@
+use std::{
+    fs::{copy, create_dir_all, read_dir},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::util::next_id;
+
+// プロジェクトはディレクトリ単位
+// <name>/<name>.json 曲
+// <name>/samples/    サンプル
+// <name>/renders/    書き出し
+// <name>/freeze/     フリーズしたトラック
+// <name>/recordings/ 録音したテイク
+// 曲の中のパスはプロジェクトディレクトリからの相対パス
+
+const SAMPLES_DIR: &str = "samples";
+const RENDERS_DIR: &str = "renders";
+const FREEZE_DIR: &str = "freeze";
+const RECORDINGS_DIR: &str = "recordings";
+
+pub struct Project {
+    pub dir: PathBuf,
+}
+
+impl Project {
+    // path は曲ファイルでもディレクトリでもよい
+    pub fn new(path: &Path) -> Self {
+        let dir = if path.is_dir() {
+            path.to_path_buf()
+        } else {
+            path.parent().map(Path::to_path_buf).unwrap_or_default()
+        };
+        Self { dir }
+    }
+
+    // 保存ダイアログで選んだパスから <name>/<name>.json を作る
+    pub fn create(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .with_context(|| format!("Invalid project name: {}", path.display()))?;
+        // 既にプロジェクトの中の曲を選んだ場合はそのまま
+        let dir = if path.parent().and_then(|x| x.file_name()) == Some(name) {
+            path.parent().unwrap().to_path_buf()
+        } else {
+            path.with_file_name(name)
+        };
+        let this = Self { dir };
+        create_dir_all(this.samples_dir())?;
+        create_dir_all(this.renders_dir())?;
+        Ok(this)
+    }
+
+    pub fn name(&self) -> String {
+        self.dir
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn song_file(&self) -> PathBuf {
+        self.dir.join(format!("{}.json", self.name()))
+    }
+
+    pub fn samples_dir(&self) -> PathBuf {
+        self.dir.join(SAMPLES_DIR)
+    }
+
+    pub fn renders_dir(&self) -> PathBuf {
+        self.dir.join(RENDERS_DIR)
+    }
+
+    pub fn freeze_dir(&self) -> PathBuf {
+        self.dir.join(FREEZE_DIR)
+    }
+
+    pub fn recordings_dir(&self) -> PathBuf {
+        self.dir.join(RECORDINGS_DIR)
+    }
+
+    // 曲に保存する形式へ
+    pub fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    pub fn absolute(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            self.dir.join(path)
+        }
+    }
+
+    // samples の外のファイルは samples にコピーして、曲に保存する形式のパスを返す
+    pub fn sample_import(&self, path: &Path) -> Result<String> {
+        let samples_dir = self.samples_dir();
+        if path.starts_with(&samples_dir) {
+            return Ok(self.relative(path));
+        }
+        let mut to = samples_dir.join(path.file_name().unwrap_or_default());
+        if to.exists() {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = path.extension().unwrap_or_default().to_string_lossy();
+            to = samples_dir.join(format!("{}_{}.{}", stem, next_id(), ext));
+        }
+        create_dir_all(&samples_dir)?;
+        copy(path, &to).with_context(|| format!("Failed to copy {}", path.display()))?;
+        Ok(self.relative(&to))
+    }
+
+    // 相対パスが切れないようにサンプルと書き出しを別のプロジェクトへコピーする
+    pub fn copy_assets_to(&self, other: &Project) -> Result<()> {
+        if self.dir == other.dir {
+            return Ok(());
+        }
+        copy_dir(&self.samples_dir(), &other.samples_dir())?;
+        copy_dir(&self.renders_dir(), &other.renders_dir())?;
+        copy_dir(&self.freeze_dir(), &other.freeze_dir())?;
+        copy_dir(&self.recordings_dir(), &other.recordings_dir())?;
+        Ok(())
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    if !from.is_dir() {
+        return Ok(());
+    }
+    create_dir_all(to)?;
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        let to = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            copy(entry.path(), to)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::{
+    fs::{copy, create_dir_all, read_to_string, remove_file, rename, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use common::util::dir_user_setting;
+
+use crate::model::{song::Song, song_file::song_to_string};
+
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+pub const BACKUP_COUNT: usize = 5;
+
+// 起動中だけ存在するファイル。起動時に残っていたら前回は正常終了していない。
+fn session_file() -> PathBuf {
+    dir_user_setting().join("session.lock")
+}
+
+pub fn recovery_file() -> PathBuf {
+    dir_user_setting().join("recovery.json")
+}
+
+// 自動保存した曲の元のファイル
+fn recovery_song_file_file() -> PathBuf {
+    dir_user_setting().join("recovery_song_file.txt")
+}
+
+// 前回が異常終了で復元できるものがあれば true
+pub fn session_start() -> Result<bool> {
+    let recovery_p = session_file().exists() && recovery_file().exists();
+    create_dir_all(dir_user_setting())?;
+    File::create(session_file())?;
+    Ok(recovery_p)
+}
+
+pub fn session_end() {
+    let _ = remove_file(session_file());
+    recovery_discard();
+}
+
+pub fn recovery_save(song: &Song, song_file: Option<&str>) -> Result<()> {
+    let json = song_to_string(song)?;
+    // 書きかけで落ちても前の復元ファイルが壊れないように
+    let tmp = recovery_file().with_extension("tmp");
+    File::create(&tmp)?.write_all(json.as_bytes())?;
+    rename(&tmp, recovery_file())?;
+    File::create(recovery_song_file_file())?.write_all(song_file.unwrap_or("").as_bytes())?;
+    Ok(())
+}
+
+pub fn recovery_song_file() -> Option<String> {
+    read_to_string(recovery_song_file_file())
+        .ok()
+        .filter(|s| !s.is_empty())
+}
+
+pub fn recovery_discard() {
+    let _ = remove_file(recovery_file());
+    let _ = remove_file(recovery_song_file_file());
+}
+
+fn backup_file(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".bak{}", n));
+    path.with_file_name(name)
+}
+
+// song.json -> song.json.bak1 -> song.json.bak2 ...
+pub fn backup_rotate(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let _ = remove_file(backup_file(path, BACKUP_COUNT));
+    for n in (1..BACKUP_COUNT).rev() {
+        let from = backup_file(path, n);
+        if from.exists() {
+            rename(&from, backup_file(path, n + 1))?;
+        }
+    }
+    copy(path, backup_file(path, 1))?;
+    Ok(())
+}
@@ -1,25 +1,36 @@
 use std::{
+    collections::{HashMap, VecDeque},
     f32::consts::PI,
-    fs::File,
-    io::BufReader,
+    fs::create_dir_all,
     ops::Range,
+    path::Path,
     sync::{
         mpsc::{Receiver, Sender},
         Arc, Mutex,
     },
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
 use crate::{
     app_state::CursorTrack,
+    audio_file::{audio_file_read, audio_file_write},
+    audio_input_device::AudioInputBuffer,
+    midi_device::MidiOutputDevice,
+    midi_file::midi_cc_message,
+    midi_sync::{MidiSync, MidiSyncMessage},
     model::{
+        audio_clip::{AudioClip, AudioFiles},
+        freeze::Freeze,
         lane_item::LaneItem,
         point::Point,
         song::{topological_levels, Song},
-        track::Track,
+        song_file::song_read,
+        track::{Track, MIDI_CC_MODULE_INDEX},
     },
+    project::Project,
     song_state::SongState,
-    undo_history::UndoHistory,
+    undo_history::{UndoContext, UndoHistory, UndoHistorySnapshot},
     util::next_id,
     view::stereo_peak_meter::DB_MIN,
 };
@@ -30,33 +41,57 @@ use clap_sys::{
     id::clap_id,
 };
 use common::{
+    audio_buffer::AudioBuffer,
+    channel_map::channel_map,
+    dsp::{db_to_linear, linear_to_db},
     event::Event,
-    module::{AudioInput, Module, ModuleIndex},
+    module::{AudioInput, AuxOutput, Module, ModuleId, ModuleIndex},
     plugin_ref::PluginRef,
-    process_data::{EventKind, ProcessData},
+    process_data::{EventKind, ProcessData, MAX_CHANNELS, MAX_FRAMES},
     process_track_context::ProcessTrackContext,
     shmem::{create_shared_memory, process_data_name, SONG_STATE_NAME},
+    util::dir_user_setting,
 };
 use rayon::prelude::*;
 use shared_memory::Shmem;
 
+// フリーズの書き出し
+const FREEZE_BLOCK_FRAMES: usize = 512;
+const FREEZE_TAIL_SECONDS: f64 = 2.0; // リバーブなどの余韻
+
 #[derive(Clone, Debug)]
 pub enum MainToAudio {
     Bpm(f64),
     Play,
     PlayLine(usize),
     Stop,
+    TimeSignature(u8, u8),
+    Channels(usize),
+    Process64(bool),
     Loop,
     LoopRange(Range<usize>),
+    MidiClockOutput(Option<String>),
+    MidiSyncFollow(bool),
     LaneAdd(usize),
+    LaneDelete(usize),
     LaneItem(Vec<(CursorTrack, Option<LaneItem>)>),
+    ModuleAudioInputs(ModuleIndex, Vec<AudioInput>),
+    ModuleAuxOutputs(ModuleIndex, Vec<AuxOutput>),
+    ModuleBypass(ModuleIndex, bool),
+    ModuleGain(ModuleIndex, f32),
+    ModuleInsert(ModuleIndex, Module),
+    ModuleMix(ModuleIndex, f32),
+    ModuleMove(ModuleIndex, isize),
     ModuleRename(ModuleIndex, String),
+    ModuleState(ModuleId, Vec<u8>),
     #[allow(dead_code)]
     NoteOn(usize, i16, i16, f64, usize),
     #[allow(dead_code)]
     NoteOff(usize, i16, i16, f64, usize),
+    PluginBypassParam(usize, Option<clap_id>),
     PluginLatency(usize, u32),
-    PluginLoad(usize, String, String),
+    PluginLoaded(usize, bool),
+    PluginLoad(ModuleIndex, String, String),
     PluginDelete(ModuleIndex),
     PluginSidechain(ModuleIndex, AudioInput),
     PointNew(CursorTrack, usize, clap_id),
@@ -64,9 +99,15 @@ pub enum MainToAudio {
     RecToggle,
     Redo,
     TrackAdd,
+    TrackAutomationParams(usize, Vec<(usize, clap_id)>),
     TrackDelete(usize),
+    TrackFreeze(usize),
+    TrackFreezeSet(usize, Option<Freeze>),
+    TrackInput(usize, bool),
     TrackInsert(usize, Track),
     TrackMove(usize, isize),
+    TrackMidiOutput(usize, Option<String>),
+    TrackMonitor(usize, bool),
     TrackMute(usize, bool),
     TrackSolo(usize, bool),
     TrackPan(usize, f32),
@@ -75,30 +116,322 @@ pub enum MainToAudio {
     TrackRename(usize, String),
     TrackVolume(usize, f32),
     Undo,
+    UndoContext(UndoContext),
+    UndoGroupBegin(String),
+    UndoGroupEnd,
+    UndoHistory,
+    UndoJump(usize),
     #[allow(dead_code)]
     Song,
     SongFile(String),
+    SongNew,
     SongOpen(String),
 }
 
 #[derive(Debug)]
 pub enum AudioToMain {
     Song(Song),
+    UndoHistory(UndoHistorySnapshot),
     Ok,
 }
 
+// モジュールの追加、削除、移動の前の状態
+struct ModulesSnapshot {
+    audio_inputs: Vec<(ModuleIndex, Vec<AudioInput>)>,
+    automation_params: Vec<(usize, clap_id)>,
+    chained: Vec<bool>, // 前のモジュールの出力を入力にしているか
+}
+
+// 書き込みスレッドが追いつくまでオーディオスレッドでためておけるフレーム数
+const AUDIO_TAKE_RING_FRAMES: usize = 1 << 16;
+
+// 録音中のオーディオ入力
+// オーディオスレッドでは確保済みのリングバッファに入れるだけにして
+// 伸びていくテイクへの追記は書き込みスレッドでする
+// リングバッファと書き込みスレッドは録音待ちになったときに audio_takes_arm で作る
+struct AudioTake {
+    track_index: usize,
+    position: Option<usize>, // 録音を始めた play_position。始まるまでは None
+    ring: Arc<Mutex<Vec<VecDeque<f32>>>>,
+    writer: JoinHandle<Vec<Vec<f32>>>,
+    latency: usize, // 入力と出力のレイテンシー(フレーム)
+    recording_p: bool,
+}
+
+impl AudioTake {
+    fn new(track_index: usize, nchannels: usize) -> Self {
+        let ring = Arc::new(Mutex::new(
+            (0..nchannels)
+                .map(|_| VecDeque::with_capacity(AUDIO_TAKE_RING_FRAMES))
+                .collect::<Vec<_>>(),
+        ));
+        let writer = {
+            let ring = ring.clone();
+            thread::spawn(move || audio_take_write(ring))
+        };
+        Self {
+            track_index,
+            position: None,
+            ring,
+            writer,
+            latency: 0,
+            recording_p: true,
+        }
+    }
+
+    fn nchannels(&self) -> usize {
+        self.ring.lock().unwrap().len()
+    }
+
+    fn push(&self, input: &[Vec<f32>], nframes: usize) {
+        let mut ring = self.ring.lock().unwrap();
+        for (ring, input) in ring.iter_mut().zip(input.iter()) {
+            ring.extend(&input[..nframes]);
+        }
+    }
+
+    // リングバッファを手放して書き込みスレッドが終わるのを待つ
+    fn finish(self) -> Vec<Vec<f32>> {
+        drop(self.ring);
+        self.writer.join().unwrap_or_default()
+    }
+}
+
+// AudioTake が ring を手放すまでリングバッファからテイクへ移す
+fn audio_take_write(ring: Arc<Mutex<Vec<VecDeque<f32>>>>) -> Vec<Vec<f32>> {
+    let nchannels = ring.lock().unwrap().len();
+    let mut audio = vec![vec![]; nchannels];
+    let mut chunk = (0..nchannels)
+        .map(|_| Vec::with_capacity(AUDIO_TAKE_RING_FRAMES))
+        .collect::<Vec<_>>();
+    loop {
+        let done_p = Arc::strong_count(&ring) == 1;
+        // ロックしている間は確保済みの chunk に移すだけ
+        for (chunk, ring) in chunk.iter_mut().zip(ring.lock().unwrap().iter_mut()) {
+            chunk.extend(ring.drain(..));
+        }
+        for (audio, chunk) in audio.iter_mut().zip(chunk.iter_mut()) {
+            audio.append(chunk);
+        }
+        if done_p {
+            return audio;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Singer をロックせずにフリーズを書き出すためのトラックとプラグイン
+struct FreezeRender {
+    track_index: usize,
+    track: Track,
+    context: ProcessTrackContext,
+    contexts: Vec<Arc<Mutex<ProcessTrackContext>>>, // サイドチェイン用
+    audio_files: AudioFiles,
+    transport: Transport,
+    nchannels: usize,
+    project: Project,
+}
+
+impl FreezeRender {
+    // 書き出してプロジェクトの freeze に保存する
+    fn run(&mut self) -> Result<Freeze> {
+        let audio = self.render()?;
+        create_dir_all(self.project.freeze_dir())?;
+        let path = self
+            .project
+            .freeze_dir()
+            .join(format!("track_{}.wav", next_id()));
+        audio_file_write(&path, &audio, self.transport.sample_rate)?;
+        Ok(Freeze::new(
+            self.project.relative(&path),
+            audio,
+            self.transport.bpm,
+        ))
+    }
+
+    fn render(&mut self) -> Result<Vec<Vec<f32>>> {
+        let Self {
+            track_index,
+            track,
+            context,
+            contexts,
+            audio_files,
+            transport,
+            nchannels: nchannels_song,
+            ..
+        } = self;
+        let track_index = *track_index;
+        let nchannels_song = *nchannels_song;
+        let sample_rate = transport.sample_rate;
+        let frames_per_delay = sample_rate * 60.0 / (transport.bpm * transport.lpb as f64 * 256.0);
+        let line_last = track
+            .lanes
+            .iter()
+            .filter_map(|lane| lane.items.keys().next_back())
+            .max()
+            .copied()
+            .unwrap_or(0);
+        // プラグインのレイテンシー分ずらす
+        let latency = context
+            .plugins
+            .iter()
+            .map(|plugin_ref| plugin_ref.latency as usize)
+            .sum::<usize>();
+        let nframes_total = (((line_last + 1) * 0x100) as f64 * frames_per_delay
+            + FREEZE_TAIL_SECONDS * sample_rate) as usize
+            + latency;
+
+        let nframes = FREEZE_BLOCK_FRAMES;
+        // 最後のモジュールの出力のチャンネル数で書き出す
+        let nchannels = context
+            .plugins
+            .last()
+            .map(|plugin_ref| plugin_ref.process_data().nchannels_out[0])
+            .filter(|nchannels| *nchannels > 0)
+            .unwrap_or(nchannels_song);
+        let mut audio = vec![Vec::with_capacity(nframes_total); nchannels];
+        let mut position = 0.0;
+        let mut end_p = false;
+        loop {
+            let play_range =
+                position as usize..(position + nframes as f64 / frames_per_delay) as usize;
+            position += nframes as f64 / frames_per_delay;
+            for plugin_ref in context.plugins.iter_mut() {
+                let process_data = plugin_ref.process_data_mut();
+                transport.set(
+                    process_data,
+                    nframes,
+                    !end_p,
+                    false,
+                    play_range.start / 0x100,
+                    0..0,
+                );
+                process_data.prepare();
+            }
+            context.nchannels = nchannels_song;
+            context.nframes = nframes;
+            context.play_p = !end_p;
+            context.bpm = transport.bpm;
+            context.play_position = play_range;
+            context.loop_range = 0..0;
+            context.prepare();
+            if end_p {
+                // 鳴りっぱなしの音を止めておく
+                context.event_list_input.push(Event::NoteAllOff);
+            } else {
+                track.compute_midi(context);
+            }
+            context.audio_clip_p = !end_p
+                && track.compute_audio_clip(context, audio_files, sample_rate, frames_per_delay);
+            for module_index in 0..track.modules.len() {
+                track.process_module(track_index, context, module_index, contexts)?;
+            }
+            if end_p {
+                break;
+            }
+
+            if let Some(plugin_ref) = context.plugins.last() {
+                let data = plugin_ref.process_data();
+                let nchannels = data.nchannels_out[0].max(1);
+                for (channel, audio) in audio.iter_mut().enumerate() {
+                    let channel = channel % nchannels;
+                    let buffer = &data.buffer_out(0)[channel];
+                    // 書き出すときに f32 にする
+                    if (data.constant_mask_out[0] & (1 << channel)) != 0 {
+                        audio.extend(std::iter::repeat_n(buffer[0] as f32, nframes));
+                    } else {
+                        audio.extend(buffer[..nframes].iter().map(|x| *x as f32));
+                    }
+                }
+            }
+            end_p = audio[0].len() >= nframes_total;
+        }
+
+        for audio in audio.iter_mut() {
+            audio.truncate(nframes_total);
+            audio.drain(..latency.min(audio.len()));
+        }
+        Ok(audio)
+    }
+}
+
+// プラグインに渡す再生位置やテンポ
+#[derive(Clone)]
+struct Transport {
+    bpm: f64,
+    lpb: u16,
+    sample_rate: f64,
+    time_signature: (u8, u8),
+    process_64_p: bool,
+    steady_time: i64,
+}
+
+impl Transport {
+    fn set(
+        &self,
+        process_data: &mut ProcessData,
+        nframes: usize,
+        play_p: bool,
+        loop_p: bool,
+        line_play: usize,
+        loop_range: Range<usize>,
+    ) {
+        process_data.nframes = nframes;
+        process_data.play_p = if play_p { 1 } else { 0 };
+        process_data.loop_p = if loop_p { 1 } else { 0 };
+        process_data.process_64_p = if self.process_64_p { 1 } else { 0 };
+        process_data.bpm = self.bpm;
+        process_data.lpb = self.lpb;
+        process_data.sample_rate = self.sample_rate;
+        process_data.steady_time = self.steady_time;
+        process_data.song_pos_beats = line_play as clap_beattime;
+        process_data.song_pos_seconds =
+            (process_data.song_pos_beats as f64 * (60.0 / process_data.bpm)) as clap_sectime;
+        process_data.loop_start_beats = loop_range.start as i64 / 0x100;
+        process_data.loop_end_beats = loop_range.end as i64 / 0x100;
+        process_data.loop_start_seconds =
+            (process_data.loop_start_beats as f64 * (60.0 / process_data.bpm)) as clap_sectime;
+        process_data.loop_end_seconds =
+            (process_data.loop_end_beats as f64 * (60.0 / process_data.bpm)) as clap_sectime;
+        // 1 小節の line 数は拍子から。6/8 なら 4 分音符 3 つ分
+        let (numerator, denominator) = self.time_signature;
+        let lines_per_bar =
+            (self.lpb as i64 * numerator as i64 * 4 / denominator.max(1) as i64).max(1);
+        process_data.bar_number = (process_data.song_pos_beats / lines_per_bar) as i32;
+        process_data.bar_start = process_data.bar_number as i64 * lines_per_bar;
+        process_data.tsig_num = self.time_signature.0 as u16;
+        process_data.tsig_denom = self.time_signature.1 as u16;
+    }
+}
+
 pub struct Singer {
     pub steady_time: i64,
+    bpm_current: f64,
     pub play_position: Range<usize>,
+    play_position_fraction: f64, // ブロックを分けてもずれないように 1 delay 未満の端数を持ち越す
     play_position_start_last: usize,
     all_notef_off_p: bool,
     midi_buffer: Arc<Mutex<Vec<Event>>>,
+    midi_outputs: HashMap<String, MidiOutputDevice>,
+    midi_output_play_p: bool,
+    midi_output_clock_next: f64,
+    midi_clock_output: Option<String>,
+    midi_sync: Arc<Mutex<MidiSync>>,
+    midi_sync_follow_p: bool,
     pub song: Song,
     _song_state_shmem: Shmem,
     song_state_ptr: *mut SongState,
     sender_to_main: Sender<AudioToMain>,
     process_track_contexts: Vec<Arc<Mutex<ProcessTrackContext>>>,
     shmems: Vec<Vec<Shmem>>,
+    audio_files: AudioFiles,
+    pub audio_input_buffer: Arc<Mutex<AudioInputBuffer>>,
+    audio_input: Vec<Vec<f32>>,
+    audio_input_p: bool,
+    audio_takes: Vec<AudioTake>,
+    pub output_latency: usize,
+    // メイントラックにモジュールがないときのミックス先
+    main_process_data: Box<ProcessData>,
     pub gui_context: Option<eframe::egui::Context>,
 
     process_count: usize,
@@ -116,16 +449,31 @@ impl Singer {
         let song = Song::new();
         let mut this = Self {
             steady_time: 0,
+            bpm_current: 120.0,
             play_position: 0..0,
+            play_position_fraction: 0.0,
             play_position_start_last: 0,
             all_notef_off_p: false,
             midi_buffer: Arc::new(Mutex::new(vec![])),
+            midi_outputs: Default::default(),
+            midi_output_play_p: false,
+            midi_output_clock_next: 0.0,
+            midi_clock_output: None,
+            midi_sync: Arc::new(Mutex::new(MidiSync::new())),
+            midi_sync_follow_p: false,
             song,
             _song_state_shmem: song_state_shmem,
             song_state_ptr,
             sender_to_main,
             process_track_contexts: vec![],
             shmems: vec![],
+            audio_files: Default::default(),
+            audio_input_buffer: Default::default(),
+            audio_input: vec![],
+            audio_input_p: false,
+            audio_takes: vec![],
+            output_latency: 0,
+            main_process_data: ProcessData::new_boxed(),
             gui_context: None,
 
             process_count: 0,
@@ -146,7 +494,7 @@ impl Singer {
         }
         self.play_position.start = self.play_position.end;
 
-        let sec_per_delay = 60.0 / (self.song.bpm * self.song.lpb as f64 * 256.0);
+        let sec_per_delay = 60.0 / (self.bpm_current * self.song.lpb as f64 * 256.0);
         {
             let song_state = self.song_state_mut();
             let line = (self.play_position.start / 0x100) as usize;
@@ -160,7 +508,9 @@ impl Singer {
         }
 
         let sec_per_frame = frames_count as f64 / self.song.sample_rate;
-        let delta = (sec_per_frame / sec_per_delay).round() as usize;
+        let delta = sec_per_frame / sec_per_delay + self.play_position_fraction;
+        self.play_position_fraction = delta.fract();
+        let delta = delta as usize;
         self.play_position.end = self.play_position.start + delta;
 
         let song_state = self.song_state_mut();
@@ -208,6 +558,197 @@ impl Singer {
         Ok(MainToAudio::LaneItem(undos))
     }
 
+    pub fn midi_outputs_open(&mut self) {
+        let names = self
+            .song
+            .tracks
+            .iter()
+            .filter_map(|track| track.midi_output.clone())
+            .chain(self.midi_clock_output.clone())
+            .collect::<Vec<_>>();
+        self.midi_outputs.retain(|name, _| names.contains(name));
+        for name in names {
+            if self.midi_outputs.contains_key(&name) {
+                continue;
+            }
+            match MidiOutputDevice::new(&name) {
+                Ok(device) => {
+                    self.midi_outputs.insert(name, device);
+                }
+                Err(e) => log::warn!("MidiOutputDevice::new is failed. {}", e),
+            }
+        }
+    }
+
+    // 外部クロックに追従しているときはそのテンポを返す
+    fn midi_sync_apply(&mut self) -> f64 {
+        if !self.midi_sync_follow_p {
+            // 追従していない間のものを後で再生しないように捨てる
+            self.midi_sync.lock().unwrap().transports.clear();
+            return self.song.bpm;
+        }
+        let (transports, bpm) = {
+            let mut midi_sync = self.midi_sync.lock().unwrap();
+            (std::mem::take(&mut midi_sync.transports), midi_sync.bpm())
+        };
+        for transport in transports {
+            match transport {
+                MidiSyncMessage::Start => {
+                    self.stop();
+                    self.play_line(0);
+                }
+                MidiSyncMessage::Continue => self.play(),
+                MidiSyncMessage::Stop => self.stop(),
+                MidiSyncMessage::SongPosition(position) => {
+                    let position = position as usize * self.song.lpb as usize * 0x100 / 4;
+                    self.play_position_start_last = position;
+                    self.play_position.end = position;
+                    self.play_position_fraction = 0.0;
+                }
+                MidiSyncMessage::Clock(_) => {}
+            }
+        }
+        bpm.unwrap_or(self.song.bpm)
+    }
+
+    fn midi_output_process(&mut self, time_start: Instant, nframes: usize) {
+        if self.midi_outputs.is_empty() {
+            return;
+        }
+
+        let sec_per_delay = 60.0 / (self.bpm_current * self.song.lpb as f64 * 256.0);
+        let sec_per_frames = nframes as f64 / self.song.sample_rate;
+        // いま計算しているバッファが鳴るのは 1 バッファ後
+        let time_base = time_start + Duration::from_secs_f64(sec_per_frames);
+
+        let mut clocks = vec![];
+        let play_p = self.song_state().play_p;
+        if play_p && !self.midi_output_play_p {
+            self.midi_output_clock_next = 0.0;
+            // 途中からの再生は Song Position Pointer で位置を合わせてから Continue
+            let position = self.play_position.start * 4 / (self.song.lpb as usize * 0x100);
+            if position == 0 {
+                clocks.push((0.0, vec![0xFA]));
+            } else {
+                let position = position.min(0x3FFF);
+                clocks.push((
+                    0.0,
+                    vec![0xF2, (position & 0x7F) as u8, (position >> 7) as u8],
+                ));
+                clocks.push((0.0, vec![0xFB]));
+            }
+        } else if !play_p && self.midi_output_play_p {
+            clocks.push((0.0, vec![0xFC]));
+        }
+        self.midi_output_play_p = play_p;
+        if play_p {
+            let sec_per_clock = 60.0 / (self.bpm_current * 24.0);
+            while self.midi_output_clock_next < sec_per_frames {
+                clocks.push((self.midi_output_clock_next, vec![0xF8]));
+                self.midi_output_clock_next += sec_per_clock;
+            }
+            self.midi_output_clock_next -= sec_per_frames;
+        }
+
+        let mut messages_map: HashMap<&String, Vec<(f64, Vec<u8>)>> = HashMap::new();
+        if let Some(name) = &self.midi_clock_output {
+            messages_map.insert(name, clocks.clone());
+        }
+        for (track, context) in self
+            .song
+            .tracks
+            .iter()
+            .zip(self.process_track_contexts.iter())
+        {
+            let Some(name) = &track.midi_output else {
+                continue;
+            };
+            let context = context.lock().unwrap();
+            let messages = messages_map.entry(name).or_insert_with(|| clocks.clone());
+            for event in context.event_list_input.iter() {
+                match event {
+                    Event::NoteOn(key, velocity, delay) => messages.push((
+                        *delay as f64 * sec_per_delay,
+                        vec![0x90, (*key as u8) & 0x7F, (*velocity as u8).min(0x7F)],
+                    )),
+                    Event::NoteOff(key, delay) => messages.push((
+                        *delay as f64 * sec_per_delay,
+                        vec![0x80, (*key as u8) & 0x7F, 0],
+                    )),
+                    // All Notes Off
+                    Event::NoteAllOff => messages.push((0.0, vec![0xB0, 123, 0])),
+                    Event::ParamValue(module_index, param_id, value, delay)
+                        if *module_index == MIDI_CC_MODULE_INDEX =>
+                    {
+                        messages.push((
+                            *delay as f64 * sec_per_delay,
+                            midi_cc_message(*param_id, *value).to_vec(),
+                        ))
+                    }
+                    Event::ParamValue(..) => {}
+                }
+            }
+        }
+
+        for (name, mut messages) in messages_map {
+            let Some(device) = self.midi_outputs.get(name) else {
+                continue;
+            };
+            messages.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for (sec, message) in messages {
+                device.send(time_base + Duration::from_secs_f64(sec), message);
+            }
+        }
+    }
+
+    pub fn plugin_bypass_param_set(&mut self, id: usize, param_id: Option<clap_id>) -> Result<()> {
+        for context in self.process_track_contexts.iter_mut() {
+            if let Some(plugin_ref) = context
+                .lock()
+                .unwrap()
+                .plugins
+                .iter_mut()
+                .find(|plugin_ref| plugin_ref.id == id)
+            {
+                plugin_ref.bypass_param_id = param_id;
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // 手で変えたらオートメーションで変わった値は捨てる
+    fn plugin_automation_reset(&mut self, module_index: ModuleIndex) {
+        let (track_index, module_index) = module_index;
+        let Some(context) = self.process_track_contexts.get(track_index) else {
+            return;
+        };
+        if let Some(plugin_ref) = context.lock().unwrap().plugins.get_mut(module_index) {
+            plugin_ref.bypass_p = None;
+            plugin_ref.mix = None;
+            plugin_ref.gain = None;
+        }
+    }
+
+    // プラグインプロセスで読み込みが終わるまでと、アンロードしてからは処理を頼まない
+    pub fn plugin_loaded_set(&mut self, id: usize, loaded_p: bool) -> Result<()> {
+        for context in self.process_track_contexts.iter_mut() {
+            if let Some(plugin_ref) = context
+                .lock()
+                .unwrap()
+                .plugins
+                .iter_mut()
+                .find(|plugin_ref| plugin_ref.id == id)
+            {
+                plugin_ref.loaded_set(loaded_p)?;
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn plugin_latency_set(&mut self, id: usize, latency: u32) -> Result<()> {
         for context in self.process_track_contexts.iter_mut() {
             if let Some(plugin_ref) = context
@@ -226,6 +767,12 @@ impl Singer {
     }
 
     fn plugin_load(&mut self, track_index: usize) -> Result<usize> {
+        let module_index = self.shmems[track_index].len();
+        self.plugin_insert((track_index, module_index))
+    }
+
+    fn plugin_insert(&mut self, module_index: ModuleIndex) -> Result<usize> {
+        let (track_index, module_index) = module_index;
         let id = next_id();
 
         let shmem_name = process_data_name(id);
@@ -235,20 +782,298 @@ impl Singer {
             .lock()
             .unwrap()
             .plugins
-            .push(PluginRef::new(id, shmem.as_ptr() as *mut ProcessData)?);
-        self.shmems[track_index].push(shmem);
+            .insert(
+                module_index,
+                PluginRef::new(id, shmem.as_ptr() as *mut ProcessData)?,
+            );
+        self.shmems[track_index].insert(module_index, shmem);
 
         Ok(id)
     }
 
+    // id は振り直す。chain_p なら前のモジュールの出力を入力にする
+    // 戻り値は undo
+    fn module_insert(
+        &mut self,
+        module_index: ModuleIndex,
+        mut module: Module,
+        chain_p: bool,
+    ) -> Result<Vec<MainToAudio>> {
+        let (track_index, index) = module_index;
+        let snapshot = self.modules_snapshot(track_index);
+        let f = |i: usize| Some(if i >= index { i + 1 } else { i });
+        self.module_references_remap(track_index, &f);
+
+        module.id = self.plugin_insert(module_index)?;
+        if chain_p && index > 0 {
+            module.audio_inputs = vec![AudioInput {
+                src_module_index: (track_index, index - 1),
+                src_port_index: 0,
+                dst_port_index: 0,
+            }];
+        }
+        self.song.tracks[track_index].modules.insert(index, module);
+
+        self.modules_rechain(track_index, &snapshot, &f);
+        let mut undos = vec![MainToAudio::PluginDelete(module_index)];
+        undos.append(&mut self.modules_snapshot_undos(track_index, snapshot, &f)?);
+        Ok(undos)
+    }
+
+    // 戻り値は undo
+    fn module_delete(&mut self, module_index: ModuleIndex) -> Result<Vec<MainToAudio>> {
+        let (track_index, index) = module_index;
+        let snapshot = self.modules_snapshot(track_index);
+        let module = self.plugin_delete(module_index)?;
+
+        let f = |i: usize| match i.cmp(&index) {
+            std::cmp::Ordering::Less => Some(i),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some(i - 1),
+        };
+        self.module_references_remap(track_index, &f);
+        self.modules_rechain(track_index, &snapshot, &f);
+
+        // 入力はそのまま戻すので chain しない
+        let mut undos = vec![MainToAudio::ModuleInsert(module_index, module)];
+        undos.append(&mut self.modules_snapshot_undos(track_index, snapshot, &f)?);
+        Ok(undos)
+    }
+
+    fn module_move(&mut self, module_index: ModuleIndex, delta: isize) -> Result<Vec<MainToAudio>> {
+        let (track_index, index) = module_index;
+        let index_new = index.saturating_add_signed(delta);
+        if index_new == index || index_new >= self.song.tracks[track_index].modules.len() {
+            return Ok(vec![]);
+        }
+        let snapshot = self.modules_snapshot(track_index);
+
+        let module = self.song.tracks[track_index].modules.remove(index);
+        self.song.tracks[track_index]
+            .modules
+            .insert(index_new, module);
+        {
+            let mut context = self.process_track_contexts[track_index].lock().unwrap();
+            let plugin = context.plugins.remove(index);
+            context.plugins.insert(index_new, plugin);
+        }
+        let shmem = self.shmems[track_index].remove(index);
+        self.shmems[track_index].insert(index_new, shmem);
+
+        let f = |i: usize| {
+            Some(if i == index {
+                index_new
+            } else if index < i && i <= index_new {
+                i - 1
+            } else if index_new <= i && i < index {
+                i + 1
+            } else {
+                i
+            })
+        };
+        self.module_references_remap(track_index, &f);
+        self.modules_rechain(track_index, &snapshot, &f);
+
+        let mut undos = vec![MainToAudio::ModuleMove((track_index, index_new), -delta)];
+        undos.append(&mut self.modules_snapshot_undos(track_index, snapshot, &f)?);
+        Ok(undos)
+    }
+
+    fn modules_snapshot(&self, track_index: usize) -> ModulesSnapshot {
+        let mut audio_inputs = vec![];
+        for (index, track) in self.song.tracks.iter().enumerate() {
+            for (module_index, module) in track.modules.iter().enumerate() {
+                if index == track_index
+                    || module
+                        .audio_inputs
+                        .iter()
+                        .any(|x| x.src_module_index.0 == track_index)
+                {
+                    audio_inputs.push(((index, module_index), module.audio_inputs.clone()));
+                }
+            }
+        }
+        let chained = self.song.tracks[track_index]
+            .modules
+            .iter()
+            .enumerate()
+            .map(|(index, module)| {
+                index > 0
+                    && module.audio_inputs.iter().any(|x| {
+                        x.src_module_index == (track_index, index - 1) && x.dst_port_index == 0
+                    })
+            })
+            .collect();
+        ModulesSnapshot {
+            audio_inputs,
+            automation_params: self.song.tracks[track_index].automation_params.clone(),
+            chained,
+        }
+    }
+
+    // サイドチェインの module_index を付け替える
+    // f は変更前の module_index から変更後へ。None は削除されたモジュール
+    fn module_references_remap(&mut self, track_index: usize, f: &impl Fn(usize) -> Option<usize>) {
+        for track in self.song.tracks.iter_mut() {
+            for module in track.modules.iter_mut() {
+                module.audio_inputs.retain_mut(|audio_input| {
+                    if audio_input.src_module_index.0 != track_index {
+                        return true;
+                    }
+                    match f(audio_input.src_module_index.1) {
+                        Some(index) => {
+                            audio_input.src_module_index.1 = index;
+                            true
+                        }
+                        None => false,
+                    }
+                });
+            }
+        }
+    }
+
+    // 直列につながっていたモジュールは並び順どおりにつなぎ直す
+    fn modules_rechain(
+        &mut self,
+        track_index: usize,
+        snapshot: &ModulesSnapshot,
+        f: &impl Fn(usize) -> Option<usize>,
+    ) {
+        for (index, chained_p) in snapshot.chained.iter().enumerate() {
+            let Some(index_new) = f(index).filter(|_| *chained_p) else {
+                continue;
+            };
+            let module = &mut self.song.tracks[track_index].modules[index_new];
+            module
+                .audio_inputs
+                .retain(|x| !(x.src_module_index.0 == track_index && x.dst_port_index == 0));
+            if index_new > 0 {
+                module.audio_inputs.push(AudioInput {
+                    src_module_index: (track_index, index_new - 1),
+                    src_port_index: 0,
+                    dst_port_index: 0,
+                });
+            }
+        }
+    }
+
+    // automation_params の module_index を付け替え、削除されたモジュールのパラメータと Point を消す
+    // 変更前に戻す undo を返す
+    fn modules_snapshot_undos(
+        &mut self,
+        track_index: usize,
+        snapshot: ModulesSnapshot,
+        f: &impl Fn(usize) -> Option<usize>,
+    ) -> Result<Vec<MainToAudio>> {
+        let track = &mut self.song.tracks[track_index];
+        let mut automation_params_index_map = vec![];
+        let mut automation_params = vec![];
+        for (module_index, param_id) in snapshot.automation_params.iter() {
+            if *module_index == MIDI_CC_MODULE_INDEX {
+                automation_params_index_map.push(Some(automation_params.len()));
+                automation_params.push((*module_index, *param_id));
+            } else if let Some(module_index) = f(*module_index) {
+                automation_params_index_map.push(Some(automation_params.len()));
+                automation_params.push((module_index, *param_id));
+            } else {
+                automation_params_index_map.push(None);
+            }
+        }
+        track.automation_params = automation_params;
+
+        let mut lane_items = vec![];
+        for (lane_index, lane) in track.lanes.iter().enumerate() {
+            for (line, item) in lane.items.iter() {
+                let LaneItem::Point(point) = item else {
+                    continue;
+                };
+                let index_new = automation_params_index_map
+                    .get(point.automation_params_index)
+                    .cloned()
+                    .flatten();
+                if index_new == Some(point.automation_params_index) {
+                    continue;
+                }
+                let cursor = CursorTrack {
+                    track: track_index,
+                    lane: lane_index,
+                    line: *line,
+                };
+                lane_items.push((
+                    cursor,
+                    index_new.map(|automation_params_index| {
+                        LaneItem::Point(Point {
+                            automation_params_index,
+                            ..point.clone()
+                        })
+                    }),
+                ));
+            }
+        }
+
+        let mut undos = snapshot
+            .audio_inputs
+            .into_iter()
+            .map(|(module_index, audio_inputs)| {
+                MainToAudio::ModuleAudioInputs(module_index, audio_inputs)
+            })
+            .collect::<Vec<_>>();
+        undos.push(MainToAudio::TrackAutomationParams(
+            track_index,
+            snapshot.automation_params,
+        ));
+        if !lane_items.is_empty() {
+            undos.push(self.lane_items_set(lane_items)?);
+        }
+        Ok(undos)
+    }
+
+    // デバイスのバッファが MAX_FRAMES より大きいときは分けて処理する
+    // 再生位置や steady_time はブロックごとに進む
     pub fn process(&mut self, output: &mut [f32], nchannels: usize) -> Result<()> {
         let this_start = Instant::now();
+        let nframes = output.len() / nchannels;
+
+        for (block_index, output) in output.chunks_mut(MAX_FRAMES * nchannels).enumerate() {
+            let block_start = this_start
+                + Duration::from_secs_f64(
+                    (block_index * MAX_FRAMES) as f64 / self.song.sample_rate,
+                );
+            self.process_block(output, nchannels, block_start)?;
+        }
+
+        self.process_count += 1;
+        let this_elapsed = this_start.elapsed();
+        self.process_elasped += this_elapsed.as_secs_f64();
+        let last_elasped = self.process_elasped_last.elapsed();
+        if last_elasped >= Duration::from_secs(1) {
+            let song_state = self.song_state_mut();
+            song_state.process_elasped_avg = self.process_elasped / self.process_count as f64;
+            song_state.cpu_usage = self.process_elasped / last_elasped.as_secs_f64();
+            song_state.nframes = nframes;
+            self.process_count = 0;
+            self.process_elasped = 0.0;
+            self.process_elasped_last = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    fn process_block(
+        &mut self,
+        output: &mut [f32],
+        nchannels: usize,
+        this_start: Instant,
+    ) -> Result<()> {
         let mut idle_p = self.song_state().tracks[0].peaks[0] <= DB_MIN
             && self.song_state().tracks[0].peaks[1] <= DB_MIN;
 
         //log::debug!("AudioProcess process steady_time {}", self.steady_time);
         let nframes = output.len() / nchannels;
+        // トラックの中はデバイスではなく曲のチャンネル数
+        let nchannels_song = self.song.nchannels.clamp(1, MAX_CHANNELS);
 
+        self.bpm_current = self.midi_sync_apply();
         self.compute_play_position(nframes);
 
         {
@@ -262,35 +1087,21 @@ impl Singer {
                 for module_index in 0..context.plugins.len() {
                     let process_data = context.plugins[module_index].process_data_mut();
                     let song_state = self.song_state();
-                    process_data.nframes = nframes;
-                    process_data.play_p = if song_state.play_p { 1 } else { 0 };
-                    process_data.loop_p = if song_state.loop_p { 1 } else { 0 };
-                    process_data.bpm = self.song.bpm;
-                    process_data.lpb = self.song.lpb;
-                    process_data.sample_rate = self.song.sample_rate;
-                    process_data.steady_time = self.steady_time;
-                    process_data.song_pos_beats = song_state.line_play as clap_beattime;
-                    process_data.song_pos_seconds = (process_data.song_pos_beats as f64
-                        * (60.0 / process_data.bpm))
-                        as clap_sectime;
-                    process_data.loop_start_beats = song_state.loop_start as i64 / 0x100;
-                    process_data.loop_end_beats = song_state.loop_end as i64 / 0x100;
-                    process_data.loop_start_seconds = (process_data.loop_start_beats as f64
-                        * (60.0 / process_data.bpm))
-                        as clap_sectime;
-                    process_data.loop_end_seconds = (process_data.loop_end_beats as f64
-                        * (60.0 / process_data.bpm))
-                        as clap_sectime;
-                    process_data.bar_number =
-                        (process_data.song_pos_beats / self.song.lpb as i64) as i32;
-                    process_data.bar_start = process_data.bar_number as i64 * self.song.lpb as i64;
+                    self.transport().set(
+                        process_data,
+                        nframes,
+                        song_state.play_p,
+                        song_state.loop_p,
+                        song_state.line_play,
+                        song_state.loop_start..song_state.loop_end,
+                    );
                     process_data.prepare();
                 }
 
-                context.nchannels = nchannels;
+                context.nchannels = nchannels_song;
                 context.nframes = nframes;
                 context.play_p = self.song_state().play_p;
-                context.bpm = self.song.bpm;
+                context.bpm = self.bpm_current;
                 context.steady_time = self.steady_time;
                 context.play_position = self.play_position.clone();
                 let song_state = self.song_state();
@@ -317,19 +1128,63 @@ impl Singer {
         }
 
         self.all_notef_off_p = false;
+        self.audio_input_read(nframes);
 
         // tracks process
         for track_index in 0..self.song.tracks.len() {
             let mut context = self.process_track_contexts[track_index].lock().unwrap();
             idle_p &= !self.song.tracks[track_index].compute_midi(&mut context);
         }
+        let frames_per_delay = self.frames_per_delay(self.bpm_current);
+        for track_index in 1..self.song.tracks.len() {
+            let track = &self.song.tracks[track_index];
+            let mut context = self.process_track_contexts[track_index].lock().unwrap();
+            context.audio_clip_p = track.freeze.is_none()
+                && track.compute_audio_clip(
+                    &mut context,
+                    &self.audio_files,
+                    self.song.sample_rate,
+                    frames_per_delay,
+                );
+            if self.audio_input_p
+                && self.song_state().tracks[track_index].monitor_p
+                && track.freeze.is_none()
+            {
+                if !context.audio_clip_p {
+                    for buffer in context.buffer.buffer.iter_mut() {
+                        buffer[..nframes].fill(0.0);
+                    }
+                    context.buffer.constant_mask = 0;
+                    context.audio_clip_p = true;
+                }
+                for (src_ch, dst_ch, gain) in channel_map(self.audio_input.len(), nchannels_song) {
+                    let input = &self.audio_input[src_ch];
+                    let buffer = &mut context.buffer.buffer[dst_ch];
+                    for frame in 0..nframes {
+                        buffer[frame] += input[frame] * gain;
+                    }
+                }
+            }
+            idle_p &= !context.audio_clip_p;
+        }
+        self.audio_record(nframes);
+
+        self.midi_output_process(this_start, nframes);
+
+        let freeze_p = self.song.tracks.iter().any(|track| track.freeze.is_some());
+        idle_p &= !(freeze_p && self.song_state().play_p);
 
         if !idle_p {
+            if freeze_p {
+                self.freeze_process(nframes);
+            }
+
             // TODO topological_levels は必要な時だけ行う
             let levels = topological_levels(&self.song)?;
             for level in levels {
                 level
                     .into_par_iter()
+                    .filter(|(track_index, _)| self.song.tracks[*track_index].freeze.is_none())
                     .try_for_each(|(track_index, module_index)| {
                         let track = &self.song.tracks[track_index];
                         let mut context = self.process_track_contexts[track_index].lock().unwrap();
@@ -358,71 +1213,50 @@ impl Singer {
                         })
                 })
                 .zip(self.song.tracks.iter().map(|track| {
-                    if (track.pan - 0.5).abs() < 0.001 {
-                        (
-                            track.mute,
-                            track.solo,
-                            track.volume,
-                            track.volume,
-                            track.volume,
-                        )
-                    } else {
-                        let normalized_pan = (track.pan - 0.5) * 2.0;
-                        let pan_angle = (normalized_pan + 1.0) * PI / 4.0;
-                        (
-                            track.mute,
-                            track.solo,
-                            track.volume * pan_angle.cos(),
-                            track.volume * pan_angle.sin(),
-                            track.volume,
-                        )
-                    }
+                    let [gain_ch0, gain_ch1, gain_ch_rest] = pan_gains(track.volume, track.pan);
+                    (track.mute, track.solo, gain_ch0, gain_ch1, gain_ch_rest)
                 }))
                 .collect::<Vec<_>>();
 
-            let mut buffers = data
+            // モジュールのないトラックのオーディオクリップ
+            let clip_buffers = self
+                .process_track_contexts
                 .iter()
-                .enumerate()
-                .map(|(track_index, x)| {
-                    x.0.map(|x| {
-                        let process_data = unsafe { &mut *(x.0) };
-                        if track_index == 0 {
-                            &mut process_data.buffer_in
-                        } else {
-                            &mut process_data.buffer_out
-                        }
-                    })
+                .map(|x| {
+                    let context = x.lock().unwrap();
+                    (context.plugins.is_empty() && context.audio_clip_p)
+                        .then(|| &context.buffer as *const AudioBuffer)
                 })
                 .collect::<Vec<_>>();
 
             let main_gains = [data[0].1 .2, data[0].1 .3, data[0].1 .4];
 
             // tracks pan pan volume
-            for (
-                (buffer_constant_mask, (_mute, _solo, gain_ch0, gain_ch1, gain_ch_restg)),
-                buffer,
-            ) in data[1..].iter_mut().zip(buffers[1..].iter_mut())
+            for (buffer_constant_mask, (_mute, _solo, gain_ch0, gain_ch1, gain_ch_restg)) in
+                data[1..].iter_mut()
             {
-                if let (Some((process_data, constant_mask, _nports_in, nports_out)), Some(buffer)) =
-                    (buffer_constant_mask, buffer)
+                if let Some((process_data, constant_mask, _nports_in, nports_out)) =
+                    buffer_constant_mask
                 {
-                    let process_data = unsafe { &**process_data };
-                    for port in 0..*nports_out {
-                        for channel in 0..process_data.nchannels_out[port] {
-                            let constp = (constant_mask[port] & (1 << channel)) != 0;
+                    let process_data = unsafe { &mut **process_data };
+                    for (port, constant_mask) in constant_mask.iter().enumerate().take(*nports_out)
+                    {
+                        let buffer = process_data.buffer_out_mut(port);
+                        for (channel, buffer) in buffer.iter_mut().enumerate() {
+                            let constp = (constant_mask & (1 << channel)) != 0;
                             let gain = if channel == 0 {
                                 *gain_ch0
                             } else if channel == 1 {
                                 *gain_ch1
                             } else {
                                 *gain_ch_restg
-                            };
+                            } as f64;
 
                             if constp {
-                                buffer[port][channel][0] *= gain;
+                                buffer[0] *= gain;
                             } else {
-                                for frame in 0..nframes {
-                                    buffer[port][channel][frame] *= gain;
+                                for x in buffer[..nframes].iter_mut() {
+                                    *x *= gain;
                                 }
                             }
                         }
@@ -431,12 +1265,15 @@ impl Singer {
             }
 
             // tracks mute solo -> main track
-            let mut dummy = ProcessData::new();
-            dummy.prepare();
             let dummy_p = self.song.tracks[0].modules.is_empty();
 
             let main_process_data = if dummy_p {
-                &mut dummy
+                let ptr: *mut ProcessData = &mut *self.main_process_data;
+                let process_data = unsafe { &mut *(ptr) };
+                process_data.prepare();
+                process_data.nframes = nframes;
+                process_data.nchannels_out[0] = nchannels_song;
+                process_data
             } else {
                 let ptr = self.process_track_contexts[0]
                     .lock()
@@ -448,40 +1285,92 @@ impl Singer {
                 let process_data = unsafe { &mut *(ptr) };
                 process_data
             };
+            // メイントラックに入れるチャンネル数
+            let nchannels_main = if dummy_p {
+                nchannels_song
+            } else {
+                main_process_data.nchannels_in[0]
+            };
 
             let solo_any = self.song.tracks.iter().any(|t| t.solo);
+            let aux_sources = self.aux_sources(solo_any);
+            // トラックからメイントラックへのチャンネルの対応
+            let channel_maps = data[1..]
+                .iter()
+                .zip(clip_buffers[1..].iter())
+                .map(|((buffer_constant_mask, _), clip_buffer)| {
+                    if let Some((process_data, _, _, _)) = buffer_constant_mask {
+                        let process_data = unsafe { &**process_data };
+                        channel_map(process_data.nchannels_out[0], nchannels_main)
+                    } else if clip_buffer.is_some() {
+                        channel_map(nchannels_song, nchannels_main)
+                    } else {
+                        vec![]
+                    }
+                })
+                .collect::<Vec<_>>();
+            let aux_channel_maps = aux_sources
+                .iter()
+                .map(|(process_data, port, _)| {
+                    let process_data = unsafe { &**process_data };
+                    channel_map(process_data.nchannels_out[*port], nchannels_main)
+                })
+                .collect::<Vec<_>>();
             for frame in 0..nframes {
-                for channel in 0..nchannels {
-                    let value = data[1..]
+                for channel in 0..nchannels_main {
+                    let tracks = data[1..]
                         .iter()
-                        .zip(buffers[1..].iter())
-                        .map(|((buffer_constant_mask, (mute, solo, _, _, _)), buffer)| {
-                            if let (Some((process_data, constant_mask, _, _)), Some(buffer)) =
-                                (&buffer_constant_mask, buffer)
+                        .zip(clip_buffers[1..].iter())
+                        .zip(channel_maps.iter())
+                        .map(|(((buffer_constant_mask, gains), clip_buffer), map)| {
+                            let (mute, solo, gain_ch0, gain_ch1, gain_ch_rest) = gains;
+                            if *mute || (solo_any && !*solo) {
+                                return 0.0;
+                            }
+                            if let Some((process_data, constant_mask, _, _)) = &buffer_constant_mask
                             {
-                                let process_data = unsafe { &**process_data };
-                                let channel = channel % process_data.nchannels_out[0];
-                                if *mute || (solo_any && !*solo) {
-                                    0.0
-                                } else {
-                                    let constp = (constant_mask[0] & (1 << channel)) != 0;
-                                    if constp {
-                                        buffer[0][channel][0]
+                                let buffer = unsafe { &**process_data }.buffer_out(0);
+                                channel_map_sum(map, channel, |src_channel| {
+                                    if (constant_mask[0] & (1 << src_channel)) != 0 {
+                                        buffer[src_channel][0]
                                     } else {
-                                        buffer[0][channel][frame]
+                                        buffer[src_channel][frame]
                                     }
-                                }
+                                })
+                            } else if let Some(clip_buffer) = clip_buffer {
+                                let clip_buffer = unsafe { &**clip_buffer };
+                                channel_map_sum(map, channel, |src_channel| {
+                                    let gain = match src_channel {
+                                        0 => *gain_ch0,
+                                        1 => *gain_ch1,
+                                        _ => *gain_ch_rest,
+                                    };
+                                    clip_buffer.buffer[src_channel][frame] as f64 * gain as f64
+                                })
                             } else {
                                 0.0
                             }
-                        })
-                        .sum();
+                        });
+                    let auxes = aux_sources.iter().zip(aux_channel_maps.iter()).map(
+                        |((process_data, port, gains), map)| {
+                            let process_data = unsafe { &**process_data };
+                            channel_map_sum(map, channel, |src_channel| {
+                                let constp = (process_data.constant_mask_out[*port]
+                                    & (1 << src_channel))
+                                    != 0;
+                                let buffer = &process_data.buffer_out(*port)[src_channel];
+                                let x = if constp { buffer[0] } else { buffer[frame] };
+                                x * gains[src_channel.min(2)] as f64
+                            })
+                        },
+                    );
+                    let value = tracks.chain(auxes).sum::<f64>();
 
                     if dummy_p {
-                        main_process_data.buffer_out[0][channel][frame] = value;
+                        main_process_data.buffer_out_mut(0)[channel][frame] = value;
                         main_process_data.constant_mask_out[0] = 0;
                     } else {
-                        main_process_data.buffer_in[0][channel][frame] = value;
+                        main_process_data.buffer_in_mut(0)[channel][frame] = value;
                         main_process_data.constant_mask_in[0] = 0;
                     }
                 }
@@ -501,29 +1390,38 @@ impl Singer {
 
             // main track pan volume -> audio device
             let main_track = &self.song.tracks[0];
+            let nchannels_main_out = main_process_data.nchannels_out[0];
+            for channel in 0..nchannels_main_out {
+                let gain = main_gains[channel.min(2)] as f64;
+                let constp = (main_process_data.constant_mask_out[0] & (1 << channel)) != 0;
+                let buffer = &mut main_process_data.buffer_out_mut(0)[channel];
+                if constp {
+                    buffer[0] *= gain;
+                } else {
+                    for x in buffer[..nframes].iter_mut() {
+                        *x *= gain;
+                    }
+                }
+            }
+            // 曲とデバイスのチャンネル数が違うときはダウンミックスなど
+            let output_map = channel_map(nchannels_main_out, nchannels);
             for frame in 0..nframes {
                 for channel in 0..nchannels {
                     // いまは solo はいらない
-                    let value = if main_track.mute {
+                    output[nchannels * frame + channel] = if main_track.mute {
                         0.0
                     } else {
-                        let gain = if channel == 0 {
-                            main_gains[0]
-                        } else if channel == 1 {
-                            main_gains[1]
-                        } else {
-                            main_gains[2]
-                        };
-                        let constp = (main_process_data.constant_mask_out[0] & (1 << channel)) != 0;
-                        if constp {
-                            main_process_data.buffer_out[0][channel][0] *= gain;
-                            main_process_data.buffer_out[0][channel][0]
-                        } else {
-                            main_process_data.buffer_out[0][channel][frame] *= gain;
-                            main_process_data.buffer_out[0][channel][frame]
-                        }
+                        channel_map_sum(&output_map, channel, |src_channel| {
+                            let constp =
+                                (main_process_data.constant_mask_out[0] & (1 << src_channel)) != 0;
+                            let buffer = &main_process_data.buffer_out(0)[src_channel];
+                            if constp {
+                                buffer[0]
+                            } else {
+                                buffer[frame]
+                            }
+                        }) as f32
                     };
-                    output[nchannels * frame + channel] = value;
                 }
             }
 
@@ -531,23 +1429,398 @@ impl Singer {
             self.compute_song_state(main_process_data);
         }
 
-        self.steady_time += nframes as i64;
+        self.steady_time += nframes as i64;
+
+        Ok(())
+    }
+
+    fn transport(&self) -> Transport {
+        Transport {
+            bpm: self.bpm_current,
+            lpb: self.song.lpb,
+            sample_rate: self.song.sample_rate,
+            time_signature: self.song.time_signature,
+            process_64_p: self.song.process_64_p,
+            steady_time: self.steady_time,
+        }
+    }
+
+    // ミキサーに流すモジュールの出力ポート (ProcessData, port, gains)
+    fn aux_sources(&self, solo_any: bool) -> Vec<(*const ProcessData, usize, [f32; 3])> {
+        let mut sources = vec![];
+        for (track_index, track) in self.song.tracks.iter().enumerate().skip(1) {
+            if track.freeze.is_some() || track.mute || (solo_any && !track.solo) {
+                continue;
+            }
+            let context = self.process_track_contexts[track_index].lock().unwrap();
+            for (module, plugin_ref) in track.modules.iter().zip(context.plugins.iter()) {
+                for aux_output in module.aux_outputs.iter() {
+                    if aux_output.mute
+                        || aux_output.port_index >= plugin_ref.process_data().nports_out
+                    {
+                        continue;
+                    }
+                    sources.push((
+                        plugin_ref.ptr as *const ProcessData,
+                        aux_output.port_index,
+                        aux_gains(aux_output),
+                    ));
+                }
+            }
+        }
+        sources
+    }
+
+    // play_position の 1 delay あたりのフレーム数
+    fn frames_per_delay(&self, bpm: f64) -> f64 {
+        self.song.sample_rate * 60.0 / (bpm * self.song.lpb as f64 * 256.0)
+    }
+
+    // フリーズしたトラックは最後のモジュールの出力に書き出した音を入れる
+    // そこから先の音量、パン、ミュート、ソロ、サイドチェインは普通のトラックと同じ
+    fn freeze_process(&self, nframes: usize) {
+        let song_state = self.song_state();
+        if !song_state.play_p {
+            return;
+        }
+        let frames_per_delay = self.frames_per_delay(self.bpm_current);
+        // ループで戻るところ
+        let (nframes_before_loop, loop_start) =
+            if self.play_position.start <= self.play_position.end {
+                (nframes, 0)
+            } else {
+                (
+                    ((song_state.loop_end.saturating_sub(self.play_position.start)) as f64
+                        * frames_per_delay) as usize,
+                    song_state.loop_start,
+                )
+            };
+
+        for (track, context) in self
+            .song
+            .tracks
+            .iter()
+            .zip(self.process_track_contexts.iter())
+        {
+            let Some(freeze) = &track.freeze else {
+                continue;
+            };
+            if !freeze.loaded_p() {
+                continue;
+            }
+            let mut context = context.lock().unwrap();
+            let Some(plugin_ref) = context.plugins.last_mut() else {
+                continue;
+            };
+            // 書き出したときとテンポが違っても曲の位置に合わせて読む
+            let freeze_frames_per_delay = if freeze.bpm > 0.0 {
+                self.frames_per_delay(freeze.bpm)
+            } else {
+                frames_per_delay
+            };
+            let speed = freeze_frames_per_delay / frames_per_delay;
+            let frame_start = self.play_position.start as f64 * freeze_frames_per_delay;
+            let frame_loop_start = loop_start as f64 * freeze_frames_per_delay;
+            let data = plugin_ref.process_data_mut();
+            for channel in 0..data.nchannels_out[0] {
+                let audio = &freeze.audio[channel % freeze.audio.len()];
+                for frame in 0..nframes {
+                    let position = if frame < nframes_before_loop {
+                        frame_start + frame as f64 * speed
+                    } else {
+                        frame_loop_start + (frame - nframes_before_loop) as f64 * speed
+                    };
+                    let i = position as usize;
+                    let x0 = audio.get(i).copied().unwrap_or(0.0) as f64;
+                    let x1 = audio.get(i + 1).copied().unwrap_or(0.0) as f64;
+                    data.buffer_out_mut(0)[channel][frame] = x0 + (x1 - x0) * position.fract();
+                }
+                data.constant_mask_out[0] &= !(1 << channel);
+            }
+        }
+    }
+
+    // 入力デバイスの音を 1 ブロック分取り出す
+    fn audio_input_read(&mut self, nframes: usize) {
+        let audio_input_buffer = self.audio_input_buffer.clone();
+        let mut audio_input_buffer = audio_input_buffer.lock().unwrap();
+        let nchannels = audio_input_buffer.nchannels;
+        self.audio_input_p = nchannels > 0;
+        if !self.audio_input_p {
+            return;
+        }
+        if self.audio_input.len() != nchannels || self.audio_input[0].len() < nframes {
+            self.audio_input = vec![vec![0.0; nframes]; nchannels];
+        }
+        // 足りない分は無音
+        let navailable = (audio_input_buffer.samples.len() / nchannels).min(nframes);
+        for frame in 0..nframes {
+            for channel in 0..nchannels {
+                self.audio_input[channel][frame] = if frame < navailable {
+                    audio_input_buffer.samples.pop_front().unwrap_or(0.0)
+                } else {
+                    0.0
+                };
+            }
+        }
+    }
+
+    // 録音中で IN のトラックはオーディオ入力をテイクにためる
+    // ループで戻ったらそのテイクは終わり
+    fn audio_record(&mut self, nframes: usize) {
+        let song_state = self.song_state();
+        let recording_p = self.audio_input_p && song_state.play_p && song_state.rec_p;
+        let nframes_before_loop = if self.play_position.start <= self.play_position.end {
+            nframes
+        } else {
+            ((song_state.loop_end.saturating_sub(self.play_position.start)) as f64
+                * self.frames_per_delay(self.bpm_current)) as usize
+        }
+        .min(nframes);
+        // 入力バッファに残っている分だけ入力は遅れて届いている
+        let latency = {
+            let audio_input_buffer = self.audio_input_buffer.lock().unwrap();
+            audio_input_buffer.latency
+                + audio_input_buffer.samples.len() / audio_input_buffer.nchannels.max(1)
+                + self.output_latency
+        };
+
+        for track_index in 1..self.song.tracks.len() {
+            let armed_p = self.track_rec_armed_p(track_index);
+            // テイクは audio_takes_arm で作ってあるものだけ使う
+            let Some(take) = self
+                .audio_takes
+                .iter_mut()
+                .find(|take| take.track_index == track_index)
+            else {
+                continue;
+            };
+            if !(recording_p && armed_p) {
+                if take.position.is_some() {
+                    take.recording_p = false;
+                }
+                continue;
+            }
+            if !take.recording_p {
+                continue;
+            }
+            if take.position.is_none() {
+                take.position = Some(self.play_position.start);
+                take.latency = latency;
+            }
+            take.push(&self.audio_input, nframes_before_loop);
+            if nframes_before_loop < nframes {
+                take.recording_p = false;
+            }
+        }
+    }
+
+    // 録音待ちのトラックのテイクを先に作っておく
+    // オーディオスレッドでリングバッファの確保や書き込みスレッドの起動をしないように
+    fn audio_takes_arm(&mut self) {
+        let nchannels = self.audio_input_buffer.lock().unwrap().nchannels;
+        let rec_p = nchannels > 0 && self.song_state().rec_p;
+        // 始まる前に外されたテイクと入力のチャンネル数が変わったテイクは作り直す
+        // 捨てたテイクの書き込みスレッドは ring がなくなれば終わるので待たない
+        let takes = std::mem::take(&mut self.audio_takes);
+        self.audio_takes = takes
+            .into_iter()
+            .filter(|take| {
+                take.position.is_some()
+                    || (rec_p
+                        && self.track_rec_armed_p(take.track_index)
+                        && take.nchannels() == nchannels)
+            })
+            .collect();
+        if !rec_p {
+            return;
+        }
+        for track_index in 1..self.song.tracks.len() {
+            if self.track_rec_armed_p(track_index)
+                && !self
+                    .audio_takes
+                    .iter()
+                    .any(|take| take.track_index == track_index)
+            {
+                self.audio_takes
+                    .push(AudioTake::new(track_index, nchannels));
+            }
+        }
+    }
+
+    fn track_rec_armed_p(&self, track_index: usize) -> bool {
+        let track_state = &self.song_state().tracks[track_index];
+        track_state.rec_p && track_state.input_p
+    }
+
+    // 録ったテイクをファイルに書いてオーディオクリップとして置く
+    // track_index が None なら全トラック
+    fn audio_takes_finish(
+        &mut self,
+        track_index: Option<usize>,
+    ) -> Vec<(CursorTrack, Option<LaneItem>)> {
+        let mut items = vec![];
+        let (takes, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.audio_takes)
+            .into_iter()
+            .partition(|take| track_index.is_none_or(|x| x == take.track_index));
+        self.audio_takes = rest;
+        if takes.is_empty() {
+            return items;
+        }
+        // 保存前の曲はユーザー設定のディレクトリへ
+        let song_file = self.song_state().song_file_get();
+        let (dir, project) = match &song_file {
+            Some(song_file) => {
+                let project = Project::new(Path::new(song_file));
+                (project.recordings_dir(), Some(project))
+            }
+            None => (dir_user_setting().join("recordings"), None),
+        };
+        if let Err(e) = create_dir_all(&dir) {
+            log::error!("create {} failed: {}", dir.display(), e);
+            return items;
+        }
+        for take in takes {
+            let (track_index, position, latency) = (take.track_index, take.position, take.latency);
+            let audio = take.finish();
+            let Some(position) = position else {
+                continue;
+            };
+            let length = audio.first().map_or(0, |x| x.len());
+            if length <= latency || track_index >= self.song.tracks.len() {
+                continue;
+            }
+            let path = dir.join(format!("take_{}.wav", next_id()));
+            if let Err(e) = audio_file_write(&path, &audio, self.song.sample_rate) {
+                log::error!("write {} failed: {}", path.display(), e);
+                continue;
+            }
+            let file = match &project {
+                Some(project) => project.relative(&path),
+                None => path.to_string_lossy().to_string(),
+            };
+            // レイテンシー分、頭を飛ばす
+            let mut clip = AudioClip::new(file.clone(), self.song.sample_rate, length - latency);
+            clip.offset = latency;
+            clip.delay = (position % 0x100) as u8;
+            let line = position / 0x100;
+            // 空いているレーンに置く
+            let track = &self.song.tracks[track_index];
+            let lane = track
+                .lanes
+                .iter()
+                .position(|lane| !lane.items.contains_key(&line))
+                .unwrap_or(0);
+            self.audio_files.insert(file, Arc::new(audio));
+            items.push((
+                CursorTrack {
+                    track: track_index,
+                    lane,
+                    line,
+                },
+                Some(LaneItem::AudioClip(clip)),
+            ));
+        }
+        items
+    }
+
+    // 読み込んでいないフリーズとオーディオクリップの音を読む
+    fn audio_files_load(&mut self) {
+        // 保存前の曲のオーディオクリップは絶対パス
+        let song_file = self.song_state().song_file_get().unwrap_or_default();
+        let project = Project::new(Path::new(&song_file));
+        for track in self.song.tracks.iter() {
+            for lane in track.lanes.iter() {
+                for item in lane.items.values() {
+                    let LaneItem::AudioClip(clip) = item else {
+                        continue;
+                    };
+                    if self.audio_files.contains_key(&clip.file) {
+                        continue;
+                    }
+                    match audio_file_read(&project.absolute(&clip.file)) {
+                        Ok((audio, _sample_rate)) => {
+                            self.audio_files.insert(clip.file.clone(), Arc::new(audio));
+                        }
+                        Err(e) => {
+                            log::warn!("audio clip load failed {}: {}", clip.file, e);
+                            // 何度も読みにいかないように
+                            self.audio_files
+                                .insert(clip.file.clone(), Default::default());
+                        }
+                    }
+                }
+            }
+        }
+
+        if song_file.is_empty() {
+            return;
+        }
+        for track in self.song.tracks.iter_mut() {
+            let Some(freeze) = &mut track.freeze else {
+                continue;
+            };
+            if freeze.loaded_p() {
+                continue;
+            }
+            match audio_file_read(&project.absolute(&freeze.file)) {
+                Ok((audio, _sample_rate)) => freeze.audio = Arc::new(audio),
+                Err(e) => log::warn!("freeze audio load failed {}: {}", freeze.file, e),
+            }
+        }
+    }
 
-        self.process_count += 1;
-        let this_elapsed = this_start.elapsed();
-        self.process_elasped += this_elapsed.as_secs_f64();
-        let last_elasped = self.process_elasped_last.elapsed();
-        if last_elasped >= Duration::from_secs(1) {
-            let song_state = self.song_state_mut();
-            song_state.process_elasped_avg = self.process_elasped / self.process_count as f64;
-            song_state.cpu_usage = self.process_elasped / last_elasped.as_secs_f64();
-            song_state.nframes = nframes;
-            self.process_count = 0;
-            self.process_elasped = 0.0;
-            self.process_elasped_last = Instant::now();
+    // フリーズの書き出しを始める。書き出し中はオーディオスレッドがこのトラックのプラグインに触らないように、
+    // プラグインを取り出して書き出し中のフリーズにしておく
+    fn track_freeze_begin(&mut self, track_index: usize) -> Result<FreezeRender> {
+        let Some(song_file) = self.song_state().song_file_get() else {
+            anyhow::bail!("The song has not been saved.");
+        };
+        let Some(track) = self.song.tracks.get(track_index) else {
+            anyhow::bail!("No track {}.", track_index);
+        };
+        if track.modules.is_empty() {
+            anyhow::bail!("The track has no modules.");
+        }
+        if track.freeze.is_some() {
+            anyhow::bail!("The track is already frozen.");
         }
+        let track = track.clone();
+        self.stop();
+        let plugins = std::mem::take(
+            &mut self.process_track_contexts[track_index]
+                .lock()
+                .unwrap()
+                .plugins,
+        );
+        self.song.tracks[track_index].freeze = Some(Freeze::new(String::new(), vec![], 0.0));
+        Ok(FreezeRender {
+            track_index,
+            track,
+            context: ProcessTrackContext {
+                plugins,
+                ..Default::default()
+            },
+            contexts: self.process_track_contexts.clone(),
+            audio_files: self.audio_files.clone(),
+            transport: self.transport(),
+            nchannels: self.song.nchannels.clamp(1, MAX_CHANNELS),
+            project: Project::new(Path::new(&song_file)),
+        })
+    }
 
-        Ok(())
+    // 取り出したプラグインを戻して、書き出した音に差し替える
+    fn track_freeze_end(&mut self, render: FreezeRender, freeze: Option<Freeze>) {
+        let FreezeRender {
+            track_index,
+            context,
+            ..
+        } = render;
+        self.process_track_contexts[track_index]
+            .lock()
+            .unwrap()
+            .plugins = context.plugins;
+        self.song.tracks[track_index].freeze = freeze;
     }
 
     pub fn play(&mut self) {
@@ -556,6 +1829,7 @@ impl Singer {
         }
         self.song_state_mut().play_p = true;
         self.play_position.end = self.play_position_start_last;
+        self.play_position_fraction = 0.0;
     }
 
     pub fn play_line(&mut self, line: usize) {
@@ -566,10 +1840,11 @@ impl Singer {
         let position = line * 0x100;
         self.play_position.end = position;
         self.play_position_start_last = position;
+        self.play_position_fraction = 0.0;
     }
 
-    pub fn plugin_delete(&mut self, module_index: ModuleIndex) -> Result<()> {
-        let _module = self.song.tracks[module_index.0]
+    pub fn plugin_delete(&mut self, module_index: ModuleIndex) -> Result<Module> {
+        let module = self.song.tracks[module_index.0]
             .modules
             .remove(module_index.1);
         self.process_track_contexts[module_index.0]
@@ -578,7 +1853,7 @@ impl Singer {
             .plugins
             .remove(module_index.1);
         self.shmems[module_index.0].remove(module_index.1);
-        Ok(())
+        Ok(module)
     }
 
     pub fn plugin_sidechain(
@@ -597,7 +1872,7 @@ impl Singer {
         cursor: CursorTrack,
         module_index: usize,
         param_id: clap_id,
-    ) -> Result<()> {
+    ) -> Result<MainToAudio> {
         let automation_params = &mut self.song.tracks[cursor.track].automation_params;
         let automation_params_index = if let Some(index) = automation_params
             .iter()
@@ -614,9 +1889,9 @@ impl Singer {
             value: 0,
             delay: 0,
         };
-        self.lane_item_set(cursor, Some(LaneItem::Point(point)))?;
+        let undo = self.lane_item_set(cursor, Some(LaneItem::Point(point)))?;
 
-        Ok(())
+        Ok(MainToAudio::LaneItem(vec![undo]))
     }
 
     fn rec_toggle(&mut self) {
@@ -639,9 +1914,7 @@ impl Singer {
     }
 
     pub fn song_open(&mut self, song_file: String) -> Result<()> {
-        let file = File::open(&song_file)?;
-        let reader = BufReader::new(file);
-        let song = serde_json::from_reader(reader)?;
+        let song = song_read(Path::new(&song_file))?;
 
         self.song = song;
 
@@ -657,7 +1930,11 @@ impl Singer {
             }
         }
 
-        self.song_state_mut().song_file_set(&song_file);
+        self.midi_outputs_open();
+
+        self.song_state_mut().song_file_set(&song_file)?;
+        self.audio_files.clear();
+        self.audio_files_load();
         Ok(())
     }
 
@@ -684,6 +1961,14 @@ impl Singer {
         });
     }
 
+    pub fn start_listener_midi_sync(singer: Arc<Mutex<Self>>, receiver: Receiver<MidiSyncMessage>) {
+        let singer = singer.lock().unwrap();
+        let midi_sync = singer.midi_sync.clone();
+        tokio::spawn(async move {
+            midi_sync_loop(midi_sync, receiver).await.unwrap();
+        });
+    }
+
     pub fn start_listener_midi(singer: Arc<Mutex<Self>>, receiver: Receiver<Event>) {
         let singer = singer.lock().unwrap();
         let midi_buffer = singer.midi_buffer.clone();
@@ -697,25 +1982,65 @@ impl Singer {
 
         for track_index in 0..self.process_track_contexts.len() {
             let context = self.process_track_contexts[track_index].lock().unwrap();
+            let peaks = &mut song_state.tracks[track_index].peaks;
             if let Some(plugin_ref) = context.plugins.last() {
-                let process_data = plugin_ref.process_data();
-                let nchannels = process_data.nchannels_out[0];
-                for channel in 0..2 {
-                    song_state.tracks[track_index].peaks[channel] =
-                        process_data.peak(0, channel % nchannels);
+                peaks_set(peaks, plugin_ref.process_data());
+            } else if context.audio_clip_p {
+                let buffer = &context.buffer.buffer;
+                let nchannels = context.nchannels;
+                for (channel, peak) in peaks.iter_mut().enumerate() {
+                    *peak = if channel < nchannels.max(2) {
+                        let x = buffer[channel % nchannels][..context.nframes]
+                            .iter()
+                            .fold(0.0f32, |acc, x| acc.max(x.abs()));
+                        linear_to_db(x)
+                    } else {
+                        DB_MIN
+                    };
                 }
             } else if track_index == 0 {
-                let process_data = main_process_data;
-                let nchannels = process_data.nchannels_out[0];
-                for channel in 0..process_data.nchannels_out[0] {
-                    song_state.tracks[track_index].peaks[channel] =
-                        process_data.peak(0, channel % nchannels);
-                }
+                peaks_set(peaks, main_process_data);
             } else {
+                peaks.fill(DB_MIN);
+            }
+
+            let track = &self.song.tracks[track_index];
+            let aux_peaks = &mut song_state.tracks[track_index].aux_peaks;
+            for peaks in aux_peaks.iter_mut() {
+                *peaks = [DB_MIN; 2];
+            }
+            if track_index == 0 || track.freeze.is_some() {
+                continue;
+            }
+            let aux_outputs = track.modules.iter().zip(context.plugins.iter()).flat_map(
+                |(module, plugin_ref)| module.aux_outputs.iter().map(move |x| (x, plugin_ref)),
+            );
+            for (peaks, (aux_output, plugin_ref)) in aux_peaks.iter_mut().zip(aux_outputs) {
+                let process_data = plugin_ref.process_data();
+                let port = aux_output.port_index;
+                if port >= process_data.nports_out || process_data.nchannels_out[port] == 0 {
+                    continue;
+                }
+                let gains = aux_gains(aux_output);
                 for channel in 0..2 {
-                    song_state.tracks[track_index].peaks[channel] = DB_MIN;
+                    peaks[channel] = process_data
+                        .peak(port, channel % process_data.nchannels_out[port])
+                        + linear_to_db(gains[channel]);
+                }
+            }
+        }
+
+        for (track_index, context) in self.process_track_contexts.iter().enumerate() {
+            let mut module_faults = 0;
+            for (module_index, plugin_ref) in context.lock().unwrap().plugins.iter().enumerate() {
+                let process_data = plugin_ref.process_data();
+                song_state.events_dropped +=
+                    process_data.nevents_dropped_input + process_data.nevents_dropped_output;
+                if plugin_ref.fault_p && module_index < u64::BITS as usize {
+                    module_faults |= 1 << module_index;
                 }
             }
+            song_state.tracks[track_index].module_faults = module_faults;
         }
 
         // オートメンション対象のパラメータを特定するため
@@ -752,14 +2077,17 @@ impl Singer {
         self.song.track_at(track_index)
     }
 
-    fn track_delete(&mut self, track_index: usize) -> Result<()> {
+    fn track_delete(&mut self, track_index: usize) -> Result<Track> {
+        let mut modules = vec![];
         for module_index in (0..self.song.tracks[track_index].modules.len()).rev() {
-            self.plugin_delete((track_index, module_index))?;
+            modules.push(self.plugin_delete((track_index, module_index))?);
         }
-        self.song.track_delete(track_index);
+        modules.reverse();
+        let mut track = self.song.track_delete(track_index);
+        track.modules = modules;
         self.process_track_contexts.remove(track_index);
         self.shmems.remove(track_index);
-        Ok(())
+        Ok(track)
     }
 
     fn track_insert(&mut self, track_index: usize, track: Track) -> Result<()> {
@@ -774,6 +2102,7 @@ impl Singer {
             let module = &mut self.song.tracks[track_index].modules[module_index];
             module.id = id;
         }
+        self.midi_outputs_open();
         Ok(())
     }
 
@@ -798,12 +2127,20 @@ async fn singer_loop(singer: Arc<Mutex<Singer>>, receiver: Receiver<MainToAudio>
     let mut undo_history = UndoHistory::new();
     let mut break_p = false;
     while let Ok(msg) = receiver.recv() {
-        let mut singer = singer.lock().unwrap();
         undo_history.traveling_p = false;
         if matches!(msg, MainToAudio::Quit) {
             break_p = true;
         }
-        let response = run_main_to_audio(&mut singer, msg, &mut undo_history)?;
+        let response = if let MainToAudio::TrackFreeze(track_index) = msg {
+            track_freeze(&singer, track_index, &mut undo_history)
+        } else {
+            let mut singer = singer.lock().unwrap();
+            let response = run_main_to_audio(&mut singer, msg, &mut undo_history)?;
+            // 録音の準備はオーディオスレッドではしない
+            singer.audio_takes_arm();
+            response
+        };
+        let singer = singer.lock().unwrap();
         if let AudioToMain::Song(_) = &response {
             singer.song_state_mut().song_dirty_p = false;
         }
@@ -816,6 +2153,40 @@ async fn singer_loop(singer: Arc<Mutex<Singer>>, receiver: Receiver<MainToAudio>
     Ok(())
 }
 
+// オーディオスレッドが止まらないように、書き出しの間は Singer をロックしない
+fn track_freeze(
+    singer: &Arc<Mutex<Singer>>,
+    track_index: usize,
+    undo_history: &mut UndoHistory,
+) -> AudioToMain {
+    let render = singer.lock().unwrap().track_freeze_begin(track_index);
+    let freeze = match render {
+        Ok(mut render) => {
+            let freeze = render.run();
+            let mut singer = singer.lock().unwrap();
+            match freeze {
+                Ok(freeze) => {
+                    singer.track_freeze_end(render, Some(freeze.clone()));
+                    // redo で書き出し直さない
+                    undo_history.add(
+                        vec![MainToAudio::TrackFreezeSet(track_index, None)],
+                        MainToAudio::TrackFreezeSet(track_index, Some(freeze)),
+                    );
+                    return AudioToMain::Song(singer.song.clone());
+                }
+                Err(e) => {
+                    singer.track_freeze_end(render, None);
+                    e
+                }
+            }
+        }
+        Err(e) => e,
+    };
+    // 失敗したらフリーズしないだけ
+    log::error!("track freeze failed {}: {}", track_index, freeze);
+    AudioToMain::Song(singer.lock().unwrap().song.clone())
+}
+
 fn run_main_to_audio(
     singer: &mut Singer,
     message: MainToAudio,
@@ -824,9 +2195,29 @@ fn run_main_to_audio(
     let redo = message.clone();
     match message {
         MainToAudio::Bpm(bpm) => {
+            undo_history.add(vec![MainToAudio::Bpm(singer.song.bpm)], redo);
             singer.song.bpm = bpm;
             Ok(AudioToMain::Song(singer.song.clone()))
         }
+        MainToAudio::TimeSignature(numerator, denominator) => {
+            let (numerator_old, denominator_old) = singer.song.time_signature;
+            undo_history.add(
+                vec![MainToAudio::TimeSignature(numerator_old, denominator_old)],
+                redo,
+            );
+            singer.song.time_signature = (numerator, denominator);
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::Channels(nchannels) => {
+            undo_history.add(vec![MainToAudio::Channels(singer.song.nchannels)], redo);
+            singer.song.nchannels = nchannels.clamp(1, MAX_CHANNELS);
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::Process64(process_64_p) => {
+            undo_history.add(vec![MainToAudio::Process64(singer.song.process_64_p)], redo);
+            singer.song.process_64_p = process_64_p;
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
         MainToAudio::Play => {
             singer.play();
             Ok(AudioToMain::Ok)
@@ -837,7 +2228,7 @@ fn run_main_to_audio(
         }
         MainToAudio::Stop => {
             singer.stop();
-            Ok(AudioToMain::Ok)
+            audio_takes_place(singer, undo_history, None)
         }
         MainToAudio::Loop => {
             singer.song_state_mut().loop_p = !singer.song_state().loop_p;
@@ -848,60 +2239,158 @@ fn run_main_to_audio(
             singer.song_state_mut().loop_end = range.end;
             Ok(AudioToMain::Ok)
         }
+        MainToAudio::MidiClockOutput(midi_clock_output) => {
+            singer.midi_clock_output = midi_clock_output;
+            singer.midi_outputs_open();
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::MidiSyncFollow(follow_p) => {
+            singer.midi_sync_follow_p = follow_p;
+            singer.midi_sync.lock().unwrap().transports.clear();
+            Ok(AudioToMain::Ok)
+        }
         MainToAudio::Song => Ok(AudioToMain::Song(singer.song.clone())),
         MainToAudio::LaneItem(items) => {
             let undo = singer.lane_items_set(items)?;
-            undo_history.add(undo, redo);
+            undo_history.add(vec![undo], redo);
+            singer.audio_files_load();
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::ModuleAuxOutputs(module_index, aux_outputs) => {
+            if let Some(module) = singer.song.module_at_mut(module_index) {
+                let aux_outputs_old = std::mem::replace(&mut module.aux_outputs, aux_outputs);
+                undo_history.add(
+                    vec![MainToAudio::ModuleAuxOutputs(module_index, aux_outputs_old)],
+                    redo,
+                );
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::ModuleAudioInputs(module_index, audio_inputs) => {
+            if let Some(module) = singer.song.module_at_mut(module_index) {
+                let audio_inputs_old = std::mem::replace(&mut module.audio_inputs, audio_inputs);
+                undo_history.add(
+                    vec![MainToAudio::ModuleAudioInputs(
+                        module_index,
+                        audio_inputs_old,
+                    )],
+                    redo,
+                );
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::ModuleInsert(module_index, module) => {
+            let undos = singer.module_insert(module_index, module, false)?;
+            undo_history.add(undos, redo);
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::ModuleMove(module_index, delta) => {
+            let undos = singer.module_move(module_index, delta)?;
+            undo_history.add(undos, redo);
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::ModuleRename(module_index, name) => {
             if let Some(module) = singer.song.module_at_mut(module_index) {
-                module.name = name;
+                let name_old = std::mem::replace(&mut module.name, name);
+                undo_history.add(
+                    vec![MainToAudio::ModuleRename(module_index, name_old)],
+                    redo,
+                );
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::ModuleBypass(module_index, bypass_p) => {
+            if let Some(module) = singer.song.module_at_mut(module_index) {
+                let bypass_p_old = std::mem::replace(&mut module.bypass_p, bypass_p);
+                undo_history.add(
+                    vec![MainToAudio::ModuleBypass(module_index, bypass_p_old)],
+                    redo,
+                );
+                singer.plugin_automation_reset(module_index);
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::ModuleGain(module_index, gain) => {
+            if let Some(module) = singer.song.module_at_mut(module_index) {
+                let gain_old = std::mem::replace(&mut module.gain, gain);
+                undo_history.add(vec![MainToAudio::ModuleGain(module_index, gain_old)], redo);
+                singer.plugin_automation_reset(module_index);
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::ModuleMix(module_index, mix) => {
+            if let Some(module) = singer.song.module_at_mut(module_index) {
+                let mix_old = std::mem::replace(&mut module.mix, mix);
+                undo_history.add(vec![MainToAudio::ModuleMix(module_index, mix_old)], redo);
+                singer.plugin_automation_reset(module_index);
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
+        MainToAudio::ModuleState(id, state) => {
+            if let Some(module) = singer.song.module_by_id_mut(id) {
+                module.state = Some(state);
+            }
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::PluginBypassParam(id, param_id) => {
+            singer.plugin_bypass_param_set(id, param_id)?;
+            Ok(AudioToMain::Ok)
+        }
         MainToAudio::PluginLatency(id, latency) => {
             singer.plugin_latency_set(id, latency)?;
             Ok(AudioToMain::Ok)
         }
-        MainToAudio::PluginLoad(track_index, clap_plugin_id, name) => {
-            let id = singer.plugin_load(track_index)?;
-            let track = &mut singer.song.tracks[track_index];
-            let audio_inputs = if track.modules.is_empty() {
-                vec![]
-            } else {
-                vec![AudioInput {
-                    src_module_index: (track_index, track.modules.len() - 1),
-                    src_port_index: 0,
-                    dst_port_index: 0,
-                }]
-            };
-            singer.song.tracks[track_index].modules.push(Module::new(
-                id,
-                clap_plugin_id,
-                name,
-                audio_inputs,
-            ));
+        MainToAudio::PluginLoaded(id, loaded_p) => {
+            singer.plugin_loaded_set(id, loaded_p)?;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::PluginLoad(module_index, clap_plugin_id, name) => {
+            let module = Module::new(0, clap_plugin_id, name, vec![]);
+            let undos = singer.module_insert(module_index, module, true)?;
+            undo_history.add(undos, redo);
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::PluginDelete(module_index) => {
-            singer.plugin_delete(module_index)?;
+            // state は main で StateSave 済み
+            let undos = singer.module_delete(module_index)?;
+            undo_history.add(undos, redo);
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::PluginSidechain(module_index, audio_input) => {
+            if let Some(module) = singer.song.module_at(module_index) {
+                undo_history.add(
+                    vec![MainToAudio::ModuleAudioInputs(
+                        module_index,
+                        module.audio_inputs.clone(),
+                    )],
+                    redo,
+                );
+            }
             singer.plugin_sidechain(module_index, audio_input)?;
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::PointNew(cursor, module_index, param_id) => {
-            singer.point_new(cursor, module_index, param_id)?;
+            let automation_params = singer.song.tracks[cursor.track].automation_params.clone();
+            let undo = singer.point_new(cursor, module_index, param_id)?;
+            undo_history.add(
+                vec![
+                    undo,
+                    MainToAudio::TrackAutomationParams(cursor.track, automation_params),
+                ],
+                redo,
+            );
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::RecToggle => {
             singer.rec_toggle();
-            Ok(AudioToMain::Ok)
+            if singer.song_state().rec_p {
+                Ok(AudioToMain::Ok)
+            } else {
+                audio_takes_place(singer, undo_history, None)
+            }
         }
         MainToAudio::Redo => {
-            if let Some(redo) = undo_history.redo() {
+            for redo in undo_history.redo() {
                 run_main_to_audio(singer, redo, undo_history)?;
             }
             Ok(AudioToMain::Song(singer.song.clone()))
@@ -924,34 +2413,102 @@ fn run_main_to_audio(
         }
         MainToAudio::TrackAdd => {
             singer.track_add();
+            undo_history.add(
+                vec![MainToAudio::TrackDelete(singer.song.tracks.len() - 1)],
+                redo,
+            );
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TrackAutomationParams(track_index, automation_params) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                let automation_params_old =
+                    std::mem::replace(&mut track.automation_params, automation_params);
+                undo_history.add(
+                    vec![MainToAudio::TrackAutomationParams(
+                        track_index,
+                        automation_params_old,
+                    )],
+                    redo,
+                );
+            }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::TrackDelete(track_index) => {
-            singer.track_delete(track_index)?;
+            // 他のトラックからのサイドチェインも消えるので戻せるように
+            let mut undos = vec![];
+            for (index, track) in singer.song.tracks.iter().enumerate() {
+                for (module_index, module) in track.modules.iter().enumerate() {
+                    if module
+                        .audio_inputs
+                        .iter()
+                        .any(|x| x.src_module_index.0 == track_index)
+                    {
+                        undos.push(MainToAudio::ModuleAudioInputs(
+                            (index, module_index),
+                            module.audio_inputs.clone(),
+                        ));
+                    }
+                }
+            }
+            let track = singer.track_delete(track_index)?;
+            undos.insert(0, MainToAudio::TrackInsert(track_index, track));
+            undo_history.add(undos, redo);
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        // 書き出しに時間がかかるので singer_loop で Singer をロックせずに行う
+        MainToAudio::TrackFreeze(_) => anyhow::bail!("TrackFreeze is handled in singer_loop."),
+        MainToAudio::TrackFreezeSet(track_index, freeze) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                let freeze_old = std::mem::replace(&mut track.freeze, freeze);
+                undo_history.add(
+                    vec![MainToAudio::TrackFreezeSet(track_index, freeze_old)],
+                    redo,
+                );
+            }
+            singer.audio_files_load();
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::TrackInsert(track_index, track) => {
             singer.track_insert(track_index, track)?;
+            singer.audio_files_load();
+            undo_history.add(vec![MainToAudio::TrackDelete(track_index)], redo);
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::TrackMove(track_index, delta) => {
-            singer.track_move(track_index, delta)?;
+            if singer.track_move(track_index, delta)? {
+                let track_index_new = track_index.saturating_add_signed(delta);
+                undo_history.add(vec![MainToAudio::TrackMove(track_index_new, -delta)], redo);
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TrackMidiOutput(track_index, midi_output) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                let midi_output_old = std::mem::replace(&mut track.midi_output, midi_output);
+                undo_history.add(
+                    vec![MainToAudio::TrackMidiOutput(track_index, midi_output_old)],
+                    redo,
+                );
+            }
+            singer.midi_outputs_open();
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::TrackMute(track_index, mute) => {
             if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                undo_history.add(vec![MainToAudio::TrackMute(track_index, track.mute)], redo);
                 track.mute = mute;
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::TrackSolo(track_index, solo) => {
             if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                undo_history.add(vec![MainToAudio::TrackSolo(track_index, track.solo)], redo);
                 track.solo = solo;
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::TrackPan(track_index, pan) => {
             if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                undo_history.add(vec![MainToAudio::TrackPan(track_index, track.pan)], redo);
                 track.pan = pan;
             }
             Ok(AudioToMain::Song(singer.song.clone()))
@@ -962,37 +2519,102 @@ fn run_main_to_audio(
         }
         MainToAudio::TrackRecOff(track_index) => {
             singer.song_state_mut().tracks[track_index].rec_p = false;
+            audio_takes_place(singer, undo_history, Some(track_index))
+        }
+        MainToAudio::TrackInput(track_index, input_p) => {
+            singer.song_state_mut().tracks[track_index].input_p = input_p;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::TrackMonitor(track_index, monitor_p) => {
+            singer.song_state_mut().tracks[track_index].monitor_p = monitor_p;
             Ok(AudioToMain::Ok)
         }
         MainToAudio::TrackRename(track_index, name) => {
             if let Some(track) = singer.song.tracks.get_mut(track_index) {
-                track.name = name;
+                let name_old = std::mem::replace(&mut track.name, name);
+                undo_history.add(vec![MainToAudio::TrackRename(track_index, name_old)], redo);
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::TrackVolume(track_index, volume) => {
             if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                undo_history.add(
+                    vec![MainToAudio::TrackVolume(track_index, track.volume)],
+                    redo,
+                );
                 track.volume = volume;
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::Undo => {
-            if let Some(undo) = undo_history.undo() {
+            for undo in undo_history.undo() {
                 run_main_to_audio(singer, undo, undo_history)?;
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
+        MainToAudio::UndoContext(context) => {
+            undo_history.context = context;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::UndoGroupBegin(description) => {
+            undo_history.group_begin(description);
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::UndoGroupEnd => {
+            undo_history.group_end();
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::UndoHistory => Ok(AudioToMain::UndoHistory(undo_history.snapshot())),
+        MainToAudio::UndoJump(position) => {
+            while undo_history.position() > position {
+                for undo in undo_history.undo() {
+                    run_main_to_audio(singer, undo, undo_history)?;
+                }
+            }
+            while undo_history.position() < position {
+                let redos = undo_history.redo();
+                if redos.is_empty() {
+                    break;
+                }
+                for redo in redos {
+                    run_main_to_audio(singer, redo, undo_history)?;
+                }
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
         MainToAudio::LaneAdd(track_index) => {
             if let Some(track) = singer.song.tracks.get_mut(track_index) {
                 track.lane_add();
+                undo_history.add(vec![MainToAudio::LaneDelete(track_index)], redo);
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::LaneDelete(track_index) => {
+            // LaneAdd の undo 用。その後の編集は先に戻っているので最後のレーンは空
+            if let Some(track) = singer.song.tracks.get_mut(track_index)
+                && track.lanes.len() > 1
+            {
+                track.lanes.pop();
+                undo_history.add(vec![MainToAudio::LaneAdd(track_index)], redo);
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::SongFile(song_file) => {
-            singer.song_state_mut().song_file_set(&song_file);
+            singer.song_state_mut().song_file_set(&song_file)?;
+            // 相対パスの指す先が変わるので、読めなかったものも含めて読み直す
+            singer.audio_files.clear();
+            singer.audio_files_load();
             Ok(AudioToMain::Ok)
         }
+        MainToAudio::SongNew => {
+            undo_history.clear();
+            singer.song_close()?;
+            singer.song_state_mut().song_file_set("")?;
+            singer.midi_outputs_open();
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
         MainToAudio::SongOpen(song_file) => {
+            undo_history.clear();
             singer.song_close()?;
             singer.song_open(song_file)?;
             Ok(AudioToMain::Song(singer.song.clone()))
@@ -1001,6 +2623,62 @@ fn run_main_to_audio(
     }
 }
 
+// 音量とパンから ch0, ch1, それ以外のゲイン
+fn pan_gains(volume: f32, pan: f32) -> [f32; 3] {
+    if (pan - 0.5).abs() < 0.001 {
+        [volume; 3]
+    } else {
+        let normalized_pan = (pan - 0.5) * 2.0;
+        let pan_angle = (normalized_pan + 1.0) * PI / 4.0;
+        [volume * pan_angle.cos(), volume * pan_angle.sin(), volume]
+    }
+}
+
+fn aux_gains(aux_output: &AuxOutput) -> [f32; 3] {
+    pan_gains(db_to_linear(aux_output.volume), aux_output.pan)
+}
+
+// モノラルはステレオのメーターの両方に出す
+fn peaks_set(peaks: &mut [f32; MAX_CHANNELS], process_data: &ProcessData) {
+    let nchannels = process_data.nchannels_out[0];
+    for (channel, peak) in peaks.iter_mut().enumerate() {
+        *peak = if nchannels > 0 && channel < nchannels.max(2) {
+            process_data.peak(0, channel % nchannels)
+        } else {
+            DB_MIN
+        };
+    }
+}
+
+// channel_map の対応で dst の channel に入る音を足し合わせる
+// ミックスの足し算は f64 で行う
+fn channel_map_sum(
+    map: &[(usize, usize, f32)],
+    channel: usize,
+    sample: impl Fn(usize) -> f64,
+) -> f64 {
+    map.iter()
+        .filter(|(_, dst_channel, _)| *dst_channel == channel)
+        .map(|(src_channel, _, gain)| sample(*src_channel) * *gain as f64)
+        .sum()
+}
+
+// 録音を止めたときにテイクを置く
+fn audio_takes_place(
+    singer: &mut Singer,
+    undo_history: &mut UndoHistory,
+    track_index: Option<usize>,
+) -> Result<AudioToMain> {
+    let items = singer.audio_takes_finish(track_index);
+    if items.is_empty() {
+        return Ok(AudioToMain::Ok);
+    }
+    let redo = MainToAudio::LaneItem(items.clone());
+    let undo = singer.lane_items_set(items)?;
+    undo_history.add(vec![undo], redo);
+    Ok(AudioToMain::Song(singer.song.clone()))
+}
+
 async fn midi_loop(midi_buffer: Arc<Mutex<Vec<Event>>>, receiver: Receiver<Event>) -> Result<()> {
     while let Ok(event) = receiver.recv() {
         let mut midi_buffer = midi_buffer.lock().unwrap();
@@ -1008,3 +2686,308 @@ async fn midi_loop(midi_buffer: Arc<Mutex<Vec<Event>>>, receiver: Receiver<Event
     }
     Ok(())
 }
+
+async fn midi_sync_loop(
+    midi_sync: Arc<Mutex<MidiSync>>,
+    receiver: Receiver<MidiSyncMessage>,
+) -> Result<()> {
+    while let Ok(message) = receiver.recv() {
+        midi_sync.lock().unwrap().receive(message);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    // SongState の共有メモリは名前が固定なので Singer を作るテストは順番に
+    static SINGER_LOCK: Mutex<()> = Mutex::new(());
+
+    fn singer_new() -> Singer {
+        let (sender, _receiver) = channel();
+        Singer::new(sender)
+    }
+
+    fn run(singer: &mut Singer, undo_history: &mut UndoHistory, message: MainToAudio) {
+        run_main_to_audio(singer, message, undo_history).unwrap();
+    }
+
+    fn module(name: &str, audio_inputs: Vec<AudioInput>) -> Module {
+        Module::new(0, "plugin".to_string(), name.to_string(), audio_inputs)
+    }
+
+    fn audio_input(src_module_index: ModuleIndex, dst_port_index: usize) -> AudioInput {
+        AudioInput {
+            src_module_index,
+            src_port_index: 0,
+            dst_port_index,
+        }
+    }
+
+    fn module_names(singer: &Singer, track_index: usize) -> Vec<String> {
+        singer.song.tracks[track_index]
+            .modules
+            .iter()
+            .map(|module| module.name.clone())
+            .collect()
+    }
+
+    fn sources(singer: &Singer, module_index: ModuleIndex) -> Vec<ModuleIndex> {
+        singer.song.tracks[module_index.0].modules[module_index.1]
+            .audio_inputs
+            .iter()
+            .map(|input| input.src_module_index)
+            .collect()
+    }
+
+    fn point_indexes(singer: &Singer, track_index: usize) -> Vec<(usize, usize)> {
+        singer.song.tracks[track_index].lanes[0]
+            .items
+            .iter()
+            .filter_map(|(line, item)| match item {
+                LaneItem::Point(point) => Some((*line, point.automation_params_index)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // プラグインの並びがモジュールの並びと同じか
+    fn assert_plugins_in_order(singer: &Singer) {
+        for (track, context) in singer
+            .song
+            .tracks
+            .iter()
+            .zip(singer.process_track_contexts.iter())
+        {
+            let context = context.lock().unwrap();
+            let ids = context.plugins.iter().map(|x| x.id).collect::<Vec<_>>();
+            let module_ids = track.modules.iter().map(|x| x.id).collect::<Vec<_>>();
+            assert_eq!(ids, module_ids);
+        }
+    }
+
+    // T01: Synth -> Comp -> EQ (直列)、Comp と EQ のパラメータをオートメーション
+    // T02: Gate に T01 の EQ のサイドチェイン
+    fn song_for_module_test(singer: &mut Singer, undo_history: &mut UndoHistory) {
+        singer.track_add();
+        let t = 1;
+        run(
+            singer,
+            undo_history,
+            MainToAudio::ModuleInsert((t, 0), module("Synth", vec![])),
+        );
+        run(
+            singer,
+            undo_history,
+            MainToAudio::ModuleInsert((t, 1), module("Comp", vec![audio_input((t, 0), 0)])),
+        );
+        run(
+            singer,
+            undo_history,
+            MainToAudio::ModuleInsert((t, 2), module("EQ", vec![audio_input((t, 1), 0)])),
+        );
+        run(
+            singer,
+            undo_history,
+            MainToAudio::ModuleInsert((2, 0), module("Gate", vec![audio_input((t, 2), 1)])),
+        );
+        let track = &mut singer.song.tracks[t];
+        track.automation_params = vec![(1, 10), (MIDI_CC_MODULE_INDEX, 7), (2, 20)];
+        for (line, automation_params_index) in [(0, 0), (1, 1), (2, 2)] {
+            track.lanes[0].items.insert(
+                line,
+                LaneItem::Point(Point {
+                    automation_params_index,
+                    value: 0x80,
+                    delay: 0,
+                }),
+            );
+        }
+        undo_history.clear();
+    }
+
+    #[test]
+    fn module_move_within_track() {
+        let _lock = SINGER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut singer = singer_new();
+        let mut undo_history = UndoHistory::new();
+        song_for_module_test(&mut singer, &mut undo_history);
+
+        run(
+            &mut singer,
+            &mut undo_history,
+            MainToAudio::ModuleMove((1, 2), -1),
+        );
+
+        assert_eq!(module_names(&singer, 1), ["Synth", "EQ", "Comp"]);
+        assert_plugins_in_order(&singer);
+        // 直列のつながりは並び順どおり
+        assert_eq!(sources(&singer, (1, 1)), [(1, 0)]);
+        assert_eq!(sources(&singer, (1, 2)), [(1, 1)]);
+        // 他のトラックからのサイドチェインは EQ を指したまま
+        assert_eq!(sources(&singer, (2, 0)), [(1, 1)]);
+        assert_eq!(
+            singer.song.tracks[1].automation_params,
+            [(2, 10), (MIDI_CC_MODULE_INDEX, 7), (1, 20)]
+        );
+        assert_eq!(point_indexes(&singer, 1), [(0, 0), (1, 1), (2, 2)]);
+
+        // 端を越える移動は何もしない
+        run(
+            &mut singer,
+            &mut undo_history,
+            MainToAudio::ModuleMove((1, 2), 1),
+        );
+        assert_eq!(module_names(&singer, 1), ["Synth", "EQ", "Comp"]);
+    }
+
+    #[test]
+    fn module_move_undo() {
+        let _lock = SINGER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut singer = singer_new();
+        let mut undo_history = UndoHistory::new();
+        song_for_module_test(&mut singer, &mut undo_history);
+        let ids = singer.song.tracks[1]
+            .modules
+            .iter()
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+
+        run(
+            &mut singer,
+            &mut undo_history,
+            MainToAudio::ModuleMove((1, 0), 2),
+        );
+        assert_eq!(module_names(&singer, 1), ["Comp", "EQ", "Synth"]);
+        assert_plugins_in_order(&singer);
+        assert_eq!(sources(&singer, (2, 0)), [(1, 1)]);
+
+        run(&mut singer, &mut undo_history, MainToAudio::Undo);
+
+        assert_eq!(module_names(&singer, 1), ["Synth", "Comp", "EQ"]);
+        assert_eq!(
+            singer.song.tracks[1]
+                .modules
+                .iter()
+                .map(|x| x.id)
+                .collect::<Vec<_>>(),
+            ids
+        );
+        assert_plugins_in_order(&singer);
+        assert_eq!(sources(&singer, (1, 0)), []);
+        assert_eq!(sources(&singer, (1, 1)), [(1, 0)]);
+        assert_eq!(sources(&singer, (1, 2)), [(1, 1)]);
+        assert_eq!(sources(&singer, (2, 0)), [(1, 2)]);
+        assert_eq!(
+            singer.song.tracks[1].automation_params,
+            [(1, 10), (MIDI_CC_MODULE_INDEX, 7), (2, 20)]
+        );
+    }
+
+    #[test]
+    fn module_delete_remaps_references() {
+        let _lock = SINGER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut singer = singer_new();
+        let mut undo_history = UndoHistory::new();
+        song_for_module_test(&mut singer, &mut undo_history);
+
+        run(
+            &mut singer,
+            &mut undo_history,
+            MainToAudio::PluginDelete((1, 1)),
+        );
+
+        assert_eq!(module_names(&singer, 1), ["Synth", "EQ"]);
+        assert_plugins_in_order(&singer);
+        assert_eq!(sources(&singer, (1, 1)), [(1, 0)]);
+        assert_eq!(sources(&singer, (2, 0)), [(1, 1)]);
+        // Comp のパラメータと Point は消え、後ろの index は詰める
+        assert_eq!(
+            singer.song.tracks[1].automation_params,
+            [(MIDI_CC_MODULE_INDEX, 7), (1, 20)]
+        );
+        assert_eq!(point_indexes(&singer, 1), [(1, 0), (2, 1)]);
+
+        run(&mut singer, &mut undo_history, MainToAudio::Undo);
+
+        assert_eq!(module_names(&singer, 1), ["Synth", "Comp", "EQ"]);
+        assert_plugins_in_order(&singer);
+        assert_eq!(sources(&singer, (1, 2)), [(1, 1)]);
+        assert_eq!(sources(&singer, (2, 0)), [(1, 2)]);
+        assert_eq!(point_indexes(&singer, 1), [(0, 0), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn module_move_to_other_track() {
+        let _lock = SINGER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut singer = singer_new();
+        let mut undo_history = UndoHistory::new();
+        song_for_module_test(&mut singer, &mut undo_history);
+
+        // 別のトラックへは削除して挿入する
+        run(
+            &mut singer,
+            &mut undo_history,
+            MainToAudio::UndoGroupBegin("Move".to_string()),
+        );
+        let comp = singer.song.tracks[1].modules[1].clone();
+        run(
+            &mut singer,
+            &mut undo_history,
+            MainToAudio::PluginDelete((1, 1)),
+        );
+        run(
+            &mut singer,
+            &mut undo_history,
+            MainToAudio::ModuleInsert((2, 0), comp),
+        );
+        run(&mut singer, &mut undo_history, MainToAudio::UndoGroupEnd);
+
+        assert_eq!(module_names(&singer, 1), ["Synth", "EQ"]);
+        assert_eq!(module_names(&singer, 2), ["Comp", "Gate"]);
+        assert_plugins_in_order(&singer);
+        // Gate は後ろにずれても T01 の EQ を指す
+        assert_eq!(sources(&singer, (2, 1)), [(1, 1)]);
+        // 元のトラックの Comp への入力は移した先でも Synth のまま
+        assert_eq!(sources(&singer, (2, 0)), [(1, 0)]);
+
+        run(&mut singer, &mut undo_history, MainToAudio::Undo);
+
+        assert_eq!(module_names(&singer, 1), ["Synth", "Comp", "EQ"]);
+        assert_eq!(module_names(&singer, 2), ["Gate"]);
+        assert_plugins_in_order(&singer);
+        assert_eq!(sources(&singer, (1, 2)), [(1, 1)]);
+        assert_eq!(sources(&singer, (2, 0)), [(1, 2)]);
+        assert_eq!(
+            singer.song.tracks[1].automation_params,
+            [(1, 10), (MIDI_CC_MODULE_INDEX, 7), (2, 20)]
+        );
+    }
+
+    // デバイスのバッファがどんな大きさでも MAX_FRAMES ずつに分けて処理して再生位置がずれない
+    #[test]
+    fn process_any_buffer_size() {
+        let _lock = SINGER_LOCK.lock().unwrap();
+        let mut singer = singer_new();
+        // 1 delay がちょうど 25 フレームになるように
+        singer.song.sample_rate = 48000.0;
+        singer.song.lpb = 4;
+        singer.song.bpm = 48000.0 * 60.0 / (4.0 * 256.0 * 25.0);
+        singer.song_state_mut().loop_p = false;
+        singer.play_line(0);
+
+        let nchannels = 2;
+        let mut frames_total = 0;
+        for nframes in [1, 1023, MAX_FRAMES + 1, 3 * MAX_FRAMES + 17] {
+            let steady_time = singer.steady_time;
+            let mut output = vec![0.0; nframes * nchannels];
+            singer.process(&mut output, nchannels).unwrap();
+            frames_total += nframes;
+            assert_eq!(singer.steady_time - steady_time, nframes as i64);
+            assert_eq!(singer.play_position.end, frames_total / 25);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::{bail, Result};
 use clap_sys::id::clap_id;
 use common::process_data::MAX_CHANNELS;
 
@@ -5,6 +6,7 @@ use crate::view::stereo_peak_meter::DB_MIN;
 
 pub const MAX_PATH_LEN: usize = 1024;
 pub const MAX_TRACKS: usize = 0xff;
+pub const MAX_AUX_OUTPUTS: usize = 0x10; // トラックごと
 
 #[repr(C)]
 #[derive(Debug)]
@@ -19,6 +21,8 @@ pub struct SongState {
     pub process_elasped_avg: f64,
     pub cpu_usage: f64,
     pub nframes: usize,
+    // イベントがいっぱいで落とした数の累計
+    pub events_dropped: usize,
     pub tracks: [TrackState; MAX_TRACKS],
     pub param_track_index: usize,
     pub param_module_index: usize,
@@ -39,11 +43,16 @@ impl SongState {
         self.process_elasped_avg = 0.0;
         self.cpu_usage = 0.0;
         self.nframes = 1;
+        self.events_dropped = 0;
         for track in self.tracks.iter_mut() {
             for peak in track.peaks.iter_mut() {
                 *peak = DB_MIN;
             }
+            track.aux_peaks = [[DB_MIN; 2]; MAX_AUX_OUTPUTS];
             track.rec_p = false;
+            track.input_p = false;
+            track.monitor_p = false;
+            track.module_faults = 0;
         }
         self.param_track_index = usize::MAX;
         self.rec_p = false;
@@ -62,11 +71,15 @@ impl SongState {
             .map(|s| s.to_string())
     }
 
-    pub fn song_file_set(&mut self, name: &str) {
+    pub fn song_file_set(&mut self, name: &str) -> Result<()> {
         let bytes = name.as_bytes();
-        let len = bytes.len().min(self.song_file.len() - 1);
-        self.song_file[..len].copy_from_slice(&bytes[..len]);
-        self.song_file[len] = 0; // null 終端
+        if bytes.len() >= self.song_file.len() {
+            // 切り詰めると別のファイルに保存してしまう
+            bail!("Path is too long ({} bytes): {}", bytes.len(), name);
+        }
+        self.song_file[..bytes.len()].copy_from_slice(bytes);
+        self.song_file[bytes.len()] = 0; // null 終端
+        Ok(())
     }
 }
 
@@ -74,5 +87,9 @@ impl SongState {
 #[derive(Debug)]
 pub struct TrackState {
     pub peaks: [f32; MAX_CHANNELS],
+    pub aux_peaks: [[f32; 2]; MAX_AUX_OUTPUTS], // モジュール順に並べた aux_outputs
     pub rec_p: bool,
+    pub input_p: bool,      // 録音するときにオーディオ入力を録る
+    pub monitor_p: bool,    // オーディオ入力をトラックに流す
+    pub module_faults: u64, // 応答しなくなったモジュールのビット
 }
@@ -1,14 +1,55 @@
-use crate::singer::MainToAudio;
+use std::time::{Duration, Instant};
 
+use crate::{
+    app_state::{CursorTrack, FocusedPart},
+    singer::MainToAudio,
+};
+
+// これより間が空いたら同じフェーダーでも別の操作にする
+const MERGE_WINDOW: Duration = Duration::from_millis(1000);
+
+// 操作したときのカーソルなど。undo/redo でそこに戻る
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UndoContext {
+    pub cursor_track: CursorTrack,
+    pub focused_part: FocusedPart,
+    pub select_p: bool,
+    pub selection_track_min: Option<CursorTrack>,
+    pub selection_track_max: Option<CursorTrack>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UndoHistoryEntry {
+    pub description: String,
+    pub context: UndoContext,
+}
+
+// undo 履歴パネル用
+#[derive(Clone, Debug)]
+pub struct UndoHistorySnapshot {
+    pub entries: Vec<UndoHistoryEntry>,
+    pub position: usize, // entries[..position] が適用済み
+    pub context_last: Option<UndoContext>,
+}
+
+// undos は適用する順
 #[derive(Clone)]
 struct UndoHistoryItem {
-    pub undo: MainToAudio,
-    pub redo: MainToAudio,
+    pub undos: Vec<MainToAudio>,
+    pub redos: Vec<MainToAudio>,
+    pub description: String,
+    pub context: UndoContext,
+    time: Instant, // 最後にまとめた時刻
 }
 
 pub struct UndoHistory {
     undos: Vec<UndoHistoryItem>,
     redos: Vec<UndoHistoryItem>,
+    group_depth: usize,
+    group_description: Option<String>,
+    group_open_p: bool,
+    pub context: UndoContext,
+    context_last: Option<UndoContext>,
     pub traveling_p: bool,
 }
 
@@ -17,35 +58,222 @@ impl UndoHistory {
         Self {
             undos: vec![],
             redos: vec![],
+            group_depth: 0,
+            group_description: None,
+            group_open_p: false,
+            context: Default::default(),
+            context_last: None,
             traveling_p: false,
         }
     }
 
-    pub fn add(&mut self, undo: MainToAudio, redo: MainToAudio) {
-        if self.traveling_p {
+    pub fn add(&mut self, undos: Vec<MainToAudio>, redo: MainToAudio) {
+        if self.traveling_p || undos.is_empty() {
+            return;
+        }
+        self.context_last = None;
+        if self.group_depth == 0 && self.redos.is_empty() {
+            // フェーダーを動かし続けたときなどは 1 つにまとめる
+            if let Some(item) = self.undos.last_mut()
+                && item.redos.len() == 1
+                && item.time.elapsed() < MERGE_WINDOW
+                && mergeable_p(&item.redos[0], &redo)
+            {
+                item.description = description(&redo);
+                item.redos[0] = redo;
+                item.time = Instant::now();
+                return;
+            }
+        }
+        self.redos.clear();
+        if self.group_open_p
+            && let Some(item) = self.undos.last_mut()
+        {
+            // 後の操作から先に戻す
+            item.undos.splice(0..0, undos);
+            item.redos.push(redo);
             return;
         }
-        self.undos.push(UndoHistoryItem { undo, redo });
+        self.undos.push(UndoHistoryItem {
+            undos,
+            description: self
+                .group_description
+                .clone()
+                .unwrap_or_else(|| description(&redo)),
+            redos: vec![redo],
+            context: self.context,
+            time: Instant::now(),
+        });
+        self.group_open_p = self.group_depth > 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.undos.clear();
         self.redos.clear();
+        self.group_depth = 0;
+        self.group_description = None;
+        self.group_open_p = false;
+        self.context_last = None;
+    }
+
+    pub fn group_begin(&mut self, description: String) {
+        if self.group_depth == 0 {
+            self.group_description = Some(description);
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn group_end(&mut self) {
+        self.group_depth = self.group_depth.saturating_sub(1);
+        if self.group_depth == 0 {
+            self.group_description = None;
+            self.group_open_p = false;
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.undos.len()
     }
 
-    pub fn undo(&mut self) -> Option<MainToAudio> {
+    pub fn snapshot(&self) -> UndoHistorySnapshot {
+        UndoHistorySnapshot {
+            entries: self
+                .undos
+                .iter()
+                .chain(self.redos.iter().rev())
+                .map(|item| UndoHistoryEntry {
+                    description: item.description.clone(),
+                    context: item.context,
+                })
+                .collect(),
+            position: self.undos.len(),
+            context_last: self.context_last,
+        }
+    }
+
+    pub fn undo(&mut self) -> Vec<MainToAudio> {
         self.traveling_p = true;
         if let Some(item) = self.undos.pop() {
+            self.context_last = Some(item.context);
             self.redos.push(item.clone());
-            Some(item.undo)
+            item.undos
         } else {
-            None
+            vec![]
         }
     }
 
-    pub fn redo(&mut self) -> Option<MainToAudio> {
+    pub fn redo(&mut self) -> Vec<MainToAudio> {
         self.traveling_p = true;
         if let Some(item) = self.redos.pop() {
+            self.context_last = Some(item.context);
             self.undos.push(item.clone());
-            Some(item.redo)
+            item.redos
         } else {
-            None
+            vec![]
+        }
+    }
+}
+
+fn mergeable_p(a: &MainToAudio, b: &MainToAudio) -> bool {
+    match (a, b) {
+        (MainToAudio::Bpm(_), MainToAudio::Bpm(_)) => true,
+        (MainToAudio::TrackPan(a, _), MainToAudio::TrackPan(b, _)) => a == b,
+        (MainToAudio::TrackVolume(a, _), MainToAudio::TrackVolume(b, _)) => a == b,
+        (MainToAudio::ModuleGain(a, _), MainToAudio::ModuleGain(b, _)) => a == b,
+        (MainToAudio::ModuleMix(a, _), MainToAudio::ModuleMix(b, _)) => a == b,
+        // 音量やパンを動かしているとき
+        (MainToAudio::ModuleAuxOutputs(a, xs), MainToAudio::ModuleAuxOutputs(b, ys)) => {
+            a == b
+                && xs.len() == ys.len()
+                && xs.iter().zip(ys).all(|(x, y)| x.port_index == y.port_index)
+        }
+        _ => false,
+    }
+}
+
+fn description(message: &MainToAudio) -> String {
+    match message {
+        MainToAudio::Bpm(bpm) => format!("BPM {}", bpm),
+        MainToAudio::TimeSignature(numerator, denominator) => {
+            format!("Time Signature {}/{}", numerator, denominator)
         }
+        MainToAudio::Channels(nchannels) => format!("Channels {}", nchannels),
+        MainToAudio::Process64(process_64_p) => {
+            format!(
+                "64-bit Processing {}",
+                if *process_64_p { "On" } else { "Off" }
+            )
+        }
+        MainToAudio::LaneAdd(track_index) => format!("Add Lane to Track {}", track_index),
+        MainToAudio::LaneDelete(track_index) => format!("Delete Lane of Track {}", track_index),
+        MainToAudio::LaneItem(items) => {
+            let ndeletes = items.iter().filter(|(_, item)| item.is_none()).count();
+            if ndeletes == items.len() {
+                format!("Delete {} Items", ndeletes)
+            } else {
+                format!("Edit {} Items", items.len())
+            }
+        }
+        MainToAudio::ModuleAudioInputs(..) | MainToAudio::PluginSidechain(..) => {
+            "Sidechain".to_string()
+        }
+        MainToAudio::ModuleAuxOutputs(..) => "Aux Outputs".to_string(),
+        MainToAudio::ModuleBypass(_, bypass_p) => {
+            if *bypass_p { "Bypass" } else { "Unbypass" }.to_string()
+        }
+        MainToAudio::ModuleGain(..) => "Module Gain".to_string(),
+        MainToAudio::ModuleInsert(_, module) => format!("Insert {}", module.name),
+        MainToAudio::ModuleMix(..) => "Dry/Wet".to_string(),
+        MainToAudio::ModuleMove(..) => "Move Module".to_string(),
+        MainToAudio::ModuleRename(_, name) => format!("Rename Module to {}", name),
+        MainToAudio::PluginLoad(_, _, name) => format!("Load {}", name),
+        MainToAudio::PluginDelete(_) => "Delete Module".to_string(),
+        MainToAudio::PointNew(..) => "New Automation Point".to_string(),
+        MainToAudio::TrackAdd => "Add Track".to_string(),
+        MainToAudio::TrackAutomationParams(..) => "Automation Params".to_string(),
+        MainToAudio::TrackDelete(track_index) => format!("Delete Track {}", track_index),
+        MainToAudio::TrackFreeze(_) | MainToAudio::TrackFreezeSet(_, Some(_)) => {
+            "Freeze Track".to_string()
+        }
+        MainToAudio::TrackFreezeSet(_, None) => "Unfreeze Track".to_string(),
+        MainToAudio::TrackInsert(_, track) => format!("Insert Track {}", track.name),
+        MainToAudio::TrackMove(..) => "Move Track".to_string(),
+        MainToAudio::TrackMidiOutput(..) => "MIDI Output".to_string(),
+        MainToAudio::TrackMute(_, mute) => if *mute { "Mute" } else { "Unmute" }.to_string(),
+        MainToAudio::TrackSolo(_, solo) => if *solo { "Solo" } else { "Unsolo" }.to_string(),
+        MainToAudio::TrackPan(..) => "Pan".to_string(),
+        MainToAudio::TrackRename(_, name) => format!("Rename Track to {}", name),
+        MainToAudio::TrackVolume(..) => "Volume".to_string(),
+        // 再生などは履歴に載らない
+        _ => "Edit".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_only_within_window() {
+        let mut undo_history = UndoHistory::new();
+        for volume in [0.1, 0.2, 0.3] {
+            undo_history.add(
+                vec![MainToAudio::TrackVolume(1, 0.0)],
+                MainToAudio::TrackVolume(1, volume),
+            );
+        }
+        assert_eq!(undo_history.position(), 1);
+
+        // 間が空いたら別の undo にする
+        undo_history.undos[0].time -= MERGE_WINDOW;
+        undo_history.add(
+            vec![MainToAudio::TrackVolume(1, 0.3)],
+            MainToAudio::TrackVolume(1, 0.4),
+        );
+        assert_eq!(undo_history.position(), 2);
+        assert!(matches!(
+            undo_history.undos[0].redos[..],
+            [MainToAudio::TrackVolume(1, 0.3)]
+        ));
     }
 }
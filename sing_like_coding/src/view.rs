@@ -3,11 +3,15 @@ mod db_slider;
 mod eval_window;
 mod knob;
 pub mod main_view;
+mod midi_import_window;
 pub mod param_select_view;
+mod plugin_process_window;
 pub mod plugin_select_view;
 pub mod root_view;
 pub mod select_view;
 mod shortcut_key;
 pub mod sidechain_select_view;
+mod size_report_window;
 pub mod stereo_peak_meter;
+mod undo_history_window;
 mod util;
@@ -3,7 +3,10 @@ use std::{collections::HashMap, ops::Range, path::PathBuf};
 use anyhow::Result;
 use common::{
     dsp::{db_from_norm, db_to_norm},
-    protocol::MainToPlugin,
+    module::{
+        MODULE_BYPASS_PARAM_ID, MODULE_GAIN_DB_MAX, MODULE_GAIN_DB_MIN, MODULE_GAIN_PARAM_ID,
+        MODULE_MIX_PARAM_ID,
+    },
 };
 use eframe::egui::{
     self, text::LayoutJob, CentralPanel, Color32, DragValue, DroppedFile, FontId, Key, Label,
@@ -16,8 +19,11 @@ use crate::{
         UiCommand,
     },
     device::Device,
-    model::lane_item::LaneItem,
-    util::with_font_mono,
+    model::{
+        lane_item::LaneItem,
+        track::{MIDI_CC_MODULE_INDEX, MIDI_PITCH_BEND_PARAM_ID},
+    },
+    util::{with_font_mono, with_font_mono_result},
 };
 
 use super::{
@@ -357,6 +363,18 @@ impl MainView {
                 (Modifier::None, Key::L),
                 UiCommand::Module(ModuleCommand::CursorRight),
             ),
+            (
+                (Modifier::C, Key::K),
+                UiCommand::Module(ModuleCommand::MoveUp),
+            ),
+            (
+                (Modifier::C, Key::J),
+                UiCommand::Module(ModuleCommand::MoveDown),
+            ),
+            (
+                (Modifier::None, Key::B),
+                UiCommand::Module(ModuleCommand::Bypass),
+            ),
             (
                 (Modifier::None, Key::C),
                 UiCommand::Module(ModuleCommand::Sidechain),
@@ -545,6 +563,12 @@ impl MainView {
                 ));
                 ui.label(format!("{:.3}%", state.song_state.cpu_usage * 100.0));
                 ui.label(format!("{:.1}fps", 1.0 / state.elapsed));
+                if state.song_state.events_dropped > 0 {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        format!("{} events dropped", state.song_state.events_dropped),
+                    );
+                }
                 Ok(())
             });
         });
@@ -722,6 +746,20 @@ impl MainView {
                     .automation_params
                     .get(point.automation_params_index)
                     .map(|(module_index, param_id)| {
+                        if *module_index == MIDI_CC_MODULE_INDEX
+                            && *param_id == MIDI_PITCH_BEND_PARAM_ID
+                        {
+                            return "PB ".to_string();
+                        }
+                        if *module_index == MIDI_CC_MODULE_INDEX {
+                            return format!("C{:02X}", param_id % 0x100);
+                        }
+                        match *param_id {
+                            MODULE_BYPASS_PARAM_ID => return format!("{:x}By", module_index),
+                            MODULE_MIX_PARAM_ID => return format!("{:x}Mx", module_index),
+                            MODULE_GAIN_PARAM_ID => return format!("{:x}Gn", module_index),
+                            _ => {}
+                        }
                         // 8桁あるけど表示スペースがないので下2桁だけ表示
                         format!("{:x}{:X}", module_index, param_id % 0x100)
                     })
@@ -735,6 +773,8 @@ impl MainView {
 
             Some(LaneItem::Ret) => "^        ".to_string(),
 
+            Some(LaneItem::AudioClip(clip)) => format!("~{:<8.8}", clip.name()),
+
             None => "         ".to_string(),
         }
     }
@@ -838,6 +878,16 @@ impl MainView {
                     commands.push(UiCommand::TrackRecOff(track_index));
                 }
             }
+            ui.horizontal(|ui| {
+                let mut input_p = state.song_state.tracks[track_index].input_p;
+                if ui.toggle_value(&mut input_p, "IN").clicked() {
+                    commands.push(UiCommand::TrackInput(track_index, input_p));
+                }
+                let mut monitor_p = state.song_state.tracks[track_index].monitor_p;
+                if ui.toggle_value(&mut monitor_p, "MON").clicked() {
+                    commands.push(UiCommand::TrackMonitor(track_index, monitor_p));
+                }
+            });
 
             ui.horizontal(|ui| -> anyhow::Result<()> {
                 let height = 160.0;
@@ -958,6 +1008,21 @@ impl MainView {
         module_index: usize,
     ) -> anyhow::Result<()> {
         let module = &state.song.tracks[track_index].modules[module_index];
+        let mut bypass_p = module.bypass_p;
+        let mut mix = module.mix * 100.0;
+        let mut gain = module.gain;
+        let aux_outputs = module.aux_outputs.clone();
+        let plugin_id = module.plugin_id.clone();
+        let output_ports = state
+            .module_output_ports
+            .get(&module.id)
+            .cloned()
+            .unwrap_or_default();
+        // song_state の aux_peaks はトラックのモジュール順
+        let aux_peak_offset = state.song.tracks[track_index].modules[..module_index]
+            .iter()
+            .map(|module| module.aux_outputs.len())
+            .sum::<usize>();
         let (color, bg_color) = if state.cursor_track.track == track_index
             && state.cursor_module.index == module_index
             && state.focused_part == FocusedPart::Module
@@ -966,20 +1031,129 @@ impl MainView {
         } else {
             (Color32::GRAY, Color32::BLACK)
         };
+        // 止まっているモジュールは無音になっている
+        let color = if state.module_fault_p((track_index, module_index)) {
+            ui.visuals().error_fg_color
+        } else {
+            color
+        };
         let label = LabelBuilder::new(ui, &module.name)
             .color(color)
             .bg_color(bg_color)
             .size([DEFAULT_TRACK_WIDTH, 0.0])
             .build();
         if label.clicked() {
-            state.send_to_plugin(MainToPlugin::GuiOpen(module.id), Box::new(|_, _| Ok(())))?;
+            state.module_gui_open(module.id)?;
         }
         label.context_menu(|ui: &mut Ui| {
             if ui.button("Delete").clicked() {
                 state.plugin_delete((track_index, module_index)).unwrap();
                 ui.close();
             }
+            let mut isolated_p = state.plugin_isolated_p(&plugin_id);
+            if ui.checkbox(&mut isolated_p, "Own Process").clicked() {
+                state
+                    .plugin_isolate_toggle((track_index, module_index))
+                    .unwrap();
+                ui.close();
+            }
+            for (port_index, name) in output_ports.iter().enumerate().skip(1) {
+                let mut aux_p = aux_outputs.iter().any(|x| x.port_index == port_index);
+                if ui
+                    .checkbox(&mut aux_p, format!("Aux {} {}", port_index, name))
+                    .clicked()
+                {
+                    state
+                        .module_aux_output_toggle((track_index, module_index), port_index)
+                        .unwrap();
+                }
+            }
         });
+
+        ui.horizontal(|ui| -> anyhow::Result<()> {
+            ui.spacing_mut().item_spacing.x = 2.0;
+            with_font_mono_result(ui, |ui| {
+                if ui.toggle_value(&mut bypass_p, "B").clicked() {
+                    state.module_bypass_set((track_index, module_index), bypass_p)?;
+                }
+                anyhow::Ok(())
+            })?;
+            let response = ui.add(
+                DragValue::new(&mut mix)
+                    .speed(0.5)
+                    .range(0.0..=100.0)
+                    .max_decimals(0),
+            );
+            if response.changed() {
+                state.module_mix_set((track_index, module_index), mix / 100.0)?;
+            } else if response.double_clicked() {
+                state.module_mix_set((track_index, module_index), 1.0)?;
+            }
+            let response = ui.add(
+                DragValue::new(&mut gain)
+                    .speed(0.1)
+                    .range(MODULE_GAIN_DB_MIN..=MODULE_GAIN_DB_MAX)
+                    .max_decimals(1),
+            );
+            if response.changed() {
+                state.module_gain_set((track_index, module_index), gain)?;
+            } else if response.double_clicked() {
+                state.module_gain_set((track_index, module_index), 0.0)?;
+            }
+            Ok(())
+        });
+
+        for (aux_index, aux_output) in aux_outputs.iter().enumerate() {
+            let mut aux_output_new = aux_output.clone();
+            ui.horizontal(|ui| -> anyhow::Result<()> {
+                ui.spacing_mut().item_spacing.x = 2.0;
+                let name = output_ports
+                    .get(aux_output.port_index)
+                    .cloned()
+                    .unwrap_or_default();
+                ui.label(format!("{}", aux_output.port_index))
+                    .on_hover_text(name);
+                with_font_mono(ui, |ui| {
+                    ui.toggle_value(&mut aux_output_new.mute, "M");
+                });
+                let response = ui.add(
+                    DragValue::new(&mut aux_output_new.volume)
+                        .speed(0.1)
+                        .range(MODULE_GAIN_DB_MIN..=MODULE_GAIN_DB_MAX)
+                        .max_decimals(1),
+                );
+                if response.double_clicked() {
+                    aux_output_new.volume = 0.0;
+                }
+                let mut pan = (aux_output.pan - 0.5) * 200.0;
+                let response = ui.add(
+                    DragValue::new(&mut pan)
+                        .speed(1.0)
+                        .range(-100.0..=100.0)
+                        .max_decimals(0),
+                );
+                if response.changed() {
+                    aux_output_new.pan = pan / 200.0 + 0.5;
+                } else if response.double_clicked() {
+                    aux_output_new.pan = 0.5;
+                }
+                let peak = state.song_state.tracks[track_index]
+                    .aux_peaks
+                    .get(aux_peak_offset + aux_index)
+                    .map_or(DB_MIN, |peaks| peaks[0].max(peaks[1]));
+                ui.label(if peak <= DB_MIN {
+                    "-inf".to_string()
+                } else {
+                    format!("{:.0}", peak)
+                });
+                Ok(())
+            });
+            if aux_output_new != *aux_output {
+                let mut aux_outputs = aux_outputs.clone();
+                aux_outputs[aux_index] = aux_output_new;
+                state.module_aux_outputs_set((track_index, module_index), aux_outputs)?;
+            }
+        }
         Ok(())
     }
 
@@ -990,6 +1164,12 @@ impl MainView {
         track_index: usize,
     ) -> Result<()> {
         let inner = ui.vertical(|ui| -> anyhow::Result<()> {
+            if state.song.tracks[track_index].freeze.is_some() {
+                LabelBuilder::new(ui, "Frozen")
+                    .color(Color32::LIGHT_BLUE)
+                    .size([DEFAULT_TRACK_WIDTH, 0.0])
+                    .build();
+            }
             for module_index in 0..state.song.tracks[track_index].modules.len() {
                 self.view_module(state, ui, track_index, module_index)?;
             }
@@ -1142,6 +1322,12 @@ impl MainView {
                             state.cursor_track.lane,
                             &path,
                         )?;
+                    } else if path
+                        .extension()
+                        .and_then(|ext| ext.to_str())
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+                    {
+                        state.audio_clip_insert(path)?;
                     }
                 }
             }
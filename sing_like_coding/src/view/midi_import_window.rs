@@ -0,0 +1,90 @@
+use anyhow::Result;
+use eframe::egui::{Align2, Context, Grid, Key, Window};
+
+use crate::app_state::AppState;
+
+pub struct MidiImportWindow {}
+
+impl MidiImportWindow {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn view(&mut self, ctx: &Context, state: &mut AppState) -> Result<()> {
+        let mut import_p = false;
+        let mut cancel_p = false;
+        let Some(midi_import) = state.midi_import.as_mut() else {
+            return Ok(());
+        };
+
+        Window::new("MIDI Import")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(midi_import.path.display().to_string());
+                ui.label(&midi_import.timing);
+                ui.label(match midi_import.bpm {
+                    Some(bpm) => format!("Tempo {:.2}", bpm),
+                    None => "Tempo -".to_string(),
+                });
+                ui.label(match midi_import.time_signature {
+                    Some((numerator, denominator)) => {
+                        format!("Time signature {}/{}", numerator, denominator)
+                    }
+                    None => "Time signature -".to_string(),
+                });
+
+                ui.separator();
+                Grid::new("midi_import_tracks")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        ui.label("Track");
+                        ui.label("Notes");
+                        ui.label("CC");
+                        ui.end_row();
+                        for track in midi_import.tracks.iter() {
+                            ui.label(&track.name);
+                            ui.label(track.nnotes().to_string());
+                            ui.label(track.ncontrollers().to_string());
+                            ui.end_row();
+                        }
+                    });
+
+                for warning in midi_import.warnings.iter() {
+                    ui.colored_label(ui.visuals().warn_fg_color, warning);
+                }
+
+                ui.separator();
+                ui.checkbox(
+                    &mut midi_import.tracks_new_p,
+                    "Create a track for each MIDI track",
+                );
+                ui.checkbox(&mut midi_import.tempo_p, "Set tempo and time signature");
+                ui.checkbox(&mut midi_import.cc_p, "Import CC and pitch bend");
+
+                ui.horizontal(|ui| {
+                    if ui.button("Import").clicked() {
+                        import_p = true;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        cancel_p = true;
+                    }
+                });
+
+                if ui.input(|i| i.key_pressed(Key::Enter)) {
+                    import_p = true;
+                }
+                if ui.input(|i| i.key_pressed(Key::Escape)) {
+                    cancel_p = true;
+                }
+            });
+
+        if import_p {
+            state.midi_import_apply()?;
+        } else if cancel_p {
+            state.midi_import = None;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use eframe::egui::{Align2, Context, Grid, Key, Window};
+
+use crate::app_state::AppState;
+
+use super::size_report_window::size_format;
+
+pub struct PluginProcessWindow {}
+
+impl PluginProcessWindow {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn view(&mut self, ctx: &Context, state: &mut AppState) -> Result<()> {
+        let plugin_processes = state.plugin_processes.lock().unwrap().clone();
+        let mut close_p = false;
+
+        Window::new("Plugin Processes")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                Grid::new("plugin_processes").striped(true).show(ui, |ui| {
+                    ui.label("PID");
+                    ui.label("Memory");
+                    ui.label("CPU");
+                    ui.label("Modules");
+                    ui.end_row();
+                    for process in plugin_processes.iter() {
+                        let names = process
+                            .module_ids
+                            .iter()
+                            .filter_map(|id| {
+                                state
+                                    .song
+                                    .tracks
+                                    .iter()
+                                    .flat_map(|track| track.modules.iter())
+                                    .find(|module| module.id == *id)
+                                    .map(|module| module.name.clone())
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        ui.label(process.pid.to_string());
+                        ui.label(size_format(process.memory));
+                        ui.label(format!("{:.1}%", process.cpu_usage * 100.0));
+                        if process.shared_p {
+                            ui.label(format!("(shared) {}", names));
+                        } else {
+                            ui.label(names);
+                        }
+                        ui.end_row();
+                    }
+                });
+                ui.separator();
+                if ui.button("Close").clicked()
+                    || ui.input(|i| i.key_pressed(Key::Escape) || i.key_pressed(Key::Enter))
+                {
+                    close_p = true;
+                }
+            });
+
+        if close_p {
+            state.plugin_process_window_open_p = false;
+        }
+        Ok(())
+    }
+}
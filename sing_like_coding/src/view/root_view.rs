@@ -1,14 +1,14 @@
+use std::{fs::read_dir, path::PathBuf};
+
 use anyhow::Result;
-use common::{
-    module::AudioInput,
-    protocol::{MainToPlugin, PluginToMain},
-};
+use common::module::AudioInput;
 use eframe::egui::{ahash::HashMap, Align2, Key, TextEdit, Window};
 
 use crate::{
-    app_state::{AppState, UiCommand},
+    app_state::{preset_directory, template_directory, AppState, UiCommand},
+    audio_input_device::AudioInputDevice,
     device::Device,
-    midi_device::MidiDevice,
+    midi_device::{MidiDevice, MidiOutputDevice},
     view::param_select_view::ReturnState,
 };
 
@@ -16,22 +16,32 @@ use super::{
     command_view::CommandView,
     eval_window::EvalWindow,
     main_view::MainView,
+    midi_import_window::MidiImportWindow,
     param_select_view::ParamSelectView,
+    plugin_process_window::PluginProcessWindow,
     plugin_select_view::{self, PluginSelectView},
     select_view::{self, SelectItem, SelectView},
     shortcut_key::{shortcut_key, Modifier},
     sidechain_select_view::{self, SidechainSelectView},
+    size_report_window::SizeReportWindow,
+    undo_history_window::UndoHistoryWindow,
     util::select_all_text,
 };
 
 #[derive(Debug)]
 pub enum Route {
     Track,
+    AudioDeviceInputSelect,
     Command,
+    MidiClockOutputSelect,
     MidiDeviceInputSelect,
+    MidiDeviceOutputSelect,
     PluginSelect,
     ParamSelect,
+    RecentProjectSelect,
     SidechainSelect,
+    TemplateSelect,
+    TrackPresetSelect,
 }
 
 #[derive(Clone)]
@@ -45,12 +55,43 @@ impl SelectItem for MidiPort {
     }
 }
 
+#[derive(Clone)]
+struct FileItem {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl SelectItem for FileItem {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Clone)]
+struct RecentProject {
+    pub song_file: String,
+}
+
+impl SelectItem for RecentProject {
+    fn name(&self) -> &str {
+        &self.song_file
+    }
+}
+
 pub struct RootView {
     eval_window: EvalWindow,
     shortcut_map: HashMap<(Modifier, Key), UiCommand>,
     main_view: MainView,
+    midi_import_window: MidiImportWindow,
+    plugin_process_window: PluginProcessWindow,
+    size_report_window: SizeReportWindow,
+    undo_history_window: UndoHistoryWindow,
     command_view: CommandView,
+    audio_device_input_select_view: Option<SelectView<MidiPort>>,
     midi_device_input_select_view: Option<SelectView<MidiPort>>,
+    midi_device_output_select_view: Option<SelectView<MidiPort>>,
+    recent_project_select_view: Option<SelectView<RecentProject>>,
+    file_select_view: Option<SelectView<FileItem>>,
     param_select_view: Option<ParamSelectView>,
     plugin_select_view: Option<PluginSelectView>,
     sidechain_select_view: Option<SidechainSelectView>,
@@ -73,8 +114,16 @@ impl RootView {
             eval_window: EvalWindow::new(),
             shortcut_map,
             main_view: MainView::new(),
+            midi_import_window: MidiImportWindow::new(),
+            plugin_process_window: PluginProcessWindow::new(),
+            size_report_window: SizeReportWindow::new(),
+            undo_history_window: UndoHistoryWindow::new(),
             command_view: CommandView::new(),
+            audio_device_input_select_view: None,
             midi_device_input_select_view: None,
+            midi_device_output_select_view: None,
+            recent_project_select_view: None,
+            file_select_view: None,
             param_select_view: None,
             plugin_select_view: None,
             sidechain_select_view: None,
@@ -96,23 +145,75 @@ impl RootView {
         if state.rename_target.is_some() {
             self.view_rename_window(gui_context, state)?;
         }
+        if state.midi_import.is_some() {
+            self.midi_import_window.view(gui_context, state)?;
+        }
+        if state.size_report.is_some() {
+            self.size_report_window.view(gui_context, state)?;
+        }
+        if state.undo_history.is_some() {
+            self.undo_history_window.view(gui_context, state)?;
+        }
+        if state.plugin_process_window_open_p {
+            self.plugin_process_window.view(gui_context, state)?;
+        }
 
         state.receive_from_communicator()?;
 
         match &state.route {
             Route::Track => self.main_view.view(gui_context, state, device)?,
+            Route::AudioDeviceInputSelect => {
+                self.audio_device_input_select_view(gui_context, state)?
+            }
             Route::Command => self.command_view.view(gui_context, state)?,
             Route::MidiDeviceInputSelect => {
                 self.midi_device_input_select_view(gui_context, state)?
             }
+            Route::MidiClockOutputSelect => {
+                self.midi_device_output_select_view(gui_context, state, true)?
+            }
+            Route::MidiDeviceOutputSelect => {
+                self.midi_device_output_select_view(gui_context, state, false)?
+            }
             Route::ParamSelect => self.param_select_view(gui_context, state)?,
             Route::PluginSelect => self.plugin_select_view(gui_context, state)?,
+            Route::RecentProjectSelect => self.recent_project_select_view(gui_context, state)?,
             Route::SidechainSelect => self.sidechain_select_view(gui_context, state)?,
+            Route::TemplateSelect => self.file_select_view(gui_context, state, true)?,
+            Route::TrackPresetSelect => self.file_select_view(gui_context, state, false)?,
         }
 
         Ok(())
     }
 
+    fn audio_device_input_select_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+    ) -> Result<()> {
+        let view = self.audio_device_input_select_view.get_or_insert_with(|| {
+            let items = AudioInputDevice::list()
+                .into_iter()
+                .map(|name| MidiPort { name })
+                .collect();
+            SelectView::<MidiPort>::new(items)
+        });
+
+        match view.view(gui_context)? {
+            select_view::ReturnState::Selected(item) => {
+                state.audio_device_input_open(&item.name)?;
+                self.audio_device_input_select_view = None;
+                state.route = Route::Track;
+            }
+            select_view::ReturnState::Continue => {}
+            select_view::ReturnState::Cancel => {
+                self.audio_device_input_select_view = None;
+                state.route = Route::Track;
+            }
+        }
+        Ok(())
+    }
+
     fn midi_device_input_select_view(
         &mut self,
         gui_context: &eframe::egui::Context,
@@ -141,6 +242,124 @@ impl RootView {
         Ok(())
     }
 
+    fn midi_device_output_select_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+        clock_p: bool,
+    ) -> Result<()> {
+        let view = self.midi_device_output_select_view.get_or_insert_with(|| {
+            // 先頭は出力なし
+            let items = std::iter::once("None".to_string())
+                .chain(MidiOutputDevice::list())
+                .map(|name| MidiPort { name })
+                .collect();
+            SelectView::<MidiPort>::new(items)
+        });
+
+        match view.view(gui_context)? {
+            select_view::ReturnState::Selected(item) => {
+                let midi_output = if item.name == "None" {
+                    None
+                } else {
+                    Some(item.name.clone())
+                };
+                if clock_p {
+                    state.midi_clock_output_set(midi_output)?;
+                } else {
+                    state.track_midi_output_set(midi_output)?;
+                }
+                self.midi_device_output_select_view = None;
+                state.route = Route::Track;
+            }
+            select_view::ReturnState::Continue => {}
+            select_view::ReturnState::Cancel => {
+                self.midi_device_output_select_view = None;
+                state.route = Route::Track;
+            }
+        }
+        Ok(())
+    }
+
+    fn file_select_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+        template_p: bool,
+    ) -> Result<()> {
+        let view = self.file_select_view.get_or_insert_with(|| {
+            let dir = if template_p {
+                template_directory()
+            } else {
+                preset_directory()
+            };
+            let mut items = read_dir(dir)
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| entry.ok().map(|x| x.path()))
+                        .filter(|path| path.extension().is_some_and(|x| x == "json"))
+                        .map(|path| FileItem {
+                            name: path.file_stem().unwrap().to_string_lossy().to_string(),
+                            path,
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            items.sort_by(|a, b| a.name.cmp(&b.name));
+            SelectView::<FileItem>::new(items)
+        });
+
+        match view.view(gui_context)? {
+            select_view::ReturnState::Selected(item) => {
+                self.file_select_view = None;
+                state.route = Route::Track;
+                if template_p {
+                    state.song_new_from_template(item.path.to_string_lossy().to_string())?;
+                } else {
+                    state.track_preset_insert(&item.path)?;
+                }
+            }
+            select_view::ReturnState::Continue => {}
+            select_view::ReturnState::Cancel => {
+                self.file_select_view = None;
+                state.route = Route::Track;
+            }
+        }
+        Ok(())
+    }
+
+    fn recent_project_select_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+    ) -> Result<()> {
+        let view = self.recent_project_select_view.get_or_insert_with(|| {
+            let items = state
+                .config
+                .recent_projects
+                .iter()
+                .map(|song_file| RecentProject {
+                    song_file: song_file.clone(),
+                })
+                .collect();
+            SelectView::<RecentProject>::new(items)
+        });
+
+        match view.view(gui_context)? {
+            select_view::ReturnState::Selected(item) => {
+                self.recent_project_select_view = None;
+                state.route = Route::Track;
+                state.song_open_path(item.song_file)?;
+            }
+            select_view::ReturnState::Continue => {}
+            select_view::ReturnState::Cancel => {
+                self.recent_project_select_view = None;
+                state.route = Route::Track;
+            }
+        }
+        Ok(())
+    }
+
     fn param_select_view(
         &mut self,
         gui_context: &eframe::egui::Context,
@@ -168,23 +387,12 @@ impl RootView {
                     state.param_set(module_index, param.id)?;
                 }
                 ReturnState::Params(module_index) => {
-                    let callback: Box<dyn Fn(&mut AppState, PluginToMain) -> Result<()>> =
-                        Box::new(|state, command| {
-                            if let PluginToMain::DidParams(params) = command {
-                                state.param_select_view_params = params;
-                            }
-                            Ok(())
-                        });
-                    state.send_to_plugin(
-                        MainToPlugin::Params(
-                            state
-                                .song
-                                .module_at((state.cursor_track.track, module_index))
-                                .unwrap()
-                                .id,
-                        ),
-                        callback,
-                    )?;
+                    let module_id = state
+                        .song
+                        .module_at((state.cursor_track.track, module_index))
+                        .unwrap()
+                        .id;
+                    state.param_select_view_params_fetch(module_id)?;
                 }
                 ReturnState::Continue => {}
                 ReturnState::Cancel => {
@@ -247,6 +455,7 @@ impl RootView {
         let view = self.sidechain_select_view.get_or_insert_with(|| {
             let cursor_track_index = state.cursor_track.track;
             let cursor_module_index = state.cursor_module.index;
+            let output_ports = &state.module_output_ports;
             let items = state
                 .song
                 .tracks
@@ -260,9 +469,29 @@ impl RootView {
                         .filter(move |(module_index, _module)| {
                             track_index != cursor_track_index || *module_index < cursor_module_index
                         })
-                        .map(move |(module_index, module)| sidechain_select_view::Item {
-                            name: format!("{} {}", track.name, module.name),
-                            module_index: (track_index, module_index),
+                        .flat_map(move |(module_index, module)| {
+                            // 複数出力のプラグインは 2 番目以降のポートも選べる
+                            let nports = output_ports
+                                .get(&module.id)
+                                .map_or(1, |names| names.len().max(1));
+                            (0..nports).map(move |port_index| {
+                                let name = if port_index == 0 {
+                                    format!("{} {}", track.name, module.name)
+                                } else {
+                                    format!(
+                                        "{} {} #{} {}",
+                                        track.name,
+                                        module.name,
+                                        port_index,
+                                        output_ports[&module.id][port_index]
+                                    )
+                                };
+                                sidechain_select_view::Item {
+                                    name,
+                                    module_index: (track_index, module_index),
+                                    port_index,
+                                }
+                            })
                         })
                 })
                 .collect();
@@ -273,7 +502,7 @@ impl RootView {
             sidechain_select_view::ReturnState::Selected(item) => {
                 let audio_input = AudioInput {
                     src_module_index: item.module_index,
-                    src_port_index: 0,
+                    src_port_index: item.port_index,
                     dst_port_index: 1,
                 };
                 state.plugin_sidechain(state.module_index_at_cursor(), audio_input)?;
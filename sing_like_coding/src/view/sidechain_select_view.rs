@@ -8,6 +8,7 @@ use crate::util::is_subsequence_case_insensitive;
 pub struct Item {
     pub name: String,
     pub module_index: ModuleIndex,
+    pub port_index: usize,
 }
 
 pub struct SidechainSelectView {
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use eframe::egui::{Align2, Context, Grid, Key, Window};
+
+use crate::app_state::AppState;
+
+pub struct SizeReportWindow {}
+
+impl SizeReportWindow {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn view(&mut self, ctx: &Context, state: &mut AppState) -> Result<()> {
+        let Some(size_report) = &state.size_report else {
+            return Ok(());
+        };
+        let mut close_p = false;
+
+        Window::new("Song Size Report")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!("Song file {}", size_format(size_report.song_size)));
+                ui.label(format!(
+                    "Plugin states {} ({} in file)",
+                    size_format(size_report.states_size()),
+                    size_format(size_report.states_size_in_file()),
+                ));
+                ui.separator();
+                Grid::new("size_report").striped(true).show(ui, |ui| {
+                    ui.label("Track");
+                    ui.label("Module");
+                    ui.label("State");
+                    ui.label("In file");
+                    ui.end_row();
+                    for item in size_report.items.iter() {
+                        ui.label(&item.track_name);
+                        ui.label(&item.module_name);
+                        ui.label(size_format(item.state_size));
+                        ui.label(size_format(item.state_size_in_file));
+                        ui.end_row();
+                    }
+                });
+                ui.separator();
+                if ui.button("Close").clicked()
+                    || ui.input(|i| i.key_pressed(Key::Escape) || i.key_pressed(Key::Enter))
+                {
+                    close_p = true;
+                }
+            });
+
+        if close_p {
+            state.size_report = None;
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn size_format(size: usize) -> String {
+    if size >= 1024 * 1024 {
+        format!("{:.1} MB", size as f64 / (1024.0 * 1024.0))
+    } else if size >= 1024 {
+        format!("{:.1} KB", size as f64 / 1024.0)
+    } else {
+        format!("{} B", size)
+    }
+}
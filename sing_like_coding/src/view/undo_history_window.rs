@@ -0,0 +1,69 @@
+use anyhow::Result;
+use eframe::egui::{Align2, Context, Key, ScrollArea, Window};
+
+use crate::app_state::AppState;
+
+pub struct UndoHistoryWindow {}
+
+impl UndoHistoryWindow {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn view(&mut self, ctx: &Context, state: &mut AppState) -> Result<()> {
+        let Some(undo_history) = &state.undo_history else {
+            return Ok(());
+        };
+        let mut position_next = None;
+        let mut close_p = false;
+
+        Window::new("Undo History")
+            .collapsible(false)
+            .anchor(Align2::RIGHT_TOP, [-10.0, 10.0])
+            .show(ctx, |ui| {
+                ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                    // 0 は何もしていない状態
+                    if ui
+                        .selectable_label(undo_history.position == 0, "(Initial)")
+                        .clicked()
+                    {
+                        position_next = Some(0);
+                    }
+                    for (index, entry) in undo_history.entries.iter().enumerate() {
+                        let position = index + 1;
+                        let text = if position <= undo_history.position {
+                            entry.description.clone()
+                        } else {
+                            // redo できるもの
+                            format!("({})", entry.description)
+                        };
+                        // undo/redo で戻る場所
+                        let cursor = entry.context.cursor_track;
+                        let response = ui
+                            .selectable_label(position == undo_history.position, text)
+                            .on_hover_text(format!("Track {} Line {}", cursor.track, cursor.line));
+                        if position == undo_history.position {
+                            response.scroll_to_me(None);
+                        }
+                        if response.clicked() {
+                            position_next = Some(position);
+                        }
+                    }
+                });
+                ui.separator();
+                if ui.button("Close").clicked() || ui.input(|i| i.key_pressed(Key::Escape)) {
+                    close_p = true;
+                }
+            });
+
+        if let Some(position) = position_next
+            && position != undo_history.position
+        {
+            state.undo_jump(position)?;
+        }
+        if close_p {
+            state.undo_history = None;
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,23 @@
 use std::sync::mpsc::channel;
-use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 
+use common::protocol::Envelope;
 use common::protocol::MainToPlugin;
 use common::protocol::PluginToMain;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
 
 use crate::communicator::Communicator;
 use crate::manager::Manager;
 
 pub fn main() {
     let (sender_to_loop, receiver_from_main) = channel();
-    let (sender_to_main, receiver_from_loop) = channel();
-    let mut plugin_host = Manager::new(sender_to_loop, receiver_from_loop).unwrap();
+    let (sender_to_main, receiver_from_loop) = unbounded_channel();
+    let mut plugin_host = Manager::new(sender_to_main, receiver_from_main).unwrap();
     log::debug!("$$$$$$$ before thread::spawn");
     tokio::spawn(async move {
         log::debug!("$$$$$$$ before receive_from_main_process");
-        receive_from_main_process(sender_to_main, receiver_from_main)
+        receive_from_main_process(sender_to_loop, receiver_from_loop)
             .await
             .unwrap();
     });
@@ -23,10 +25,10 @@ pub fn main() {
 }
 
 async fn receive_from_main_process(
-    sender_to_main: Sender<MainToPlugin>,
-    receiver_from_main: Receiver<PluginToMain>,
+    sender_to_loop: Sender<Envelope<MainToPlugin>>,
+    receiver_from_loop: UnboundedReceiver<Envelope<PluginToMain>>,
 ) -> anyhow::Result<()> {
-    let mut main_comminicator = Communicator::new(sender_to_main, receiver_from_main).await?;
+    let main_comminicator = Communicator::new(sender_to_loop, receiver_from_loop).await?;
     main_comminicator.run().await?;
 
     Ok(())
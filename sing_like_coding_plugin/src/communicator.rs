@@ -1,43 +1,65 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
 
 use common::{
-    protocol::{receive, send, MainToPlugin, PluginToMain},
-    PIPE_CTRL_NAME,
+    ipc::{ControlClient, ControlConnect},
+    protocol::{handshake, receive, send, Envelope, MainToPlugin, PluginToMain},
 };
-use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 pub struct Communicator {
-    pipe: NamedPipeClient,
-    sender_to_main: Sender<MainToPlugin>,
-    receiver_from_main: Receiver<PluginToMain>,
+    pipe: ControlClient,
+    sender_to_loop: Sender<Envelope<MainToPlugin>>,
+    receiver_from_loop: UnboundedReceiver<Envelope<PluginToMain>>,
 }
 
 impl Communicator {
     pub async fn new(
-        sender_to_main: Sender<MainToPlugin>,
-        receiver_from_main: Receiver<PluginToMain>,
+        sender_to_loop: Sender<Envelope<MainToPlugin>>,
+        receiver_from_loop: UnboundedReceiver<Envelope<PluginToMain>>,
     ) -> anyhow::Result<Self> {
-        let pipe = ClientOptions::new().open(PIPE_CTRL_NAME)?;
+        let mut pipe = ControlClient::control_connect().await?;
+        handshake(&mut pipe).await?;
 
         Ok(Self {
             pipe,
-            sender_to_main,
-            receiver_from_main,
+            sender_to_loop,
+            receiver_from_loop,
         })
     }
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        loop {
-            let message: MainToPlugin = receive(&mut self.pipe).await?;
-            // log::debug!("$$$$ DID RECEIVE {:?}", message);
-            self.sender_to_main.send(message)?;
-            let message = self.receiver_from_main.recv()?;
-            send(&mut self.pipe, &message).await?;
-            // log::debug!("$$$$ DiD SEND {:?}", message);
-            if message == PluginToMain::Quit {
+    // 要求の返事を待たずに次の要求を受け取る。返事と通知は出来た順に送る
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            pipe,
+            sender_to_loop,
+            mut receiver_from_loop,
+        } = self;
+        let (mut reader, mut writer) = tokio::io::split(pipe);
+
+        tokio::spawn(async move {
+            loop {
+                let envelope: Envelope<MainToPlugin> = match receive(&mut reader).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::debug!("$$$$ receive {e}");
+                        return;
+                    }
+                };
+                // log::debug!("$$$$ DID RECEIVE {:?}", envelope);
+                if sender_to_loop.send(envelope).is_err() {
+                    return;
+                }
+            }
+        });
+
+        while let Some(envelope) = receiver_from_loop.recv().await {
+            send(&mut writer, &envelope).await?;
+            // log::debug!("$$$$ DiD SEND {:?}", envelope);
+            if envelope.message == PluginToMain::Quit {
                 // log::debug!("$$$$ end Communicator run loop.");
                 return Ok(());
             }
         }
+        Ok(())
     }
 }
@@ -52,7 +52,7 @@ impl EventListInput {
             .unwrap_or(std::ptr::null())
     }
 
-    pub fn midi(&mut self, value: u8, time: u32) {
+    pub fn midi(&mut self, data: [u8; 3], time: u32) {
         let event = Box::new(clap_event_midi {
             header: clap_event_header {
                 size: size_of::<clap_event_note>() as u32,
@@ -62,7 +62,7 @@ impl EventListInput {
                 flags: 0,
             },
             port_index: 0,
-            data: [value, 0, 0],
+            data,
         });
         self.events
             .push(Box::into_raw(event) as *const clap_event_header);
@@ -240,8 +240,4 @@ impl EventListOutput {
         }
         true
     }
-
-    pub fn clear(&mut self) {
-        self.events.clear();
-    }
 }
@@ -1,28 +1,20 @@
 use std::{path::Path, pin::Pin, sync::mpsc::Sender};
 
 use anyhow::Result;
+use clap_sys::id::clap_id;
 use common::{
+    ipc::{ProcessQuit, ProcessQuitter, ProcessResponder, ProcessResponse, ProcessWake},
     plugin::{description::Description, param::Param},
     process_data::ProcessData,
-    shmem::{
-        event_quit_name, event_request_name, event_response_name, open_shared_memory,
-        process_data_name,
-    },
-    str::to_pcstr,
-};
-use windows::Win32::{
-    Foundation::{HANDLE, WAIT_EVENT, WAIT_OBJECT_0},
-    Storage::FileSystem::SYNCHRONIZE,
-    System::Threading::{
-        CreateEventA, OpenEventA, SetEvent, WaitForMultipleObjects, EVENT_MODIFY_STATE, INFINITE,
-        SYNCHRONIZATION_ACCESS_RIGHTS,
-    },
+    protocol::{Envelope, PluginToMain},
+    shmem::{open_shared_memory, process_data_name},
 };
+use tokio::sync::mpsc::UnboundedSender;
 
-use crate::{manager::EVENT_QUIT_ALL_NAME, plugin::Plugin, plugin_ptr::PluginPtr};
+use crate::{plugin::Plugin, plugin_ptr::PluginPtr};
 
 pub struct Host {
-    event_quit: HANDLE,
+    process_quitter: ProcessQuitter,
     pub plugin: Pin<Box<Plugin>>,
 }
 
@@ -31,15 +23,16 @@ impl Host {
         id: usize,
         description: &Description,
         sender: Sender<PluginPtr>,
+        sender_to_main: UnboundedSender<Envelope<PluginToMain>>,
         gui_open_p: bool,
         hwnd: isize,
+        nchannels: usize,
     ) -> Result<Self> {
-        let (event_quit_name, _x) = event_quit_name(id);
-        let event_quit =
-            unsafe { CreateEventA(None, false.into(), false.into(), event_quit_name)? };
+        let process_quitter = ProcessQuitter::create(id)?;
 
-        let mut plugin = Plugin::new(sender, hwnd);
+        let mut plugin = Plugin::new(id, sender, sender_to_main, hwnd);
         plugin.load(Path::new(&description.path), description.index);
+        plugin.audio_ports_config_select(nchannels)?;
         plugin.start()?;
         if gui_open_p {
             plugin.gui_open()?;
@@ -50,13 +43,24 @@ impl Host {
             process_loop(id, plugin_ptr).await.unwrap();
         });
 
-        Ok(Self { event_quit, plugin })
+        Ok(Self {
+            process_quitter,
+            plugin,
+        })
     }
 
     pub fn latency(&self) -> u32 {
         self.plugin.latency().unwrap_or(0)
     }
 
+    pub fn bypass_param_id(&mut self) -> Option<clap_id> {
+        self.plugin.bypass_param_id()
+    }
+
+    pub fn audio_output_names(&self) -> Vec<String> {
+        self.plugin.audio_output_names()
+    }
+
     pub fn load(&mut self, state: Vec<u8>) -> Result<()> {
         self.plugin.state_load(state)
     }
@@ -66,8 +70,7 @@ impl Host {
     }
 
     pub fn unload(&mut self) -> Result<()> {
-        unsafe { SetEvent(self.event_quit) }?;
-        Ok(())
+        self.process_quitter.quit()
     }
 
     pub fn save(&mut self) -> Result<Vec<u8>> {
@@ -77,51 +80,19 @@ impl Host {
 
 async fn process_loop(id: usize, plugin_ptr: PluginPtr) -> Result<()> {
     let shmem = open_shared_memory::<ProcessData>(&process_data_name(id))?;
-    let process_data: &mut ProcessData = unsafe { &mut *(shmem.as_ptr() as *mut ProcessData) };
-
-    let (event_name, _x) = event_request_name(id);
-    let event_request = unsafe {
-        OpenEventA(
-            EVENT_MODIFY_STATE | SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE.0),
-            false,
-            event_name,
-        )?
-    };
-
-    let (event_quit_name, _x) = event_quit_name(id);
-    let event_quit = unsafe {
-        OpenEventA(
-            SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE.0),
-            false,
-            event_quit_name,
-        )?
-    };
-
-    let (event_quit_all_name, _x) = to_pcstr(EVENT_QUIT_ALL_NAME)?;
-    let event_quit_all = unsafe {
-        OpenEventA(
-            SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE.0),
-            false,
-            event_quit_all_name,
-        )?
-    };
-
-    let events_wait = [event_request, event_quit, event_quit_all];
-
-    let (event_name, _x) = event_response_name(id);
-    let event_response = unsafe { OpenEventA(EVENT_MODIFY_STATE, false, event_name)? };
+    let process_data_ptr = shmem.as_ptr() as *mut ProcessData;
+    let process_data: &mut ProcessData = unsafe { &mut *process_data_ptr };
+
+    let process_responder = ProcessResponder::open(id, process_data_ptr)?;
 
     let plugin = unsafe { plugin_ptr.as_mut() };
     loop {
-        // log::debug!("$$$$ host will WaitForSingleObject process request");
-        let event = unsafe { WaitForMultipleObjects(&events_wait, false.into(), INFINITE) };
-        if event == WAIT_OBJECT_0 {
-            plugin.process(process_data)?;
-            unsafe { SetEvent(event_response) }?;
-        } else if event == WAIT_EVENT(1) || event == WAIT_EVENT(2) {
-            return Ok(());
-        } else {
-            return Err(anyhow::anyhow!("WaitForMultipleObjects failed"));
+        match process_responder.wait()? {
+            ProcessWake::Process => {
+                plugin.process(process_data)?;
+                process_responder.respond()?;
+            }
+            ProcessWake::Quit => return Ok(()),
         }
     }
 }
@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    path::PathBuf,
     sync::mpsc::{channel, Receiver, Sender},
     thread::{self, sleep},
     time::Duration,
@@ -8,55 +9,51 @@ use std::{
 use anyhow::Result;
 use common::{
     clap_manager::ClapManager,
-    protocol::{MainToPlugin, PluginToMain},
-    str::to_pcstr,
+    protocol::{Envelope, MainToPlugin, PluginToMain, RequestId},
 };
+use tokio::sync::mpsc::UnboundedSender;
+#[cfg(windows)]
 use windows::Win32::{
-    Foundation::{HANDLE, LPARAM, WPARAM},
-    System::Threading::{CreateEventA, SetEvent},
+    Foundation::{LPARAM, WPARAM},
+    System::Threading::GetCurrentThreadId,
     UI::WindowsAndMessaging::{
-        DispatchMessageW, PeekMessageW, PostThreadMessageW, TranslateMessage, MSG, WM_NULL,
+        DispatchMessageW, PeekMessageW, PostThreadMessageW, TranslateMessage, MSG, PM_REMOVE,
+        WM_NULL,
     },
 };
-use windows::Win32::{System::Threading::GetCurrentThreadId, UI::WindowsAndMessaging::PM_REMOVE};
 
 use crate::{host::Host, plugin_ptr::PluginPtr};
 
+// スキャン中のプラグインファイル
+struct Scan {
+    request_ids: Vec<RequestId>,
+    clap_manager: ClapManager,
+    paths: Vec<PathBuf>,
+}
+
 pub struct Manager {
-    sender_to_loop: Sender<PluginToMain>,
-    receiver_from_loop: Receiver<MainToPlugin>,
+    sender_to_main: UnboundedSender<Envelope<PluginToMain>>,
+    receiver_from_main: Receiver<Envelope<MainToPlugin>>,
     sender_from_plugin: Sender<PluginPtr>,
     receiver_from_plugin: Receiver<PluginPtr>,
-    event_quit_all: HANDLE,
+    scan: Option<Scan>,
     hosts: HashMap<usize, Host>,
     clap_manager: ClapManager,
     hwnd: isize,
 }
 
-pub const EVENT_QUIT_ALL_NAME: &str = "SingLikeCoding.Plugin.Quit.All";
-
 impl Manager {
     pub fn new(
-        sender_to_loop: Sender<PluginToMain>,
-        receiver_from_loop: Receiver<MainToPlugin>,
+        sender_to_main: UnboundedSender<Envelope<PluginToMain>>,
+        receiver_from_main: Receiver<Envelope<MainToPlugin>>,
     ) -> anyhow::Result<Self> {
         let (sender_from_plugin, receiver_from_plugin) = channel();
-        let (event_quit_all_name, _x) = to_pcstr(EVENT_QUIT_ALL_NAME)?;
-        let event_quit_all = unsafe {
-            CreateEventA(
-                None,
-                true.into(),  // 手動リセット
-                false.into(), // 初期非シグナル
-                event_quit_all_name,
-            )?
-        };
-
         Ok(Self {
-            sender_to_loop,
-            receiver_from_loop,
+            sender_to_main,
+            receiver_from_main,
             sender_from_plugin,
             receiver_from_plugin,
-            event_quit_all,
+            scan: None,
             hosts: Default::default(),
             clap_manager: ClapManager::new(),
             hwnd: 0,
@@ -65,40 +62,52 @@ impl Manager {
 
     pub fn run(&mut self) -> Result<()> {
         // 最初は窓が一つもないために、これがないと PeekMessageW がエラーになる
+        #[cfg(windows)]
         unsafe { PostThreadMessageW(GetCurrentThreadId(), WM_NULL, WPARAM(0), LPARAM(0)) }?;
+        #[cfg(windows)]
         let mut win_msg = MSG::default();
         loop {
-            if let Ok(message) = self.receiver_from_loop.try_recv() {
+            if let Ok(Envelope {
+                request_id,
+                message,
+            }) = self.receiver_from_main.try_recv()
+            {
                 match message {
                     MainToPlugin::Hwnd(hwnd) => {
                         self.hwnd = hwnd;
-                        self.sender_to_loop.send(PluginToMain::DidHwnd)?;
+                        self.reply(request_id, PluginToMain::DidHwnd)?;
                     }
-                    MainToPlugin::Load(id, clap_id, gui_open_p, state) => {
+                    MainToPlugin::Load(id, clap_id, gui_open_p, state, nchannels, _isolated_p) => {
                         log::debug!("will load {id}");
                         let description = self.clap_manager.description(&clap_id).unwrap();
                         let mut host = Host::new(
                             id,
                             description,
                             self.sender_from_plugin.clone(),
+                            self.sender_to_main.clone(),
                             gui_open_p,
                             self.hwnd,
+                            nchannels,
                         )?;
                         let latency = host.latency();
+                        let bypass_param_id = host.bypass_param_id();
+                        let audio_output_names = host.audio_output_names();
                         if let Some(state) = state {
                             host.load(state)?;
                         }
                         self.hosts.insert(id, host);
 
-                        self.sender_to_loop
-                            .send(PluginToMain::DidLoad(id, latency))?;
+                        self.reply(
+                            request_id,
+                            PluginToMain::DidLoad(id, latency, bypass_param_id, audio_output_names),
+                        )?;
                     }
                     MainToPlugin::Unload(id) => {
                         if let Some(host) = self.host(id) {
                             host.unload()?;
                             self.hosts.remove(&id);
                         }
-                        self.sender_to_loop.send(PluginToMain::DidUnload(id))?;
+                        self.reply(request_id, PluginToMain::DidUnload(id))?;
                     }
                     MainToPlugin::GuiOpen(id) => {
                         if let Some(host) = self.host(id) {
@@ -108,20 +117,20 @@ impl Manager {
                                 host.plugin.gui_open()?;
                             }
                         }
-                        self.sender_to_loop.send(PluginToMain::DidGuiOpen)?;
+                        self.reply(request_id, PluginToMain::DidGuiOpen)?;
                     }
                     MainToPlugin::Params(id) => {
                         let mut params = vec![];
                         if let Some(host) = self.host(id) {
                             params = host.params()?;
                         }
-                        self.sender_to_loop.send(PluginToMain::DidParams(params))?;
+                        self.reply(request_id, PluginToMain::DidParams(params))?;
                     }
                     MainToPlugin::StateLoad(id, state) => {
                         if let Some(host) = self.host(id) {
                             host.load(state)?;
                         }
-                        self.sender_to_loop.send(PluginToMain::DidStateLoad)?;
+                        self.reply(request_id, PluginToMain::DidStateLoad)?;
                     }
                     MainToPlugin::StateSave(id) => {
                         let state = if let Some(host) = self.host(id) {
@@ -129,25 +138,44 @@ impl Manager {
                         } else {
                             vec![]
                         };
-                        self.sender_to_loop
-                            .send(PluginToMain::DidStateSave(id, state))?;
+                        self.reply(request_id, PluginToMain::DidStateSave(id, state))?;
+                    }
+                    MainToPlugin::Channels(nchannels) => {
+                        for (id, host) in self.hosts.iter_mut() {
+                            if let Err(e) = host.plugin.audio_ports_config_select(nchannels) {
+                                log::warn!("audio_ports_config_select {id} {e}");
+                            }
+                        }
+                        self.reply(request_id, PluginToMain::DidChannels)?;
                     }
                     MainToPlugin::Scan => {
-                        log::debug!("clap_manager.scan() start...");
-                        self.clap_manager.scan();
-                        log::debug!("clap_manager.scan() end");
-                        self.sender_to_loop.send(PluginToMain::DidScan)?;
+                        if let Some(scan) = self.scan.as_mut() {
+                            scan.request_ids.push(request_id);
+                        } else {
+                            log::debug!("clap_manager.scan() start...");
+                            let mut clap_manager = ClapManager::new();
+                            let paths = clap_manager.scan_begin();
+                            self.scan = Some(Scan {
+                                request_ids: vec![request_id],
+                                clap_manager,
+                                paths,
+                            });
+                        }
                     }
                     MainToPlugin::Quit => {
                         log::debug!("$$$$ quit");
-                        self.sender_to_loop.send(PluginToMain::Quit)?;
-                        unsafe { SetEvent(self.event_quit_all) }?;
+                        self.reply(request_id, PluginToMain::Quit)?;
+                        for host in self.hosts.values_mut() {
+                            host.unload()?;
+                        }
                         sleep(Duration::from_millis(1000));
                         return Ok(());
                     }
                 }
             }
 
+            self.scan_step()?;
+
             if let Ok(plugin_ptr) = self.receiver_from_plugin.try_recv() {
                 let plugin = unsafe { plugin_ptr.as_mut() };
                 let plugin = unsafe { &*plugin.plugin };
@@ -156,6 +184,7 @@ impl Manager {
                 log::debug!("did on_main_thread");
             }
 
+            #[cfg(windows)]
             unsafe {
                 while PeekMessageW(&mut win_msg, None, 0, 0, PM_REMOVE).as_bool() {
                     let _ = TranslateMessage(&win_msg);
@@ -169,6 +198,32 @@ impl Manager {
         }
     }
 
+    // 要求と同じ request_id で返す
+    fn reply(&self, request_id: RequestId, message: PluginToMain) -> Result<()> {
+        self.sender_to_main
+            .send(Envelope::new(request_id, message))?;
+        Ok(())
+    }
+
+    // 終わるまで他の要求を待たせないように 1 ループで 1 ファイルずつ
+    fn scan_step(&mut self) -> Result<()> {
+        let Some(scan) = self.scan.as_mut() else {
+            return Ok(());
+        };
+        if let Some(path) = scan.paths.pop() {
+            scan.clap_manager.scan_file(&path);
+            return Ok(());
+        }
+        let mut scan = self.scan.take().unwrap();
+        scan.clap_manager.scan_end();
+        log::debug!("clap_manager.scan() end");
+        self.clap_manager = scan.clap_manager;
+        for request_id in scan.request_ids {
+            self.reply(request_id, PluginToMain::DidScan)?;
+        }
+        Ok(())
+    }
+
     fn host(&mut self, id: usize) -> Option<&mut Host> {
         self.hosts.get_mut(&id)
     }
@@ -8,6 +8,10 @@ use std::{
 };
 
 use anyhow::Result;
+#[cfg(windows)]
+use clap_sys::ext::gui::CLAP_WINDOW_API_WIN32;
+#[cfg(not(windows))]
+use clap_sys::ext::gui::CLAP_WINDOW_API_X11;
 use clap_sys::{
     audio_buffer::clap_audio_buffer,
     entry::clap_plugin_entry,
@@ -20,12 +24,12 @@ use clap_sys::{
     ext::{
         audio_ports::{
             clap_audio_port_info, clap_host_audio_ports, clap_plugin_audio_ports,
-            CLAP_EXT_AUDIO_PORTS,
+            CLAP_AUDIO_PORT_SUPPORTS_64BITS, CLAP_EXT_AUDIO_PORTS,
         },
-        gui::{
-            clap_host_gui, clap_plugin_gui, clap_window, clap_window_handle, CLAP_EXT_GUI,
-            CLAP_WINDOW_API_WIN32,
+        audio_ports_config::{
+            clap_audio_ports_config, clap_plugin_audio_ports_config, CLAP_EXT_AUDIO_PORTS_CONFIG,
         },
+        gui::{clap_host_gui, clap_plugin_gui, clap_window, clap_window_handle, CLAP_EXT_GUI},
         latency::{clap_host_latency, clap_plugin_latency, CLAP_EXT_LATENCY},
         log::{
             clap_host_log, clap_log_severity, CLAP_EXT_LOG, CLAP_LOG_DEBUG, CLAP_LOG_ERROR,
@@ -33,7 +37,7 @@ use clap_sys::{
         },
         params::{
             clap_host_params, clap_param_clear_flags, clap_param_info, clap_param_rescan_flags,
-            clap_plugin_params, CLAP_EXT_PARAMS,
+            clap_plugin_params, CLAP_EXT_PARAMS, CLAP_PARAM_IS_BYPASS,
         },
         state::{clap_plugin_state, CLAP_EXT_STATE},
     },
@@ -49,11 +53,17 @@ use clap_sys::{
 };
 use common::{
     cstr,
+    module::ModuleId,
     plugin::param::Param,
-    process_data::{EventKind, ProcessData, MAX_PORTS},
+    process_data::{
+        EventKind, ProcessData, MAX_BUFFER_CHANNELS, MAX_EVENTS, MAX_FRAMES, MAX_PORTS,
+    },
+    protocol::{Envelope, LogLevel, PluginToMain},
 };
 use libloading::{Library, Symbol};
 use stream::{IStream, OStream};
+use tokio::sync::mpsc::UnboundedSender;
+#[cfg(windows)]
 use window::{create_handler, destroy_handler, resize};
 
 use crate::{
@@ -62,25 +72,38 @@ use crate::{
 };
 
 mod stream;
+#[cfg(windows)]
 mod window;
 
 pub struct Plugin {
+    id: ModuleId,
     clap_host: clap_host,
     lib: Option<Library>,
     pub plugin: *const clap_plugin,
     ext_audio_ports: Option<*const clap_plugin_audio_ports>,
+    ext_audio_ports_config: Option<*const clap_plugin_audio_ports_config>,
     ext_gui: Option<*const clap_plugin_gui>,
     ext_latency: Option<*const clap_plugin_latency>,
     ext_params: Option<*const clap_plugin_params>,
     ext_state: Option<*const clap_plugin_state>,
     pub gui_open_p: bool,
+    #[cfg(windows)]
     window_handler: Option<*mut c_void>,
     process_start_p: bool,
     sender_to_view: Sender<PluginPtr>,
+    sender_to_main: UnboundedSender<Envelope<PluginToMain>>,
     audio_port_info_inputs: Vec<clap_audio_port_info>,
     audio_port_info_outputs: Vec<clap_audio_port_info>,
+    // 共有メモリのバッファに入りきらないチャンネル用。入力は無音で出力は捨てる
+    channel_overflow_in: Vec<f64>,
+    channel_overflow_out: Vec<f64>,
+    // ProcessData は f64 なので、32 ビットで処理するポートの変換用 [port][channel][frame]
+    buffers32_in: Vec<Vec<Vec<f32>>>,
+    buffers32_out: Vec<Vec<Vec<f32>>>,
     event_list_input: Pin<Box<EventListInput>>,
     event_list_output: Pin<Box<EventListOutput>>,
+    // ProcessData に入りきらなかった出力イベント。次のブロックの頭で渡す
+    events_output_pending: Vec<common::event::Event>,
     host_audio_ports: clap_host_audio_ports,
     host_gui: clap_host_gui,
     host_latency: clap_host_latency,
@@ -93,13 +116,21 @@ pub struct Plugin {
     play_p: bool,
 }
 
+// 出力イベントを持ち越すのはここまで。ホストが受け取れないほど出し続けるなら落とす
+const EVENTS_OUTPUT_PENDING_MAX: usize = MAX_EVENTS * 8;
+
 pub const NAME: &CStr = cstr!("Sing Like Coding");
 pub const VENDER: &CStr = cstr!("Sing Like Coding");
 pub const URL: &CStr = cstr!("https://github.com/quek/sing_like_coding");
 pub const VERSION: &CStr = cstr!("0.0.1");
 
 impl Plugin {
-    pub fn new(sender_to_view: Sender<PluginPtr>, hwnd: isize) -> Pin<Box<Self>> {
+    pub fn new(
+        id: ModuleId,
+        sender_to_view: Sender<PluginPtr>,
+        sender_to_main: UnboundedSender<Envelope<PluginToMain>>,
+        hwnd: isize,
+    ) -> Pin<Box<Self>> {
         let clap_host = clap_host {
             clap_version: CLAP_VERSION,
             host_data: null_mut::<c_void>(),
@@ -141,22 +172,31 @@ impl Plugin {
         };
 
         let mut this = Box::pin(Self {
+            id,
             clap_host,
             lib: None,
             plugin: null(),
             ext_audio_ports: None,
+            ext_audio_ports_config: None,
             ext_gui: None,
             ext_latency: None,
             ext_params: None,
             ext_state: None,
             gui_open_p: false,
+            #[cfg(windows)]
             window_handler: None,
             process_start_p: false,
             sender_to_view,
+            sender_to_main,
             audio_port_info_inputs: vec![],
             audio_port_info_outputs: vec![],
+            channel_overflow_in: vec![0.0; MAX_FRAMES],
+            channel_overflow_out: vec![0.0; MAX_FRAMES],
+            buffers32_in: vec![],
+            buffers32_out: vec![],
             event_list_input: EventListInput::new(),
             event_list_output: EventListOutput::new(),
+            events_output_pending: vec![],
             host_audio_ports,
             host_gui,
             host_latency,
@@ -191,6 +231,7 @@ impl Plugin {
         log::debug!("gui_resize_hints_changed");
     }
 
+    #[cfg(windows)]
     unsafe extern "C" fn gui_request_resize(
         host: *const clap_host,
         width: u32,
@@ -203,6 +244,16 @@ impl Plugin {
         true
     }
 
+    // フローティングの窓はプラグインが自分でリサイズする
+    #[cfg(not(windows))]
+    unsafe extern "C" fn gui_request_resize(
+        _host: *const clap_host,
+        _width: u32,
+        _height: u32,
+    ) -> bool {
+        true
+    }
+
     unsafe extern "C" fn gui_request_show(_host: *const clap_host) -> bool {
         log::debug!("gui_request_show");
         true
@@ -213,34 +264,54 @@ impl Plugin {
         true
     }
 
-    unsafe extern "C" fn gui_closed(_host: *const clap_host, _was_destroyed: bool) {
+    unsafe extern "C" fn gui_closed(host: *const clap_host, was_destroyed: bool) {
         log::debug!("gui_closed");
+        let this = unsafe { &mut *((*host).host_data as *mut Self) };
+        // 壊されていなければ次に閉じるときに destroy する
+        if was_destroyed {
+            this.gui_open_p = false;
+        }
+        this.notify(PluginToMain::GuiClosed(this.id));
     }
 
-    unsafe extern "C" fn latency_changed(_host: *const clap_host) {
+    unsafe extern "C" fn latency_changed(host: *const clap_host) {
         log::debug!("latency_changed");
+        let this = unsafe { &mut *((*host).host_data as *mut Self) };
+        let latency = this.latency().unwrap_or(0);
+        this.notify(PluginToMain::LatencyChanged(this.id, latency));
     }
 
     unsafe extern "C" fn log_log(
-        _host: *const clap_host,
+        host: *const clap_host,
         severity: clap_log_severity,
         msg: *const c_char,
     ) {
         let msg = unsafe { CStr::from_ptr(msg) };
 
-        match severity {
-            CLAP_LOG_DEBUG => log::debug!("{:?}", msg),
-            CLAP_LOG_INFO => log::info!("{:?}", msg),
-            CLAP_LOG_WARNING => log::warn!("{:?}", msg),
-            CLAP_LOG_ERROR => log::error!("{:?}", msg),
-            _ => log::debug!("severity {severity} {:?}", msg),
-        }
+        let level = match severity {
+            CLAP_LOG_DEBUG => LogLevel::Debug,
+            CLAP_LOG_INFO => LogLevel::Info,
+            CLAP_LOG_WARNING => LogLevel::Warning,
+            CLAP_LOG_ERROR => LogLevel::Error,
+            _ => {
+                log::debug!("severity {severity} {:?}", msg);
+                LogLevel::Debug
+            }
+        };
+        log::log!(log::Level::from(level), "{:?}", msg);
+        let this = unsafe { &*((*host).host_data as *const Self) };
+        this.notify(PluginToMain::Log(
+            this.id,
+            level,
+            msg.to_string_lossy().into_owned(),
+        ));
     }
 
     unsafe extern "C" fn params_rescan(host: *const clap_host, _flags: clap_param_rescan_flags) {
         log::debug!("params_rescan start");
         let this = unsafe { &mut *((*host).host_data as *mut Self) };
         let _ = this.params();
+        this.notify(PluginToMain::ParamsRescan(this.id));
         log::debug!("params_rescan end");
     }
 
@@ -269,11 +340,11 @@ impl Plugin {
         log::debug!("request_process");
     }
 
+    // どのスレッドから呼ばれるかわからないので、メイン側で読み込み直してもらう
     unsafe extern "C" fn request_restart(host: *const clap_host) {
         log::debug!("request_restart");
-        let this = unsafe { &mut *((*host).host_data as *mut Self) };
-        this.stop().unwrap();
-        this.start().unwrap();
+        let this = unsafe { &*((*host).host_data as *const Self) };
+        this.notify(PluginToMain::RestartRequested(this.id));
     }
 
     unsafe extern "C" fn get_extension(host: *const clap_host, id: *const c_char) -> *const c_void {
@@ -366,6 +437,13 @@ impl Plugin {
                 self.ext_audio_ports = Some(audio_ports);
             }
 
+            let audio_ports_config =
+                (plugin.get_extension.unwrap())(plugin, CLAP_EXT_AUDIO_PORTS_CONFIG.as_ptr())
+                    as *const clap_plugin_audio_ports_config;
+            if !audio_ports_config.is_null() {
+                self.ext_audio_ports_config = Some(audio_ports_config);
+            }
+
             let gui = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_GUI.as_ptr())
                 as *const clap_plugin_gui;
             if !gui.is_null() {
@@ -451,9 +529,91 @@ impl Plugin {
                 MAX_PORTS
             );
         }
+        for infos in [&self.audio_port_info_inputs, &self.audio_port_info_outputs] {
+            let nchannels = infos
+                .iter()
+                .map(|info| info.channel_count as usize)
+                .sum::<usize>();
+            if nchannels > MAX_BUFFER_CHANNELS {
+                log::warn!(
+                    "channel_count total {} > MAX_BUFFER_CHANNELS {}",
+                    nchannels,
+                    MAX_BUFFER_CHANNELS
+                );
+            }
+        }
+        for (buffers32, infos) in [
+            (&mut self.buffers32_in, &self.audio_port_info_inputs),
+            (&mut self.buffers32_out, &self.audio_port_info_outputs),
+        ] {
+            *buffers32 = infos
+                .iter()
+                .map(|info| vec![vec![0.0; MAX_FRAMES]; info.channel_count as usize])
+                .collect();
+        }
         Ok(())
     }
 
+    // メインの出力が曲のチャンネル数と同じ設定があればそれにする
+    pub fn audio_ports_config_select(&mut self, nchannels: usize) -> Result<()> {
+        let Some(ext_audio_ports_config) = self.ext_audio_ports_config else {
+            return Ok(());
+        };
+        unsafe {
+            let ext_audio_ports_config = &*ext_audio_ports_config;
+            let (Some(count), Some(get), Some(select)) = (
+                ext_audio_ports_config.count,
+                ext_audio_ports_config.get,
+                ext_audio_ports_config.select,
+            ) else {
+                return Ok(());
+            };
+            let mut config_id = None;
+            for i in 0..count(self.plugin) {
+                let mut config = std::mem::zeroed::<clap_audio_ports_config>();
+                if get(self.plugin, i, &mut config)
+                    && config.has_main_output
+                    && config.main_output_channel_count as usize == nchannels
+                {
+                    log::debug!(
+                        "audio ports config {} {}",
+                        config.id,
+                        CStr::from_ptr(config.name.as_ptr()).to_string_lossy()
+                    );
+                    config_id = Some(config.id);
+                    break;
+                }
+            }
+            let Some(config_id) = config_id else {
+                return Ok(());
+            };
+
+            // 選ぶのは deactivate しているときだけ
+            let process_start_p = self.process_start_p;
+            self.stop()?;
+            if !select(self.plugin, config_id) {
+                log::warn!("Failed to select audio ports config {}", config_id);
+            }
+            self.audio_ports()?;
+            if process_start_p {
+                self.start()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn audio_output_names(&self) -> Vec<String> {
+        self.audio_port_info_outputs
+            .iter()
+            .take(MAX_PORTS)
+            .map(|info| {
+                unsafe { CStr::from_ptr(info.name.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
     pub fn gui_available(&self) -> bool {
         if self.ext_gui.is_none() {
             return false;
@@ -485,6 +645,34 @@ impl Plugin {
         log::debug!("gui_open did gui_available");
         let plugin = unsafe { &*self.plugin };
         let gui = unsafe { &*self.ext_gui.unwrap() };
+        #[cfg(not(windows))]
+        unsafe {
+            // Linux では埋め込まずにプラグイン自身の窓で開き、メインの窓に載せる
+            if !gui.is_api_supported.unwrap()(plugin, CLAP_WINDOW_API_X11.as_ptr(), true) {
+                log::debug!("GUI API not supported");
+                return Ok(());
+            }
+            if !gui.create.unwrap()(plugin, CLAP_WINDOW_API_X11.as_ptr(), true) {
+                log::warn!("GUI create failed");
+                return Ok(());
+            }
+            if self.hwnd != 0 {
+                gui.set_transient.unwrap()(
+                    plugin,
+                    &clap_window {
+                        api: CLAP_WINDOW_API_X11.as_ptr(),
+                        specific: clap_window_handle {
+                            x11: self.hwnd as _,
+                        },
+                    },
+                );
+            }
+            if !gui.show.unwrap()(plugin) {
+                log::debug!("GUI show failed");
+            }
+            self.gui_open_p = true;
+        }
+        #[cfg(windows)]
         unsafe {
             if !gui.is_api_supported.unwrap()(plugin, CLAP_WINDOW_API_WIN32.as_ptr(), false) {
                 log::debug!("GUI API not supported");
@@ -554,11 +742,14 @@ impl Plugin {
         unsafe {
             gui.hide.unwrap()(plugin);
             gui.destroy.unwrap()(plugin);
+            #[cfg(windows)]
             destroy_handler(self.window_handler.take().unwrap());
         }
         Ok(())
     }
 
+    // 埋め込み窓の WM_SIZE から呼ばれる
+    #[cfg(windows)]
     pub fn gui_size(&self, width: u32, height: u32) -> Result<()> {
         let gui = unsafe { &*self.ext_gui.unwrap() };
         unsafe { gui.set_size.unwrap()(self.plugin, width, height) };
@@ -573,6 +764,23 @@ impl Plugin {
         }
     }
 
+    // メイン側への通知。返事は待たない
+    fn notify(&self, message: PluginToMain) {
+        if let Err(e) = self.sender_to_main.send(Envelope::notification(message)) {
+            log::warn!("notify {e}");
+        }
+    }
+
+    // バイパスパラメータがあればホストのバイパスの代わりに使う
+    pub fn bypass_param_id(&mut self) -> Option<clap_id> {
+        self.ext_params?;
+        self.params()
+            .ok()?
+            .into_iter()
+            .find(|param| param.flags & CLAP_PARAM_IS_BYPASS != 0)
+            .map(|param| param.id)
+    }
+
     pub fn params(&mut self) -> Result<Vec<Param>> {
         unsafe {
             let plugin = &*self.plugin;
@@ -617,48 +825,118 @@ impl Plugin {
     }
 
     pub fn process(&mut self, context: &mut ProcessData) -> Result<()> {
-        context.nports_in = self.audio_port_info_inputs.len().min(MAX_PORTS);
-        context.nports_out = self.audio_port_info_outputs.len().min(MAX_PORTS);
-        for port in 0..context.nports_in {
-            context.nchannels_in[port] = self.audio_port_info_inputs[port].channel_count as usize;
-        }
-        for port in 0..context.nports_out {
-            context.nchannels_out[port] = self.audio_port_info_outputs[port].channel_count as usize;
+        // 入りきらなかった入力イベントを先に受け取るだけ
+        if context.events_only_p != 0 {
+            self.events_input_receive(context);
+            return Ok(());
         }
 
+        // ポートのチャンネル数はプラグインに合わせる
+        let nchannels_in = self
+            .audio_port_info_inputs
+            .iter()
+            .map(|info| info.channel_count as usize)
+            .collect::<Vec<_>>();
+        let nchannels_out = self
+            .audio_port_info_outputs
+            .iter()
+            .map(|info| info.channel_count as usize)
+            .collect::<Vec<_>>();
+        context.channels_set(&nchannels_in, &nchannels_out);
+        self.channel_overflow_in.fill(0.0);
+        let nframes = context.nframes;
+        // 64 ビットに対応していないポートは今まで通り 32 ビットで渡す
+        let process_64_p = context.process_64_p != 0;
+        let inputs_64_p = self
+            .audio_port_info_inputs
+            .iter()
+            .map(|info| process_64_p && (info.flags & CLAP_AUDIO_PORT_SUPPORTS_64BITS) != 0)
+            .collect::<Vec<_>>();
+        let outputs_64_p = self
+            .audio_port_info_outputs
+            .iter()
+            .map(|info| process_64_p && (info.flags & CLAP_AUDIO_PORT_SUPPORTS_64BITS) != 0)
+            .collect::<Vec<_>>();
+
         let mut audio_inputs = Vec::with_capacity(context.nports_in);
         let mut buffer_keeps = vec![];
-        for port in 0..context.nports_in {
+        let mut buffer64_keeps = vec![];
+        for (port, &input_64_p) in inputs_64_p.iter().enumerate().take(context.nports_in) {
+            let channel_count = self.audio_port_info_inputs[port].channel_count;
             let mut in_buffer = vec![];
-            for channel in 0..context.nchannels_in[port] {
-                in_buffer.push(context.buffer_in[port][channel].as_mut_ptr());
+            let mut in_buffer64 = vec![];
+            for channel in 0..channel_count as usize {
+                if input_64_p {
+                    if channel < context.nchannels_in[port] {
+                        in_buffer64.push(context.buffer_in_mut(port)[channel].as_mut_ptr());
+                    } else {
+                        in_buffer64.push(self.channel_overflow_in.as_mut_ptr());
+                    }
+                } else {
+                    let buffer32 = &mut self.buffers32_in[port][channel];
+                    if channel < context.nchannels_in[port] {
+                        let buffer = &context.buffer_in(port)[channel];
+                        for (x, y) in buffer32[..nframes].iter_mut().zip(&buffer[..nframes]) {
+                            *x = *y as f32;
+                        }
+                    } else {
+                        buffer32.fill(0.0);
+                    }
+                    in_buffer.push(buffer32.as_mut_ptr());
+                }
             }
             let audio_input = clap_audio_buffer {
-                data32: in_buffer.as_mut_ptr(),
-                data64: null_mut::<*mut f64>(),
-                channel_count: context.nchannels_in[port] as u32,
+                data32: if input_64_p {
+                    null_mut::<*mut f32>()
+                } else {
+                    in_buffer.as_mut_ptr()
+                },
+                data64: if input_64_p {
+                    in_buffer64.as_mut_ptr()
+                } else {
+                    null_mut::<*mut f64>()
+                },
+                channel_count,
                 latency: 0,
                 constant_mask: context.constant_mask_in[port],
             };
             audio_inputs.push(audio_input);
             buffer_keeps.push(in_buffer);
+            buffer64_keeps.push(in_buffer64);
         }
 
         let mut audio_outputs = Vec::with_capacity(context.nports_out);
-        for port in 0..context.nports_out {
+        for (port, &output_64_p) in outputs_64_p.iter().enumerate().take(context.nports_out) {
+            let channel_count = self.audio_port_info_outputs[port].channel_count;
             let mut out_buffer = vec![];
-            for channel in 0..context.nchannels_out[port] {
-                out_buffer.push(context.buffer_out[port][channel].as_mut_ptr());
+            let mut out_buffer64 = vec![];
+            for channel in 0..channel_count as usize {
+                if !output_64_p {
+                    out_buffer.push(self.buffers32_out[port][channel].as_mut_ptr());
+                } else if channel < context.nchannels_out[port] {
+                    out_buffer64.push(context.buffer_out_mut(port)[channel].as_mut_ptr());
+                } else {
+                    out_buffer64.push(self.channel_overflow_out.as_mut_ptr());
+                }
             }
             let audio_output = clap_audio_buffer {
-                data32: out_buffer.as_mut_ptr(),
-                data64: null_mut::<*mut f64>(),
-                channel_count: context.nchannels_out[port] as u32,
+                data32: if output_64_p {
+                    null_mut::<*mut f32>()
+                } else {
+                    out_buffer.as_mut_ptr()
+                },
+                data64: if output_64_p {
+                    out_buffer64.as_mut_ptr()
+                } else {
+                    null_mut::<*mut f64>()
+                },
+                channel_count,
                 latency: 0,
                 constant_mask: 0,
             };
             audio_outputs.push(audio_output);
             buffer_keeps.push(out_buffer);
+            buffer64_keeps.push(out_buffer64);
         }
 
         let mut transport_flags = CLAP_TRANSPORT_HAS_TEMPO
@@ -690,48 +968,25 @@ impl Plugin {
             loop_end_seconds: context.loop_end_seconds,
             bar_start: context.bar_start,
             bar_number: context.bar_number,
-            tsig_num: 4,
-            tsig_denom: 4,
+            tsig_num: context.tsig_num,
+            tsig_denom: context.tsig_denom,
         };
 
-        let samples_per_delay =
-            (context.sample_rate * 60.0) / (context.bpm * context.lpb as f64 * 256.0);
-        self.event_list_output.samples_per_delay = samples_per_delay;
-
-        for i in 0..context.nevents_input {
-            let event = &context.events_input[i];
-            let delay = (event.delay as f64 * samples_per_delay).round() as u32;
-            match &event.kind {
-                EventKind::NoteOn => {
-                    self.event_list_input
-                        .note_on(event.key, event.channel, event.velocity, delay)
-                }
-                EventKind::NoteOff => {
-                    self.event_list_input
-                        .note_off(event.key, event.channel, event.velocity, delay)
-                }
-                EventKind::ParamValue => {
-                    if self.params.contains_key(&event.param_id) {
-                        self.event_list_input
-                            .param_value(event.param_id, event.value, delay);
-                    }
-                }
-            }
-        }
+        self.events_input_receive(context);
 
         {
             if !self.play_p && context.play_p == 1 {
                 self.next_clock_sample = 0.0;
-                self.event_list_input.midi(0xFA, 0);
+                self.event_list_input.midi([0xFA, 0, 0], 0);
             } else if self.play_p && context.play_p == 0 {
-                self.event_list_input.midi(0xFC, 0);
+                self.event_list_input.midi([0xFC, 0, 0], 0);
             }
             self.play_p = context.play_p == 1;
             if self.play_p {
                 let samples_per_clock = context.sample_rate / ((context.bpm / 60.0) * 24.0);
                 while self.next_clock_sample < context.nframes as f64 {
                     let frame = self.next_clock_sample as u32;
-                    self.event_list_input.midi(0xF8, frame);
+                    self.event_list_input.midi([0xF8, 0, 0], frame);
                     self.next_clock_sample += samples_per_clock;
                 }
                 self.next_clock_sample -= context.nframes as f64;
@@ -773,28 +1028,88 @@ impl Plugin {
         // 書き戻す
         for (port, out_buf) in audio_outputs.iter().enumerate() {
             context.constant_mask_out[port] = out_buf.constant_mask;
+            if !outputs_64_p[port] {
+                let buffers32 = &self.buffers32_out[port];
+                for (buffer, buffer32) in context.buffer_out_mut(port).iter_mut().zip(buffers32) {
+                    for (x, y) in buffer[..nframes].iter_mut().zip(&buffer32[..nframes]) {
+                        *x = *y as f64;
+                    }
+                }
+            }
         }
 
         self.event_list_input.clear();
+        self.events_output_send(context);
+
+        Ok(())
+    }
+
+    fn events_input_receive(&mut self, context: &ProcessData) {
+        let samples_per_delay =
+            (context.sample_rate * 60.0) / (context.bpm * context.lpb as f64 * 256.0);
+        self.event_list_output.samples_per_delay = samples_per_delay;
+
+        for i in 0..context.nevents_input {
+            let event = &context.events_input[i];
+            let delay = (event.delay as f64 * samples_per_delay).round() as u32;
+            match &event.kind {
+                EventKind::NoteOn => {
+                    self.event_list_input
+                        .note_on(event.key, event.channel, event.velocity, delay)
+                }
+                EventKind::NoteOff => {
+                    self.event_list_input
+                        .note_off(event.key, event.channel, event.velocity, delay)
+                }
+                EventKind::ParamValue => {
+                    if self.params.contains_key(&event.param_id) {
+                        self.event_list_input
+                            .param_value(event.param_id, event.value, delay);
+                    }
+                }
+                EventKind::Midi => self.event_list_input.midi(event.midi, delay),
+            }
+        }
+    }
 
-        for event in self.event_list_output.events.iter() {
+    // 前のブロックで入りきらなかったものは delay 0 で先に渡す
+    fn events_output_send(&mut self, context: &mut ProcessData) {
+        let pending = std::mem::take(&mut self.events_output_pending);
+        let events = pending
+            .into_iter()
+            .map(|event| match event {
+                common::event::Event::NoteOn(key, velocity, _) => {
+                    common::event::Event::NoteOn(key, velocity, 0)
+                }
+                common::event::Event::NoteOff(key, _) => common::event::Event::NoteOff(key, 0),
+                common::event::Event::ParamValue(module_index, param_id, value, _) => {
+                    common::event::Event::ParamValue(module_index, param_id, value, 0)
+                }
+                event => event,
+            })
+            .chain(self.event_list_output.events.drain(..));
+        for event in events {
+            if context.events_output_full_p() {
+                if self.events_output_pending.len() < EVENTS_OUTPUT_PENDING_MAX {
+                    self.events_output_pending.push(event);
+                } else {
+                    context.nevents_dropped_output += 1;
+                }
+                continue;
+            }
             match event {
                 common::event::Event::NoteOn(key, velocity, delay) => {
-                    context.output_note_on(*key, *velocity, 0, *delay);
+                    context.output_note_on(key, velocity, 0, delay);
                 }
                 common::event::Event::NoteOff(key, delay) => {
-                    context.output_note_off(*key, 0, *delay);
+                    context.output_note_off(key, 0, delay);
                 }
                 common::event::Event::NoteAllOff => { /* 無視 */ }
                 common::event::Event::ParamValue(_, param_id, value, delay) => {
-                    context.output_param_value(*param_id, *value, *delay);
+                    context.output_param_value(param_id, value, delay);
                 }
             }
         }
-
-        self.event_list_output.clear();
-
-        Ok(())
     }
 
     pub fn start(&mut self) -> Result<()> {